@@ -1,8 +1,8 @@
 use super::*;
 use crate::paging::error::PagingError;
 use crate::paging::table::{
-    PagingLevel, PagingStruct, PagingStructEntry, ALL_FLAGS, PD, PDPT, PML4, PRESENT_FLAG, PS_FLAG,
-    PT, RW_FLAG,
+    PagingLevel, PagingStruct, PagingStructEntry, ALL_FLAGS, PAT_FLAG, PD, PDPT, PML4,
+    PRESENT_FLAG, PS_FLAG, PT, RW_FLAG,
 };
 use alloc::collections::{BTreeMap, BTreeSet};
 use core::ptr;
@@ -38,6 +38,16 @@ impl<'a> From<&LeafEntry> for &'a mut PagingStructEntry {
     }
 }
 
+/// The leaf mapping of a virtual address, as returned by `Mapper::mapping`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Mapping {
+    /// Physical address of the mapped page.
+    pub phys_addr: usize,
+    pub page_size: PageSize,
+    /// Flags of the leaf paging structure entry.
+    pub flags: usize,
+}
+
 // TODO: make this a trait if we support architectures other than x86_64.
 pub struct Mapper<E>
 where
@@ -252,6 +262,19 @@ impl<E: Environment> Mapper<E> {
         self.map(phys_addr, virt_addr);
     }
 
+    /// Removes a mapping made with `map_mmio()`.
+    pub fn unmap_mmio(&mut self, phys_addr: usize) -> Result<(), PagingError> {
+        self.unmap(phys_addr + MMIO_BASE)?;
+        self.environment.flush_tlb();
+        Ok(())
+    }
+
+    /// Gives the physical pages in `start..start + size` to the page allocator. They must not be
+    /// mapped or used anymore.
+    pub fn free_pages(&mut self, start: usize, size: usize) {
+        self.page_allocator.add_free(start, size);
+    }
+
     pub fn phys_addr(&self, virt_addr: usize) -> Option<usize> {
         let pml4 = self.environment.paging_structure_base() as *mut PagingStruct;
         let leaf = self.walk_to_leaf(pml4, virt_addr)?;
@@ -262,6 +285,27 @@ impl<E: Environment> Mapper<E> {
         }
     }
 
+    /// Returns the page that `virt_addr` is mapped to, with the flags of the leaf entry.
+    pub fn mapping(&self, virt_addr: usize) -> Option<Mapping> {
+        let pml4 = self.environment.paging_structure_base() as *mut PagingStruct;
+        let leaf = self.walk_to_leaf(pml4, virt_addr)?;
+        let entry: &mut PagingStructEntry = (&leaf).into();
+        // Bit 12 is part of the address in entries that map 4KiB pages.
+        let flags = match leaf.page_size {
+            PageSize::Normal => ALL_FLAGS & !PAT_FLAG,
+            _ => ALL_FLAGS,
+        };
+        Some(Mapping {
+            phys_addr: leaf.phys_addr,
+            page_size: leaf.page_size,
+            flags: entry.get_flags(flags),
+        })
+    }
+
+    pub fn page_allocator(&self) -> &PageAllocator {
+        &self.page_allocator
+    }
+
     pub fn fork(&mut self, paging_struct_base: *mut PagingStruct) -> usize {
         let src_pml4 = paging_struct_base;
         let dst_pml4 = self.new_table();
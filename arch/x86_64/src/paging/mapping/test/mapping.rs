@@ -0,0 +1,67 @@
+use super::*;
+use paging_common::physical::PageAllocator;
+
+#[test]
+fn test_mapping() {
+    let allocator = PageAllocator::new();
+    let layout = core::alloc::Layout::new::<[PagingStruct; PAGING_STRUCTURE_REGION_LEN]>();
+    let base: *mut u8 = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    let fake_native = UserlandTest(base);
+    let mut mapper = Mapper::new(
+        base as *mut PagingStruct,
+        0x200000,
+        1,
+        allocator,
+        fake_native,
+    );
+
+    let phys_addr = 0xdeadb000usize;
+    let virt_addr = phys_addr + PAGING_STRUCTURE_BASE;
+    let pml4_idx = (virt_addr & MASK_47_39) >> 39;
+    let pdpt_idx = (virt_addr & MASK_38_30) >> 30;
+    let pd_idx = (virt_addr & MASK_29_21) >> 21;
+    let pt_idx = (virt_addr & MASK_20_12) >> 12;
+
+    let pml4 = base as *mut PagingStruct;
+
+    let pdpt = mapper.new_table();
+    let pd = mapper.new_table();
+    let pt = mapper.new_table();
+
+    unsafe {
+        let pml4e = (*pml4).get_entry_mut(pml4_idx);
+        pml4e.set_addr((*pdpt).phys_addr::<UserlandTest>());
+        pml4e.set_flags(PRESENT_FLAG | RW_FLAG, true);
+
+        let pdpte = (*pdpt).get_entry_mut(pdpt_idx);
+        pdpte.set_addr((*pd).phys_addr::<UserlandTest>());
+        pdpte.set_flags(PRESENT_FLAG | RW_FLAG, true);
+
+        let pde = (*pd).get_entry_mut(pd_idx);
+        pde.set_addr((*pt).phys_addr::<UserlandTest>());
+        pde.set_flags(PRESENT_FLAG | RW_FLAG, true);
+
+        let pte = (*pt).get_entry_mut(pt_idx);
+        pte.set_addr(phys_addr);
+        pte.set_flags(PRESENT_FLAG, true);
+    }
+
+    let mapping = mapper
+        .mapping(virt_addr + 0x123)
+        .expect("Mapping should exist");
+    assert_eq!(
+        mapping,
+        Mapping {
+            phys_addr,
+            page_size: PageSize::Normal,
+            flags: PRESENT_FLAG,
+        }
+    );
+
+    assert_eq!(
+        mapper.mapping(virt_addr + 0x1000),
+        None,
+        "The next page should not be mapped"
+    );
+    unsafe { alloc::alloc::dealloc(base, layout) };
+}
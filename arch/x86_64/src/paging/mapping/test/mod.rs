@@ -1,6 +1,7 @@
 mod cow;
 mod fork;
 mod map;
+mod mapping;
 mod new_table;
 mod phys_addr;
 mod unmap;
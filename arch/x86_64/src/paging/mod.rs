@@ -20,8 +20,8 @@ pub const MASK_20_0: usize = 0x0000_0000_001f_ffff;
 pub const PAGING_STRUCTURE_BASE: usize = 0xffff_ff80_0000_0000;
 pub const MMIO_BASE: usize = 0xffff_ff00_0000_0000;
 
-#[derive(Debug, PartialEq, Eq)]
-enum PageSize {
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum PageSize {
     Normal,
     Huge,
     Gigantic,
@@ -4,6 +4,13 @@ use core::ffi::c_void;
 use uefi::table;
 use uefi::table::boot;
 
+/// Longest kernel command line that the bootloader passes, in bytes.
+pub const MAX_CMDLINE_LEN: usize = 2048;
+
+/// Memory type of the pages that hold the initrd. The kernel keeps them out of the page allocator
+/// until it is done with the initrd.
+pub const INITRD_MEMORY_TYPE: boot::MemoryType = boot::MemoryType::custom(0x8000_0000);
+
 /// BootData is the data structure passed to the kernel.
 #[repr(C)]
 pub struct BootData {
@@ -17,6 +24,31 @@ pub struct BootData {
 
     // ACPI RSDP
     pub acpi_rsdp: *const c_void,
+
+    /// Physical address of the kernel command line, which the kernel reads at `KERNEL_BASE` plus
+    /// this address. The command line is UTF-8 and is not NUL-terminated.
+    pub cmdline: *const u8,
+
+    /// Length of the command line in bytes, at most `MAX_CMDLINE_LEN`.
+    pub cmdline_len: usize,
+
+    /// Physical address of the initrd. It is page-aligned, and its pages are of
+    /// `INITRD_MEMORY_TYPE`.
+    pub initrd: *const u8,
+
+    /// Length of the initrd in bytes, or 0 if no initrd was loaded.
+    pub initrd_len: usize,
+
+    /// Physical address of a copy of the kernel's `.symtab` section, for symbolized backtraces.
+    pub symtab: *const u8,
+
+    /// Length of the symbol table in bytes, or 0 if the kernel has no symbol table.
+    pub symtab_len: usize,
+
+    /// Physical address of a copy of the string table that the symbol table refers to.
+    pub strtab: *const u8,
+
+    pub strtab_len: usize,
 }
 
 #[repr(C)]
@@ -27,4 +59,7 @@ pub struct RawFramebuffer {
     pub vertical_resolution: usize,
     pub pixels_per_scan_line: usize,
     pub pixel_format: uefi::proto::console::gop::PixelFormat,
+
+    /// Positions of the color components in a pixel, if `pixel_format` is `Bitmask`.
+    pub pixel_bitmask: Option<uefi::proto::console::gop::PixelBitmask>,
 }
@@ -0,0 +1,27 @@
+//! Boot configuration read from `CONFIG_PATH`. The format is described in `util::boot_config`.
+
+use alloc::format;
+use alloc::string::String;
+
+use bootlib::types::MAX_CMDLINE_LEN;
+
+pub use util::boot_config::{Config, Entry};
+
+/// Path of the config file on the ESP.
+pub const CONFIG_PATH: &str = "\\aosir.cfg";
+
+/// Kernel that is booted when there is no config file.
+const DEFAULT_KERNEL_PATH: &str = "\\aosir";
+
+/// Returns the config used when there is no valid config file.
+pub fn default() -> Config {
+    Config::single("aosir", DEFAULT_KERNEL_PATH)
+}
+
+/// Parses the contents of the config file.
+pub fn parse(s: &str) -> Result<Config, String> {
+    Config::parse(s, MAX_CMDLINE_LEN).map_err(|e| match e.line {
+        Some(line) => format!("{}:{}: {}", CONFIG_PATH, line, e.message),
+        None => format!("{}: {}", CONFIG_PATH, e.message),
+    })
+}
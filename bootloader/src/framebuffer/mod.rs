@@ -1,15 +1,19 @@
 extern crate uefi;
 
-use alloc::vec::Vec;
+use alloc::vec;
 
 use uefi::prelude::*;
 use uefi::proto::console::gop::{BltOp, BltPixel, BltRegion, GraphicsOutput};
 
 const SQUARE_SIZE: usize = 50;
 
-mod fonts;
-use crate::framebuffer::fonts::{FONT_HEIGHT, FONT_WIDTH};
+const TAB_WIDTH: usize = 8;
+
+const FG_COLOR: BltPixel = BltPixel::new(0xee, 0xee, 0xee);
+const BG_COLOR: BltPixel = BltPixel::new(0x35, 0x33, 0x2b);
+
 use bootlib::types::RawFramebuffer;
+use font::{FONT_HEIGHT, FONT_WIDTH};
 use uefi::table::boot::ScopedProtocol;
 
 // TODO: make this generic in the sense that it can either use gop or the raw framebuffer behind it.
@@ -25,7 +29,10 @@ pub struct Framebuffer<'a> {
     cursor_x: usize,
     cursor_y: usize,
 
-    font: fonts::Terminus16x18Font,
+    // Whether characters are drawn with the colors swapped.
+    highlight: bool,
+
+    font: font::Terminus16x18Font,
 }
 
 impl Framebuffer<'_> {
@@ -48,7 +55,8 @@ impl Framebuffer<'_> {
             n_row: nr,
             cursor_x: 0,
             cursor_y: 0,
-            font: fonts::parse_bdf(),
+            highlight: false,
+            font: font::parse_bdf(),
         };
         fb
     }
@@ -57,7 +65,7 @@ impl Framebuffer<'_> {
         let mode_info = self.gop.current_mode_info();
         let (width_px, height_px) = mode_info.resolution();
         match self.gop.blt(BltOp::VideoFill {
-            color: BltPixel::new(0x35, 0x33, 0x2b),
+            color: BG_COLOR,
             dest: (0, 0),
             dims: (width_px, height_px),
         }) {
@@ -67,25 +75,35 @@ impl Framebuffer<'_> {
     }
 
     pub fn write_char_impl(&mut self, c: char) {
-        const BUF_SIZE: usize = FONT_HEIGHT * FONT_WIDTH;
-        if c == '\n' {
-            self.newline();
-            return;
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.cursor_x = 0,
+            '\t' => {
+                let next = (self.cursor_x / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.cursor_x < next.min(self.n_col) {
+                    self.put_char(' ');
+                }
+            }
+            c if c.is_control() => {}
+            c => self.put_char(c),
         }
+    }
+
+    /// Draws `c` at the cursor and advances the cursor, wrapping to the next line at the right
+    /// edge of the screen. Characters without a glyph are drawn as `font::REPLACEMENT_CHARACTER`.
+    fn put_char(&mut self, c: char) {
+        const BUF_SIZE: usize = FONT_HEIGHT * FONT_WIDTH;
         if self.cursor_x >= self.n_col {
             self.newline();
         }
-        let bitmap = self.font.glyphs[c as usize - 32].bitmap;
-        let mut buffer = Vec::<BltPixel>::with_capacity(BUF_SIZE);
-        for row in bitmap.iter() {
-            for b in row {
-                buffer.push(if *b == 1 {
-                    BltPixel::new(0xee, 0xee, 0xee)
-                } else {
-                    BltPixel::new(0x35, 0x33, 0x2b)
-                });
-            }
-        }
+        let (fg, bg) = if self.highlight {
+            (BG_COLOR, FG_COLOR)
+        } else {
+            (FG_COLOR, BG_COLOR)
+        };
+        let glyph = self.font.get_glyph(c);
+        let mut buffer = vec![bg; BUF_SIZE];
+        font::draw_glyph(glyph, fg, bg, &mut buffer, FONT_WIDTH);
         self.gop.blt(BltOp::BufferToVideo {
             buffer: &buffer,
             src: BltRegion::SubRectangle {
@@ -98,12 +116,51 @@ impl Framebuffer<'_> {
         self.cursor_x += 1;
     }
 
+    /// Returns the size of the screen in characters, as (columns, rows).
+    pub fn size(&self) -> (usize, usize) {
+        (self.n_col, self.n_row)
+    }
+
+    /// Returns the position of the cursor, as (column, row).
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.cursor_x, self.cursor_y)
+    }
+
+    pub fn set_cursor(&mut self, x: usize, y: usize) {
+        self.cursor_x = x.min(self.n_col);
+        self.cursor_y = y.min(self.n_row.saturating_sub(1));
+    }
+
+    /// Sets whether characters are drawn with the foreground and background colors swapped.
+    pub fn set_highlight(&mut self, highlight: bool) {
+        self.highlight = highlight;
+    }
+
     pub fn newline(&mut self) {
-        if self.cursor_y < self.n_row {
-            self.cursor_x = 0;
+        self.cursor_x = 0;
+        if self.cursor_y + 1 < self.n_row {
             self.cursor_y += 1;
+        } else {
+            self.scroll();
         }
-        // TODO: implement scrolling
+    }
+
+    /// Moves all lines up by one, and clears the last line.
+    fn scroll(&mut self) {
+        if self.n_row == 0 {
+            return;
+        }
+        let width = self.n_col * FONT_WIDTH;
+        self.gop.blt(BltOp::VideoToVideo {
+            src: (0, FONT_HEIGHT),
+            dest: (0, 0),
+            dims: (width, (self.n_row - 1) * FONT_HEIGHT),
+        });
+        self.gop.blt(BltOp::VideoFill {
+            color: BG_COLOR,
+            dest: (0, (self.n_row - 1) * FONT_HEIGHT),
+            dims: (width, FONT_HEIGHT),
+        });
     }
 
     /// Converts to RawFramebuffer, for passing to the kernel.
@@ -121,6 +178,7 @@ impl Framebuffer<'_> {
             vertical_resolution: vr,
             pixels_per_scan_line: mode_info.stride(),
             pixel_format: mode_info.pixel_format(),
+            pixel_bitmask: mode_info.pixel_bitmask(),
         }
     }
 }
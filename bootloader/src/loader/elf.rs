@@ -1,13 +1,27 @@
-use alloc::format;
-use alloc::string::String;
-use core::mem;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use core::mem::{self, size_of};
+use core::ptr;
 
-use core::ptr::{slice_from_raw_parts, slice_from_raw_parts_mut};
-use core::str::from_utf8;
+use uefi::table::boot::{AllocateType, BootServices, MemoryType};
+use uefi::Status;
+
+use crate::KERNEL_BASE;
 
 const E_IDENT: usize = 16;
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+const PAGE_SIZE: usize = 0x1000;
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 struct ElfHeader {
     e_ident: [u8; E_IDENT],
     e_type: u16,
@@ -26,6 +40,7 @@ struct ElfHeader {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 struct ProgramHeader {
     p_type: u32,
     p_flags: u32,
@@ -37,38 +52,298 @@ struct ProgramHeader {
     p_aligh: usize,
 }
 
-pub fn load_elf(
-    elf_file: &[u8],
-) -> Result<unsafe extern "C" fn(&bootlib::types::BootData), String> {
-    let elf_header: *const u8 = &elf_file[0];
-    let elf_header = unsafe { &*(elf_header as *const ElfHeader) };
-    let magic = from_utf8(&elf_header.e_ident[1..4])
-        .map_err(|e| format!("failed to read magic: {:?}", e))?;
-    assert_eq!(magic, "ELF");
-
-    let program_header: *const u8 = &elf_file[elf_header.e_phoff];
-    let program_headers = unsafe {
-        let head = &*(program_header as *const ProgramHeader);
-        &*slice_from_raw_parts::<ProgramHeader>(head, elf_header.e_phnum as usize)
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SectionHeader {
+    sh_name: u32,
+    sh_type: u32,
+    sh_flags: usize,
+    sh_addr: usize,
+    sh_offset: usize,
+    sh_size: usize,
+    sh_link: u32,
+    sh_info: u32,
+    sh_addralign: usize,
+    sh_entsize: usize,
+}
+
+/// Entry point of the kernel, at its physical address.
+pub type EntryPoint = unsafe extern "C" fn(&bootlib::types::BootData);
+
+/// Reasons the kernel cannot be loaded.
+#[derive(Debug)]
+pub enum Error {
+    /// The file ends before the ELF header, a program header or a section header.
+    Truncated,
+    BadMagic,
+    /// `EI_CLASS` is not `ELFCLASS64`.
+    UnsupportedClass(u8),
+    /// `EI_DATA` is not little-endian.
+    UnsupportedByteOrder(u8),
+    UnsupportedVersion(u8),
+    /// `e_type` is not `ET_EXEC`.
+    UnsupportedType(u16),
+    /// `e_machine` is not `EM_X86_64`.
+    UnsupportedMachine(u16),
+    BadProgramHeaderSize(u16),
+    BadSectionHeaderSize(u16),
+    /// The contents of the section at this index are not within the file.
+    SectionOutOfFile(usize),
+    /// The file contents of the segment at this index are not within the file.
+    SegmentOutOfFile(usize),
+    /// The segment at this index is larger in the file than in memory.
+    SegmentTooLarge(usize),
+    /// The segment at this index is neither identity-mapped nor mapped at `KERNEL_BASE`.
+    UnsupportedAddress(usize),
+    /// The segments at these indices overlap in physical memory.
+    Overlap(usize, usize),
+    NoLoadableSegments,
+    /// The entry point is not in any loaded segment.
+    EntryOutOfSegments(usize),
+    /// The pages at this physical address could not be allocated.
+    AllocationFailed(usize, usize, Status),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "file is too short"),
+            Error::BadMagic => write!(f, "not an ELF file"),
+            Error::UnsupportedClass(class) => write!(f, "unsupported ELF class {}", class),
+            Error::UnsupportedByteOrder(data) => write!(f, "unsupported byte order {}", data),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported ELF version {}", version)
+            }
+            Error::UnsupportedType(ty) => write!(f, "not an executable (type {})", ty),
+            Error::UnsupportedMachine(machine) => {
+                write!(f, "not an x86_64 executable (machine {})", machine)
+            }
+            Error::BadProgramHeaderSize(size) => {
+                write!(f, "unexpected program header size {}", size)
+            }
+            Error::BadSectionHeaderSize(size) => {
+                write!(f, "unexpected section header size {}", size)
+            }
+            Error::SectionOutOfFile(i) => write!(f, "section {} is out of the file", i),
+            Error::SegmentOutOfFile(i) => write!(f, "segment {} is out of the file", i),
+            Error::SegmentTooLarge(i) => {
+                write!(f, "segment {} is larger in the file than in memory", i)
+            }
+            Error::UnsupportedAddress(i) => write!(
+                f,
+                "segment {} is not loaded at its virtual address or at KERNEL_BASE below it",
+                i
+            ),
+            Error::Overlap(i, j) => write!(f, "segments {} and {} overlap", i, j),
+            Error::NoLoadableSegments => write!(f, "no loadable segments"),
+            Error::EntryOutOfSegments(entry) => {
+                write!(f, "entry point {:#x} is not in a loaded segment", entry)
+            }
+            Error::AllocationFailed(addr, pages, status) => write!(
+                f,
+                "failed to allocate {} pages at {:#x}: {:?}",
+                pages, addr, status
+            ),
+        }
+    }
+}
+
+/// A `PT_LOAD` segment.
+struct Segment {
+    // Index of the program header.
+    index: usize,
+    phys_addr: usize,
+    virt_addr: usize,
+    offset: usize,
+    file_size: usize,
+    mem_size: usize,
+}
+
+impl Segment {
+    fn phys_end(&self) -> usize {
+        self.phys_addr + self.mem_size
+    }
+}
+
+/// Loads the `PT_LOAD` segments of the kernel executable `elf_file` at their physical addresses,
+/// and returns the physical address of its entry point. The pages are allocated from the firmware,
+/// so this must be called before exiting boot services.
+pub fn load_elf(boot_services: &BootServices, elf_file: &[u8]) -> Result<EntryPoint, Error> {
+    let header: ElfHeader = read(elf_file, 0)?;
+    validate_header(&header)?;
+
+    let mut segments = Vec::new();
+    for index in 0..header.e_phnum as usize {
+        let offset = index
+            .checked_mul(size_of::<ProgramHeader>())
+            .and_then(|o| o.checked_add(header.e_phoff))
+            .ok_or(Error::Truncated)?;
+        let ph: ProgramHeader = read(elf_file, offset)?;
+        if ph.p_type != PT_LOAD || ph.p_memsz == 0 {
+            continue;
+        }
+        segments.push(validate_segment(index, &ph, elf_file.len())?);
+    }
+    if segments.is_empty() {
+        return Err(Error::NoLoadableSegments);
+    }
+
+    segments.sort_by_key(|s| s.phys_addr);
+    for pair in segments.windows(2) {
+        if pair[0].phys_end() > pair[1].phys_addr {
+            return Err(Error::Overlap(pair[0].index, pair[1].index));
+        }
+    }
+
+    // The bootloader runs identity-mapped, so jump to the physical address of the entry point.
+    let entry = header.e_entry;
+    let entry = segments
+        .iter()
+        .find(|s| (s.virt_addr..s.virt_addr + s.mem_size).contains(&entry))
+        .map(|s| entry - s.virt_addr + s.phys_addr)
+        .ok_or(Error::EntryOutOfSegments(entry))?;
+
+    allocate_pages(boot_services, &segments)?;
+    for s in segments.iter() {
+        // SAFETY: The pages of the segment have just been allocated for us, and the file contents
+        //         are within `elf_file`.
+        unsafe {
+            let dest = s.phys_addr as *mut u8;
+            ptr::copy_nonoverlapping(elf_file.as_ptr().add(s.offset), dest, s.file_size);
+            ptr::write_bytes(dest.add(s.file_size), 0, s.mem_size - s.file_size);
+        }
+    }
+
+    // SAFETY: The entry point is in a segment that has been loaded.
+    Ok(unsafe { mem::transmute::<usize, EntryPoint>(entry) })
+}
+
+/// Returns the symbol table (`.symtab`) of `elf_file` and the string table it refers to, or `None`
+/// if the executable is stripped. `elf_file` must have been checked by `load_elf()`.
+pub fn symbol_table(elf_file: &[u8]) -> Result<Option<(&[u8], &[u8])>, Error> {
+    let header: ElfHeader = read(elf_file, 0)?;
+    if header.e_shnum == 0 {
+        return Ok(None);
+    }
+    if header.e_shentsize as usize != size_of::<SectionHeader>() {
+        return Err(Error::BadSectionHeaderSize(header.e_shentsize));
+    }
+    let section = |index: usize| -> Result<SectionHeader, Error> {
+        let offset = index
+            .checked_mul(size_of::<SectionHeader>())
+            .and_then(|o| o.checked_add(header.e_shoff))
+            .ok_or(Error::Truncated)?;
+        read(elf_file, offset)
+    };
+    let contents = |index: usize, sh: &SectionHeader| {
+        sh.sh_offset
+            .checked_add(sh.sh_size)
+            .and_then(|end| elf_file.get(sh.sh_offset..end))
+            .ok_or(Error::SectionOutOfFile(index))
     };
-    assert_eq!(size_of::<ProgramHeader>(), elf_header.e_phentsize as usize);
 
-    // Load segments
-    for ph in program_headers.iter() {
-        if ph.p_paddr == 0 {
+    for index in 0..header.e_shnum as usize {
+        let sh = section(index)?;
+        if sh.sh_type != SHT_SYMTAB {
             continue;
         }
-        let paddr: *mut u8 = ph.p_paddr as *mut u8;
-        let buf = unsafe { &mut *slice_from_raw_parts_mut::<u8>(paddr, ph.p_memsz) };
-        for b in buf.iter_mut() {
-            *b = 0
+        let strtab_index = sh.sh_link as usize;
+        if strtab_index >= header.e_shnum as usize {
+            return Err(Error::SectionOutOfFile(strtab_index));
         }
-        buf[0..ph.p_filesz].copy_from_slice(&elf_file[ph.p_offset..ph.p_offset + ph.p_filesz])
+        let strtab = section(strtab_index)?;
+        return Ok(Some((
+            contents(index, &sh)?,
+            contents(strtab_index, &strtab)?,
+        )));
+    }
+    Ok(None)
+}
+
+/// Reads a `T` at `offset` of `buf`, which need not be aligned.
+fn read<T: Copy>(buf: &[u8], offset: usize) -> Result<T, Error> {
+    let end = offset.checked_add(size_of::<T>()).ok_or(Error::Truncated)?;
+    if end > buf.len() {
+        return Err(Error::Truncated);
+    }
+    // SAFETY: The bytes are within `buf`, and `T` is only instantiated with the plain-old-data
+    //         header structs above.
+    Ok(unsafe { ptr::read_unaligned(buf.as_ptr().add(offset) as *const T) })
+}
+
+fn validate_header(header: &ElfHeader) -> Result<(), Error> {
+    let ident = &header.e_ident;
+    if ident[..4] != ELF_MAGIC {
+        return Err(Error::BadMagic);
+    }
+    if ident[4] != ELFCLASS64 {
+        return Err(Error::UnsupportedClass(ident[4]));
     }
+    if ident[5] != ELFDATA2LSB {
+        return Err(Error::UnsupportedByteOrder(ident[5]));
+    }
+    if ident[6] != EV_CURRENT {
+        return Err(Error::UnsupportedVersion(ident[6]));
+    }
+    if header.e_type != ET_EXEC {
+        return Err(Error::UnsupportedType(header.e_type));
+    }
+    if header.e_machine != EM_X86_64 {
+        return Err(Error::UnsupportedMachine(header.e_machine));
+    }
+    if header.e_phentsize as usize != size_of::<ProgramHeader>() {
+        return Err(Error::BadProgramHeaderSize(header.e_phentsize));
+    }
+    Ok(())
+}
+
+fn validate_segment(index: usize, ph: &ProgramHeader, file_len: usize) -> Result<Segment, Error> {
+    if ph.p_filesz > ph.p_memsz {
+        return Err(Error::SegmentTooLarge(index));
+    }
+    match ph.p_offset.checked_add(ph.p_filesz) {
+        Some(end) if end <= file_len => {}
+        _ => return Err(Error::SegmentOutOfFile(index)),
+    }
+    // Segments are either identity-mapped, like the low entry code, or in the higher half at
+    // KERNEL_BASE above their physical address.
+    let phys_end = ph.p_paddr.checked_add(ph.p_memsz);
+    if phys_end.is_none()
+        || (ph.p_vaddr != ph.p_paddr && ph.p_vaddr != ph.p_paddr.wrapping_add(KERNEL_BASE))
+    {
+        return Err(Error::UnsupportedAddress(index));
+    }
+    Ok(Segment {
+        index,
+        phys_addr: ph.p_paddr,
+        virt_addr: ph.p_vaddr,
+        offset: ph.p_offset,
+        file_size: ph.p_filesz,
+        mem_size: ph.p_memsz,
+    })
+}
 
-    // use transmute() to forcefully cast e_entry to a fn()
-    let void_ptr = elf_header.e_entry as *const ();
-    let fn_ptr: unsafe extern "C" fn(&bootlib::types::BootData) =
-        unsafe { mem::transmute(void_ptr) };
-    Ok(fn_ptr)
+/// Allocates the pages that `segments` are loaded into. `segments` are sorted by address and do
+/// not overlap, but neighbouring segments may share a page.
+fn allocate_pages(boot_services: &BootServices, segments: &[Segment]) -> Result<(), Error> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for s in segments {
+        let start = s.phys_addr & !(PAGE_SIZE - 1);
+        let end = s.phys_end().next_multiple_of(PAGE_SIZE);
+        match ranges.last_mut() {
+            Some((_, last_end)) if *last_end >= start => *last_end = end.max(*last_end),
+            _ => ranges.push((start, end)),
+        }
+    }
+    for (start, end) in ranges {
+        let pages = (end - start) / PAGE_SIZE;
+        boot_services
+            .allocate_pages(
+                AllocateType::Address(start as u64),
+                MemoryType::LOADER_DATA,
+                pages,
+            )
+            .map_err(|e| Error::AllocationFailed(start, pages, e.status()))?;
+    }
+    Ok(())
 }
@@ -1,51 +1,51 @@
 use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::convert::TryFrom;
 
 use uefi::prelude::*;
 use uefi::proto::media::file::{File, FileAttribute, FileInfo, FileMode, FileType};
-use uefi::proto::media::fs::SimpleFileSystem;
-use uefi::CStr16;
+use uefi::CString16;
 
 pub mod elf;
 
-pub fn load_file(system_table: &SystemTable<Boot>) -> Result<Vec<u8>, String> {
+/// Reads the file at `path` on the file system that the bootloader was loaded from.
+pub fn load_file(system_table: &SystemTable<Boot>, path: &str) -> Result<Vec<u8>, String> {
+    try_load_file(system_table, path)?.ok_or_else(|| format!("{} not found", path))
+}
+
+/// Like `load_file()`, but returns `None` if there is no file at `path`.
+pub fn try_load_file(
+    system_table: &SystemTable<Boot>,
+    path: &str,
+) -> Result<Option<Vec<u8>>, String> {
     let bs = system_table.boot_services();
-    let handle = bs
-        .get_handle_for_protocol::<SimpleFileSystem>()
-        .map_err(|e| {
-            String::from(format!(
-                "Simple File System Protocol support is required: {:?}",
-                e.status()
-            ))
-        })?;
-    let mut fs = bs
-        .open_protocol_exclusive::<SimpleFileSystem>(handle)
-        .map_err(|e| {
-            String::from(format!(
-                "Simple File System Protocol support is required: {:?}",
-                e.status()
-            ))
-        })?;
-    let mut kernel_obj = [0; 8];
-    let kernel_obj = CStr16::from_str_with_buf("aosir", &mut kernel_obj);
+    let mut fs = bs.get_image_file_system(bs.image_handle()).map_err(|e| {
+        String::from(format!(
+            "Simple File System Protocol support is required: {:?}",
+            e.status()
+        ))
+    })?;
+    let file_name = CString16::try_from(path)
+        .map_err(|e| String::from(format!("invalid path {}: {:?}", path, e)))?;
     let dir = &mut fs
         .open_volume()
         .map_err(|e| String::from(format!("failed to open root directory: {:?}", e.status())))?;
-    let kernel_file = dir
-        .open(
-            kernel_obj.unwrap(),
-            FileMode::Read,
-            FileAttribute::READ_ONLY,
-        )
-        .map_err(|e| String::from(format!("failed to obtain file handle: {:?}", e.status())))?;
-    let kernel_file = kernel_file
+    let file = match dir.open(&file_name, FileMode::Read, FileAttribute::READ_ONLY) {
+        Ok(file) => file,
+        Err(e) if e.status() == Status::NOT_FOUND => return Ok(None),
+        Err(e) => {
+            return Err(String::from(format!(
+                "failed to obtain file handle: {:?}",
+                e.status()
+            )))
+        }
+    };
+    let file = file
         .into_type()
         .map_err(|e| String::from(format!("failed to get file type: {:?}", e.status())))?;
-    match kernel_file {
-        FileType::Dir(_) => Err(String::from(
-            "directory found instead of kernel binary at /aosir",
-        )),
+    match file {
+        FileType::Dir(_) => Err(format!("directory found instead of file at {}", path)),
         FileType::Regular(mut f) => {
             let mut info_buf = Vec::with_capacity(1);
             unsafe {
@@ -65,9 +65,9 @@ pub fn load_file(system_table: &SystemTable<Boot>) -> Result<Vec<u8>, String> {
                     buf.set_len(info.file_size() as usize);
                 }
                 f.read(&mut buf).map_err(|e| {
-                    String::from(format!("failed to read kernel: {:?}", e.status()))
+                    String::from(format!("failed to read {}: {:?}", path, e.status()))
                 })?;
-                Ok(buf)
+                Ok(Some(buf))
             } else {
                 Err(format!(
                     "unexpected error in obtaining file info: {:?}",
@@ -6,6 +6,8 @@ extern crate rlibc;
 extern crate uefi;
 
 use crate::framebuffer::Framebuffer;
+use alloc::format;
+use alloc::string::String;
 use alloc::vec::*;
 use core::ffi::c_void;
 use core::fmt::Write;
@@ -13,14 +15,17 @@ use core::ptr;
 
 use uefi::prelude::*;
 use uefi::proto::loaded_image::LoadedImage;
-use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use uefi::table::boot::{AllocateType, MemoryDescriptor, MemoryType};
 use uefi::table::Runtime;
 
+pub mod config;
 pub mod framebuffer;
 pub mod loader;
-use crate::loader::elf::load_elf;
-use crate::loader::load_file;
-use bootlib::types::BootData;
+pub mod menu;
+use crate::config::CONFIG_PATH;
+use crate::loader::elf::{load_elf, symbol_table};
+use crate::loader::{load_file, try_load_file};
+use bootlib::types::{BootData, INITRD_MEMORY_TYPE, MAX_CMDLINE_LEN};
 use uefi::table::cfg::ACPI2_GUID;
 
 static mut SYSTEM_TABLE: *const () = 0x0 as *const ();
@@ -48,9 +53,31 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         .expect("error when loading loaded image protocol");
     let (base, _size) = loaded_image.info();
     writeln!(fb, "Bootloader was loaded at {:p}", base);
-    writeln!(fb, "Loading kernel...");
     drop(loaded_image);
 
+    let config = match try_load_file(&system_table, CONFIG_PATH) {
+        Ok(Some(buf)) => match core::str::from_utf8(&buf) {
+            Ok(s) => config::parse(s).unwrap_or_else(|e| {
+                writeln!(fb, "{}, using the default config", e);
+                config::default()
+            }),
+            Err(_) => {
+                writeln!(fb, "{} is not UTF-8, using the default config", CONFIG_PATH);
+                config::default()
+            }
+        },
+        Ok(None) => config::default(),
+        Err(e) => {
+            writeln!(fb, "failed to read {}: {}", CONFIG_PATH, e);
+            config::default()
+        }
+    };
+    let entry = menu::select(&mut fb, &system_table, &config);
+    writeln!(fb, "Loading kernel {}...", entry.kernel);
+    if !entry.cmdline.is_empty() {
+        writeln!(fb, "Command line: {}", entry.cmdline);
+    }
+
     // ACPI RSDP
     let mut acpi_rsdp: *const c_void = 0x0 as *const c_void;
 
@@ -61,7 +88,7 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     }
 
     // Proceed to bootstrapping the kernel.
-    let file = match load_file(&system_table) {
+    let file = match load_file(&system_table, &entry.kernel) {
         Ok(file) => {
             writeln!(fb, "kernel loaded: {}", file.len());
             file
@@ -71,6 +98,47 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
             return Status::ABORTED;
         }
     };
+    // Load the segments before allocating anything else, so that the initrd is not put where the
+    // kernel has to go.
+    let entry_point = match load_elf(system_table.boot_services(), &file) {
+        Ok(entry_point) => entry_point,
+        Err(e) => {
+            writeln!(fb, "{} is not a valid kernel: {}", entry.kernel, e);
+            return Status::LOAD_ERROR;
+        }
+    };
+    // Keep the symbol table for backtraces. The kernel boots without one.
+    let symbols = match symbol_table(&file) {
+        Ok(Some((symtab, strtab))) => match copy_symbols(&system_table, symtab, strtab) {
+            Ok(symbols) => Some(symbols),
+            Err(e) => {
+                writeln!(fb, "failed to copy the kernel symbol table: {}", e);
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(e) => {
+            writeln!(fb, "invalid kernel symbol table: {}", e);
+            None
+        }
+    };
+    let ((symtab, symtab_len), (strtab, strtab_len)) =
+        symbols.unwrap_or(((ptr::null(), 0), (ptr::null(), 0)));
+    drop(file);
+
+    let (initrd, initrd_len) = match &entry.initrd {
+        Some(path) => match load_initrd(&system_table, path) {
+            Ok(initrd) => {
+                writeln!(fb, "initrd loaded: {}", initrd.1);
+                initrd
+            }
+            Err(e) => {
+                writeln!(fb, "initrd read failed: {}", e);
+                return Status::ABORTED;
+            }
+        },
+        None => (ptr::null(), 0),
+    };
 
     writeln!(fb, "Booting kernel...");
     let raw_fb = fb.raw_framebuffer();
@@ -112,16 +180,15 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     };
 
     let system_table = &system_table as *const SystemTable<Runtime>;
-    let entry_point = match load_elf(&file) {
-        Ok(ep) => ep,
-        Err(_e) => {
-            return Status::ABORTED;
-        }
-    };
 
     let memory_map_buf = 0x401000 as *mut MemoryDescriptor;
     unsafe { ptr::copy_nonoverlapping(virt_mmap.as_ptr(), memory_map_buf, virt_mmap.len()) };
 
+    // The command line follows BootData in the same page.
+    let cmdline = 0x400800 as *mut u8;
+    let cmdline_len = entry.cmdline.len().min(MAX_CMDLINE_LEN);
+    unsafe { ptr::copy_nonoverlapping(entry.cmdline.as_ptr(), cmdline, cmdline_len) };
+
     let boot_data = 0x400000 as *mut BootData;
 
     let boot_data = unsafe {
@@ -133,6 +200,14 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
                 framebuffer: raw_fb,
                 system_table,
                 acpi_rsdp,
+                cmdline,
+                cmdline_len,
+                initrd,
+                initrd_len,
+                symtab,
+                symtab_len,
+                strtab,
+                strtab_len,
             },
         );
         &mut *boot_data
@@ -140,3 +215,39 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     unsafe { entry_point(boot_data) };
     loop {}
 }
+
+/// Loads the initrd at `path` into pages of `INITRD_MEMORY_TYPE`, so that they are not mistaken
+/// for free memory by the kernel. Returns the physical address and length of the initrd.
+fn load_initrd(system_table: &SystemTable<Boot>, path: &str) -> Result<(*const u8, usize), String> {
+    let file = load_file(system_table, path)?;
+    let pages = file.len().div_ceil(0x1000).max(1);
+    let addr = system_table
+        .boot_services()
+        .allocate_pages(AllocateType::AnyPages, INITRD_MEMORY_TYPE, pages)
+        .map_err(|e| format!("failed to allocate {} pages: {:?}", pages, e.status()))?;
+    let initrd = addr as *mut u8;
+    unsafe { ptr::copy_nonoverlapping(file.as_ptr(), initrd, file.len()) };
+    Ok((initrd, file.len()))
+}
+
+/// Copies the kernel's symbol table and string table into pages that the kernel keeps. Returns
+/// their physical addresses and lengths.
+fn copy_symbols(
+    system_table: &SystemTable<Boot>,
+    symtab: &[u8],
+    strtab: &[u8],
+) -> Result<((*const u8, usize), (*const u8, usize)), String> {
+    let len = symtab.len() + strtab.len();
+    let pages = len.div_ceil(0x1000).max(1);
+    let addr = system_table
+        .boot_services()
+        .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+        .map_err(|e| format!("failed to allocate {} pages: {:?}", pages, e.status()))?;
+    let symtab_copy = addr as *mut u8;
+    let strtab_copy = unsafe { symtab_copy.add(symtab.len()) };
+    unsafe {
+        ptr::copy_nonoverlapping(symtab.as_ptr(), symtab_copy, symtab.len());
+        ptr::copy_nonoverlapping(strtab.as_ptr(), strtab_copy, strtab.len());
+    }
+    Ok(((symtab_copy, symtab.len()), (strtab_copy, strtab.len())))
+}
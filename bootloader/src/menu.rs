@@ -0,0 +1,138 @@
+use alloc::format;
+use alloc::string::String;
+
+use uefi::prelude::*;
+use uefi::proto::console::text::{Key, ScanCode};
+
+use crate::config::{Config, Entry};
+use crate::framebuffer::Framebuffer;
+
+// How often the keyboard is polled, in microseconds.
+const POLL_INTERVAL: usize = 100_000;
+const POLLS_PER_SECOND: usize = 1_000_000 / POLL_INTERVAL;
+
+// Watchdog timeout in seconds that the firmware sets before it starts a boot option.
+const WATCHDOG_TIMEOUT: usize = 300;
+
+/// Shows the boot entries of `config` on `fb`, and returns the one that is selected with the
+/// keyboard. The default entry is returned if no key is pressed for `config.timeout` seconds.
+pub fn select<'c>(
+    fb: &mut Framebuffer,
+    system_table: &SystemTable<Boot>,
+    config: &'c Config,
+) -> &'c Entry {
+    let entries = &config.entries;
+    if config.timeout == 0 {
+        return &entries[config.default];
+    }
+
+    // SAFETY: The clone is only used to read keys while this function runs. Boot services are
+    // not exited with it.
+    let mut st = unsafe { system_table.unsafe_clone() };
+    let _ = st.stdin().reset(false);
+
+    // Keep the firmware from resetting the machine while we wait for a selection.
+    let _ = system_table
+        .boot_services()
+        .set_watchdog_timer(0, 0x10000, None);
+
+    // Make room for the title, the entries and the countdown, scrolling if needed.
+    let n_lines = entries.len() + 2;
+    for _ in 0..n_lines {
+        fb.newline();
+    }
+    let top = (fb.cursor().1 + 1).saturating_sub(n_lines);
+
+    let mut selected = config.default;
+    // Polls left until the default entry is booted, or None once a key has been pressed.
+    let mut polls_left = Some(config.timeout * POLLS_PER_SECOND);
+    draw(fb, top, config, selected, polls_left);
+
+    loop {
+        if let Ok(Some(key)) = st.stdin().read_key() {
+            polls_left = None;
+            match key {
+                Key::Special(ScanCode::UP) => selected = selected.saturating_sub(1),
+                Key::Special(ScanCode::DOWN) => selected = (selected + 1).min(entries.len() - 1),
+                Key::Printable(c) if char::from(c) == '\r' => break,
+                Key::Printable(c) => {
+                    // Entries are numbered from 1.
+                    let n = char::from(c).to_digit(10).unwrap_or(0) as usize;
+                    if (1..=entries.len()).contains(&n) {
+                        selected = n - 1;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            draw(fb, top, config, selected, polls_left);
+        }
+
+        if let Some(polls) = polls_left {
+            if polls == 0 {
+                break;
+            }
+            if polls % POLLS_PER_SECOND == 0 {
+                draw(fb, top, config, selected, polls_left);
+            }
+            polls_left = Some(polls - 1);
+        }
+        system_table.boot_services().stall(POLL_INTERVAL);
+    }
+
+    // Loading the kernel may hang like any other boot option, so let the firmware reset the
+    // machine again if it does.
+    let _ = system_table
+        .boot_services()
+        .set_watchdog_timer(WATCHDOG_TIMEOUT, 0x10000, None);
+
+    // Clear the countdown, and continue with boot messages on its line.
+    draw(fb, top, config, selected, None);
+    fb.set_cursor(0, top + n_lines - 1);
+    &entries[selected]
+}
+
+/// Draws the menu starting at row `top`.
+fn draw(
+    fb: &mut Framebuffer,
+    top: usize,
+    config: &Config,
+    selected: usize,
+    polls_left: Option<usize>,
+) {
+    let mut line = top;
+    draw_line(
+        fb,
+        line,
+        "Select an entry with Up/Down and press Enter to boot:",
+    );
+    for (i, entry) in config.entries.iter().enumerate() {
+        line += 1;
+        fb.set_highlight(i == selected);
+        draw_line(fb, line, &format!("  {}. {}  ", i + 1, entry.title));
+    }
+    line += 1;
+    let countdown = match polls_left {
+        Some(polls) => format!(
+            "Booting {} in {} s...",
+            config.entries[selected].title,
+            polls.div_ceil(POLLS_PER_SECOND)
+        ),
+        None => String::new(),
+    };
+    draw_line(fb, line, &countdown);
+}
+
+/// Replaces row `y` of the screen with `text`.
+fn draw_line(fb: &mut Framebuffer, y: usize, text: &str) {
+    let (n_col, _) = fb.size();
+    fb.set_cursor(0, y);
+    let len = text.chars().count().min(n_col);
+    for c in text.chars().take(len) {
+        fb.write_char_impl(c);
+    }
+    fb.set_highlight(false);
+    for _ in len..n_col {
+        fb.write_char_impl(' ');
+    }
+}
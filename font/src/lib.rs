@@ -0,0 +1,253 @@
+#![no_std]
+//! This crate contains the Terminus font and the text rendering shared by the bootloader and the
+//! kernel.
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+static TERMINUS_U18N: &'static str = include_str!("./ter-u18n.bdf");
+
+pub const FONT_WIDTH: usize = 16;
+pub const FONT_HEIGHT: usize = 18;
+
+/// Number of glyphs we load, which covers Latin-1.
+pub const N_GLYPHS: usize = 256;
+
+/// Drawn for characters that the font has no glyph for.
+pub const REPLACEMENT_CHARACTER: char = '?';
+
+pub type Bitmap = [[u8; FONT_WIDTH]; FONT_HEIGHT];
+
+pub struct Terminus16x18Glyph {
+    pub codepoint: u8,
+    pub bitmap: Bitmap,
+}
+
+pub struct Terminus16x18Font {
+    /// Glyphs indexed by codepoint, or `None` for codepoints the font doesn't have.
+    pub glyphs: Vec<Option<Terminus16x18Glyph>>,
+}
+
+impl Terminus16x18Font {
+    /// Returns the glyph of `c`, or of `REPLACEMENT_CHARACTER` if the font doesn't have one.
+    pub fn get_glyph(&self, c: char) -> &Terminus16x18Glyph {
+        self.glyphs
+            .get(c as usize)
+            .and_then(Option::as_ref)
+            .or_else(|| self.glyphs[REPLACEMENT_CHARACTER as usize].as_ref())
+            .expect("font has no replacement character")
+    }
+
+    /// Returns whether the font has a glyph for `c`.
+    pub fn has_glyph(&self, c: char) -> bool {
+        matches!(self.glyphs.get(c as usize), Some(Some(_)))
+    }
+}
+
+/// Draws `glyph` into `pixels`, with `fg` for the pixels of the character and `bg` for the rest.
+/// The top left corner of the glyph is drawn at `pixels[0]`, and rows of pixels are `stride`
+/// pixels apart.
+pub fn draw_glyph<P: Copy>(
+    glyph: &Terminus16x18Glyph,
+    fg: P,
+    bg: P,
+    pixels: &mut [P],
+    stride: usize,
+) {
+    for (y, row) in glyph.bitmap.iter().enumerate() {
+        let line = &mut pixels[y * stride..y * stride + FONT_WIDTH];
+        for (pixel, b) in line.iter_mut().zip(row.iter()) {
+            *pixel = if *b == 1 { fg } else { bg };
+        }
+    }
+}
+
+pub fn parse_bdf() -> Terminus16x18Font {
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Token {
+        STARTCHAR,
+        ENCODING,
+        BITMAP,
+        UNUSED,
+        EMPTY,
+    }
+    impl core::fmt::Display for Token {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            match *self {
+                Token::STARTCHAR => f.write_str("STARTCHAR"),
+                Token::ENCODING => f.write_str("ENCODING"),
+                Token::BITMAP => f.write_str("BITMAP"),
+                Token::UNUSED => f.write_str("UNUSED"),
+                Token::EMPTY => f.write_str("EMPTY"),
+            }
+        }
+    }
+
+    let stack = &mut Vec::<Token>::with_capacity(2);
+    let mut font = Terminus16x18Font {
+        glyphs: Vec::<Option<Terminus16x18Glyph>>::with_capacity(N_GLYPHS),
+    };
+    for _i in 0..N_GLYPHS {
+        font.glyphs.push(None);
+    }
+
+    // Glyph being parsed. Glyphs are moved into `font` at ENDCHAR.
+    let mut glyph = Terminus16x18Glyph {
+        codepoint: 0,
+        bitmap: [[0; FONT_WIDTH]; FONT_HEIGHT],
+    };
+    let lines = &mut TERMINUS_U18N.lines();
+    // Current row of pixels.
+    let mut row = 0 as usize;
+    'lines: for (line_no, l) in lines.enumerate() {
+        if line_no < 27 {
+            continue 'lines;
+        }
+        let tokens = l.split(" ");
+        'tokens: for t in tokens {
+            match t {
+                "STARTCHAR" => {
+                    if !stack.is_empty() {
+                        //TODO: propagate this error to caller using Result<T, U>
+                        panic!("corrupt bdt: STARTCHAR")
+                    }
+                    row = 0;
+                    stack.push(Token::STARTCHAR);
+                    continue 'lines;
+                }
+                // ENCODING
+                "ENCODING" => {
+                    if *stack.last().unwrap_or(&Token::EMPTY) != Token::STARTCHAR {
+                        //TODO: propagate this error to caller usint Result<T, U>
+                        panic!("corrupt bdt: ENCODING")
+                    }
+                    stack.push(Token::ENCODING);
+                    continue 'tokens;
+                }
+                "BITMAP" => {
+                    let top = *stack.last().unwrap_or(&Token::EMPTY);
+                    if top == Token::UNUSED {
+                        continue 'lines;
+                    }
+                    if top != Token::STARTCHAR {
+                        //TODO: propagate this error to caller usint Result<T, U>
+                        panic!("corrupt bdt: BITMAP")
+                    }
+                    stack.push(Token::BITMAP);
+                    continue 'lines;
+                }
+                "ENDCHAR" => {
+                    let top = *stack.last().unwrap_or(&Token::EMPTY);
+                    if top != Token::BITMAP && top != Token::UNUSED {
+                        //TODO: propagate this error to caller usint Result<T, U>
+                        panic!("corrupt bdt: ENDCHAR")
+                    }
+                    if stack.pop() == Some(Token::BITMAP) {
+                        let codepoint = glyph.codepoint as usize;
+                        font.glyphs[codepoint] = Some(Terminus16x18Glyph {
+                            codepoint: glyph.codepoint,
+                            bitmap: glyph.bitmap,
+                        });
+                    }
+                    stack.pop(); // STARTCHAR
+                    continue 'lines;
+                }
+                "ENDFONT" => continue 'lines,
+                v => match *stack.last().unwrap_or(&Token::EMPTY) {
+                    Token::ENCODING => {
+                        // Codepoints beyond Latin-1 don't parse as u8.
+                        let parsed = v.parse::<u8>();
+                        match parsed {
+                            Ok(enc) => {
+                                glyph.codepoint = enc;
+                                glyph.bitmap = [[0; FONT_WIDTH]; FONT_HEIGHT];
+                                stack.pop();
+                            }
+                            Err(_) => {
+                                stack.pop();
+                                stack.push(Token::UNUSED);
+                            }
+                        }
+                        continue 'lines;
+                    }
+                    Token::UNUSED => {
+                        continue 'lines;
+                    }
+                    Token::BITMAP => {
+                        // Each hex digit holds 4 pixels, leftmost in the most significant bit.
+                        for (i, c) in v.chars().enumerate() {
+                            //TODO: propagate this error to caller using Result<T, U>
+                            let d = c.to_digit(16).expect("corrupt bdf: BITMAP") as u8;
+                            for bit in 0..4 {
+                                let x = i * 4 + bit;
+                                if row < FONT_HEIGHT && x < FONT_WIDTH {
+                                    glyph.bitmap[row][x] = (d >> (3 - bit)) & 0b1;
+                                }
+                            }
+                        }
+                        row += 1;
+                        continue 'lines;
+                    }
+                    Token::EMPTY => {
+                        panic!("corrupt bdt: value {:?}, stack: {:?}", v, &stack)
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+    font
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_bdf() {
+        let font = parse_bdf();
+        for c in ' '..='~' {
+            assert!(font.has_glyph(c));
+            assert_eq!(font.get_glyph(c).codepoint as u32, c as u32);
+        }
+        assert!(font.get_glyph(' ').bitmap.iter().flatten().all(|b| *b == 0));
+        assert!(font.get_glyph('A').bitmap.iter().flatten().any(|b| *b == 1));
+    }
+
+    #[test]
+    fn test_latin1() {
+        let font = parse_bdf();
+        for c in ['\u{a0}', 'é', 'ß', 'ÿ'] {
+            assert!(font.has_glyph(c));
+            assert_eq!(font.get_glyph(c).codepoint as u32, c as u32);
+        }
+        // 'é' and 'e' differ by the accent.
+        assert_ne!(font.get_glyph('é').bitmap, font.get_glyph('e').bitmap);
+    }
+
+    #[test]
+    fn test_replacement_character() {
+        let font = parse_bdf();
+        for c in ['\u{1b}', '\u{85}', '€', '日'] {
+            assert!(!font.has_glyph(c));
+            assert_eq!(font.get_glyph(c).codepoint, b'?');
+        }
+    }
+
+    #[test]
+    fn test_draw_glyph() {
+        let font = parse_bdf();
+        let glyph = font.get_glyph('A');
+        let stride = FONT_WIDTH + 2;
+        let mut pixels = [b'x'; (FONT_WIDTH + 2) * FONT_HEIGHT];
+        draw_glyph(glyph, b'#', b'.', &mut pixels, stride);
+        for (y, row) in glyph.bitmap.iter().enumerate() {
+            for (x, b) in row.iter().enumerate() {
+                let expected = if *b == 1 { b'#' } else { b'.' };
+                assert_eq!(pixels[y * stride + x], expected);
+            }
+            // Pixels right of the glyph are left alone.
+            assert_eq!(&pixels[y * stride + FONT_WIDTH..(y + 1) * stride], b"xx");
+        }
+    }
+}
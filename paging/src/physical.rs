@@ -25,29 +25,31 @@ impl PageAllocator {
         }
 
         for b in init_free {
-            let mut start = b.0;
-            let mut size = b.1;
-            loop {
-                let mut shift = PAGE_SIZE;
-                for sz in PAGE_SIZE_ORDER..=PAGE_SIZE_ORDER + BLOCK_ORDER_COUNT {
-                    let block_size = 1 << sz;
-                    if !is_buddy_in_range(start, block_size, start, size) {
-                        self.free(Block {
-                            addr: start,
-                            order: sz,
-                        });
-                        break;
-                    }
-                    shift = block_size << 1;
-                }
-
-                start += shift;
-                size -= shift;
+            self.add_free(b.0, b.1);
+        }
+    }
 
-                if size == 0 {
+    /// Adds the pages in `start..start + size` to the free lists, such as memory that was
+    /// reserved at boot and is no longer used. Both must be page-aligned.
+    pub fn add_free(&mut self, start: usize, size: usize) {
+        let mut start = start;
+        let mut size = size;
+        while size > 0 {
+            let mut shift = PAGE_SIZE;
+            for sz in PAGE_SIZE_ORDER..=PAGE_SIZE_ORDER + BLOCK_ORDER_COUNT {
+                let block_size = 1 << sz;
+                if !is_buddy_in_range(start, block_size, start, size) {
+                    self.free(Block {
+                        addr: start,
+                        order: sz,
+                    });
                     break;
                 }
+                shift = block_size << 1;
             }
+
+            start += shift;
+            size -= shift;
         }
     }
 
@@ -88,6 +90,15 @@ impl PageAllocator {
             self.free_lists[list_idx].insert(block.addr);
         }
     }
+
+    /// Returns the free list for each block order, from the smallest blocks to the largest.
+    /// Each item is the order and the addresses of the free blocks of that order.
+    pub fn free_lists(&self) -> impl Iterator<Item = (usize, &BTreeSet<usize>)> {
+        self.free_lists
+            .iter()
+            .enumerate()
+            .map(|(i, list)| (i + PAGE_SIZE_ORDER, list))
+    }
 }
 
 fn is_buddy_in_range(addr: usize, block_size: usize, start: usize, size: usize) -> bool {
@@ -231,4 +242,34 @@ mod test {
             "Case 4: Blocks with 0x8000 size should be 1"
         );
     }
+
+    #[test]
+    fn free_lists_works() {
+        let mut pa = PageAllocator::new();
+        // Range [0x2000, 0x8000].
+        let init_free = [(0x2000, 0x6000)];
+        pa.init(&init_free);
+
+        let lists = pa
+            .free_lists()
+            .filter(|(_, list)| !list.is_empty())
+            .map(|(order, list)| (order, list.iter().copied().collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(lists, [(13, vec![0x2000]), (14, vec![0x4000])]);
+    }
+
+    #[test]
+    fn add_free_works() {
+        let mut pa = PageAllocator::new();
+        pa.init(&[(0x2000, 0x2000), (0x4000, 0x2000)]);
+        // Merges with its buddy at 0x4000 into one 0x4000-byte block.
+        pa.add_free(0x6000, 0x2000);
+
+        let lists = pa
+            .free_lists()
+            .filter(|(_, list)| !list.is_empty())
+            .map(|(order, list)| (order, list.iter().copied().collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(lists, [(13, vec![0x2000]), (14, vec![0x4000])]);
+    }
 }
@@ -0,0 +1,53 @@
+//! Stack backtraces by following the frame pointers.
+//!
+//! The kernel is built with frame pointers, so each frame starts with a record of the caller's
+//! `%rbp` and the return address. The chain ends at a null `%rbp`, which `boot.s` and
+//! `_task_entry` set up, and ISRs push a record for the interrupted code.
+
+use crate::arch::x86_64::mm::KERNEL_BASE;
+use crate::kernel::symbols;
+
+use core::arch::asm;
+use core::fmt::Write;
+use util::demangle::Demangle;
+
+// Stop at this many frames, in case the chain is corrupted into a loop.
+const MAX_FRAMES: usize = 32;
+
+/// Writes the backtrace of the caller to `out`, one frame per line.
+#[inline(never)]
+pub fn write(out: &mut impl Write) {
+    let rbp: usize;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp);
+    }
+    // SAFETY: %rbp is the frame pointer of this function.
+    unsafe { write_from(out, rbp) }
+}
+
+/// Writes the backtrace that starts at the frame record at `rbp`.
+///
+/// # Safety
+/// `rbp` must be 0, or point to a frame record of a chain that ends with 0.
+pub unsafe fn write_from(out: &mut impl Write, mut rbp: usize) {
+    for i in 0..MAX_FRAMES {
+        // Kernel stacks are all above KERNEL_BASE.
+        if rbp < KERNEL_BASE || rbp % 8 != 0 {
+            return;
+        }
+        let record = rbp as *const usize;
+        let (next, return_addr) = unsafe { (*record, *record.add(1)) };
+        if return_addr == 0 {
+            return;
+        }
+        // The return address is just after the call, which may be the last instruction of the
+        // function.
+        let _ = write!(out, "  #{:<2} {:#018x} ", i, return_addr);
+        let _ = match symbols::lookup(return_addr - 1) {
+            Some(sym) => writeln!(out, "{}+{:#x}", Demangle(sym.name), return_addr - sym.addr),
+            None => writeln!(out, "??"),
+        };
+        rbp = next;
+    }
+    let _ = writeln!(out, "  ...");
+}
@@ -160,4 +160,6 @@ _low_start:
     # This was conveniently passed as first argument of _low_start by the bootloader.
     movabs $KERNEL_BASE, %rcx
     addq %rcx, %rdi
+    # End the frame pointer chain for backtraces.
+    xorq %rbp, %rbp
     call start
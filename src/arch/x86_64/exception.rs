@@ -0,0 +1,188 @@
+//! Handlers for the 32 architectural exceptions.
+//!
+//! Every exception goes through `exception_common` in `isr.s`, which saves the registers as an
+//! `ExceptionFrame`. Page faults that the memory manager can handle, debug exceptions and
+//! breakpoints return to the interrupted code. Any other exception in a task that can be stopped
+//! safely kills the task, and otherwise panics the kernel.
+
+use crate::arch::x86_64::{backtrace, mm};
+use crate::drivers::serial;
+use crate::kernel::sched;
+
+use core::fmt::{self, Display, Formatter, Write};
+use log::{error, warn};
+
+extern "C" {
+    pub(super) static exception_isrs: [usize; 32];
+}
+
+pub const DEBUG: u64 = 0x1;
+pub const NMI: u64 = 0x2;
+pub const BREAKPOINT: u64 = 0x3;
+pub const DOUBLE_FAULT: u64 = 0x8;
+pub const PAGE_FAULT: u64 = 0xe;
+pub const MACHINE_CHECK: u64 = 0x12;
+
+const NAMES: [&str; 32] = [
+    "divide error",
+    "debug exception",
+    "non-maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "BOUND range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid TSS",
+    "segment not present",
+    "stack-segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved exception 15",
+    "x87 floating-point error",
+    "alignment check",
+    "machine check",
+    "SIMD floating-point exception",
+    "virtualization exception",
+    "control protection exception",
+    "reserved exception 22",
+    "reserved exception 23",
+    "reserved exception 24",
+    "reserved exception 25",
+    "reserved exception 26",
+    "reserved exception 27",
+    "hypervisor injection exception",
+    "VMM communication exception",
+    "security exception",
+    "reserved exception 31",
+];
+
+/// Registers of the interrupted code, as saved by `exception_common`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    // %rsp in the middle of saving the registers, which is of no use.
+    _rsp: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Error code pushed by the CPU, or 0 for exceptions without one.
+    pub error_code: u64,
+    // Pushed by the CPU.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    /// Returns whether interrupts were enabled in the interrupted code.
+    fn interrupts_enabled(&self) -> bool {
+        (self.rflags >> 9) & 1 != 0
+    }
+}
+
+impl Display for ExceptionFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RIP: {:#018x}  CS: {:#06x}  RFLAGS: {:#010x}",
+            self.rip, self.cs, self.rflags
+        )?;
+        writeln!(f, "RSP: {:#018x}  SS: {:#06x}", self.rsp, self.ss)?;
+        let registers = [
+            ("RAX", self.rax),
+            ("RBX", self.rbx),
+            ("RCX", self.rcx),
+            ("RDX", self.rdx),
+            ("RSI", self.rsi),
+            ("RDI", self.rdi),
+            ("RBP", self.rbp),
+            ("R8 ", self.r8),
+            ("R9 ", self.r9),
+            ("R10", self.r10),
+            ("R11", self.r11),
+            ("R12", self.r12),
+            ("R13", self.r13),
+            ("R14", self.r14),
+            ("R15", self.r15),
+        ];
+        for line in registers.chunks(3) {
+            for (i, (name, value)) in line.iter().enumerate() {
+                if i > 0 {
+                    f.write_str("  ")?;
+                }
+                write!(f, "{}: {:#018x}", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the name of exception `vector`.
+pub fn name(vector: u64) -> &'static str {
+    NAMES
+        .get(vector as usize)
+        .copied()
+        .unwrap_or("unknown exception")
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn exception_handler(frame: &mut ExceptionFrame, cr2: usize) {
+    let vector = frame.vector;
+    match vector {
+        PAGE_FAULT if mm::handle_page_fault(frame.error_code as usize, cr2) => return,
+        DEBUG | BREAKPOINT => {
+            warn!("{} at {:#x}\n{}", name(vector), frame.rip, frame);
+            return;
+        }
+        _ => {}
+    }
+
+    // A double fault runs on its own stack, where there is no current task. The other fatal
+    // exceptions leave the machine in a state we can't recover from.
+    let fatal = matches!(vector, DOUBLE_FAULT | NMI | MACHINE_CHECK);
+    // Task 0 runs the kernel main loop. With interrupts disabled, the task may hold a lock that
+    // would never be released.
+    if fatal || usize::from(sched::current_task()) == 0 || !frame.interrupts_enabled() {
+        panic!(
+            "{} (error code {:#x}, CR2 {:#x})\n{}",
+            name(vector),
+            frame.error_code,
+            cr2,
+            frame
+        );
+    }
+
+    let task = sched::current_task();
+    error!(
+        "{} in task {} (error code {:#x}, CR2 {:#x}), killing it\n{}",
+        name(vector),
+        task,
+        frame.error_code,
+        cr2,
+        frame
+    );
+    let mut out = serial::Handle::new();
+    let _ = writeln!(out, "Backtrace:");
+    // SAFETY: The task was running kernel code, which keeps the chain of frame records that
+    //         starts at its %rbp.
+    unsafe { backtrace::write_from(&mut out, frame.rbp as usize) };
+    sched::lock().exit();
+}
@@ -177,6 +177,10 @@ impl ClockSource for WithSpinLock<Option<HPET>> {
             None => 0,
         }
     }
+
+    fn try_get_tick(&self) -> Option<u64> {
+        try_get_time()
+    }
 }
 
 pub fn init(hpet: acpi::HPET) -> &'static (dyn ClockSource + Sync + Send) {
@@ -229,6 +233,19 @@ pub fn get_time() -> u64 {
     }
 }
 
+/// Like `get_time()`, but returns `None` instead of waiting if the HPET is locked, such as when
+/// called from a tick handler.
+pub fn try_get_time() -> Option<u64> {
+    let hpet = HPET.try_lock()?;
+    match hpet.as_ref() {
+        Some(hpet) => {
+            let ticks = hpet.read_main_counter();
+            Some(ticks * hpet.counter_clock_period as u64)
+        }
+        None => Some(0),
+    }
+}
+
 pub fn register_tick(tick: fn(u64)) {
     let mut hpet = HPET.lock();
     if let Some(hpet) = hpet.as_mut() {
@@ -1,8 +1,13 @@
+use alloc::collections::BTreeMap;
 use alloc::vec;
+use alloc::vec::Vec;
 use core::arch::asm;
 use core::ffi::c_void;
+use core::ops::Range;
 use core::ptr::{read_volatile, write_volatile};
 
+use super::exception::{self, exception_isrs};
+use super::pm::DOUBLE_FAULT_IST;
 use super::{hpet, pit};
 use crate::drivers::{acpi, serial};
 use crate::kernel::sched;
@@ -11,20 +16,20 @@ use crate::locking::spinlock::WithSpinLock;
 
 use crate::arch::x86_64::syscall;
 use crate::arch::x86_64::syscall::SYSCALL;
+use log::warn;
 
 extern "C" {
-    fn page_fault_isr();
-    fn general_protection_fault_isr();
     fn ps2_keyboard_isr();
     fn pit_isr();
     fn hpet_isr();
     fn syscall_isr();
     fn com0_isr();
+    fn com1_isr();
     fn reload_idt(idtr: *const IDTR);
 }
 
 extern "C" {
-    static mut device_isr_entries: [[u8; 7]; 96];
+    static mut device_isr_entries: [[u8; 7]; 128];
 }
 
 pub static IOAPIC: WithSpinLock<IOAPIC> = WithSpinLock::new(IOAPIC::new(0));
@@ -33,8 +38,67 @@ pub static LOCAL_APIC: WithSpinLock<LocalAPIC> = WithSpinLock::new(LocalAPIC::ne
 
 static IDT: WithSpinLock<[u128; 256]> = WithSpinLock::new([0; 256]);
 
-// TODO: lock this properly
-static mut IRQ_HANDLERS: [usize; 128] = [0; 128];
+/// Vectors for device interrupts. The ones below are for exceptions, and the ones above are for
+/// system calls and the spurious interrupt.
+const DEVICE_VECTORS: Range<u8> = 0x20..0x80;
+
+// Device vectors with fixed uses: the timer, the PS/2 keyboard, and COM2/4 and COM1/3.
+const FIXED_VECTORS: [u8; 4] = [0x20, 0x21, 0x23, 0x24];
+
+static IRQS: WithSpinLock<IrqTable> = WithSpinLock::new(IrqTable::new());
+
+/// Handlers and allocation state of the device vectors.
+struct IrqTable {
+    /// Handlers of each vector, in the order they were registered, with their IDs.
+    handlers: [Vec<(usize, extern "C" fn(u64))>; 128],
+    allocated: [bool; 128],
+    /// Vectors that I/O APIC lines are routed to.
+    gsi_vectors: BTreeMap<u32, u8>,
+    /// Flags of the MADT interrupt source overrides, by the line they override.
+    gsi_flags: BTreeMap<u32, u16>,
+    next_id: usize,
+}
+
+impl IrqTable {
+    const fn new() -> Self {
+        let mut allocated = [false; 128];
+        let mut i = 0;
+        while i < FIXED_VECTORS.len() {
+            allocated[FIXED_VECTORS[i] as usize] = true;
+            i += 1;
+        }
+        Self {
+            handlers: [const { Vec::new() }; 128],
+            allocated,
+            gsi_vectors: BTreeMap::new(),
+            gsi_flags: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn allocate(&mut self) -> Option<u8> {
+        self.allocate_block(1)
+    }
+
+    /// Allocates `count` contiguous vectors, starting at a multiple of `count`.
+    fn allocate_block(&mut self, count: usize) -> Option<u8> {
+        let first = DEVICE_VECTORS.clone().step_by(count).find(|v| {
+            let v = *v as usize;
+            v + count <= DEVICE_VECTORS.end as usize
+                && self.allocated[v..v + count].iter().all(|a| !a)
+        })?;
+        let first = first as usize;
+        self.allocated[first..first + count].fill(true);
+        Some(first as u8)
+    }
+}
+
+/// A handler registered with `register_handler()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: usize,
+}
 
 #[repr(C)]
 #[repr(packed)]
@@ -49,61 +113,36 @@ pub fn init(madt: &acpi::MADT) -> u32 {
     let mut idt = unsafe { IDT.lock() };
     // Set up each fault and IRQ handler.
     // TODO: make IRQ handlers pluggable from outside this module.
-    // page fault handler
-    {
+    // exception handlers
+    for (vector, &handler) in unsafe { exception_isrs.iter().enumerate() } {
         let mut descriptor: u128 = 0;
-        let handler = page_fault_isr as usize;
         descriptor |= (handler & 0xffff) as u128; // offset 15:0
         descriptor |= ((handler & 0xffffffffffff0000) as u128) << 32; // offset 63:16
         descriptor |= 0x8 << 16; // segment selector
+        if vector as u64 == exception::DOUBLE_FAULT {
+            descriptor |= (DOUBLE_FAULT_IST as u128) << 32; // interrupt stack table
+        }
         descriptor |= 0xe << 40; // type: 0b1110
         descriptor |= 8 << 44; // Present flag
 
-        idt[0xe] = descriptor;
+        idt[vector] = descriptor;
     }
 
-    // general protection fault handler
-    {
-        let mut descriptor: u128 = 0;
-        let handler = general_protection_fault_isr as usize;
-        descriptor |= (handler & 0xffff) as u128; // offset 15:0
-        descriptor |= ((handler & 0xffffffffffff0000) as u128) << 32; // offset 63:16
-        descriptor |= 0x8 << 16; // segment selector
-        descriptor |= 0xe << 40; // type: 0b1110
-        descriptor |= 8 << 44; // Present flag
-
-        idt[0xd] = descriptor;
-    }
-
-    // pit handler
-    // Disable in hopes that HPET is available.
-    // TODO: register the correct handler at runtime.
-    // {
-    //     let mut descriptor: u128 = 0;
-    //     let handler = pit_isr as usize;
-    //     descriptor |= (handler & 0xffff) as u128; // offset 15:0
-    //     descriptor |= ((handler & 0xffffffffffff0000) as u128) << 32; // offset 63:16
-    //     descriptor |= 0x8 << 16; // segment selector
-    //     descriptor |= 0xe << 40; // type: 0b1110
-    //     descriptor |= 8 << 44; // Present flag
-    //
-    //     idt[0x20] = descriptor;
-    // }
-
-    // serial port handler
-    {
+    // serial port handlers
+    // COM1 and COM3 share IRQ 4, and COM2 and COM4 share IRQ 3.
+    for (vector, handler) in [(0x24, com0_isr as usize), (0x23, com1_isr as usize)] {
         let mut descriptor: u128 = 0;
-        let handler = com0_isr as usize;
         descriptor |= (handler & 0xffff) as u128; // offset 15:0
         descriptor |= ((handler & 0xffffffffffff0000) as u128) << 32; // offset 63:16
         descriptor |= 0x8 << 16; // segment selector
         descriptor |= 0xe << 40; // type: 0b1110
         descriptor |= 8 << 44; // Present flag
 
-        idt[0x24] = descriptor;
+        idt[vector] = descriptor;
     }
 
     // HPET handler
+    // `use_pit()` replaces it when the PIT is the clock source.
     {
         let mut descriptor: u128 = 0;
         let handler = hpet_isr as usize;
@@ -129,18 +168,22 @@ pub fn init(madt: &acpi::MADT) -> u32 {
         idt[0x80] = descriptor;
     }
 
-    // Interrupt 0x26
-    // TODO: set up for all interrupts in 0x20-0x7f inclusive.
-    {
+    // device interrupt handlers
+    // Vectors are handed out by `allocate_vector()` and `route_gsi()`. Until a handler is
+    // registered, an interrupt on them is logged as spurious.
+    for vector in DEVICE_VECTORS.map(usize::from) {
+        if FIXED_VECTORS.contains(&(vector as u8)) {
+            continue;
+        }
         let mut descriptor: u128 = 0;
-        let handler = unsafe { &device_isr_entries[0x26] } as *const u8 as usize;
+        let handler = unsafe { &device_isr_entries[vector] } as *const u8 as usize;
         descriptor |= (handler & 0xffff) as u128; // offset 15:0
         descriptor |= ((handler & 0xffffffffffff0000) as u128) << 32; // offset 63:16
         descriptor |= 0x8 << 16; // segment selector
         descriptor |= 0xe << 40; // type: 0b1110
         descriptor |= 8 << 44; // Present flag
 
-        idt[0x26] = descriptor;
+        idt[vector] = descriptor;
     }
 
     // Set IDTR
@@ -159,6 +202,11 @@ pub fn init(madt: &acpi::MADT) -> u32 {
     let ioapic = IOAPIC::new(madt.ioapic_addr);
     // Don't consider global interrupt base for now.
     let lapic_id = lapic.id();
+    IRQS.lock().gsi_flags = madt
+        .interrupt_mappings
+        .iter()
+        .map(|m| (m.global_system_interrupt, m.flags))
+        .collect();
     ioapic.remap_all(lapic_id);
 
     unsafe {
@@ -171,6 +219,21 @@ pub fn init(madt: &acpi::MADT) -> u32 {
     lapic_id
 }
 
+/// Handles the timer interrupt (vector 0x20) with the PIT handler instead of the HPET one. Both
+/// timers are on line 2 of the I/O APIC.
+pub fn use_pit() {
+    let mut idt = unsafe { IDT.lock() };
+    let mut descriptor: u128 = 0;
+    let handler = pit_isr as usize;
+    descriptor |= (handler & 0xffff) as u128; // offset 15:0
+    descriptor |= ((handler & 0xffffffffffff0000) as u128) << 32; // offset 63:16
+    descriptor |= 0x8 << 16; // segment selector
+    descriptor |= 0xe << 40; // type: 0b1110
+    descriptor |= 8 << 44; // Present flag
+
+    idt[0x20] = descriptor;
+}
+
 /// Returns whether interrupts (`IF`) are currently enabled.
 pub fn interrupts_enabled() -> bool {
     let rflags: u64;
@@ -194,12 +257,6 @@ pub fn disable_interrupts() {
     }
 }
 
-#[unsafe(no_mangle)]
-unsafe extern "C" fn general_protection_fault_handler() {
-    let foo = 1 + 1;
-    /* no-op */
-}
-
 #[unsafe(no_mangle)]
 unsafe extern "C" fn ps2_keyboard_handler() {
     let foo = 1 + 1;
@@ -255,30 +312,123 @@ unsafe extern "C" fn syscall_handler(
 
 #[unsafe(no_mangle)]
 unsafe extern "C" fn com0_handler() {
-    serial::read_com1();
+    serial::handle_interrupt();
     let mut lapic = LOCAL_APIC.lock();
     lapic.write(0xb0, 0)
 }
 
-pub fn register_handler(vector: u8, handler: extern "C" fn(u64)) {
-    // SAFETY: not safe yet
-    unsafe {
-        IRQ_HANDLERS[vector as usize] = handler as usize;
+/// Allocates a device vector that nothing else uses.
+pub fn allocate_vector() -> Option<u8> {
+    IRQS.lock().allocate()
+}
+
+/// Allocates `count` contiguous device vectors, starting at a multiple of `count`, which must be a
+/// power of two. This is what MSI with multiple messages needs. Returns the first vector.
+pub fn allocate_vectors(count: usize) -> Option<u8> {
+    assert!(
+        count.is_power_of_two(),
+        "vector count must be a power of two"
+    );
+    IRQS.lock().allocate_block(count)
+}
+
+/// Frees a vector from `allocate_vector()` or `allocate_vectors()`, once its handlers are
+/// unregistered.
+pub fn free_vector(vector: u8) {
+    let mut irqs = IRQS.lock();
+    debug_assert!(irqs.handlers[vector as usize].is_empty());
+    if !FIXED_VECTORS.contains(&vector) {
+        irqs.allocated[vector as usize] = false;
+    }
+}
+
+/// Returns the vector that I/O APIC line `gsi` is routed to, routing it to a newly allocated
+/// vector first if it isn't yet. Devices on a shared line get the same vector, so their handlers
+/// must check whether their device raised the interrupt.
+///
+/// The line is programmed with the polarity and trigger mode of its interrupt source override.
+/// Lines without one are taken to be PCI INTx lines, which are level-triggered and active low.
+pub fn route_gsi(gsi: u32) -> Option<u8> {
+    let mut irqs = IRQS.lock();
+    if let Some(&vector) = irqs.gsi_vectors.get(&gsi) {
+        // The line is masked when its last handler is unregistered.
+        IOAPIC.lock().mask_line(false, gsi);
+        return Some(vector);
+    }
+    let vector = irqs.allocate()?;
+    irqs.gsi_vectors.insert(gsi, vector);
+    let mode = match irqs.gsi_flags.get(&gsi) {
+        Some(&flags) => redirection_mode(flags),
+        None => IOREDTBL_ACTIVE_LOW | IOREDTBL_LEVEL_TRIGGERED,
+    };
+    let lapic_id = LOCAL_APIC.lock().id();
+    IOAPIC.lock().remap(lapic_id, gsi, vector as u32 | mode);
+    Some(vector)
+}
+
+// Bits of the low half of an I/O APIC redirection table entry.
+const IOREDTBL_ACTIVE_LOW: u32 = 1 << 13;
+const IOREDTBL_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+/// Returns the polarity and trigger mode bits of a redirection table entry for the MPS INTI
+/// `flags` of an interrupt source override. Overrides are for ISA interrupts, so the modes that
+/// conform to the bus are active high and edge-triggered.
+fn redirection_mode(flags: u16) -> u32 {
+    let mut mode = 0;
+    if flags & 0b11 == 0b11 {
+        mode |= IOREDTBL_ACTIVE_LOW;
+    }
+    if (flags >> 2) & 0b11 == 0b11 {
+        mode |= IOREDTBL_LEVEL_TRIGGERED;
+    }
+    mode
+}
+
+/// Adds `handler` to the handlers of `vector`, which it shares with any handlers already there.
+/// Handlers are called with the vector in interrupt context, and must not register or unregister
+/// handlers. The end of interrupt is signalled after all of them have run.
+pub fn register_handler(vector: u8, handler: extern "C" fn(u64)) -> HandlerId {
+    let mut irqs = IRQS.lock();
+    let id = irqs.next_id;
+    irqs.next_id += 1;
+    irqs.handlers[vector as usize].push((id, handler));
+    HandlerId { vector, id }
+}
+
+/// Removes a handler added by `register_handler()`. When the last handler of a vector that an
+/// I/O APIC line is routed to is removed, the line is masked.
+pub fn unregister_handler(handler: HandlerId) {
+    let mut irqs = IRQS.lock();
+    let handlers = &mut irqs.handlers[handler.vector as usize];
+    handlers.retain(|(id, _)| *id != handler.id);
+    if !handlers.is_empty() {
+        return;
+    }
+    for (&gsi, _) in irqs
+        .gsi_vectors
+        .iter()
+        .filter(|(_, v)| **v == handler.vector)
+    {
+        IOAPIC.lock().mask_line(true, gsi);
     }
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn device_handler(vector: u64) {
-    // SAFETY: NOT safe, because the static mut [usize; 128] is not behind any lock.
-    let handler = unsafe { IRQ_HANDLERS[vector as usize] } as *const ();
-
-    // SAFETY: All code that has been written to IRQ_HANDLERS should have made sure that what
-    //         they write are valid function address.
-    let handler = unsafe {
-        // Should `handler` be null checked? Or is it alright to just page fault.
-        core::mem::transmute::<*const (), fn(u64)>(handler)
-    };
-    handler(vector)
+    {
+        let irqs = IRQS.lock();
+        let handlers = irqs
+            .handlers
+            .get(vector as usize)
+            .map_or(&[][..], |h| &h[..]);
+        if handlers.is_empty() {
+            warn!("Spurious interrupt on vector {:#x}", vector);
+        }
+        for (_, handler) in handlers {
+            handler(vector);
+        }
+    }
+    LOCAL_APIC.lock().end_of_interrupt();
 }
 
 /// Mask 8259 PIC.
@@ -352,9 +502,11 @@ impl IOAPIC {
         // self.write(0x19, (lapic_id << 24) & 0x0f000000);
         self.remap(lapic_id, 4, 0x24);
 
-        // HPET
-        // TODO: remap either the PIT or HPET handler depending on availability.
-        // We mask HPET until it is properly initialized later.
+        // COM 2, 4
+        self.remap(lapic_id, 3, 0x23);
+
+        // HPET or PIT, whichever is the clock source.
+        // We mask the line until the timer is properly initialized later.
         self.remap(lapic_id, 2, 0x10020);
 
         // Mouse (masked)
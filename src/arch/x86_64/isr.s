@@ -26,39 +26,78 @@
     popq %rax
 .endm
 
+# Pushes a frame record for the interrupted code, so that backtraces continue past the ISR. The
+# return address of the record is the interrupted %rip, which is at 96(%rsp) after the vector and
+# pusha.
+.macro push_frame rip=96
+    pushq \rip(%rsp)
+    pushq %rbp
+    movq %rsp, %rbp
+.endm
+
+.macro pop_frame
+    addq $16, %rsp
+.endm
+
 
 .code64
 .section .text
 
-# 13 General Protection Fault
-.global general_protection_fault_isr
-general_protection_fault_isr:
-    # Just halt
-    hlt
+# Exceptions 0-31
+# The stubs push a 0 for the exceptions that don't come with an error code, so that every
+# exception has the same stack layout: the vector at 128(%rsp) and the error code at 136(%rsp) after
+# exception_common has pushed the registers. Rust sees it as an `ExceptionFrame`.
+.macro exception_isr vector
+exception_isr_\vector:
+    pushq $0 /* error code */
+    pushq $\vector
+    jmp exception_common
+.endm
+
+.macro exception_isr_with_error_code vector
+exception_isr_\vector:
+    pushq $\vector
+    jmp exception_common
+.endm
+
+.irp vector, 0, 1, 2, 3, 4, 5, 6, 7, 9, 15, 16, 18, 19, 20, 22, 23, 24, 25, 26, 27, 28, 31
+    exception_isr \vector
+.endr
+.irp vector, 8, 10, 11, 12, 13, 14, 17, 21, 29, 30
+    exception_isr_with_error_code \vector
+.endr
+
+exception_common:
     pusha
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
     cld
-    call general_protection_fault_handler
+    movq %rsp, %rdi
+    movq %cr2, %rsi
+    push_frame 144
+    call exception_handler
+    pop_frame
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    call check_runtime
     popa
-    # we need to pop the CPU-pushed error code here.
-    addq %rsp, 8
+    addq $16, %rsp /* pop vector and error code */
     iretq
 
-# 14 Page Fault
-.global page_fault_isr
-page_fault_isr:
-    pusha
-    cld
-    mov 88(%rsp), %rdi
-    mov %cr2, %rsi
-    call page_fault_handler
-    jmp isr_exit
-
 .global ps2_keyboard_isr
 ps2_keyboard_isr:
     pushq $0x21 /* vector: u64,  88(%rsp) */
     pusha
     cld
+    push_frame
     call ps2_keyboard_handler
+    pop_frame
     jmp isr_exit
 
 .global pit_isr
@@ -66,7 +105,9 @@ pit_isr:
     pushq $0x20 /* vector: u64,  88(%rsp) */
     pusha
     cld
+    push_frame
     call pit_handler
+    pop_frame
     jmp isr_exit
 
 
@@ -75,7 +116,19 @@ com0_isr:
     pushq $0x24 /* vector: u64,  88(%rsp) */
     pusha
     cld
+    push_frame
     call com0_handler
+    pop_frame
+    jmp isr_exit
+
+.global com1_isr
+com1_isr:
+    pushq $0x23 /* vector: u64,  88(%rsp) */
+    pusha
+    cld
+    push_frame
+    call com0_handler
+    pop_frame
     jmp isr_exit
 
 .global hpet_isr
@@ -83,7 +136,9 @@ hpet_isr:
     pushq $0x20 /* vector: u64,  88(%rsp) */
     pusha
     cld
+    push_frame
     call hpet_handler
+    pop_frame
     jmp isr_exit
 
 .global syscall_isr
@@ -97,7 +152,9 @@ syscall_isr:
     movq 8(%rsp),  %r8  /* %r10: arg3 */
     movq 24(%rsp), %r9  /* %r8: arg4 */
     cld
+    push_frame
     call syscall_handler
+    pop_frame
     jmp isr_exit
 
 .macro gen_device_isrs from=0, to
@@ -110,14 +167,18 @@ syscall_isr:
 
 .global device_isr_entries
 device_isr_entries:
-gen_device_isrs to=63
+# Split in two, because GAS limits how deep macros can nest.
+gen_device_isrs 0, 63
+gen_device_isrs 64, 127
 .fill 128, 1, 0xcc /* To ensure near jump is used */
 
 device_isr_common:
     pusha
     cld
     mov 88(%rsp), %rdi
+    push_frame
     call device_handler
+    pop_frame
     jmp isr_exit
 
 .global isr_exit
@@ -128,6 +189,14 @@ isr_exit:
     iretq
 
 
+.section .rodata
+.global exception_isrs
+exception_isrs:
+.irp vector, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    .quad exception_isr_\vector
+.endr
+
+.section .text
 .global reload_idt
 reload_idt:
     lidt 0(%rdi)
@@ -55,6 +55,7 @@ pub fn init_mm(memory_map: &[MemoryDescriptor]) {
                     free_blocks.push(block)
                 }
             }
+            // Pages of the initrd are freed by `initrd::release()`.
             _ => { /* noop */ }
         }
     }
@@ -190,26 +191,24 @@ fn exclude_ranges(
     block
 }
 
-#[unsafe(no_mangle)]
-unsafe extern "C" fn page_fault_handler(error_code: usize, virt_addr: usize) {
+/// Handles a page fault at `virt_addr` by allocating a page or copying a copy-on-write one.
+/// Returns false for faults that the kernel cannot handle, such as null pointer dereferences.
+pub(crate) fn handle_page_fault(error_code: usize, virt_addr: usize) -> bool {
+    let mut mapper = MAPPER.lock();
+    let Some(mapper) = mapper.as_mut() else {
+        return false;
+    };
     // TODO: support other page faults
     if error_code & PRESENT_FLAG == 0 {
         // TODO: on-demand paging
         if virt_addr == 0 {
-            // TODO: SIGSEGV to userland, or kernel panic if kernel mode
-            asm!("cli; hlt");
-            return;
+            // TODO: SIGSEGV to userland
+            return false;
         }
-        MAPPER.lock().as_mut().unwrap().alloc_page_at(virt_addr);
-        return;
+        return mapper.alloc_page_at(virt_addr).is_ok();
     }
     if error_code & RW_FLAG == RW_FLAG {
-        MAPPER
-            .lock()
-            .as_mut()
-            .unwrap()
-            .cow(virt_addr as *mut u8, SCRATCH);
-        return;
+        return mapper.cow(virt_addr as *mut u8, SCRATCH).is_ok();
     }
-    asm!("cli; hlt");
+    false
 }
@@ -1,7 +1,10 @@
+pub mod backtrace;
+pub mod exception;
 pub mod hpet;
 pub mod interrupt;
 pub mod mm;
 pub mod pit;
 pub mod pm;
 pub mod port;
+pub mod reset;
 pub mod syscall;
@@ -5,6 +5,9 @@ use crate::kernel::clock::Clock;
 use crate::kernel::clocksource::ClockSource;
 use crate::locking::spinlock::WithSpinLock;
 
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::Relaxed;
+
 const CHAN0_DATA: u8 = 0x40;
 const CHAN1_DATA: u8 = 0x41;
 const CHAN2_DATA: u8 = 0x42;
@@ -14,6 +17,10 @@ const IOAPIC_LINE: u32 = 2;
 
 static PIT: WithSpinLock<PIT> = WithSpinLock::new(PIT::new());
 
+// Time since the PIT was started in nanoseconds. It is kept outside of `PIT`, which is locked while
+// the tick callback runs.
+static ELAPSED: AtomicU64 = AtomicU64::new(0);
+
 pub const TICK_INTERVAL: u64 = 1_000_000; // In nanoseconds
 
 pub struct PIT {
@@ -45,7 +52,23 @@ impl PIT {
     }
 }
 
+impl ClockSource for WithSpinLock<PIT> {
+    fn get_tick(&self) -> u64 {
+        ELAPSED.load(Relaxed)
+    }
+}
+
+/// Starts the PIT with a 1 ms period, and routes its interrupt to the PIT handler. The PIT only
+/// counts in ticks, so it is a coarser clock source than the HPET.
+pub fn init() -> &'static (dyn ClockSource + Sync + Send) {
+    PIT.lock().start_rate();
+    interrupt::use_pit();
+    interrupt::mask_line(false, IOAPIC_LINE);
+    &PIT
+}
+
 pub fn pit_tick() {
+    ELAPSED.fetch_add(TICK_INTERVAL, Relaxed);
     let pit = PIT.lock();
     if let Some(tick) = pit.tick {
         tick(TICK_INTERVAL);
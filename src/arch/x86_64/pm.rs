@@ -1,4 +1,6 @@
 use alloc::vec;
+use core::arch::asm;
+use core::mem::size_of;
 
 #[link(name = "pm")]
 extern "C" {
@@ -14,6 +16,44 @@ struct GDTR {
 
 type GDT = vec::Vec<u64>;
 
+/// Selector of the task state segment, which takes GDT entries 3 and 4.
+const TSS_SELECTOR: u16 = 0x18;
+
+/// Interrupt stack table index of the stack that double faults run on. The IST indices in IDT
+/// descriptors start at 1.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+const IST_STACK_SIZE: usize = 0x2000;
+
+/// 64-bit task state segment. We only use it for the interrupt stack table.
+#[repr(C, packed)]
+struct TaskStateSegment {
+    reserved0: u32,
+    rsp: [u64; 3],
+    reserved1: u64,
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+// Aligned like kernel stacks, so that masking %rsp finds zeros instead of a task.
+#[repr(C, align(0x2000))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+static mut TSS: TaskStateSegment = TaskStateSegment {
+    reserved0: 0,
+    rsp: [0; 3],
+    reserved1: 0,
+    ist: [0; 7],
+    reserved2: 0,
+    reserved3: 0,
+    // No I/O permission bitmap.
+    iomap_base: size_of::<TaskStateSegment>() as u16,
+};
+
+static mut DOUBLE_FAULT_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
+
 struct SegmentDescriptor {
     base: usize,
     limit: u16,
@@ -51,6 +91,16 @@ pub fn init() -> GDT {
     gdt[1] = kernel_code.into();
     gdt[2] = kernel_data.into();
 
+    // A double fault can be caused by a kernel stack overflow, so it gets a stack of its own.
+    let tss = unsafe {
+        TSS.ist[(DOUBLE_FAULT_IST - 1) as usize] =
+            (&raw const DOUBLE_FAULT_STACK as usize + IST_STACK_SIZE) as u64;
+        &raw const TSS as usize as u64
+    };
+    let limit = (size_of::<TaskStateSegment>() - 1) as u64;
+    gdt[3] = limit | (tss & 0xffffff) << 16 | 0x89 << 40 | (tss & 0xff000000) << 32;
+    gdt[4] = tss >> 32;
+
     // Set GDTR
     let gdtr = GDTR {
         limit: 39,
@@ -60,7 +110,9 @@ pub fn init() -> GDT {
         reload_gdt(&gdtr as *const GDTR);
     }
 
-    // TODO: setup a task state segment for kernel-context task
+    unsafe {
+        asm!("ltr {0:x}", in(reg) TSS_SELECTOR);
+    }
 
     // Return to caller so that our new GDT wouldn't get torn down.
     // maybe consider using Box::leak()
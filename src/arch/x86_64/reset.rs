@@ -0,0 +1,39 @@
+use crate::arch::x86_64::port;
+
+use core::arch::asm;
+use core::hint::spin_loop;
+
+// 8042 keyboard controller ports
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+
+// Reset Control Register of PIIX/ICH chipsets.
+const RESET_CONTROL: u16 = 0xcf9;
+
+/// Resets the machine.
+pub fn reboot() -> ! {
+    unsafe {
+        // Pulse the CPU reset line through the 8042 keyboard controller, once it can take a
+        // command.
+        for _ in 0..0x10000 {
+            if port::inb(KBC_STATUS) & 0b10 == 0 {
+                break;
+            }
+            spin_loop();
+        }
+        port::outb(KBC_COMMAND, 0xfe);
+
+        // Hard reset through the chipset.
+        port::outb(RESET_CONTROL, 0x02);
+        port::outb(RESET_CONTROL, 0x06);
+
+        // If we are still running, triple fault by raising an exception with an empty IDT.
+        let idtr = [0u8; 10];
+        asm!(
+            "lidt [{}]",
+            "int3",
+            in(reg) idtr.as_ptr(),
+            options(noreturn),
+        );
+    }
+}
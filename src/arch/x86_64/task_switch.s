@@ -31,4 +31,6 @@ _task_entry:
     movq %rbp, %rdi
     movq %rbx, %rsi
     movq %rax, %rdx
+    # End the frame pointer chain of the new task for backtraces.
+    xorq %rbp, %rbp
     call task_entry
@@ -11,6 +11,16 @@ pub(crate) struct BootData<'a> {
     pub framebuffer: RawFramebuffer<'a>,
     pub system_table: &'a table::SystemTable<table::Runtime>,
     pub acpi_rsdp: *const c_void,
+
+    /// Kernel command line. It is empty if the bootloader passed one that is not UTF-8.
+    pub cmdline: &'a str,
+
+    /// Physical address and length of the initrd, if the bootloader loaded one. It is not mapped
+    /// until `initrd::init()`.
+    pub initrd: Option<(usize, usize)>,
+
+    /// The kernel's symbol and string tables, relocated to `base`, if the kernel has symbols.
+    pub symbols: Option<(&'a [u8], &'a [u8])>,
 }
 
 impl<'a> BootData<'a> {
@@ -22,26 +32,51 @@ impl<'a> BootData<'a> {
         let mm_ptr = (phys_boot_data.memory_map_buf as usize + mm::KERNEL_BASE) as *const MemoryDescriptor;
         let mmap = slice_from_raw_parts(mm_ptr, mm_sz);
 
+        let cmdline_ptr = (phys_boot_data.cmdline as usize + mm::KERNEL_BASE) as *const u8;
+        let cmdline_len = phys_boot_data.cmdline_len.min(bootlib::types::MAX_CMDLINE_LEN);
+        let cmdline = unsafe { &*slice_from_raw_parts(cmdline_ptr, cmdline_len) };
+
+        let symbols = match phys_boot_data.symtab_len {
+            0 => None,
+            symtab_len => unsafe {
+                let symtab = (phys_boot_data.symtab as usize + base) as *const u8;
+                let strtab = (phys_boot_data.strtab as usize + base) as *const u8;
+                Some((
+                    &*slice_from_raw_parts(symtab, symtab_len),
+                    &*slice_from_raw_parts(strtab, phys_boot_data.strtab_len),
+                ))
+            },
+        };
+
         Self {
             memory_map: unsafe { &*mmap },
             framebuffer: RawFramebuffer::relocate(&phys_boot_data.framebuffer, base),
             system_table: unsafe { &*phys_boot_data.system_table },
             acpi_rsdp,
+            cmdline: core::str::from_utf8(cmdline).unwrap_or(""),
+            initrd: match phys_boot_data.initrd_len {
+                0 => None,
+                len => Some((phys_boot_data.initrd as usize, len)),
+            },
+            symbols,
         }
     }
 }
 
+/// The framebuffer, relocated to `base`. Its pages must be mapped, e.g. with `map_mmio()` when
+/// `base` is `MMIO_BASE`, before it is accessed.
 pub(crate) struct RawFramebuffer<'a> {
     pub framebuffer: &'a mut [u8],
     pub horizontal_resolution: usize,
     pub vertical_resolution: usize,
     pub pixels_per_scan_line: usize,
     pub pixel_format: uefi::proto::console::gop::PixelFormat,
+    pub pixel_bitmask: Option<uefi::proto::console::gop::PixelBitmask>,
 }
 
 impl<'a> RawFramebuffer<'a> {
-    fn relocate(phys_fb: &bootlib::types::RawFramebuffer, kernel_base: usize) -> Self {
-        let fb_ptr = phys_fb.framebuffer_base;
+    fn relocate(phys_fb: &bootlib::types::RawFramebuffer, base: usize) -> Self {
+        let fb_ptr = (phys_fb.framebuffer_base as usize + base) as *mut u8;
         let fb_sz = phys_fb.framebuffer_size;
         let fb_buf = slice_from_raw_parts_mut(fb_ptr, fb_sz);
         Self {
@@ -50,6 +85,7 @@ impl<'a> RawFramebuffer<'a> {
             vertical_resolution: phys_fb.vertical_resolution,
             pixels_per_scan_line: phys_fb.pixels_per_scan_line,
             pixel_format: phys_fb.pixel_format,
+            pixel_bitmask: phys_fb.pixel_bitmask,
         }
     }
 }
@@ -74,13 +74,15 @@ struct _MCFG {
     reserved: u64,
 }
 
+/// An ECAM region of the MCFG table: the configuration space of buses `start_bus_number` to
+/// `end_bus_number` of a PCI segment group.
 #[repr(C, packed)]
 #[derive(Clone)]
-struct ConfigurationSpaceBaseAddressAllocationStructure {
-    base_address: u64,
-    segment_group_number: u16,
-    start_bus_number: u8,
-    end_bus_number: u8,
+pub struct ConfigurationSpaceBaseAddressAllocationStructure {
+    pub(crate) base_address: u64,
+    pub(crate) segment_group_number: u16,
+    pub(crate) start_bus_number: u8,
+    pub(crate) end_bus_number: u8,
     reserved: u32,
 }
 
@@ -214,14 +216,16 @@ struct InterruptSourceOverride {
     source: u8,
 
     // Global System Interrupt which the bus-relative IRQ will signal.
-    global_system_interrupt: u8,
+    global_system_interrupt: u32,
     flags: u16,
 }
 
 #[derive(Copy, Clone)]
 pub struct InterruptMapping {
     pub(crate) irq_number: u8,
-    pub(crate) global_system_interrupt: u8,
+    pub(crate) global_system_interrupt: u32,
+    /// MPS INTI flags: the polarity in bits 1:0 and the trigger mode in bits 3:2.
+    pub(crate) flags: u16,
 }
 
 pub fn _parse_xsdt(rsdp: *const core::ffi::c_void) -> (*const usize, usize) {
@@ -271,6 +275,7 @@ fn _parse_madt(madt_addr: usize, length: u32) -> MADT {
                 let mapping = InterruptMapping {
                     irq_number: mapping.type_specific.source,
                     global_system_interrupt: mapping.type_specific.global_system_interrupt,
+                    flags: mapping.type_specific.flags,
                 };
                 madt_info.interrupt_mappings.push(mapping)
             }
@@ -0,0 +1,733 @@
+//! AHCI SATA controllers.
+//!
+//! Each port with an ATA disk on it gets a command list with a single command slot, so a port
+//! runs one command at a time. Data moves through a bounce buffer of the port, which limits a
+//! command to `MAX_TRANSFER` bytes. The interrupt handler signals the task that issued the
+//! command when the port completes it.
+
+use crate::arch::x86_64::interrupt::{self, register_handler, HandlerId};
+use crate::arch::x86_64::mm;
+use crate::drivers::block::dma::{Data, DmaBuffer, PAGE_SIZE};
+use crate::drivers::block::{self, wait_for, BlockDevice};
+use crate::drivers::pci::{self, Bar, DeviceId, MessageInterrupts, PCIDevice};
+use crate::locking::semaphore::Semaphore;
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::Ordering::{AcqRel, Acquire, Release};
+use core::sync::atomic::{AtomicBool, AtomicU32};
+use log::{info, warn};
+use util::volatile::Volatile;
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "ahci",
+    // Mass storage controller, SATA, AHCI 1.0.
+    ids: &[DeviceId::class(0x01, 0x06, Some(0x01))],
+    probe,
+    remove,
+};
+
+static CONTROLLERS: WithSpinLock<BTreeMap<pci::BDF, Arc<Controller>>> =
+    WithSpinLock::new(BTreeMap::new());
+
+// Handlers registered for the vectors of the controllers. Controllers on a shared line share the
+// handler.
+static HANDLERS: WithSpinLock<BTreeMap<u8, HandlerId>> = WithSpinLock::new(BTreeMap::new());
+
+// The HBA registers are in BAR5, which is called ABAR.
+const ABAR: usize = 5;
+
+// HBA registers
+const REG_CAP: usize = 0x00;
+const REG_GHC: usize = 0x04;
+const REG_IS: usize = 0x08;
+const REG_PI: usize = 0x0c;
+const REG_VS: usize = 0x10;
+
+// CAP bits
+const CAP_SSS: u32 = 1 << 27;
+const CAP_S64A: u32 = 1 << 31;
+
+// GHC bits
+const GHC_HR: u32 = 1 << 0;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+// Port registers, at PORT_BASE + port * PORT_SIZE.
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const MAX_PORTS: usize = 32;
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+// PxCMD bits
+const CMD_ST: u32 = 1 << 0;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+// PxIS and PxIE bits
+const INT_DHRS: u32 = 1 << 0;
+const INT_PSS: u32 = 1 << 1;
+const INT_DSS: u32 = 1 << 2;
+const INT_SDBS: u32 = 1 << 3;
+const INT_IFS: u32 = 1 << 27;
+const INT_HBDS: u32 = 1 << 28;
+const INT_HBFS: u32 = 1 << 29;
+const INT_TFES: u32 = 1 << 30;
+const INT_ERRORS: u32 = INT_IFS | INT_HBDS | INT_HBFS | INT_TFES;
+
+// PxTFD bits, which are the ATA status register.
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+// Device detection in PxSSTS: a device is present and communication is established.
+const SSTS_DET_MASK: u32 = 0xf;
+const SSTS_DET_PRESENT: u32 = 0x3;
+
+// PxSIG of ATA disks. ATAPI devices and port multipliers have other signatures.
+const SIG_ATA: u32 = 0x0000_0101;
+
+// Register FIS, host to device.
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+// The FIS carries a command rather than a device control update.
+const FIS_COMMAND: u8 = 1 << 7;
+const FIS_LEN_DWORDS: u16 = 5;
+// Device register value that selects LBA addressing.
+const DEVICE_LBA: u8 = 1 << 6;
+
+// Command header bits
+const HEADER_WRITE: u16 = 1 << 6;
+
+// ATA commands
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_IDENTIFY: u8 = 0xec;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+
+// Largest transfer of one command, which is the size of the bounce buffer of each port.
+const MAX_TRANSFER: usize = 0x10000;
+const MAX_PRDT_ENTRIES: usize = MAX_TRANSFER / PAGE_SIZE;
+
+// How long the HBA and ports get to finish resets and to stop or start.
+const RESET_TIMEOUT_MS: u64 = 1000;
+const STOP_TIMEOUT_MS: u64 = 500;
+// How long a port gets to establish communication with a device after spin-up.
+const LINK_TIMEOUT_MS: u64 = 10;
+
+/// Initializes an AHCI controller, and registers the disks attached to it.
+fn probe(pci_dev: &PCIDevice, _id: &DeviceId) -> Result<(), &'static str> {
+    let Some(Bar::Memory { base, size, .. }) = pci_dev.bar(ABAR) else {
+        return Err("ABAR is not a memory space BAR");
+    };
+    {
+        let mut mapper = mm::mapper();
+        let mapper = mapper.as_mut().unwrap();
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            mapper.map_mmio(base + offset);
+        }
+    }
+
+    // Use MSI where the controller has it. Otherwise, route the PCI interrupt line to a vector
+    // through the I/O APIC.
+    let msi = pci_dev.enable_message_interrupts(1).ok();
+    let vector = match &msi {
+        Some(msi) => Some(msi.vectors()[0]),
+        None => interrupt::route_gsi(pci_dev.gsi()),
+    };
+    let Some(vector) = vector else {
+        return Err("no free interrupt vector");
+    };
+
+    // Enable bus mastering and memory space access. This lets the controller perform DMA.
+    pci_dev.write_control_register(0x0006);
+
+    let controller = match Controller::init(pci_dev.clone(), base + mm::MMIO_BASE, vector) {
+        Ok(controller) => controller,
+        Err(e) => return Err(abort_probe(pci_dev, msi, e)),
+    };
+    *controller.msi.lock() = msi;
+    let controller = Arc::new(controller);
+    let bdf = pci_dev.bdf;
+    CONTROLLERS.lock().insert(bdf, controller.clone());
+    HANDLERS
+        .lock()
+        .entry(vector)
+        .or_insert_with(|| register_handler(vector, ahci_handler));
+    controller.enable_interrupts();
+
+    for port in controller.ports.iter() {
+        match Disk::identify(port.clone()) {
+            Ok(disk) => {
+                let name = block::register("sd", Arc::new(disk));
+                controller.disks.lock().push(name);
+            }
+            Err(e) => warn!("ahci {} port {}: {}", bdf, port.number, e),
+        }
+    }
+    info!(
+        "AHCI controller initialized with {} disks",
+        controller.disks.lock().len()
+    );
+    Ok(())
+}
+
+/// Undoes a probe that failed before the controller was registered, the way `remove()` does, and
+/// returns `e`.
+fn abort_probe(
+    pci_dev: &PCIDevice,
+    msi: Option<MessageInterrupts>,
+    e: &'static str,
+) -> &'static str {
+    pci_dev.write_control_register(0x0000);
+    if let Some(msi) = msi {
+        msi.disable();
+    }
+    e
+}
+
+/// Stops an AHCI controller and removes its disks.
+fn remove(pci_dev: &PCIDevice) {
+    let Some(controller) = CONTROLLERS.lock().get(&pci_dev.bdf).cloned() else {
+        return;
+    };
+    for name in controller.disks.lock().drain(..) {
+        block::unregister(&name);
+    }
+
+    unsafe {
+        let ghc = controller.read_reg(REG_GHC);
+        controller.write_reg(REG_GHC, ghc & !GHC_IE);
+    }
+    for port in controller.ports.iter() {
+        if let Err(e) = port.stop() {
+            warn!("ahci {} port {}: {}", pci_dev.bdf, port.number, e);
+        }
+    }
+    controller.pci.write_control_register(0x0000);
+    CONTROLLERS.lock().remove(&pci_dev.bdf);
+
+    let vector_in_use = CONTROLLERS
+        .lock()
+        .values()
+        .any(|c| c.vector == controller.vector);
+    if !vector_in_use {
+        if let Some(handler) = HANDLERS.lock().remove(&controller.vector) {
+            interrupt::unregister_handler(handler);
+        }
+    }
+    let msi = controller.msi.lock().take();
+    if let Some(msi) = msi {
+        msi.disable();
+    }
+}
+
+struct Controller {
+    pci: PCIDevice,
+
+    // The interrupt vector
+    vector: u8,
+
+    // MSI or MSI-X, if the vector is not routed through the I/O APIC. Taken when the controller
+    // is removed.
+    msi: WithSpinLock<Option<MessageInterrupts>>,
+
+    // Virtual address of the HBA registers mapped from ABAR.
+    abar: usize,
+
+    // Ports with a device attached.
+    ports: Vec<Arc<Port>>,
+
+    // Names of the block devices of the disks.
+    disks: WithSpinLock<Vec<String>>,
+}
+
+impl Controller {
+    fn init(pci: PCIDevice, abar: usize, vector: u8) -> Result<Controller, &'static str> {
+        let mut controller = Controller {
+            pci,
+            vector,
+            // Set by `probe()` once the controller is initialized.
+            msi: WithSpinLock::new(None),
+            abar,
+            ports: Vec::new(),
+            disks: WithSpinLock::new(Vec::new()),
+        };
+
+        unsafe {
+            // Reset the HBA, which stops all ports. AHCI mode must be enabled before any other
+            // register is used, and again after the reset.
+            controller.write_reg(REG_GHC, GHC_AE);
+            controller.write_reg(REG_GHC, GHC_AE | GHC_HR);
+            if !wait_for(RESET_TIMEOUT_MS, || {
+                controller.read_reg(REG_GHC) & GHC_HR == 0
+            }) {
+                return Err("HBA reset timed out");
+            }
+            controller.write_reg(REG_GHC, GHC_AE);
+        }
+
+        let cap = unsafe { controller.read_reg(REG_CAP) };
+        let implemented = unsafe { controller.read_reg(REG_PI) };
+        let version = unsafe { controller.read_reg(REG_VS) };
+        info!(
+            "ahci {}: AHCI {}.{}, ports {:#x}",
+            controller.pci.bdf,
+            version >> 16,
+            (version >> 8) & 0xff,
+            implemented
+        );
+
+        for number in (0..MAX_PORTS as u32).filter(|n| implemented & (1 << n) != 0) {
+            let regs = controller.abar + PORT_BASE + number as usize * PORT_SIZE;
+            match Port::init(regs, number, cap) {
+                Ok(Some(port)) => controller.ports.push(Arc::new(port)),
+                Ok(None) => {}
+                Err(e) => warn!("ahci {} port {}: {}", controller.pci.bdf, number, e),
+            }
+        }
+        Ok(controller)
+    }
+
+    fn enable_interrupts(&self) {
+        unsafe {
+            // Clear interrupts that are pending from the initialization.
+            self.write_reg(REG_IS, self.read_reg(REG_IS));
+            self.write_reg(REG_GHC, GHC_AE | GHC_IE);
+        }
+    }
+
+    /// Reads a 32-bit HBA register.
+    // SAFETY: Caller must ensure that `reg` is the offset of an HBA register.
+    unsafe fn read_reg(&self, reg: usize) -> u32 {
+        ptr::read_volatile((self.abar + reg) as *const u32)
+    }
+
+    /// Writes a 32-bit HBA register.
+    // SAFETY: Caller must ensure that `reg` is the offset of an HBA register.
+    unsafe fn write_reg(&self, reg: usize, value: u32) {
+        ptr::write_volatile((self.abar + reg) as *mut u32, value)
+    }
+}
+
+/// Command header, which points the HBA to a command table.
+#[repr(C)]
+struct CommandHeader {
+    // Command FIS length, direction and other flags.
+    flags: Volatile<u16>,
+    // Number of PRDT entries.
+    prdtl: Volatile<u16>,
+    // Number of bytes transferred, written by the HBA.
+    prdbc: Volatile<u32>,
+    ctba: Volatile<u32>,
+    ctbau: Volatile<u32>,
+    _reserved: [u32; 4],
+}
+
+/// Command list and received FIS area of a port, which fit in a page.
+#[repr(C, align(4096))]
+struct PortMemory {
+    commands: [CommandHeader; 32],
+    received_fis: [u8; 256],
+}
+
+/// Physical region descriptor table entry, which points to a piece of the data of a command.
+#[repr(C)]
+struct PrdtEntry {
+    dba: Volatile<u32>,
+    dbau: Volatile<u32>,
+    _reserved: u32,
+    // Byte count minus one, and whether to interrupt on completion.
+    dbc: Volatile<u32>,
+}
+
+/// Command table. It is aligned to a power of two larger than itself, so that it never crosses a
+/// page boundary.
+#[repr(C, align(512))]
+struct CommandTable {
+    cfis: Volatile<[u8; 64]>,
+    acmd: [u8; 16],
+    _reserved: [u8; 48],
+    prdt: [PrdtEntry; MAX_PRDT_ENTRIES],
+}
+
+struct Port {
+    number: u32,
+
+    // Virtual address of the port registers.
+    regs: usize,
+
+    // Whether the HBA can use physical addresses above 4 GiB.
+    is_64: bool,
+
+    memory: Box<PortMemory>,
+
+    // Command table of slot 0, the only slot used.
+    table: Box<CommandTable>,
+
+    buffer: WithSpinLock<DmaBuffer>,
+
+    // Held while a command runs.
+    idle: Semaphore,
+
+    // Whether a command was issued and has not completed. Cleared by the interrupt handler.
+    pending: AtomicBool,
+
+    // Signalled by the interrupt handler when the command completes.
+    done: Semaphore,
+
+    // PxIS when the command completed.
+    status: AtomicU32,
+}
+
+impl Port {
+    /// Sets up the port at `regs`, and returns it if there is an ATA disk on it.
+    fn init(regs: usize, number: u32, cap: u32) -> Result<Option<Port>, &'static str> {
+        let port = Port {
+            number,
+            regs,
+            is_64: cap & CAP_S64A != 0,
+            // SAFETY: All-zero bytes are valid command headers and tables, and zeroing the memory
+            //         makes sure that it is mapped before we ask for its physical address.
+            memory: unsafe { Box::new_zeroed().assume_init() },
+            table: unsafe { Box::new_zeroed().assume_init() },
+            buffer: WithSpinLock::new(DmaBuffer::new(MAX_TRANSFER)?),
+            idle: Semaphore::new(1, 1),
+            pending: AtomicBool::new(false),
+            done: Semaphore::new(0, 1),
+            status: AtomicU32::new(0),
+        };
+        port.stop()?;
+
+        let memory = port.phys_addr(port.memory.commands.as_ptr() as usize)?;
+        let received_fis = port.phys_addr(port.memory.received_fis.as_ptr() as usize)?;
+        let table = port.phys_addr(&raw const *port.table as usize)?;
+        let header = &port.memory.commands[0];
+        header.ctba.write(table as u32);
+        header.ctbau.write((table >> 32) as u32);
+
+        unsafe {
+            port.write_reg(PX_CLB, memory as u32);
+            port.write_reg(PX_CLBU, (memory >> 32) as u32);
+            port.write_reg(PX_FB, received_fis as u32);
+            port.write_reg(PX_FBU, (received_fis >> 32) as u32);
+            port.write_reg(PX_CMD, port.read_reg(PX_CMD) | CMD_FRE);
+
+            // Spin up the device if the HBA does staggered spin-up, which leaves it to us.
+            if cap & CAP_SSS != 0 {
+                port.write_reg(PX_CMD, port.read_reg(PX_CMD) | CMD_SUD);
+            }
+            let present = wait_for(LINK_TIMEOUT_MS, || {
+                port.read_reg(PX_SSTS) & SSTS_DET_MASK == SSTS_DET_PRESENT
+            });
+            if !present {
+                port.write_reg(PX_CMD, port.read_reg(PX_CMD) & !CMD_FRE);
+                return Ok(None);
+            }
+
+            // Clear errors from the link coming up. Both registers are cleared by writing ones.
+            port.write_reg(PX_SERR, 0xffff_ffff);
+            port.write_reg(PX_IS, 0xffff_ffff);
+
+            // The device sends its signature in its first D2H register FIS.
+            if !wait_for(RESET_TIMEOUT_MS, || {
+                port.read_reg(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0
+            }) {
+                return Err("device stays busy");
+            }
+            let signature = port.read_reg(PX_SIG);
+            if signature != SIG_ATA {
+                info!(
+                    "ahci port {}: skipping device with signature {:#x}",
+                    number, signature
+                );
+                port.write_reg(PX_CMD, port.read_reg(PX_CMD) & !CMD_FRE);
+                return Ok(None);
+            }
+
+            port.write_reg(PX_IE, INT_DHRS | INT_PSS | INT_DSS | INT_SDBS | INT_ERRORS);
+            port.write_reg(PX_CMD, port.read_reg(PX_CMD) | CMD_ST);
+        }
+        Ok(Some(port))
+    }
+
+    /// Stops the port from processing commands and receiving FISes.
+    fn stop(&self) -> Result<(), &'static str> {
+        unsafe {
+            self.write_reg(PX_CMD, self.read_reg(PX_CMD) & !CMD_ST);
+            if !wait_for(STOP_TIMEOUT_MS, || self.read_reg(PX_CMD) & CMD_CR == 0) {
+                return Err("command list does not stop");
+            }
+            self.write_reg(PX_CMD, self.read_reg(PX_CMD) & !CMD_FRE);
+            if !wait_for(STOP_TIMEOUT_MS, || self.read_reg(PX_CMD) & CMD_FR == 0) {
+                return Err("FIS receive does not stop");
+            }
+        }
+        Ok(())
+    }
+
+    /// Restarts the port after a command failed, which stops the HBA from processing commands.
+    fn recover(&self) {
+        unsafe {
+            self.write_reg(PX_CMD, self.read_reg(PX_CMD) & !CMD_ST);
+            if !wait_for(STOP_TIMEOUT_MS, || self.read_reg(PX_CMD) & CMD_CR == 0) {
+                warn!("ahci port {}: command list does not stop", self.number);
+                return;
+            }
+            self.write_reg(PX_SERR, 0xffff_ffff);
+            self.write_reg(PX_IS, 0xffff_ffff);
+            self.write_reg(PX_CMD, self.read_reg(PX_CMD) | CMD_ST);
+        }
+    }
+
+    fn phys_addr(&self, linear_addr: usize) -> Result<usize, &'static str> {
+        let phys_addr = mm::phys_addr(linear_addr).ok_or("unmapped DMA memory")?;
+        if !self.is_64 && phys_addr >> 32 != 0 {
+            return Err("DMA memory above 4 GiB");
+        }
+        Ok(phys_addr)
+    }
+
+    /// Runs ATA `command` with `lba` and sector `count`, moving `data` through the bounce buffer.
+    /// Returns once the device has completed it.
+    fn execute(&self, command: u8, lba: u64, count: u16, data: Data) -> Result<(), &'static str> {
+        assert!(data.len() <= MAX_TRANSFER, "AHCI transfer is too large");
+        self.idle.wait();
+        let result = self.execute_locked(command, lba, count, data);
+        self.idle.signal();
+        result
+    }
+
+    fn execute_locked(
+        &self,
+        command: u8,
+        lba: u64,
+        count: u16,
+        data: Data,
+    ) -> Result<(), &'static str> {
+        let len = data.len();
+        {
+            let mut buffer = self.buffer.lock();
+            if let Data::Out(bytes) = data {
+                buffer.copy_from(bytes);
+            }
+            let mut entries = 0;
+            for (entry, (phys_addr, len)) in self.table.prdt.iter().zip(buffer.segments(len)) {
+                if !self.is_64 && phys_addr >> 32 != 0 {
+                    return Err("DMA memory above 4 GiB");
+                }
+                entry.dba.write(phys_addr as u32);
+                entry.dbau.write((phys_addr >> 32) as u32);
+                entry.dbc.write((len - 1) as u32);
+                entries += 1;
+            }
+            self.table.cfis.write(h2d_fis(command, lba, count));
+
+            let header = &self.memory.commands[0];
+            let write = match data {
+                Data::Out(_) => HEADER_WRITE,
+                _ => 0,
+            };
+            header.flags.write(FIS_LEN_DWORDS | write);
+            header.prdtl.write(entries);
+            header.prdbc.write(0);
+        }
+
+        self.pending.store(true, Release);
+        unsafe { self.write_reg(PX_CI, 1) };
+        self.done.wait();
+
+        let status = self.status.load(Acquire);
+        let tfd = unsafe { self.read_reg(PX_TFD) };
+        if status & INT_ERRORS != 0 || tfd & TFD_ERR != 0 {
+            warn!(
+                "ahci port {}: command {:#x} failed, PxIS {:#x}, PxTFD {:#x}",
+                self.number, command, status, tfd
+            );
+            self.recover();
+            return Err("ATA command failed");
+        }
+        if let Data::In(out) = data {
+            self.buffer.lock().copy_to(out);
+        }
+        Ok(())
+    }
+
+    /// Acknowledges the interrupts of the port, and completes the command if it is done.
+    fn handle_interrupt(&self) {
+        let status = unsafe { self.read_reg(PX_IS) };
+        unsafe { self.write_reg(PX_IS, status) };
+        // The HBA clears the bit of the slot in PxCI when the command completes. It stops
+        // processing commands on errors, and leaves the bit set.
+        let completed = status & INT_ERRORS != 0 || unsafe { self.read_reg(PX_CI) } & 1 == 0;
+        if completed && self.pending.swap(false, AcqRel) {
+            self.status.store(status, Release);
+            self.done.try_signal();
+        }
+    }
+
+    /// Reads a 32-bit port register.
+    // SAFETY: Caller must ensure that `reg` is the offset of a port register.
+    unsafe fn read_reg(&self, reg: usize) -> u32 {
+        ptr::read_volatile((self.regs + reg) as *const u32)
+    }
+
+    /// Writes a 32-bit port register.
+    // SAFETY: Caller must ensure that `reg` is the offset of a port register.
+    unsafe fn write_reg(&self, reg: usize, value: u32) {
+        ptr::write_volatile((self.regs + reg) as *mut u32, value)
+    }
+}
+
+/// Returns a register FIS that issues ATA `command`, padded to the size of the command FIS area.
+fn h2d_fis(command: u8, lba: u64, count: u16) -> [u8; 64] {
+    let lba = lba.to_le_bytes();
+    let count = count.to_le_bytes();
+    let mut fis = [0; 64];
+    fis[0] = FIS_TYPE_REG_H2D;
+    fis[1] = FIS_COMMAND;
+    fis[2] = command;
+    fis[4..7].copy_from_slice(&lba[0..3]);
+    fis[7] = DEVICE_LBA;
+    fis[8..11].copy_from_slice(&lba[3..6]);
+    fis[12..14].copy_from_slice(&count);
+    fis
+}
+
+/// An ATA disk on an AHCI port.
+pub struct Disk {
+    port: Arc<Port>,
+    sectors: u64,
+    sector_size: usize,
+    model: String,
+}
+
+impl Disk {
+    /// Asks the disk on `port` for its size and model.
+    fn identify(port: Arc<Port>) -> Result<Disk, &'static str> {
+        let mut id = [0u8; 512];
+        port.execute(ATA_IDENTIFY, 0, 0, Data::In(&mut id))?;
+        let word = |i: usize| u16::from_le_bytes([id[i * 2], id[i * 2 + 1]]);
+
+        // Word 83 bit 10: the 48-bit address feature set is supported.
+        if word(83) & (1 << 10) == 0 {
+            return Err("disk does not support 48-bit LBA");
+        }
+        let sectors = (100..104)
+            .rev()
+            .fold(0u64, |sectors, i| sectors << 16 | word(i) as u64);
+        // Word 106 is valid when bits 15:14 are 01. Its bit 12 tells that the logical sector is
+        // larger than 256 words, and words 117-118 have the size in words.
+        let sector_size = match word(106) {
+            w if w & 0xc000 == 0x4000 && w & (1 << 12) != 0 => {
+                (word(117) as usize | (word(118) as usize) << 16) * 2
+            }
+            _ => 512,
+        };
+        if sector_size == 0 || sector_size > MAX_TRANSFER {
+            return Err("unsupported sector size");
+        }
+        // ATA strings have the bytes of each word swapped.
+        let model = (27..47)
+            .flat_map(|i| {
+                let [high, low] = word(i).to_be_bytes();
+                [high as char, low as char]
+            })
+            .collect::<String>()
+            .trim()
+            .into();
+
+        Ok(Disk {
+            port,
+            sectors,
+            sector_size,
+            model,
+        })
+    }
+
+    /// Largest number of sectors that one command can move.
+    fn max_sectors(&self) -> usize {
+        MAX_TRANSFER / self.sector_size
+    }
+}
+
+impl BlockDevice for Disk {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_request(self, sector, buf.len())?;
+        let chunk_size = self.max_sectors() * self.sector_size;
+        for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let lba = sector + (i * self.max_sectors()) as u64;
+            let count = (chunk.len() / self.sector_size) as u16;
+            self.port
+                .execute(ATA_READ_DMA_EXT, lba, count, Data::In(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_request(self, sector, buf.len())?;
+        let chunk_size = self.max_sectors() * self.sector_size;
+        for (i, chunk) in buf.chunks(chunk_size).enumerate() {
+            let lba = sector + (i * self.max_sectors()) as u64;
+            let count = (chunk.len() / self.sector_size) as u16;
+            self.port
+                .execute(ATA_WRITE_DMA_EXT, lba, count, Data::Out(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        self.port.execute(ATA_FLUSH_CACHE_EXT, 0, 0, Data::None)
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn ahci_handler(vector: u64) {
+    let controllers = {
+        // SAFETY: We are getting an exclusive lock in ISR context, which has interrupts disabled.
+        //         We are also not calling any blocking functions, so there is no risk of a deadlock.
+        let controllers = CONTROLLERS.lock();
+        controllers
+            .values()
+            .filter(|c| c.vector == vector as u8)
+            .cloned()
+            .collect::<Vec<Arc<Controller>>>()
+    };
+    for c in controllers.iter() {
+        let pending = unsafe { c.read_reg(REG_IS) };
+        for port in c.ports.iter().filter(|p| pending & (1 << p.number) != 0) {
+            port.handle_interrupt();
+        }
+        // Port interrupts are cleared first, or they would set the bits again.
+        unsafe { c.write_reg(REG_IS, pending) };
+    }
+}
@@ -0,0 +1,311 @@
+//! Buffer cache.
+//!
+//! Data of block devices is cached in pages of `PAGE_SIZE` bytes, addressed by byte offset on the
+//! device. Writes only change the cached page and mark it dirty. Dirty pages are written back by
+//! the flusher task every `FLUSH_INTERVAL_MS`, by `sync()`, or when they are evicted. When the
+//! cache holds more than `CAPACITY` pages, the least recently used ones are evicted.
+
+use crate::drivers::block::{BlockDevice, RequestQueue};
+use crate::kernel::sched;
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::warn;
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+// Pages kept in the cache, which is 4 MiB.
+const CAPACITY: usize = 1024;
+
+const FLUSH_INTERVAL_MS: u64 = 5000;
+
+static CACHE: WithSpinLock<Cache> = WithSpinLock::new(Cache {
+    pages: BTreeMap::new(),
+    lru: BTreeMap::new(),
+    next_use: 0,
+});
+
+// A page is identified by the ID of its device's request queue and its index on the device.
+type Key = (usize, u64);
+
+struct Cache {
+    // Each page, and when it was last used.
+    pages: BTreeMap<Key, (Arc<Page>, u64)>,
+
+    // The page that was used at each time, from the least recently used.
+    lru: BTreeMap<u64, Key>,
+
+    // Counts uses of pages, as the time in `lru`.
+    next_use: u64,
+}
+
+impl Cache {
+    /// Returns page `key` if it is cached, and makes it the most recently used.
+    fn get(&mut self, key: Key) -> Option<Arc<Page>> {
+        let now = self.next_use;
+        let (page, used) = self.pages.get_mut(&key)?;
+        self.lru.remove(used);
+        *used = now;
+        self.lru.insert(now, key);
+        self.next_use += 1;
+        Some(page.clone())
+    }
+
+    /// Adds `page`, unless it was cached meanwhile. Returns the cached page.
+    fn insert(&mut self, key: Key, page: Arc<Page>) -> Arc<Page> {
+        if let Some(page) = self.get(key) {
+            return page;
+        }
+        let now = self.next_use;
+        self.pages.insert(key, (page.clone(), now));
+        self.lru.insert(now, key);
+        self.next_use += 1;
+        page
+    }
+
+    fn remove(&mut self, key: Key) {
+        if let Some((_, used)) = self.pages.remove(&key) {
+            self.lru.remove(&used);
+        }
+    }
+}
+
+struct Page {
+    queue: Arc<RequestQueue>,
+    index: u64,
+    data: WithSpinLock<PageData>,
+}
+
+struct PageData {
+    // The page, which is shorter than PAGE_SIZE at the end of a device whose size is not a
+    // multiple of it.
+    bytes: Vec<u8>,
+    dirty: bool,
+}
+
+impl Page {
+    /// First sector of the page.
+    fn sector(&self) -> u64 {
+        self.index * (PAGE_SIZE / self.queue.sector_size()) as u64
+    }
+
+    /// Writes the page to the device if it is dirty.
+    fn write_back(&self) -> Result<(), &'static str> {
+        let bytes = {
+            let mut data = self.data.lock();
+            if !data.dirty {
+                return Ok(());
+            }
+            data.dirty = false;
+            data.bytes.clone()
+        };
+        let result = self.queue.write(self.sector(), &bytes);
+        if result.is_err() {
+            self.data.lock().dirty = true;
+        }
+        result
+    }
+}
+
+/// Returns page `index` of the device of `queue`, reading it if it is not cached. A page that is
+/// overwritten as a whole is made from the new `contents` instead of being read, and is dirty, so
+/// that readers never find it with bytes that are neither on the device nor being written to it.
+fn get_page(
+    queue: &Arc<RequestQueue>,
+    index: u64,
+    contents: Option<&[u8]>,
+) -> Result<Arc<Page>, &'static str> {
+    let key = (queue.id(), index);
+    if let Some(page) = CACHE.lock().get(key) {
+        return Ok(page);
+    }
+
+    let page = Page {
+        queue: queue.clone(),
+        index,
+        data: WithSpinLock::new(PageData {
+            bytes: Vec::new(),
+            dirty: false,
+        }),
+    };
+    let sectors = (queue.sectors() - page.sector()).min((PAGE_SIZE / queue.sector_size()) as u64);
+    let mut bytes = vec![0; sectors as usize * queue.sector_size()];
+    match contents {
+        Some(contents) => bytes.copy_from_slice(contents),
+        None => queue.read(page.sector(), &mut bytes)?,
+    }
+    *page.data.lock() = PageData {
+        bytes,
+        dirty: contents.is_some(),
+    };
+
+    let page = CACHE.lock().insert(key, Arc::new(page));
+    evict();
+    Ok(page)
+}
+
+/// Evicts the least recently used pages until the cache holds at most CAPACITY pages, writing
+/// them back if they are dirty.
+fn evict() {
+    loop {
+        let victim = {
+            let cache = CACHE.lock();
+            if cache.pages.len() <= CAPACITY {
+                return;
+            }
+            let (_, &key) = cache.lru.first_key_value().unwrap();
+            (key, cache.pages[&key].0.clone())
+        };
+        let (key, page) = victim;
+        if let Err(e) = page.write_back() {
+            warn!(
+                "{}: cannot write back page {}: {}",
+                page.queue.name(),
+                page.index,
+                e
+            );
+            return;
+        }
+        // The page may have been used, or written again, while it was written back.
+        let mut cache = CACHE.lock();
+        let oldest = cache.lru.first_key_value().map(|(_, &k)| k);
+        if oldest == Some(key) && !page.data.lock().dirty {
+            cache.remove(key);
+        }
+    }
+}
+
+/// Runs `f` on each page covering the `len` bytes from `offset` of the device of `queue`, with
+/// the range of bytes within the page and the offset of that range from `offset`. For a write,
+/// `src` is the data written at `offset`, which pages that are not cached and that it covers as a
+/// whole are made from.
+fn for_each_page(
+    queue: &Arc<RequestQueue>,
+    offset: u64,
+    len: usize,
+    src: Option<&[u8]>,
+    mut f: impl FnMut(&Page, core::ops::Range<usize>, usize),
+) -> Result<(), &'static str> {
+    if PAGE_SIZE % queue.sector_size() != 0 {
+        return Err("sector size does not divide the page size");
+    }
+    let size = queue.sectors() * queue.sector_size() as u64;
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= size => {}
+        _ => return Err("request is past the end of the device"),
+    }
+
+    let mut done = 0;
+    while done < len {
+        let position = offset + done as u64;
+        let index = position / PAGE_SIZE as u64;
+        let start = (position % PAGE_SIZE as u64) as usize;
+        let page_len = (size - index * PAGE_SIZE as u64).min(PAGE_SIZE as u64) as usize;
+        let end = (start + len - done).min(page_len);
+        let whole = start == 0 && end == page_len;
+        let contents = src.filter(|_| whole).map(|src| &src[done..done + page_len]);
+        let page = get_page(queue, index, contents)?;
+        f(&page, start..end, done);
+        done += end - start;
+    }
+    Ok(())
+}
+
+/// Reads `buf.len()` bytes from `offset` of the device of `queue`, through the cache.
+pub fn read(queue: &Arc<RequestQueue>, offset: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+    let len = buf.len();
+    for_each_page(queue, offset, len, None, |page, range, at| {
+        let data = page.data.lock();
+        buf[at..at + range.len()].copy_from_slice(&data.bytes[range]);
+    })
+}
+
+/// Writes `buf` at `offset` of the device of `queue`, through the cache. The device is written
+/// later.
+pub fn write(queue: &Arc<RequestQueue>, offset: u64, buf: &[u8]) -> Result<(), &'static str> {
+    for_each_page(queue, offset, buf.len(), Some(buf), |page, range, at| {
+        let mut data = page.data.lock();
+        let len = range.len();
+        data.bytes[range].copy_from_slice(&buf[at..at + len]);
+        data.dirty = true;
+    })
+}
+
+/// Writes back the dirty pages of the devices that `select` picks, and flushes the devices.
+/// Returns the first error, after trying every page.
+fn write_back_pages(select: impl Fn(&RequestQueue) -> bool) -> Result<(), &'static str> {
+    let dirty: Vec<Arc<Page>> = CACHE
+        .lock()
+        .pages
+        .values()
+        .map(|(page, _)| page)
+        .filter(|page| select(&page.queue) && page.data.lock().dirty)
+        .cloned()
+        .collect();
+
+    let mut result = Ok(());
+    let mut queues = BTreeMap::new();
+    for page in dirty.iter() {
+        if let Err(e) = page.write_back() {
+            warn!(
+                "{}: cannot write back page {}: {}",
+                page.queue.name(),
+                page.index,
+                e
+            );
+            result = result.and(Err(e));
+        }
+        queues.insert(page.queue.id(), page.queue.clone());
+    }
+    for queue in queues.values() {
+        result = result.and(queue.flush());
+    }
+    result
+}
+
+/// Writes the dirty pages of the device of `queue` to stable storage.
+pub fn sync(queue: &RequestQueue) -> Result<(), &'static str> {
+    write_back_pages(|q| q.id() == queue.id())
+}
+
+/// Writes every dirty page to stable storage.
+pub fn sync_all() -> Result<(), &'static str> {
+    write_back_pages(|_| true)
+}
+
+/// Drops the pages of the device of `queue`, dirty or not.
+pub(super) fn invalidate(queue: &RequestQueue) {
+    let mut cache = CACHE.lock();
+    let keys: Vec<Key> = cache
+        .pages
+        .keys()
+        .filter(|(id, _)| *id == queue.id())
+        .copied()
+        .collect();
+    for key in keys {
+        cache.remove(key);
+    }
+}
+
+/// Returns the number of cached pages, and how many of them are dirty.
+pub fn statistics() -> (usize, usize) {
+    let cache = CACHE.lock();
+    let dirty = cache
+        .pages
+        .values()
+        .filter(|(page, _)| page.data.lock().dirty)
+        .count();
+    (cache.pages.len(), dirty)
+}
+
+/// Writes back dirty pages periodically. Runs as a task.
+pub fn flusher() {
+    loop {
+        sched::lock().sleep(FLUSH_INTERVAL_MS * 1_000_000);
+        // Errors were logged for each page, which stays dirty for the next try.
+        let _ = sync_all();
+    }
+}
@@ -0,0 +1,87 @@
+//! Bounce buffers for disk controllers.
+//!
+//! The heap is not physically contiguous, so data is copied through page aligned buffers whose
+//! pages are handed to the controller one by one.
+
+use crate::arch::x86_64::mm;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+pub(crate) const PAGE_SIZE: usize = 0x1000;
+
+/// Data moved by a request.
+pub(crate) enum Data<'a> {
+    None,
+    /// Read from the device into the buffer.
+    In(&'a mut [u8]),
+    /// Written from the buffer to the device.
+    Out(&'a [u8]),
+}
+
+impl Data<'_> {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Data::None => 0,
+            Data::In(buf) => buf.len(),
+            Data::Out(buf) => buf.len(),
+        }
+    }
+}
+
+#[repr(C, align(4096))]
+struct Page([u8; PAGE_SIZE]);
+
+pub(crate) struct DmaBuffer {
+    pages: Vec<Box<Page>>,
+    // Physical address of each page.
+    phys_addrs: Vec<usize>,
+}
+
+impl DmaBuffer {
+    /// Allocates a buffer of at least `len` bytes.
+    pub(crate) fn new(len: usize) -> Result<Self, &'static str> {
+        let pages: Vec<Box<Page>> = (0..len.div_ceil(PAGE_SIZE))
+            // SAFETY: All-zero bytes are a valid page, and zeroing it makes sure that it is
+            //         mapped before we ask for its physical address.
+            .map(|_| unsafe { Box::<Page>::new_zeroed().assume_init() })
+            .collect();
+        let phys_addrs = pages
+            .iter()
+            .map(|page| mm::phys_addr(page.0.as_ptr() as usize))
+            .collect::<Option<Vec<usize>>>()
+            .ok_or("unmapped DMA buffer")?;
+        Ok(DmaBuffer { pages, phys_addrs })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+
+    /// Returns the physical address and length of each physically contiguous piece of the first
+    /// `len` bytes of the buffer.
+    pub(crate) fn segments(&self, len: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        assert!(len <= self.len(), "DMA buffer is too small");
+        self.phys_addrs
+            .iter()
+            .enumerate()
+            .take(len.div_ceil(PAGE_SIZE))
+            .map(move |(i, &phys_addr)| (phys_addr, (len - i * PAGE_SIZE).min(PAGE_SIZE)))
+    }
+
+    /// Copies `data` to the start of the buffer.
+    pub(crate) fn copy_from(&mut self, data: &[u8]) {
+        assert!(data.len() <= self.len(), "DMA buffer is too small");
+        for (page, chunk) in self.pages.iter_mut().zip(data.chunks(PAGE_SIZE)) {
+            page.0[..chunk.len()].copy_from_slice(chunk);
+        }
+    }
+
+    /// Fills `out` from the start of the buffer.
+    pub(crate) fn copy_to(&self, out: &mut [u8]) {
+        assert!(out.len() <= self.len(), "DMA buffer is too small");
+        for (page, chunk) in self.pages.iter().zip(out.chunks_mut(PAGE_SIZE)) {
+            chunk.copy_from_slice(&page.0[..chunk.len()]);
+        }
+    }
+}
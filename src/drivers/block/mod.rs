@@ -0,0 +1,191 @@
+//! Block devices.
+//!
+//! Disk drivers register an `Arc<dyn BlockDevice>` for each disk they find, under a name made of
+//! a prefix for the kind of disk and a number, such as `sd0`. Users of the disk get its request
+//! queue, which merges adjacent requests and lets them be submitted without waiting, and usually
+//! go through the buffer cache on top of it.
+
+use crate::kernel::clock;
+use crate::locking::semaphore::Semaphore;
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::{Acquire, Release};
+use log::info;
+use util::partition::{self, Partition, SectorReader};
+
+pub mod ahci;
+pub mod cache;
+pub(crate) mod dma;
+pub mod nvme;
+mod queue;
+pub mod virtio_blk;
+
+pub use queue::{Pending, RequestQueue};
+
+static DEVICES: WithSpinLock<BTreeMap<String, Arc<RequestQueue>>> =
+    WithSpinLock::new(BTreeMap::new());
+
+// Signalled when a request is queued, to wake the block task.
+static WORK: Semaphore = Semaphore::new(0, usize::MAX);
+static TASK_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Interface between disk drivers and their users.
+///
+/// Requests cover whole sectors: the length of the buffer must be a multiple of the sector size,
+/// and the request must end within the device. Requests return when the device has completed them;
+/// `RequestQueue` has the variants that return before.
+pub trait BlockDevice: Send + Sync {
+    /// Returns the size of a sector in bytes.
+    fn sector_size(&self) -> usize;
+
+    /// Returns the number of sectors.
+    fn sectors(&self) -> u64;
+
+    /// Returns the model of the device, for listing devices.
+    fn model(&self) -> &str;
+
+    /// Reads the sectors from `sector` into `buf`.
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+
+    /// Writes `buf` to the sectors from `sector`.
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), &'static str>;
+
+    /// Makes sure that completed writes are on stable storage, rather than in a volatile cache of
+    /// the device.
+    fn flush(&self) -> Result<(), &'static str>;
+
+    /// Tells the device that the `count` sectors from `sector` are no longer in use, so that it
+    /// may free them. They read back as unspecified data.
+    fn discard(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        Err("discard is not supported")
+    }
+}
+
+/// Returns an error if a request for `len` bytes from `sector` does not cover whole sectors
+/// within `device`.
+pub fn check_request(
+    device: &dyn BlockDevice,
+    sector: u64,
+    len: usize,
+) -> Result<(), &'static str> {
+    if len % device.sector_size() != 0 {
+        return Err("request is not a whole number of sectors");
+    }
+    let count = (len / device.sector_size()) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sectors() => Ok(()),
+        _ => Err("request is past the end of the device"),
+    }
+}
+
+/// Adds `device` to the block devices with a request queue, named `prefix` followed by the lowest
+/// number that is not taken. Returns the name.
+pub fn register(prefix: &str, device: Arc<dyn BlockDevice>) -> String {
+    let mut devices = DEVICES.lock();
+    let name = (0..)
+        .map(|n| format!("{}{}", prefix, n))
+        .find(|name| !devices.contains_key(name))
+        .unwrap();
+    info!(
+        "{}: {}, {} sectors of {} bytes",
+        name,
+        device.model(),
+        device.sectors(),
+        device.sector_size()
+    );
+    let queue = Arc::new(RequestQueue::new(name.clone(), device));
+    devices.insert(name.clone(), queue);
+    name
+}
+
+/// Removes the block device `name`, and drops its pages from the buffer cache. Dirty pages are
+/// lost, since the device is gone.
+pub fn unregister(name: &str) -> Option<Arc<RequestQueue>> {
+    let queue = DEVICES.lock().remove(name)?;
+    queue.abort();
+    cache::invalidate(&queue);
+    Some(queue)
+}
+
+/// Returns the request queue of block device `name`.
+pub fn get(name: &str) -> Option<Arc<RequestQueue>> {
+    DEVICES.lock().get(name).cloned()
+}
+
+/// Returns the request queue of every block device, with its name.
+pub fn devices() -> Vec<(String, Arc<RequestQueue>)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|(name, device)| (name.clone(), device.clone()))
+        .collect()
+}
+
+/// Waits up to `timeout_ms` for `done` to return true, and returns whether it did. For
+/// controllers, whose registers change on their own.
+pub(crate) fn wait_for(timeout_ms: u64, mut done: impl FnMut() -> bool) -> bool {
+    let until = clock::get_time() + timeout_ms * 1_000_000;
+    while !done() {
+        if clock::get_time() >= until {
+            return done();
+        }
+        spin_loop();
+    }
+    true
+}
+
+/// Reads the partition table of `device`, and returns it with the partitions.
+pub fn partitions(
+    device: &dyn BlockDevice,
+) -> Result<(partition::Table, Vec<Partition>), partition::Error> {
+    let mut partitions = Vec::new();
+    let mut buf = vec![0; device.sector_size()];
+    let table = partition::parse(&mut Reader(device), &mut buf, |p| partitions.push(p))?;
+    Ok((table, partitions))
+}
+
+/// Reads a block device for the partition table parser.
+struct Reader<'a>(&'a dyn BlockDevice);
+
+impl SectorReader for Reader<'_> {
+    fn sector_size(&self) -> usize {
+        self.0.sector_size()
+    }
+
+    fn sectors(&self) -> u64 {
+        self.0.sectors()
+    }
+
+    fn read_sector(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.0.read(sector, buf)
+    }
+}
+
+/// Hands the requests queued for the block devices to their drivers. Runs as a task, so that the
+/// tasks that submit requests don't have to wait for the drivers.
+pub fn run() {
+    TASK_RUNNING.store(true, Release);
+    loop {
+        WORK.wait();
+        for (_, queue) in devices() {
+            queue.dispatch();
+        }
+    }
+}
+
+/// Wakes the block task to dispatch queued requests. Returns false if it is not running.
+fn wake_task() -> bool {
+    if !TASK_RUNNING.load(Acquire) {
+        return false;
+    }
+    WORK.try_signal();
+    true
+}
@@ -0,0 +1,933 @@
+//! NVMe controllers.
+//!
+//! The controller gets an admin queue pair and one I/O queue pair, which all its namespaces
+//! share. Each queue pair has a few command slots, each with a bounce buffer and a PRP list, so
+//! that several commands can be outstanding at once. Both queues complete on the same interrupt
+//! vector, with MSI-X where the controller has it, and the interrupt handler signals the task that
+//! submitted each completed command.
+
+use crate::arch::x86_64::interrupt::{self, register_handler, HandlerId};
+use crate::arch::x86_64::mm;
+use crate::drivers::block::dma::{Data, DmaBuffer, PAGE_SIZE};
+use crate::drivers::block::{self, wait_for, BlockDevice};
+use crate::drivers::pci::{self, Bar, DeviceId, MessageInterrupts, PCIDevice};
+use crate::locking::semaphore::Semaphore;
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::Ordering::{Acquire, Release};
+use core::sync::atomic::{AtomicBool, AtomicU32};
+use log::{info, warn};
+use util::volatile::Volatile;
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "nvme",
+    // Mass storage controller, non-volatile memory controller, NVM Express.
+    ids: &[DeviceId::class(0x01, 0x08, Some(0x02))],
+    probe,
+    remove,
+};
+
+static CONTROLLERS: WithSpinLock<BTreeMap<pci::BDF, Arc<Controller>>> =
+    WithSpinLock::new(BTreeMap::new());
+
+// Handlers registered for the vectors of the controllers. Controllers on a shared line share the
+// handler.
+static HANDLERS: WithSpinLock<BTreeMap<u8, HandlerId>> = WithSpinLock::new(BTreeMap::new());
+
+// The controller registers are in BAR0.
+const BAR: usize = 0;
+
+// Controller registers
+const REG_CAP: usize = 0x00;
+const REG_VS: usize = 0x08;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1c;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const REG_DOORBELLS: usize = 0x1000;
+
+// CAP bits
+const CAP_MQES_MASK: u64 = 0xffff;
+const CAP_TO_SHIFT: u64 = 24;
+const CAP_DSTRD_SHIFT: u64 = 32;
+const CAP_CSS_NVM: u64 = 1 << 37;
+const CAP_MPSMIN_SHIFT: u64 = 48;
+
+// CC bits. The memory page size is left at 4 KiB, and the queue entry sizes are powers of two.
+const CC_EN: u32 = 1 << 0;
+const CC_SHN_NORMAL: u32 = 1 << 14;
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+
+// CSTS bits
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+const CSTS_SHST_MASK: u32 = 0b11 << 2;
+const CSTS_SHST_COMPLETE: u32 = 0b10 << 2;
+
+// Admin commands
+const ADMIN_DELETE_IO_SQ: u8 = 0x00;
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_DELETE_IO_CQ: u8 = 0x04;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+
+// Identify CNS values
+const CNS_NAMESPACE: u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+const CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+const IDENTIFY_LEN: usize = 4096;
+
+// Feature identifiers
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+// Queue creation flags in CDW11
+const QUEUE_PHYS_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS_ENABLED: u32 = 1 << 1;
+
+// NVM commands
+const NVM_FLUSH: u8 = 0x00;
+const NVM_WRITE: u8 = 0x01;
+const NVM_READ: u8 = 0x02;
+const NVM_DATASET_MANAGEMENT: u8 = 0x09;
+
+// Dataset management attribute: deallocate the ranges.
+const DSM_DEALLOCATE: u32 = 1 << 2;
+
+// ONCS bit of controllers that support the dataset management command.
+const ONCS_DSM: u16 = 1 << 2;
+
+// VWC bit of controllers that have a volatile write cache.
+const VWC_PRESENT: u8 = 1 << 0;
+
+// Entries of each queue. A submission queue of 64-byte commands fills a page.
+const QUEUE_SIZE: usize = 64;
+const ADMIN_QUEUE_ID: u16 = 0;
+const IO_QUEUE_ID: u16 = 1;
+
+// Largest transfer of one command, which is the size of the bounce buffer of each slot.
+const MAX_TRANSFER: usize = 0x20000;
+const ADMIN_SLOTS: usize = 2;
+const IO_SLOTS: usize = 16;
+
+// Status of a slot whose command has not completed.
+const NO_STATUS: u32 = u32::MAX;
+
+// How long a shutdown may take, if the controller takes less than its CAP.TO.
+const SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
+/// Initializes an NVMe controller, and registers its namespaces.
+fn probe(pci_dev: &PCIDevice, _id: &DeviceId) -> Result<(), &'static str> {
+    let Some(Bar::Memory { base, size, .. }) = pci_dev.bar(BAR) else {
+        return Err("BAR0 is not a memory space BAR");
+    };
+    {
+        let mut mapper = mm::mapper();
+        let mapper = mapper.as_mut().unwrap();
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            mapper.map_mmio(base + offset);
+        }
+    }
+
+    // Use MSI-X or MSI where the controller has it. Otherwise, route the PCI interrupt line to a
+    // vector through the I/O APIC. Both queues use message 0.
+    let msi = pci_dev.enable_message_interrupts(1).ok();
+    let vector = match &msi {
+        Some(msi) => Some(msi.vectors()[0]),
+        None => interrupt::route_gsi(pci_dev.gsi()),
+    };
+    let Some(vector) = vector else {
+        return Err("no free interrupt vector");
+    };
+
+    // Enable bus mastering and memory space access. This lets the controller perform DMA.
+    pci_dev.write_control_register(0x0006);
+
+    let controller = match Controller::init(pci_dev.clone(), base + mm::MMIO_BASE, vector) {
+        Ok(controller) => controller,
+        Err(e) => return Err(abort_probe(pci_dev, msi, e)),
+    };
+    *controller.msi.lock() = msi;
+    let controller = Arc::new(controller);
+    let bdf = pci_dev.bdf;
+    CONTROLLERS.lock().insert(bdf, controller.clone());
+    HANDLERS
+        .lock()
+        .entry(vector)
+        .or_insert_with(|| register_handler(vector, nvme_handler));
+
+    let setup = controller.identify().and_then(|identity| {
+        controller.create_io_queues()?;
+        Ok((identity, controller.active_namespaces()?))
+    });
+    let (identity, nsids) = match setup {
+        Ok(setup) => setup,
+        Err(e) => {
+            remove(pci_dev);
+            return Err(e);
+        }
+    };
+
+    for nsid in nsids {
+        match Namespace::identify(controller.clone(), &identity, nsid) {
+            Ok(Some(namespace)) => {
+                let name = block::register("nvme", Arc::new(namespace));
+                controller.namespaces.lock().push(name);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("nvme {} namespace {}: {}", bdf, nsid, e),
+        }
+    }
+    info!(
+        "NVMe controller {} initialized with {} namespaces",
+        identity.model,
+        controller.namespaces.lock().len()
+    );
+    Ok(())
+}
+
+/// Undoes a probe that failed before the controller was registered, the way `remove()` does, and
+/// returns `e`.
+fn abort_probe(
+    pci_dev: &PCIDevice,
+    msi: Option<MessageInterrupts>,
+    e: &'static str,
+) -> &'static str {
+    pci_dev.write_control_register(0x0000);
+    if let Some(msi) = msi {
+        msi.disable();
+    }
+    e
+}
+
+/// Shuts down an NVMe controller and removes its namespaces.
+fn remove(pci_dev: &PCIDevice) {
+    let Some(controller) = CONTROLLERS.lock().get(&pci_dev.bdf).cloned() else {
+        return;
+    };
+    for name in controller.namespaces.lock().drain(..) {
+        block::unregister(&name);
+    }
+
+    controller.removed.store(true, Release);
+    if let Err(e) = controller.shutdown() {
+        warn!("nvme {}: {}", pci_dev.bdf, e);
+    }
+    controller.pci.write_control_register(0x0000);
+    CONTROLLERS.lock().remove(&pci_dev.bdf);
+
+    // Commands in the queues will never complete. Wake the tasks waiting for them.
+    controller.admin.abort();
+    if let Some(io) = controller.io.get() {
+        io.abort();
+    }
+
+    let vector_in_use = CONTROLLERS
+        .lock()
+        .values()
+        .any(|c| c.vector == controller.vector);
+    if !vector_in_use {
+        if let Some(handler) = HANDLERS.lock().remove(&controller.vector) {
+            interrupt::unregister_handler(handler);
+        }
+    }
+    let msi = controller.msi.lock().take();
+    if let Some(msi) = msi {
+        msi.disable();
+    }
+}
+
+/// Submission queue entry.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Command {
+    // Opcode, and the command identifier in the high half.
+    cdw0: u32,
+    nsid: u32,
+    _cdw2: u32,
+    _cdw3: u32,
+    mptr: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+
+impl Command {
+    fn new(opcode: u8, nsid: u32) -> Command {
+        Command {
+            cdw0: opcode as u32,
+            nsid,
+            ..Default::default()
+        }
+    }
+
+    fn opcode(&self) -> u8 {
+        self.cdw0 as u8
+    }
+}
+
+/// Completion queue entry.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Completion {
+    dw0: u32,
+    _dw1: u32,
+    _sq_head: u16,
+    _sq_id: u16,
+    cid: u16,
+    // Phase tag in bit 0, and the status field above it.
+    status: u16,
+}
+
+#[repr(C, align(4096))]
+struct SubmissionEntries([Command; QUEUE_SIZE]);
+
+#[repr(C, align(4096))]
+struct CompletionEntries([Completion; QUEUE_SIZE]);
+
+/// Page of PRP entries, for commands that move more than two pages.
+#[repr(C, align(4096))]
+struct PrpList([Volatile<u64>; PAGE_SIZE / 8]);
+
+struct SubmissionQueue {
+    entries: Box<SubmissionEntries>,
+    tail: u16,
+}
+
+struct CompletionQueue {
+    entries: Box<CompletionEntries>,
+    head: u16,
+    // Phase tag of new entries, which the controller inverts on each pass through the queue.
+    phase: u16,
+}
+
+struct Slot {
+    buffer: WithSpinLock<DmaBuffer>,
+    prp_list: Box<PrpList>,
+    prp_list_addr: usize,
+
+    // Signalled by the interrupt handler when the command completes.
+    done: Semaphore,
+
+    // Status field of the completion, or NO_STATUS.
+    status: AtomicU32,
+    // Command specific result of the completion.
+    result: AtomicU32,
+}
+
+/// A submission queue and the completion queue that its commands complete on.
+struct QueuePair {
+    id: u16,
+    size: u16,
+
+    sq: WithSpinLock<SubmissionQueue>,
+    cq: WithSpinLock<CompletionQueue>,
+
+    // Virtual addresses of the doorbell registers.
+    sq_doorbell: usize,
+    cq_doorbell: usize,
+
+    // The command identifier of a command is the index of its slot.
+    slots: Vec<Slot>,
+
+    // Slots that no command is using.
+    free: WithSpinLock<Vec<usize>>,
+    free_slots: Semaphore,
+}
+
+impl QueuePair {
+    fn new(
+        id: u16,
+        size: u16,
+        slots: usize,
+        max_transfer: usize,
+        regs: usize,
+        doorbell_stride: usize,
+    ) -> Result<QueuePair, &'static str> {
+        // A full submission queue has one free entry, so there is a slot for each other entry at
+        // most.
+        let slots = slots.min(size as usize - 1);
+        let doorbells = regs + REG_DOORBELLS + 2 * id as usize * doorbell_stride;
+        Ok(QueuePair {
+            id,
+            size,
+            // SAFETY: All-zero bytes are valid queue entries, and zeroing the memory makes sure
+            //         that it is mapped before we ask for its physical address. The controller
+            //         writes a phase tag of 1 on its first pass through the completion queue.
+            sq: WithSpinLock::new(SubmissionQueue {
+                entries: unsafe { Box::new_zeroed().assume_init() },
+                tail: 0,
+            }),
+            cq: WithSpinLock::new(CompletionQueue {
+                entries: unsafe { Box::new_zeroed().assume_init() },
+                head: 0,
+                phase: 1,
+            }),
+            sq_doorbell: doorbells,
+            cq_doorbell: doorbells + doorbell_stride,
+            slots: (0..slots)
+                .map(|_| {
+                    let prp_list: Box<PrpList> = unsafe { Box::new_zeroed().assume_init() };
+                    let prp_list_addr = phys_addr(&raw const *prp_list as usize)?;
+                    Ok(Slot {
+                        buffer: WithSpinLock::new(DmaBuffer::new(max_transfer)?),
+                        prp_list,
+                        prp_list_addr,
+                        done: Semaphore::new(0, 1),
+                        status: AtomicU32::new(NO_STATUS),
+                        result: AtomicU32::new(0),
+                    })
+                })
+                .collect::<Result<Vec<Slot>, &'static str>>()?,
+            free: WithSpinLock::new((0..slots).collect()),
+            free_slots: Semaphore::new(slots, slots),
+        })
+    }
+
+    /// Returns the physical addresses of the submission queue and the completion queue.
+    fn addresses(&self) -> Result<(usize, usize), &'static str> {
+        let sq = phys_addr(&raw const *self.sq.lock().entries as usize)?;
+        let cq = phys_addr(&raw const *self.cq.lock().entries as usize)?;
+        Ok((sq, cq))
+    }
+
+    /// Submits `command`, moving `data` through the bounce buffer of a slot. Returns the command
+    /// specific result once the controller has completed it.
+    fn submit(&self, command: Command, data: Data) -> Result<u32, &'static str> {
+        self.free_slots.wait();
+        let index = self.free.lock().pop().expect("a slot is free");
+        let result = self.submit_in_slot(index, command, data);
+        self.free.lock().push(index);
+        self.free_slots.signal();
+        result
+    }
+
+    fn submit_in_slot(
+        &self,
+        index: usize,
+        mut command: Command,
+        data: Data,
+    ) -> Result<u32, &'static str> {
+        let slot = &self.slots[index];
+        {
+            let mut buffer = slot.buffer.lock();
+            if let Data::Out(bytes) = data {
+                buffer.copy_from(bytes);
+            }
+            // The first page goes in PRP1. PRP2 has the second page, or points to a list of the
+            // remaining pages if there are more than two.
+            let pages: Vec<usize> = buffer.segments(data.len()).map(|(addr, _)| addr).collect();
+            command.prp1 = pages.first().copied().unwrap_or(0) as u64;
+            command.prp2 = match pages.len() {
+                0 | 1 => 0,
+                2 => pages[1] as u64,
+                _ => {
+                    for (entry, &page) in slot.prp_list.0.iter().zip(&pages[1..]) {
+                        entry.write(page as u64);
+                    }
+                    slot.prp_list_addr as u64
+                }
+            };
+        }
+        command.cdw0 |= (index as u32) << 16;
+        slot.status.store(NO_STATUS, Release);
+
+        {
+            let mut sq = self.sq.lock();
+            let tail = sq.tail as usize;
+            unsafe { ptr::write_volatile(&mut sq.entries.0[tail], command) };
+            sq.tail = (sq.tail + 1) % self.size;
+            unsafe { ptr::write_volatile(self.sq_doorbell as *mut u32, sq.tail as u32) };
+        }
+        slot.done.wait();
+
+        let status = slot.status.load(Acquire);
+        if status == NO_STATUS {
+            return Err("controller was removed");
+        }
+        if status != 0 {
+            warn!(
+                "nvme queue {}: command {:#x} failed with status {:#x}",
+                self.id,
+                command.opcode(),
+                status
+            );
+            return Err("NVMe command failed");
+        }
+        if let Data::In(out) = data {
+            slot.buffer.lock().copy_to(out);
+        }
+        Ok(slot.result.load(Acquire))
+    }
+
+    /// Completes the commands that the controller has posted to the completion queue.
+    fn process_completions(&self) {
+        let mut cq = self.cq.lock();
+        let mut processed = false;
+        loop {
+            let entry = unsafe { ptr::read_volatile(&cq.entries.0[cq.head as usize]) };
+            if entry.status & 1 != cq.phase {
+                break;
+            }
+            if let Some(slot) = self.slots.get(entry.cid as usize) {
+                slot.result.store(entry.dw0, Release);
+                slot.status.store((entry.status >> 1) as u32, Release);
+                slot.done.try_signal();
+            }
+            cq.head += 1;
+            if cq.head == self.size {
+                cq.head = 0;
+                cq.phase ^= 1;
+            }
+            processed = true;
+        }
+        if processed {
+            unsafe { ptr::write_volatile(self.cq_doorbell as *mut u32, cq.head as u32) };
+        }
+    }
+
+    /// Wakes the tasks waiting for commands, which will never complete.
+    fn abort(&self) {
+        let free = self.free.lock().clone();
+        for (i, slot) in self.slots.iter().enumerate() {
+            if !free.contains(&i) {
+                slot.done.try_signal();
+            }
+        }
+    }
+}
+
+/// What Identify Controller tells about the controller.
+struct Identity {
+    model: String,
+    // Largest transfer of one command.
+    max_transfer: usize,
+    has_dsm: bool,
+    has_write_cache: bool,
+}
+
+struct Controller {
+    pci: PCIDevice,
+
+    // The interrupt vector
+    vector: u8,
+
+    // MSI or MSI-X, if the vector is not routed through the I/O APIC. Taken when the controller
+    // is removed.
+    msi: WithSpinLock<Option<MessageInterrupts>>,
+
+    // Virtual address of the controller registers mapped from BAR0.
+    regs: usize,
+
+    // Bytes between doorbell registers.
+    doorbell_stride: usize,
+
+    // Largest queue that the controller supports.
+    max_queue_size: u16,
+
+    // CAP.TO, how long the controller may take to become ready.
+    timeout_ms: u64,
+
+    admin: QueuePair,
+
+    // Created once the controller is enabled.
+    io: spin::Once<QueuePair>,
+
+    removed: AtomicBool,
+
+    // Names of the block devices of the namespaces.
+    namespaces: WithSpinLock<Vec<String>>,
+}
+
+impl Controller {
+    /// Resets the controller, and enables it with an admin queue.
+    fn init(pci: PCIDevice, regs: usize, vector: u8) -> Result<Controller, &'static str> {
+        let cap = unsafe { read_reg64(regs, REG_CAP) };
+        if cap & CAP_CSS_NVM == 0 {
+            return Err("controller does not support the NVM command set");
+        }
+        if (cap >> CAP_MPSMIN_SHIFT) & 0xf != 0 {
+            return Err("controller does not support 4 KiB pages");
+        }
+        let doorbell_stride = 4 << ((cap >> CAP_DSTRD_SHIFT) & 0xf);
+        // MQES is 0's based.
+        let max_queue_size = ((cap & CAP_MQES_MASK) + 1).min(QUEUE_SIZE as u64) as u16;
+        let timeout_ms = ((cap >> CAP_TO_SHIFT) & 0xff).max(1) * 500;
+
+        let controller = Controller {
+            pci,
+            vector,
+            // Set by `probe()` once the controller is initialized.
+            msi: WithSpinLock::new(None),
+            regs,
+            doorbell_stride,
+            max_queue_size,
+            timeout_ms,
+            admin: QueuePair::new(
+                ADMIN_QUEUE_ID,
+                max_queue_size,
+                ADMIN_SLOTS,
+                IDENTIFY_LEN,
+                regs,
+                doorbell_stride,
+            )?,
+            io: spin::Once::new(),
+            removed: AtomicBool::new(false),
+            namespaces: WithSpinLock::new(Vec::new()),
+        };
+
+        let version = unsafe { controller.read_reg(REG_VS) };
+        info!(
+            "nvme {}: NVMe {}.{}",
+            controller.pci.bdf,
+            version >> 16,
+            (version >> 8) & 0xff
+        );
+
+        unsafe {
+            // Disable the controller, which resets it and deletes its queues.
+            let cc = controller.read_reg(REG_CC);
+            controller.write_reg(REG_CC, cc & !CC_EN);
+            if !wait_for(timeout_ms, || controller.read_reg(REG_CSTS) & CSTS_RDY == 0) {
+                return Err("controller reset timed out");
+            }
+
+            let (sq, cq) = controller.admin.addresses()?;
+            let size = (max_queue_size - 1) as u32;
+            controller.write_reg(REG_AQA, size << 16 | size);
+            controller.write_reg64(REG_ASQ, sq as u64);
+            controller.write_reg64(REG_ACQ, cq as u64);
+
+            controller.write_reg(REG_CC, CC_EN | CC_IOSQES | CC_IOCQES);
+            if !wait_for(timeout_ms, || {
+                controller.read_reg(REG_CSTS) & (CSTS_RDY | CSTS_CFS) != 0
+            }) {
+                return Err("controller enable timed out");
+            }
+            if controller.read_reg(REG_CSTS) & CSTS_CFS != 0 {
+                return Err("controller fatal status");
+            }
+        }
+        Ok(controller)
+    }
+
+    /// Asks the controller for its model and limits.
+    fn identify(&self) -> Result<Identity, &'static str> {
+        let mut id = vec![0u8; IDENTIFY_LEN];
+        let mut command = Command::new(ADMIN_IDENTIFY, 0);
+        command.cdw10 = CNS_CONTROLLER;
+        self.admin.submit(command, Data::In(&mut id))?;
+
+        let model = String::from_utf8_lossy(&id[24..64]).trim().into();
+        // MDTS is a power of two of the minimum page size, or 0 for no limit. A limit that doesn't
+        // fit in a usize is no limit either.
+        let max_transfer = match id[77] {
+            0 => MAX_TRANSFER,
+            mdts => 1usize
+                .checked_shl(mdts as u32)
+                .and_then(|pages| pages.checked_mul(PAGE_SIZE))
+                .map_or(MAX_TRANSFER, |limit| MAX_TRANSFER.min(limit)),
+        };
+        let oncs = u16::from_le_bytes([id[520], id[521]]);
+        Ok(Identity {
+            model,
+            max_transfer,
+            has_dsm: oncs & ONCS_DSM != 0,
+            has_write_cache: id[525] & VWC_PRESENT != 0,
+        })
+    }
+
+    /// Creates the I/O queue pair, whose completion queue interrupts on message 0 like the admin
+    /// queue.
+    fn create_io_queues(&self) -> Result<(), &'static str> {
+        // Ask for one submission queue and one completion queue. Both counts are 0's based.
+        let mut command = Command::new(ADMIN_SET_FEATURES, 0);
+        command.cdw10 = FEATURE_NUMBER_OF_QUEUES;
+        command.cdw11 = 0;
+        self.admin.submit(command, Data::None)?;
+
+        let io = QueuePair::new(
+            IO_QUEUE_ID,
+            self.max_queue_size,
+            IO_SLOTS,
+            MAX_TRANSFER,
+            self.regs,
+            self.doorbell_stride,
+        )?;
+        let (sq, cq) = io.addresses()?;
+        let size = (io.size as u32 - 1) << 16;
+
+        let mut command = Command::new(ADMIN_CREATE_IO_CQ, 0);
+        command.prp1 = cq as u64;
+        command.cdw10 = size | IO_QUEUE_ID as u32;
+        command.cdw11 = QUEUE_INTERRUPTS_ENABLED | QUEUE_PHYS_CONTIGUOUS;
+        self.admin.submit(command, Data::None)?;
+
+        let mut command = Command::new(ADMIN_CREATE_IO_SQ, 0);
+        command.prp1 = sq as u64;
+        command.cdw10 = size | IO_QUEUE_ID as u32;
+        command.cdw11 = (IO_QUEUE_ID as u32) << 16 | QUEUE_PHYS_CONTIGUOUS;
+        if let Err(e) = self.admin.submit(command, Data::None) {
+            let mut command = Command::new(ADMIN_DELETE_IO_CQ, 0);
+            command.cdw10 = IO_QUEUE_ID as u32;
+            self.admin.submit(command, Data::None)?;
+            return Err(e);
+        }
+
+        self.io.call_once(|| io);
+        Ok(())
+    }
+
+    /// Returns the identifiers of the active namespaces, in increasing order.
+    fn active_namespaces(&self) -> Result<Vec<u32>, &'static str> {
+        let mut list = vec![0u8; IDENTIFY_LEN];
+        let mut command = Command::new(ADMIN_IDENTIFY, 0);
+        command.cdw10 = CNS_ACTIVE_NAMESPACES;
+        self.admin.submit(command, Data::In(&mut list))?;
+        Ok(list
+            .chunks(4)
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+            .take_while(|&nsid| nsid != 0)
+            .collect())
+    }
+
+    /// Submits an NVM command on the I/O queue.
+    fn submit_io(&self, command: Command, data: Data) -> Result<(), &'static str> {
+        if self.removed.load(Acquire) {
+            return Err("controller was removed");
+        }
+        let io = self.io.get().ok_or("no I/O queue")?;
+        io.submit(command, data).map(|_| ())
+    }
+
+    /// Deletes the I/O queues and shuts the controller down, which lets it save its state.
+    fn shutdown(&self) -> Result<(), &'static str> {
+        if self.io.get().is_some() {
+            for (opcode, id) in [
+                (ADMIN_DELETE_IO_SQ, IO_QUEUE_ID),
+                (ADMIN_DELETE_IO_CQ, IO_QUEUE_ID),
+            ] {
+                let mut command = Command::new(opcode, 0);
+                command.cdw10 = id as u32;
+                self.admin.submit(command, Data::None)?;
+            }
+        }
+        unsafe {
+            let cc = self.read_reg(REG_CC);
+            self.write_reg(REG_CC, cc | CC_SHN_NORMAL);
+            let timeout_ms = self.timeout_ms.max(SHUTDOWN_TIMEOUT_MS);
+            let shut_down = wait_for(timeout_ms, || {
+                self.read_reg(REG_CSTS) & CSTS_SHST_MASK == CSTS_SHST_COMPLETE
+            });
+            self.write_reg(REG_CC, 0);
+            if !shut_down {
+                return Err("shutdown timed out");
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a 32-bit controller register.
+    // SAFETY: Caller must ensure that `reg` is the offset of a controller register.
+    unsafe fn read_reg(&self, reg: usize) -> u32 {
+        ptr::read_volatile((self.regs + reg) as *const u32)
+    }
+
+    /// Writes a 32-bit controller register.
+    // SAFETY: Caller must ensure that `reg` is the offset of a controller register.
+    unsafe fn write_reg(&self, reg: usize, value: u32) {
+        ptr::write_volatile((self.regs + reg) as *mut u32, value)
+    }
+
+    /// Writes a 64-bit controller register, as two 32-bit halves.
+    // SAFETY: Caller must ensure that `reg` is the offset of a 64-bit controller register.
+    unsafe fn write_reg64(&self, reg: usize, value: u64) {
+        self.write_reg(reg, value as u32);
+        self.write_reg(reg + 4, (value >> 32) as u32);
+    }
+}
+
+/// Reads a 64-bit controller register, as two 32-bit halves.
+// SAFETY: Caller must ensure that `reg` is the offset of a 64-bit controller register.
+unsafe fn read_reg64(regs: usize, reg: usize) -> u64 {
+    let low = ptr::read_volatile((regs + reg) as *const u32);
+    let high = ptr::read_volatile((regs + reg + 4) as *const u32);
+    (high as u64) << 32 | low as u64
+}
+
+fn phys_addr(linear_addr: usize) -> Result<usize, &'static str> {
+    mm::phys_addr(linear_addr).ok_or("unmapped DMA memory")
+}
+
+/// A namespace of an NVMe controller.
+pub struct Namespace {
+    controller: Arc<Controller>,
+    nsid: u32,
+    sectors: u64,
+    sector_size: usize,
+    model: String,
+    max_transfer: usize,
+    has_dsm: bool,
+    has_write_cache: bool,
+}
+
+impl Namespace {
+    /// Asks the controller for the size and format of namespace `nsid`. Returns None if the
+    /// namespace has no storage.
+    fn identify(
+        controller: Arc<Controller>,
+        identity: &Identity,
+        nsid: u32,
+    ) -> Result<Option<Namespace>, &'static str> {
+        let mut id = vec![0u8; IDENTIFY_LEN];
+        let mut command = Command::new(ADMIN_IDENTIFY, nsid);
+        command.cdw10 = CNS_NAMESPACE;
+        controller.admin.submit(command, Data::In(&mut id))?;
+
+        let sectors = u64::from_le_bytes(id[0..8].try_into().unwrap());
+        if sectors == 0 {
+            return Ok(None);
+        }
+        // FLBAS picks one of the LBA formats, whose LBADS is the power of two of the sector size.
+        let format = (id[26] & 0xf) as usize;
+        let lbads = id[128 + format * 4 + 2];
+        if !(9..=12).contains(&lbads) {
+            return Err("unsupported sector size");
+        }
+        let sector_size = 1 << lbads;
+        if sector_size > identity.max_transfer {
+            return Err("unsupported sector size");
+        }
+
+        Ok(Some(Namespace {
+            controller,
+            nsid,
+            sectors,
+            sector_size,
+            model: identity.model.clone(),
+            max_transfer: identity.max_transfer,
+            has_dsm: identity.has_dsm,
+            has_write_cache: identity.has_write_cache,
+        }))
+    }
+
+    /// Largest number of sectors that one command can move.
+    fn max_sectors(&self) -> usize {
+        self.max_transfer / self.sector_size
+    }
+
+    /// Returns a read or write command for the sectors from `sector`, moving `len` bytes.
+    fn rw_command(&self, opcode: u8, sector: u64, len: usize) -> Command {
+        let mut command = Command::new(opcode, self.nsid);
+        command.cdw10 = sector as u32;
+        command.cdw11 = (sector >> 32) as u32;
+        // The number of sectors is 0's based.
+        command.cdw12 = (len / self.sector_size - 1) as u32;
+        command
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_request(self, sector, buf.len())?;
+        let chunk_size = self.max_sectors() * self.sector_size;
+        for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let sector = sector + (i * self.max_sectors()) as u64;
+            let command = self.rw_command(NVM_READ, sector, chunk.len());
+            self.controller.submit_io(command, Data::In(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_request(self, sector, buf.len())?;
+        let chunk_size = self.max_sectors() * self.sector_size;
+        for (i, chunk) in buf.chunks(chunk_size).enumerate() {
+            let sector = sector + (i * self.max_sectors()) as u64;
+            let command = self.rw_command(NVM_WRITE, sector, chunk.len());
+            self.controller.submit_io(command, Data::Out(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        // Without a volatile write cache, completed writes are already on stable storage.
+        if !self.has_write_cache {
+            return Ok(());
+        }
+        self.controller
+            .submit_io(Command::new(NVM_FLUSH, self.nsid), Data::None)
+    }
+
+    fn discard(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        if !self.has_dsm {
+            return Err("discard is not supported");
+        }
+        let len = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(self.sector_size))
+            .ok_or("request is past the end of the device")?;
+        block::check_request(self, sector, len)?;
+        let mut done = 0;
+        while done < count {
+            let n = (count - done).min(u32::MAX as u64);
+            // One range: context attributes, length in sectors and starting sector.
+            let mut range = [0u8; 16];
+            range[4..8].copy_from_slice(&(n as u32).to_le_bytes());
+            range[8..16].copy_from_slice(&(sector + done).to_le_bytes());
+            let mut command = Command::new(NVM_DATASET_MANAGEMENT, self.nsid);
+            // The number of ranges is 0's based.
+            command.cdw10 = 0;
+            command.cdw11 = DSM_DEALLOCATE;
+            self.controller.submit_io(command, Data::Out(&range))?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn nvme_handler(vector: u64) {
+    let controllers = {
+        // SAFETY: We are getting an exclusive lock in ISR context, which has interrupts disabled.
+        //         We are also not calling any blocking functions, so there is no risk of a deadlock.
+        let controllers = CONTROLLERS.lock();
+        controllers
+            .values()
+            .filter(|c| c.vector == vector as u8)
+            .cloned()
+            .collect::<Vec<Arc<Controller>>>()
+    };
+    for c in controllers.iter() {
+        c.admin.process_completions();
+        if let Some(io) = c.io.get() {
+            io.process_completions();
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! Request queues.
+//!
+//! Each block device gets a queue that requests are submitted to without waiting for them. A
+//! request that starts or ends where the last queued request of the same kind does is merged into
+//! it, up to `MAX_MERGE` bytes, so that the driver sees fewer and larger requests. The block task
+//! (`block::run()`) hands the requests to the driver in the order they were queued.
+
+use crate::drivers::block::{self, BlockDevice};
+use crate::locking::semaphore::Semaphore;
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering::{AcqRel, Release};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+// Largest request that merging makes.
+const MAX_MERGE: usize = 0x20000;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Read,
+    Write,
+    Flush,
+    /// Discard of this many sectors.
+    Discard(u64),
+}
+
+/// Completion of a submitted request, shared by the queue and the submitter.
+struct Completion {
+    done: Semaphore,
+    // The data read, or an empty buffer for other requests.
+    result: WithSpinLock<Option<Result<Vec<u8>, &'static str>>>,
+}
+
+/// A request that was submitted to a queue. Waiting for it returns the data read.
+#[must_use]
+pub struct Pending(Arc<Completion>);
+
+impl Pending {
+    fn new() -> Pending {
+        Pending(Arc::new(Completion {
+            done: Semaphore::new(0, 1),
+            result: WithSpinLock::new(None),
+        }))
+    }
+
+    /// Returns a request that has already failed with `error`.
+    fn failed(error: &'static str) -> Pending {
+        let pending = Pending::new();
+        pending.0.complete(Err(error));
+        pending
+    }
+
+    /// Returns whether the request has completed, so that `wait()` would not block.
+    pub fn is_done(&self) -> bool {
+        self.0.result.lock().is_some()
+    }
+
+    /// Waits for the request to complete. Returns the data read by a read request, and an empty
+    /// buffer for other requests.
+    pub fn wait(self) -> Result<Vec<u8>, &'static str> {
+        self.0.done.wait();
+        self.0
+            .result
+            .lock()
+            .take()
+            .expect("completed request has a result")
+    }
+}
+
+impl Completion {
+    fn complete(&self, result: Result<Vec<u8>, &'static str>) {
+        *self.result.lock() = Some(result);
+        self.done.signal();
+    }
+}
+
+/// A request in a queue, which may be several submitted requests merged together.
+struct Request {
+    operation: Operation,
+    sector: u64,
+    data: Vec<u8>,
+
+    // The submitted requests, with the offset and length of their data in `data`.
+    parts: Vec<(Arc<Completion>, usize, usize)>,
+}
+
+impl Request {
+    /// Merges `other` into this request if it is of the same kind and adjacent. Returns it back
+    /// otherwise.
+    fn merge(&mut self, mut other: Request, sector_size: usize) -> Result<(), Request> {
+        if other.operation != self.operation {
+            return Err(other);
+        }
+        if self.operation == Operation::Flush {
+            // Flushes are not adjacent to anything, but one flush does for both.
+            self.parts.append(&mut other.parts);
+            return Ok(());
+        }
+        if !matches!(self.operation, Operation::Read | Operation::Write)
+            || self.data.len() + other.data.len() > MAX_MERGE
+        {
+            return Err(other);
+        }
+        let sectors = |request: &Request| (request.data.len() / sector_size) as u64;
+        if self.sector + sectors(self) == other.sector {
+            let offset = self.data.len();
+            self.data.append(&mut other.data);
+            self.parts.extend(
+                other
+                    .parts
+                    .into_iter()
+                    .map(|(completion, start, len)| (completion, offset + start, len)),
+            );
+            Ok(())
+        } else if other.sector + sectors(&other) == self.sector {
+            let offset = other.data.len();
+            other.data.append(&mut self.data);
+            self.data = other.data;
+            self.sector = other.sector;
+            for part in self.parts.iter_mut() {
+                part.1 += offset;
+            }
+            self.parts.splice(0..0, other.parts);
+            Ok(())
+        } else {
+            Err(other)
+        }
+    }
+
+    /// Completes each submitted request with its part of the data.
+    fn complete(self, result: Result<(), &'static str>) {
+        for (completion, offset, len) in self.parts {
+            let result = match (&result, self.operation) {
+                (Err(e), _) => Err(*e),
+                (Ok(()), Operation::Read) => Ok(self.data[offset..offset + len].to_vec()),
+                (Ok(()), _) => Ok(Vec::new()),
+            };
+            completion.complete(result);
+        }
+    }
+}
+
+/// The queue of requests of a block device. It is also a block device, whose requests go
+/// through the queue and wait for it.
+pub struct RequestQueue {
+    id: usize,
+    name: String,
+    device: Arc<dyn BlockDevice>,
+
+    pending: WithSpinLock<VecDeque<Request>>,
+
+    // Whether a task is handing requests to the driver.
+    dispatching: AtomicBool,
+
+    // Number of requests submitted, and number of those that were merged into another.
+    submitted: AtomicU64,
+    merged: AtomicU64,
+}
+
+impl RequestQueue {
+    pub(super) fn new(name: String, device: Arc<dyn BlockDevice>) -> RequestQueue {
+        RequestQueue {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            name,
+            device,
+            pending: WithSpinLock::new(VecDeque::new()),
+            dispatching: AtomicBool::new(false),
+            submitted: AtomicU64::new(0),
+            merged: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns a number that identifies the queue, which is never reused.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the number of requests submitted, and how many of them were merged.
+    pub fn statistics(&self) -> (u64, u64) {
+        (
+            self.submitted.load(Ordering::Relaxed),
+            self.merged.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Submits a read of `count` sectors from `sector`.
+    pub fn read_async(&self, sector: u64, count: usize) -> Pending {
+        let len = count * self.device.sector_size();
+        self.submit(Operation::Read, sector, vec![0; len])
+    }
+
+    /// Submits a write of `data` to the sectors from `sector`.
+    pub fn write_async(&self, sector: u64, data: Vec<u8>) -> Pending {
+        self.submit(Operation::Write, sector, data)
+    }
+
+    /// Submits a flush, which completes once the writes queued before it are on stable storage.
+    pub fn flush_async(&self) -> Pending {
+        self.submit(Operation::Flush, 0, Vec::new())
+    }
+
+    fn submit(&self, operation: Operation, sector: u64, data: Vec<u8>) -> Pending {
+        let checked = match operation {
+            Operation::Read | Operation::Write => block::check_request(self, sector, data.len()),
+            Operation::Discard(count) => usize::try_from(count)
+                .ok()
+                .and_then(|count| count.checked_mul(self.sector_size()))
+                .ok_or("request is past the end of the device")
+                .and_then(|len| block::check_request(self, sector, len)),
+            Operation::Flush => Ok(()),
+        };
+        if let Err(e) = checked {
+            return Pending::failed(e);
+        }
+
+        let pending = Pending::new();
+        let request = Request {
+            operation,
+            sector,
+            parts: vec![(pending.0.clone(), 0, data.len())],
+            data,
+        };
+        self.submitted.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut queue = self.pending.lock();
+            let unmerged = match queue.back_mut() {
+                Some(last) => last.merge(request, self.device.sector_size()).err(),
+                None => Some(request),
+            };
+            match unmerged {
+                Some(request) => queue.push_back(request),
+                None => {
+                    self.merged.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        // Until the block task runs, requests are handed to the driver right away.
+        if !block::wake_task() {
+            self.dispatch();
+        }
+        pending
+    }
+
+    /// Hands the queued requests to the driver, unless another task is already doing so.
+    pub(super) fn dispatch(&self) {
+        loop {
+            if self.dispatching.swap(true, AcqRel) {
+                return;
+            }
+            loop {
+                // Requests are taken one at a time, so that later ones can still be merged into
+                // those at the back while the driver works.
+                let Some(mut request) = self.pending.lock().pop_front() else {
+                    break;
+                };
+                let result = match request.operation {
+                    Operation::Read => self.device.read(request.sector, &mut request.data),
+                    Operation::Write => self.device.write(request.sector, &request.data),
+                    Operation::Flush => self.device.flush(),
+                    Operation::Discard(count) => self.device.discard(request.sector, count),
+                };
+                request.complete(result);
+            }
+            self.dispatching.store(false, Release);
+            // A request may have been queued after the queue looked empty, and before
+            // `dispatching` was cleared, by a task that saw us dispatching.
+            if self.pending.lock().is_empty() {
+                return;
+            }
+        }
+    }
+
+    /// Fails the queued requests, because the device was removed.
+    pub(super) fn abort(&self) {
+        let requests: Vec<Request> = self.pending.lock().drain(..).collect();
+        for request in requests {
+            request.complete(Err("device was removed"));
+        }
+    }
+}
+
+impl BlockDevice for RequestQueue {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sectors(&self) -> u64 {
+        self.device.sectors()
+    }
+
+    fn model(&self) -> &str {
+        self.device.model()
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_request(self, sector, buf.len())?;
+        let data = self
+            .read_async(sector, buf.len() / self.sector_size())
+            .wait()?;
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), &'static str> {
+        self.write_async(sector, buf.to_vec()).wait().map(|_| ())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        self.flush_async().wait().map(|_| ())
+    }
+
+    fn discard(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        // Discards are queued like writes, so that they happen after the writes queued before.
+        self.submit(Operation::Discard(count), sector, Vec::new())
+            .wait()
+            .map(|_| ())
+    }
+}
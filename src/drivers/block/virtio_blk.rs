@@ -0,0 +1,533 @@
+//! Virtio block devices.
+//!
+//! Requests go through a single virtqueue. Each request takes one of a few slots, which has the
+//! request header, the status byte and a bounce buffer for the data, so that several requests can
+//! be in the queue at once. The interrupt handler signals the task that made a request when the
+//! device returns its descriptors.
+
+use crate::arch::x86_64::interrupt::{self, register_handler, HandlerId};
+use crate::arch::x86_64::mm;
+use crate::drivers::block::dma::{Data, DmaBuffer, PAGE_SIZE};
+use crate::drivers::block::{self, BlockDevice};
+use crate::drivers::pci::{self, DeviceId, MessageInterrupts, PCIDevice};
+use crate::drivers::virtio::{self, Buffer, VirtioPci, Virtqueue};
+use crate::locking::semaphore::Semaphore;
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::read_volatile;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::{Acquire, Release};
+use log::info;
+use util::volatile::Volatile;
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "virtio-blk",
+    ids: &[
+        DeviceId::device(
+            virtio::VIRTIO_VENDOR_ID,
+            virtio::modern_device_id(DEVICE_TYPE),
+        ),
+        // Transitional devices, which have the modern interface as well as the legacy one.
+        DeviceId::device(virtio::VIRTIO_VENDOR_ID, 0x1001),
+    ],
+    probe,
+    remove,
+};
+
+static DEVICES: WithSpinLock<BTreeMap<pci::BDF, Arc<VirtioBlk>>> =
+    WithSpinLock::new(BTreeMap::new());
+
+// Handlers registered for the vectors of the devices. Devices on a shared line share the handler.
+static HANDLERS: WithSpinLock<BTreeMap<u8, HandlerId>> = WithSpinLock::new(BTreeMap::new());
+
+// Virtio device type of block devices.
+const DEVICE_TYPE: u16 = 2;
+
+// Feature bits
+const F_SEG_MAX: u64 = 1 << 2;
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+const F_DISCARD: u64 = 1 << 13;
+
+// Device configuration
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_SEG_MAX: usize = 12;
+const CONFIG_MAX_DISCARD_SECTORS: usize = 36;
+
+// Request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_GET_ID: u32 = 8;
+const T_DISCARD: u32 = 11;
+
+// Request status, written by the device. The driver initializes it to STATUS_NONE.
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+const STATUS_NONE: u8 = 0xff;
+
+// Sectors of virtio block devices are always 512 bytes, whatever the block size of the device.
+const SECTOR_SIZE: usize = 512;
+
+// Length of the ID string returned by T_GET_ID.
+const ID_LEN: usize = 20;
+
+// Largest transfer of one request, which is the size of the bounce buffer of each slot.
+const MAX_TRANSFER: usize = 0x10000;
+const MAX_SEGMENTS: usize = MAX_TRANSFER / PAGE_SIZE;
+// A request is the header, the data and the status.
+const MAX_DESCS_PER_REQUEST: usize = MAX_SEGMENTS + 2;
+const MAX_SLOTS: usize = 8;
+
+const QUEUE_SIZE: u16 = 128;
+
+/// Initializes a virtio block device, and registers it as a block device.
+fn probe(pci_dev: &PCIDevice, _id: &DeviceId) -> Result<(), &'static str> {
+    let transport = VirtioPci::new(pci_dev)?;
+    let features = transport.negotiate(F_SEG_MAX | F_RO | F_FLUSH | F_DISCARD)?;
+
+    // Enable bus mastering and memory space access. This lets the device perform DMA.
+    pci_dev.write_control_register(0x0006);
+
+    // Use MSI-X or MSI where the device has it. Otherwise, route the PCI interrupt line to a
+    // vector through the I/O APIC. Virtio only assigns queues to MSI-X messages, so with MSI the
+    // device signals its interrupts the way it does on the line, and the ISR status tells them
+    // apart.
+    let msi = pci_dev.enable_message_interrupts(1).ok();
+    let uses_msix = msi.as_ref().is_some_and(MessageInterrupts::is_msix);
+    let vector = match &msi {
+        Some(msi) => Some(msi.vectors()[0]),
+        None => interrupt::route_gsi(pci_dev.gsi()),
+    };
+    let Some(vector) = vector else {
+        return Err(abort_probe(
+            pci_dev,
+            &transport,
+            msi,
+            "no free interrupt vector",
+        ));
+    };
+    let queue = match transport.setup_queue(0, QUEUE_SIZE, uses_msix.then_some(0)) {
+        Ok(queue) => queue,
+        Err(e) => return Err(abort_probe(pci_dev, &transport, msi, e)),
+    };
+    if uses_msix {
+        transport.disable_config_interrupt();
+    }
+
+    let setup = || {
+        // 64-bit fields are read as two 32-bit halves.
+        let (sectors, seg_max, max_discard_sectors) =
+            transport.read_device_config(|config| unsafe {
+                let read = |offset: usize| read_volatile((config + offset) as *const u32);
+                (
+                    read(CONFIG_CAPACITY) as u64 | (read(CONFIG_CAPACITY + 4) as u64) << 32,
+                    read(CONFIG_SEG_MAX),
+                    read(CONFIG_MAX_DISCARD_SECTORS),
+                )
+            })?;
+        // Without F_SEG_MAX, the device takes any number of segments.
+        let max_segments = match features & F_SEG_MAX {
+            0 => MAX_SEGMENTS,
+            _ => (seg_max as usize).clamp(1, MAX_SEGMENTS),
+        };
+        let slots = (queue.size() as usize / (max_segments + 2)).min(MAX_SLOTS);
+        if slots == 0 {
+            return Err("virtqueue is too small");
+        }
+        let slots = (0..slots)
+            .map(|_| Slot::new())
+            .collect::<Result<Vec<Slot>, &'static str>>()?;
+        Ok((sectors, max_segments, max_discard_sectors, slots))
+    };
+    let (sectors, max_segments, max_discard_sectors, slots) = match setup() {
+        Ok(setup) => setup,
+        // The queue is dropped after the device is reset, so it is never used after it's freed.
+        Err(e) => return Err(abort_probe(pci_dev, &transport, msi, e)),
+    };
+    let slot_count = slots.len();
+
+    let device = Arc::new(VirtioBlk {
+        pci: pci_dev.clone(),
+        transport,
+        vector,
+        uses_msix,
+        msi: WithSpinLock::new(msi),
+        queue: WithSpinLock::new(queue),
+        slots,
+        free: WithSpinLock::new((0..slot_count).collect()),
+        free_slots: Semaphore::new(slot_count, slot_count),
+        removed: AtomicBool::new(false),
+        features,
+        sectors,
+        max_transfer: max_segments * PAGE_SIZE,
+        max_discard_sectors,
+        model: spin::Once::new(),
+        name: WithSpinLock::new(None),
+    });
+    let bdf = pci_dev.bdf;
+    DEVICES.lock().insert(bdf, device.clone());
+    HANDLERS
+        .lock()
+        .entry(vector)
+        .or_insert_with(|| register_handler(vector, virtio_blk_handler));
+    device.transport.driver_ok();
+
+    let model = match device.serial() {
+        Ok(serial) if !serial.is_empty() => format!("virtio-blk {}", serial),
+        Ok(_) => String::from("virtio-blk"),
+        Err(e) => {
+            info!("virtio-blk {}: no serial: {}", bdf, e);
+            String::from("virtio-blk")
+        }
+    };
+    device.model.call_once(|| model);
+    let name = block::register("vd", device.clone());
+    *device.name.lock() = Some(name);
+    Ok(())
+}
+
+/// Undoes a probe that failed before the device was registered, the way `remove()` does, and
+/// returns `e`.
+fn abort_probe(
+    pci_dev: &PCIDevice,
+    transport: &VirtioPci,
+    msi: Option<MessageInterrupts>,
+    e: &'static str,
+) -> &'static str {
+    transport.reset();
+    pci_dev.write_control_register(0x0000);
+    if let Some(msi) = msi {
+        msi.disable();
+    }
+    e
+}
+
+/// Resets a virtio block device and removes it from the block devices.
+fn remove(pci_dev: &PCIDevice) {
+    let Some(device) = DEVICES.lock().get(&pci_dev.bdf).cloned() else {
+        return;
+    };
+    let name = device.name.lock().take();
+    if let Some(name) = name {
+        block::unregister(&name);
+    }
+
+    device.removed.store(true, Release);
+    device.transport.reset();
+    device.pci.write_control_register(0x0000);
+    DEVICES.lock().remove(&pci_dev.bdf);
+
+    // Requests in the queue will never complete. Wake the tasks waiting for them, which find
+    // that the device did not write the status.
+    let free = device.free.lock().clone();
+    for (i, slot) in device.slots.iter().enumerate() {
+        if !free.contains(&i) {
+            slot.done.try_signal();
+        }
+    }
+
+    let vector_in_use = DEVICES.lock().values().any(|d| d.vector == device.vector);
+    if !vector_in_use {
+        if let Some(handler) = HANDLERS.lock().remove(&device.vector) {
+            interrupt::unregister_handler(handler);
+        }
+    }
+    let msi = device.msi.lock().take();
+    if let Some(msi) = msi {
+        msi.disable();
+    }
+}
+
+#[repr(C)]
+struct RequestHeader {
+    type_: Volatile<u32>,
+    _reserved: u32,
+    sector: Volatile<u64>,
+}
+
+#[repr(C)]
+struct DiscardSegment {
+    sector: Volatile<u64>,
+    num_sectors: Volatile<u32>,
+    flags: Volatile<u32>,
+}
+
+/// Parts of a request other than the data. It is aligned to a power of two larger than itself, so
+/// that it never crosses a page boundary.
+#[repr(C, align(64))]
+struct RequestMemory {
+    header: RequestHeader,
+    discard: DiscardSegment,
+    status: Volatile<u8>,
+}
+
+struct Slot {
+    memory: Box<RequestMemory>,
+    buffer: WithSpinLock<DmaBuffer>,
+
+    // Physical address of `memory`.
+    phys_addr: usize,
+
+    // Signalled by the interrupt handler when the device is done with the request.
+    done: Semaphore,
+}
+
+impl Slot {
+    fn new() -> Result<Slot, &'static str> {
+        // SAFETY: All-zero bytes are a valid request, and zeroing the memory makes sure that it is
+        //         mapped before we ask for its physical address.
+        let memory: Box<RequestMemory> = unsafe { Box::new_zeroed().assume_init() };
+        let phys_addr =
+            mm::phys_addr(&raw const *memory as usize).ok_or("unmapped request memory")?;
+        Ok(Slot {
+            memory,
+            buffer: WithSpinLock::new(DmaBuffer::new(MAX_TRANSFER)?),
+            phys_addr,
+            done: Semaphore::new(0, 1),
+        })
+    }
+
+    /// Returns the part of the slot's memory at `field`, as a buffer for the device.
+    fn buffer_for<T>(&self, field: &T, device_writes: bool) -> Buffer {
+        let offset = field as *const T as usize - &raw const *self.memory as usize;
+        Buffer {
+            phys_addr: self.phys_addr + offset,
+            len: size_of::<T>(),
+            device_writes,
+        }
+    }
+}
+
+pub struct VirtioBlk {
+    pci: PCIDevice,
+    transport: VirtioPci,
+
+    // The interrupt vector
+    vector: u8,
+
+    // Whether the queue interrupts with an MSI-X message of its own, rather than the way it does
+    // on INTx, which is also how it uses MSI.
+    uses_msix: bool,
+
+    // Taken when the device is removed.
+    msi: WithSpinLock<Option<MessageInterrupts>>,
+
+    queue: WithSpinLock<Virtqueue>,
+
+    slots: Vec<Slot>,
+
+    // Slots that no request is using.
+    free: WithSpinLock<Vec<usize>>,
+    free_slots: Semaphore,
+
+    removed: AtomicBool,
+
+    // Negotiated features
+    features: u64,
+
+    sectors: u64,
+
+    // Largest transfer of one request, which the device may limit below MAX_TRANSFER.
+    max_transfer: usize,
+
+    max_discard_sectors: u32,
+
+    // Set once the device has answered T_GET_ID, before it is registered.
+    model: spin::Once<String>,
+
+    // Name of the block device.
+    name: WithSpinLock<Option<String>>,
+}
+
+impl VirtioBlk {
+    /// Makes a request of `type_` at `sector`, moving `data`, and returns once the device has
+    /// completed it.
+    fn request(&self, type_: u32, sector: u64, data: Data) -> Result<(), &'static str> {
+        if self.removed.load(Acquire) {
+            return Err("device was removed");
+        }
+        self.free_slots.wait();
+        let index = self.free.lock().pop().expect("a slot is free");
+        let result = self.request_in_slot(index, type_, sector, data);
+        self.free.lock().push(index);
+        self.free_slots.signal();
+        result
+    }
+
+    fn request_in_slot(
+        &self,
+        index: usize,
+        type_: u32,
+        sector: u64,
+        data: Data,
+    ) -> Result<(), &'static str> {
+        assert!(
+            data.len() <= self.max_transfer,
+            "virtio-blk transfer is too large"
+        );
+        let slot = &self.slots[index];
+        let memory = &slot.memory;
+        memory.header.type_.write(type_);
+        memory.header.sector.write(sector);
+        memory.status.write(STATUS_NONE);
+
+        let mut buffers = Vec::with_capacity(MAX_DESCS_PER_REQUEST);
+        buffers.push(slot.buffer_for(&memory.header, false));
+        {
+            let mut buffer = slot.buffer.lock();
+            if let Data::Out(bytes) = data {
+                buffer.copy_from(bytes);
+            }
+            let device_writes = matches!(data, Data::In(_));
+            for (phys_addr, len) in buffer.segments(data.len()) {
+                buffers.push(Buffer {
+                    phys_addr,
+                    len,
+                    device_writes,
+                });
+            }
+        }
+        if type_ == T_DISCARD {
+            buffers.push(slot.buffer_for(&memory.discard, false));
+        }
+        buffers.push(slot.buffer_for(&memory.status, true));
+
+        self.queue.lock().add(&buffers, index)?;
+        slot.done.wait();
+
+        match memory.status.read() {
+            S_OK => {}
+            S_UNSUPP => return Err("request is not supported"),
+            STATUS_NONE => return Err("device was removed"),
+            _ => return Err("I/O error"),
+        }
+        if let Data::In(out) = data {
+            slot.buffer.lock().copy_to(out);
+        }
+        Ok(())
+    }
+
+    /// Returns the serial number of the device.
+    fn serial(&self) -> Result<String, &'static str> {
+        let mut id = [0u8; ID_LEN];
+        self.request(T_GET_ID, 0, Data::In(&mut id))?;
+        // The string is NUL-terminated unless it takes the whole buffer.
+        let len = id.iter().position(|&b| b == 0).unwrap_or(ID_LEN);
+        Ok(id[..len].iter().map(|&b| b as char).collect())
+    }
+
+    /// Largest number of sectors that one request can move.
+    fn max_sectors(&self) -> usize {
+        self.max_transfer / SECTOR_SIZE
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn model(&self) -> &str {
+        self.model.get().map_or("virtio-blk", |model| model)
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_request(self, sector, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(self.max_transfer).enumerate() {
+            let sector = sector + (i * self.max_sectors()) as u64;
+            self.request(T_IN, sector, Data::In(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), &'static str> {
+        if self.features & F_RO != 0 {
+            return Err("device is read-only");
+        }
+        block::check_request(self, sector, buf.len())?;
+        for (i, chunk) in buf.chunks(self.max_transfer).enumerate() {
+            let sector = sector + (i * self.max_sectors()) as u64;
+            self.request(T_OUT, sector, Data::Out(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        // Without F_FLUSH, the device has no volatile write cache.
+        match self.features & F_FLUSH {
+            0 => Ok(()),
+            _ => self.request(T_FLUSH, 0, Data::None),
+        }
+    }
+
+    fn discard(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        if self.features & F_DISCARD == 0 || self.max_discard_sectors == 0 {
+            return Err("discard is not supported");
+        }
+        let len = usize::try_from(count)
+            .ok()
+            .and_then(|c| c.checked_mul(SECTOR_SIZE))
+            .ok_or("request is past the end of the device")?;
+        block::check_request(self, sector, len)?;
+        let mut done = 0;
+        while done < count {
+            let n = (count - done).min(self.max_discard_sectors as u64);
+            self.request_discard(sector + done, n as u32)?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioBlk {
+    fn request_discard(&self, sector: u64, count: u32) -> Result<(), &'static str> {
+        if self.removed.load(Acquire) {
+            return Err("device was removed");
+        }
+        self.free_slots.wait();
+        let index = self.free.lock().pop().expect("a slot is free");
+        let discard = &self.slots[index].memory.discard;
+        discard.sector.write(sector);
+        discard.num_sectors.write(count);
+        discard.flags.write(0);
+        let result = self.request_in_slot(index, T_DISCARD, 0, Data::None);
+        self.free.lock().push(index);
+        self.free_slots.signal();
+        result
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn virtio_blk_handler(vector: u64) {
+    let devices = {
+        // SAFETY: We are getting an exclusive lock in ISR context, which has interrupts disabled.
+        //         We are also not calling any blocking functions, so there is no risk of a deadlock.
+        let devices = DEVICES.lock();
+        devices
+            .values()
+            .filter(|d| d.vector == vector as u8)
+            .cloned()
+            .collect::<Vec<Arc<VirtioBlk>>>()
+    };
+    for d in devices.iter() {
+        // Reading the ISR status acknowledges the interrupt. It is not used with MSI-X.
+        if !d.uses_msix && !d.transport.queue_interrupt() {
+            continue;
+        }
+        let mut queue = d.queue.lock();
+        while let Some((index, _)) = queue.pop_used() {
+            d.slots[index].done.try_signal();
+        }
+    }
+}
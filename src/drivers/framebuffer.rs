@@ -0,0 +1,509 @@
+//! Text console on the framebuffer that the bootloader set up with GOP.
+//!
+//! The console understands the ANSI escape sequences for colors (SGR), clearing the screen and
+//! moving the cursor, which is enough for the kernel log.
+//!
+//! The text on the screen is kept in memory, and the framebuffer is only written: after each
+//! write, the characters that changed are drawn again. Reading the framebuffer back, such as to
+//! scroll it, is very slow because it is mapped uncached.
+
+use crate::arch::x86_64::mm::{self, MMIO_BASE};
+use crate::boot::RawFramebuffer;
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use font::{Terminus16x18Font, FONT_HEIGHT, FONT_WIDTH, REPLACEMENT_CHARACTER};
+use uefi::proto::console::gop::{PixelBitmask, PixelFormat};
+
+const PAGE_SIZE: usize = 0x1000;
+
+const TAB_WIDTH: usize = 8;
+
+// Most parameters we keep for a CSI sequence. Any more are ignored.
+const MAX_CSI_PARAMS: usize = 4;
+
+// Same colors as the bootloader.
+const DEFAULT_FG: Color = Color(0xee, 0xee, 0xee);
+const DEFAULT_BG: Color = Color(0x35, 0x33, 0x2b);
+
+const PANIC_FG: Color = Color(0xff, 0xff, 0xff);
+const PANIC_BG: Color = Color(0xaa, 0x00, 0x00);
+
+/// Colors of SGR parameters 30-37 (foreground) and 40-47 (background), followed by their bright
+/// versions 90-97 and 100-107.
+const PALETTE: [Color; 16] = [
+    Color(0x00, 0x00, 0x00),
+    Color(0xaa, 0x00, 0x00),
+    Color(0x00, 0xaa, 0x00),
+    Color(0xaa, 0x55, 0x00),
+    Color(0x00, 0x00, 0xaa),
+    Color(0xaa, 0x00, 0xaa),
+    Color(0x00, 0xaa, 0xaa),
+    Color(0xaa, 0xaa, 0xaa),
+    Color(0x55, 0x55, 0x55),
+    Color(0xff, 0x55, 0x55),
+    Color(0x55, 0xff, 0x55),
+    Color(0xff, 0xff, 0x55),
+    Color(0x55, 0x55, 0xff),
+    Color(0xff, 0x55, 0xff),
+    Color(0x55, 0xff, 0xff),
+    Color(0xff, 0xff, 0xff),
+];
+
+static CONSOLE: WithSpinLock<Option<Console>> = WithSpinLock::new(None);
+
+/// Maps the framebuffer and starts the console on it.
+pub fn init(fb: RawFramebuffer<'static>) -> Result<(), &'static str> {
+    let encoder = PixelEncoder::new(fb.pixel_format, fb.pixel_bitmask)?;
+    let bytes_per_pixel = encoder.bytes_per_pixel();
+    let min_size = if fb.vertical_resolution == 0 {
+        0
+    } else {
+        ((fb.vertical_resolution - 1) * fb.pixels_per_scan_line + fb.horizontal_resolution)
+            * bytes_per_pixel
+    };
+    if fb.framebuffer.len() < min_size || fb.pixels_per_scan_line < fb.horizontal_resolution {
+        return Err("framebuffer is smaller than its resolution");
+    }
+    let n_col = fb.horizontal_resolution / FONT_WIDTH;
+    let n_row = fb.vertical_resolution / FONT_HEIGHT;
+    if n_col == 0 || n_row == 0 {
+        return Err("framebuffer is too small for a single character");
+    }
+
+    {
+        let mut mapper = mm::mapper();
+        let mapper = mapper.as_mut().ok_or("memory manager is not initialized")?;
+        let phys_base = fb.framebuffer.as_ptr() as usize - MMIO_BASE;
+        let first_page = phys_base & !(PAGE_SIZE - 1);
+        for page in (first_page..phys_base + fb.framebuffer.len()).step_by(PAGE_SIZE) {
+            mapper.map_mmio(page);
+        }
+    }
+
+    let mut console = Console {
+        pitch: fb.pixels_per_scan_line * bytes_per_pixel,
+        cells: vec![Cell::blank(DEFAULT_BG); n_col * n_row],
+        // Nothing we know of is on the screen yet, so every character is drawn.
+        shown: vec![Cell::UNKNOWN; n_col * n_row],
+        fb: fb.framebuffer,
+        font: font::parse_bdf(),
+        encoder,
+        bytes_per_pixel,
+        n_col,
+        n_row,
+        cursor_x: 0,
+        cursor_y: 0,
+        fg: DEFAULT_FG,
+        bg: DEFAULT_BG,
+        bold: false,
+        escape: Escape::None,
+        params: [0; MAX_CSI_PARAMS],
+        n_params: 0,
+        utf8_char: 0,
+        utf8_remaining: 0,
+    };
+    console.clear();
+    console.draw();
+    CONSOLE.lock().replace(console);
+    Ok(())
+}
+
+/// Writes `buf` to the console, if there is one.
+pub fn write(buf: &[u8]) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.write_bytes(buf);
+    }
+}
+
+/// Paints the screen red and shows the panic message. Nothing is shown if the console is locked,
+/// which happens when we panicked while writing to it.
+pub fn show_panic(info: &PanicInfo) {
+    let Some(mut console) = CONSOLE.try_lock() else {
+        return;
+    };
+    let Some(console) = console.as_mut() else {
+        return;
+    };
+    console.escape = Escape::None;
+    console.utf8_remaining = 0;
+    console.bold = false;
+    console.fg = PANIC_FG;
+    console.bg = PANIC_BG;
+    console.clear();
+    writeln!(console, "Kernel panic!\n");
+    if let Some(location) = info.location() {
+        writeln!(
+            console,
+            "file: {}, line: {}, col: {}",
+            location.file(),
+            location.line(),
+            location.column(),
+        );
+    }
+    writeln!(console, "{}", info.message());
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Color(u8, u8, u8);
+
+/// A character on the screen, with its colors.
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Cell {
+    c: char,
+    fg: Color,
+    bg: Color,
+}
+
+impl Cell {
+    /// Differs from every cell that is drawn.
+    const UNKNOWN: Cell = Cell {
+        c: '\0',
+        fg: Color(0, 0, 0),
+        bg: Color(0, 0, 0),
+    };
+
+    fn blank(bg: Color) -> Cell {
+        Cell { c: ' ', fg: bg, bg }
+    }
+}
+
+/// Converts colors to the pixel values of the framebuffer.
+struct PixelEncoder {
+    // Shift and number of bits of the red, green and blue components.
+    red: (u32, u32),
+    green: (u32, u32),
+    blue: (u32, u32),
+
+    // All bits of a pixel, including reserved ones.
+    mask: u32,
+}
+
+impl PixelEncoder {
+    fn new(format: PixelFormat, bitmask: Option<PixelBitmask>) -> Result<Self, &'static str> {
+        let component = |mask: u32| (mask.trailing_zeros() % 32, mask.count_ones());
+        match format {
+            PixelFormat::Rgb => Ok(Self {
+                red: (0, 8),
+                green: (8, 8),
+                blue: (16, 8),
+                mask: u32::MAX,
+            }),
+            PixelFormat::Bgr => Ok(Self {
+                red: (16, 8),
+                green: (8, 8),
+                blue: (0, 8),
+                mask: u32::MAX,
+            }),
+            PixelFormat::Bitmask => {
+                let bitmask = bitmask.ok_or("pixel bitmask is missing")?;
+                Ok(Self {
+                    red: component(bitmask.red),
+                    green: component(bitmask.green),
+                    blue: component(bitmask.blue),
+                    mask: bitmask.red | bitmask.green | bitmask.blue | bitmask.reserved,
+                })
+            }
+            PixelFormat::BltOnly => Err("framebuffer can only be accessed through GOP"),
+        }
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        (32 - self.mask.leading_zeros() as usize).div_ceil(8).max(1)
+    }
+
+    fn encode(&self, color: Color) -> u32 {
+        let component = |value: u8, (shift, bits): (u32, u32)| {
+            let value = value as u32;
+            let value = if bits >= 8 {
+                value << (bits - 8)
+            } else {
+                value >> (8 - bits)
+            };
+            value.checked_shl(shift).unwrap_or(0)
+        };
+        component(color.0, self.red)
+            | component(color.1, self.green)
+            | component(color.2, self.blue)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Escape {
+    None,
+    Esc,
+    Csi,
+}
+
+struct Console {
+    fb: &'static mut [u8],
+    font: Terminus16x18Font,
+    encoder: PixelEncoder,
+    bytes_per_pixel: usize,
+
+    // Bytes between the starts of two rows of pixels.
+    pitch: usize,
+
+    // Characters on the screen, row by row, and those that are drawn on the framebuffer.
+    cells: Vec<Cell>,
+    shown: Vec<Cell>,
+
+    // Size of the screen in characters.
+    n_col: usize,
+    n_row: usize,
+
+    cursor_x: usize,
+    cursor_y: usize,
+
+    fg: Color,
+    bg: Color,
+    bold: bool,
+
+    // Escape sequence being received, and its parameters so far.
+    escape: Escape,
+    params: [u16; MAX_CSI_PARAMS],
+    n_params: usize,
+
+    // UTF-8 sequence being received: the bits of the character so far, and the number of bytes
+    // still to come.
+    utf8_char: u32,
+    utf8_remaining: u32,
+}
+
+impl Console {
+    fn write_bytes(&mut self, buf: &[u8]) {
+        for b in buf {
+            self.write_byte(*b);
+        }
+        self.draw();
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match self.escape {
+            Escape::Esc => {
+                self.escape = if byte == b'[' {
+                    self.params = [0; MAX_CSI_PARAMS];
+                    self.n_params = 0;
+                    Escape::Csi
+                } else {
+                    Escape::None
+                };
+                return;
+            }
+            Escape::Csi => {
+                self.csi_byte(byte);
+                return;
+            }
+            Escape::None => {}
+        }
+
+        if self.utf8_remaining > 0 && !(0x80..=0xbf).contains(&byte) {
+            // The last UTF-8 sequence was cut short.
+            self.utf8_remaining = 0;
+            self.put_char(REPLACEMENT_CHARACTER);
+        }
+
+        match byte {
+            0x1b => self.escape = Escape::Esc,
+            b'\n' => self.newline(),
+            b'\r' => self.cursor_x = 0,
+            b'\t' => {
+                let next = (self.cursor_x / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.cursor_x < next.min(self.n_col) {
+                    self.put_char(' ');
+                }
+            }
+            0x08 => self.cursor_x = self.cursor_x.saturating_sub(1),
+            0x20..=0x7e => self.put_char(byte as char),
+            0x80..=0xbf => self.utf8_continuation(byte),
+            0xc0..=0xdf => self.utf8_start(byte & 0x1f, 1),
+            0xe0..=0xef => self.utf8_start(byte & 0x0f, 2),
+            0xf0..=0xf7 => self.utf8_start(byte & 0x07, 3),
+            0xf8..=0xff => self.put_char(REPLACEMENT_CHARACTER),
+            _ => {}
+        }
+    }
+
+    /// Starts a UTF-8 sequence of `len` more bytes, whose first byte has `bits` of the character.
+    fn utf8_start(&mut self, bits: u8, len: u32) {
+        self.utf8_char = bits as u32;
+        self.utf8_remaining = len;
+    }
+
+    fn utf8_continuation(&mut self, byte: u8) {
+        if self.utf8_remaining == 0 {
+            self.put_char(REPLACEMENT_CHARACTER);
+            return;
+        }
+        self.utf8_char = (self.utf8_char << 6) | (byte & 0x3f) as u32;
+        self.utf8_remaining -= 1;
+        if self.utf8_remaining == 0 {
+            let c = char::from_u32(self.utf8_char).unwrap_or(REPLACEMENT_CHARACTER);
+            self.put_char(c);
+        }
+    }
+
+    /// Handles a byte of a CSI sequence (ESC [ ...).
+    fn csi_byte(&mut self, byte: u8) {
+        match byte {
+            b'0'..=b'9' => {
+                if self.n_params == 0 {
+                    self.n_params = 1;
+                }
+                if let Some(param) = self.params.get_mut(self.n_params - 1) {
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add((byte - b'0') as u16);
+                }
+            }
+            b';' => {
+                self.n_params = (self.n_params.max(1) + 1).min(MAX_CSI_PARAMS + 1);
+            }
+            // Final bytes
+            0x40..=0x7e => {
+                self.escape = Escape::None;
+                let n_params = self.n_params.min(MAX_CSI_PARAMS);
+                let params = self.params;
+                let params = &params[..n_params];
+                let param = |i: usize, default: u16| match params.get(i) {
+                    Some(0) | None => default,
+                    Some(p) => *p,
+                };
+                match byte {
+                    b'm' => self.select_graphic_rendition(params),
+                    b'H' => {
+                        self.cursor_y = (param(0, 1) as usize - 1).min(self.n_row - 1);
+                        self.cursor_x = (param(1, 1) as usize - 1).min(self.n_col - 1);
+                    }
+                    b'J' if params.first() == Some(&2) => {
+                        self.clear();
+                    }
+                    b'K' => {
+                        for x in self.cursor_x..self.n_col {
+                            self.fill_cell(x, self.cursor_y, self.bg);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            // Intermediate bytes, and bytes that should not appear in a CSI sequence.
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.reset_colors();
+        }
+        for param in params {
+            match *param {
+                0 => self.reset_colors(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                p @ 30..=37 => {
+                    let bright = if self.bold { 8 } else { 0 };
+                    self.fg = PALETTE[(p - 30) as usize + bright];
+                }
+                39 => self.fg = DEFAULT_FG,
+                p @ 40..=47 => self.bg = PALETTE[(p - 40) as usize],
+                49 => self.bg = DEFAULT_BG,
+                p @ 90..=97 => self.fg = PALETTE[(p - 90) as usize + 8],
+                p @ 100..=107 => self.bg = PALETTE[(p - 100) as usize + 8],
+                _ => {}
+            }
+        }
+    }
+
+    fn reset_colors(&mut self) {
+        self.fg = DEFAULT_FG;
+        self.bg = DEFAULT_BG;
+        self.bold = false;
+    }
+
+    /// Draws `c` at the cursor and advances the cursor, wrapping to the next line at the right
+    /// edge of the screen. Characters without a glyph are drawn as `REPLACEMENT_CHARACTER`.
+    fn put_char(&mut self, c: char) {
+        if self.cursor_x >= self.n_col {
+            self.newline();
+        }
+        self.cells[self.cursor_y * self.n_col + self.cursor_x] = Cell {
+            c,
+            fg: self.fg,
+            bg: self.bg,
+        };
+        self.cursor_x += 1;
+    }
+
+    fn newline(&mut self) {
+        self.cursor_x = 0;
+        if self.cursor_y + 1 < self.n_row {
+            self.cursor_y += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    /// Moves all lines up by one, and clears the last line.
+    fn scroll(&mut self) {
+        self.cells.copy_within(self.n_col.., 0);
+        for x in 0..self.n_col {
+            self.fill_cell(x, self.n_row - 1, self.bg);
+        }
+    }
+
+    /// Fills the screen with the background color and moves the cursor to the top left corner.
+    fn clear(&mut self) {
+        self.cells.fill(Cell::blank(self.bg));
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+    }
+
+    fn fill_cell(&mut self, x: usize, y: usize, color: Color) {
+        self.cells[y * self.n_col + x] = Cell::blank(color);
+    }
+
+    /// Draws the characters that changed since they were last drawn.
+    fn draw(&mut self) {
+        for i in 0..self.cells.len() {
+            let cell = self.cells[i];
+            if self.shown[i] != cell {
+                self.draw_cell(i % self.n_col, i / self.n_col, cell);
+                self.shown[i] = cell;
+            }
+        }
+    }
+
+    fn draw_cell(&mut self, x: usize, y: usize, cell: Cell) {
+        let mut pixels = [0u32; FONT_WIDTH * FONT_HEIGHT];
+        let fg = self.encoder.encode(cell.fg);
+        let bg = self.encoder.encode(cell.bg);
+        font::draw_glyph(self.font.get_glyph(cell.c), fg, bg, &mut pixels, FONT_WIDTH);
+        for (row, row_pixels) in pixels.chunks(FONT_WIDTH).enumerate() {
+            let offset = self.pixel_offset(x * FONT_WIDTH, y * FONT_HEIGHT + row);
+            self.write_pixels(offset, row_pixels.iter().copied());
+        }
+    }
+
+    fn pixel_offset(&self, x: usize, y: usize) -> usize {
+        y * self.pitch + x * self.bytes_per_pixel
+    }
+
+    /// Writes consecutive pixels of a row, starting at byte `offset` of the framebuffer.
+    fn write_pixels(&mut self, offset: usize, pixels: impl Iterator<Item = u32>) {
+        let bytes_per_pixel = self.bytes_per_pixel;
+        let row = &mut self.fb[offset..];
+        for (dest, pixel) in row.chunks_exact_mut(bytes_per_pixel).zip(pixels) {
+            dest.copy_from_slice(&pixel.to_le_bytes()[..bytes_per_pixel]);
+        }
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
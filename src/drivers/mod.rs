@@ -1,4 +1,27 @@
+use log::info;
+
 pub mod acpi;
+pub mod block;
+pub mod framebuffer;
 pub mod net;
 pub mod pci;
 pub mod serial;
+pub mod virtio;
+
+/// Drivers of PCI devices. Each is probed with the devices it matches when it is registered.
+const PCI_DRIVERS: [&pci::Driver; 5] = [
+    &net::rtl8139::DRIVER,
+    &net::e1000::DRIVER,
+    &block::ahci::DRIVER,
+    &block::virtio_blk::DRIVER,
+    &block::nvme::DRIVER,
+];
+
+/// Registers the PCI drivers, which binds them to the devices found by `pci::init()`.
+pub fn init() {
+    let bound: usize = PCI_DRIVERS
+        .iter()
+        .map(|driver| pci::register_driver(driver))
+        .sum();
+    info!("{} PCI devices bound to drivers", bound);
+}
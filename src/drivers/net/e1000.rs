@@ -0,0 +1,750 @@
+use crate::arch::x86_64::interrupt::{self, register_handler, HandlerId};
+use crate::arch::x86_64::mm;
+use crate::drivers::net::{Counters, LinkStatus, NetworkDevice, Statistics};
+use crate::drivers::pci;
+use crate::drivers::pci::{BarNumber, DeviceId, MessageInterrupts, PCIDevice};
+use crate::kernel::sched;
+use crate::locking::semaphore::Semaphore;
+use crate::locking::spinlock::WithSpinLock;
+use crate::net::ethernet::raw::VLANTag;
+use crate::net::ethernet::{EtherType, FrameBuilder, MACAddress};
+use crate::net::{Interface, NETWORK_STACK};
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::{AcqRel, Acquire, Release};
+use log::{error, info, warn};
+use util::volatile::Volatile;
+
+pub static NICS: WithSpinLock<BTreeMap<pci::BDF, Arc<E1000>>> = WithSpinLock::new(BTreeMap::new());
+
+/// Vendor ID of Intel
+const INTEL_VENDOR_ID: u16 = 0x8086;
+
+/// Device IDs of supported controllers. These are taken from the datasheets.
+const SUPPORTED_DEVICES: [(u16, Variant); 3] = [
+    // 82540EM, which QEMU emulates as `e1000`.
+    (0x100e, Variant::E1000),
+    // 82545EM
+    (0x100f, Variant::E1000),
+    // 82574L, which QEMU emulates as `e1000e`.
+    (0x10d3, Variant::E1000E),
+];
+
+// Registers
+// CTRL (Device Control): 0x0000
+const REG_CTRL: usize = 0x0000;
+
+// STATUS (Device Status): 0x0008
+const REG_STATUS: usize = 0x0008;
+
+// EERD (EEPROM Read): 0x0014
+const REG_EERD: usize = 0x0014;
+
+// ICR (Interrupt Cause Read): 0x00c0
+const REG_ICR: usize = 0x00c0;
+
+// IMS (Interrupt Mask Set/Read): 0x00d0
+const REG_IMS: usize = 0x00d0;
+
+// IMC (Interrupt Mask Clear): 0x00d8
+const REG_IMC: usize = 0x00d8;
+
+// RCTL (Receive Control): 0x0100
+const REG_RCTL: usize = 0x0100;
+
+// TCTL (Transmit Control): 0x0400
+const REG_TCTL: usize = 0x0400;
+
+// TIPG (Transmit Inter Packet Gap): 0x0410
+const REG_TIPG: usize = 0x0410;
+
+// RDBAL/RDBAH (Receive Descriptor Base Address Low/High): 0x2800-0x2807
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+
+// RDLEN (Receive Descriptor Length): 0x2808
+const REG_RDLEN: usize = 0x2808;
+
+// RDH/RDT (Receive Descriptor Head/Tail): 0x2810, 0x2818
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+
+// TDBAL/TDBAH (Transmit Descriptor Base Address Low/High): 0x3800-0x3807
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+
+// TDLEN (Transmit Descriptor Length): 0x3808
+const REG_TDLEN: usize = 0x3808;
+
+// TDH/TDT (Transmit Descriptor Head/Tail): 0x3810, 0x3818
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+
+// MPC (Missed Packets Count): 0x4010
+const REG_MPC: usize = 0x4010;
+
+// MTA (Multicast Table Array): 0x5200-0x53fc
+const REG_MTA: usize = 0x5200;
+
+// RAL0/RAH0 (Receive Address Low/High): 0x5400-0x5407
+const REG_RAL0: usize = 0x5400;
+const REG_RAH0: usize = 0x5404;
+
+// CTRL bits
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+// STATUS bits
+const STATUS_FD: u32 = 1 << 0;
+const STATUS_LU: u32 = 1 << 1;
+
+// Interrupt cause bits, shared by ICR, IMS and IMC.
+const INT_TXDW: u32 = 1 << 0;
+const INT_LSC: u32 = 1 << 2;
+const INT_RXDMT0: u32 = 1 << 4;
+const INT_RXO: u32 = 1 << 6;
+const INT_RXT0: u32 = 1 << 7;
+
+// RCTL bits
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_UPE: u32 = 1 << 3;
+const RCTL_MPE: u32 = 1 << 4;
+const RCTL_BAM: u32 = 1 << 15;
+
+// TCTL bits
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+// Descriptor bits
+const RX_STATUS_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+const TX_STATUS_DD: u8 = 1 << 0;
+const TX_STATUS_EC: u8 = 1 << 1;
+const TX_STATUS_LC: u8 = 1 << 2;
+
+// Size of the register space in BAR0. This is 128KiB on all supported controllers.
+const MMIO_SIZE: usize = 0x20000;
+
+// Number of descriptors in each ring. The ring size in bytes must be a multiple of 128.
+const RX_RING_LEN: usize = 32;
+const TX_RING_LEN: usize = 16;
+
+// Size of each packet buffer. This is the default buffer size selected by RCTL.BSIZE = 0b00.
+const PACKET_BUF_SIZE: usize = 2048;
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "e1000",
+    ids: &[
+        DeviceId::device(INTEL_VENDOR_ID, SUPPORTED_DEVICES[0].0),
+        DeviceId::device(INTEL_VENDOR_ID, SUPPORTED_DEVICES[1].0),
+        DeviceId::device(INTEL_VENDOR_ID, SUPPORTED_DEVICES[2].0),
+    ],
+    probe,
+    remove,
+};
+
+// Handlers registered for the vectors of the NICs. NICs on a shared line share the handler.
+static HANDLERS: WithSpinLock<BTreeMap<u8, HandlerId>> = WithSpinLock::new(BTreeMap::new());
+
+// Whether `e1000_bottom_half` is running. It stops when the last NIC is removed.
+static BOTTOM_HALF_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Initializes an e1000 or e1000e NIC.
+fn probe(pci_dev: &PCIDevice, _id: &DeviceId) -> Result<(), &'static str> {
+    let variant = SUPPORTED_DEVICES
+        .iter()
+        .find(|(device_id, _)| *device_id == pci_dev.device_id())
+        .map(|(_, variant)| *variant)
+        .ok_or("unsupported device")?;
+
+    // Use MSI where the controller has it. Otherwise, route the PCI interrupt line to a vector
+    // through the I/O APIC.
+    let msi = pci_dev.enable_message_interrupts(1).ok();
+    let vector = match &msi {
+        Some(msi) => Some(msi.vectors()[0]),
+        None => interrupt::route_gsi(pci_dev.gsi()),
+    };
+    let Some(vector) = vector else {
+        return Err("no free interrupt vector");
+    };
+    let e1000 = match E1000::init(pci_dev.clone(), variant, vector) {
+        Ok(e1000) => e1000,
+        Err(e) => {
+            // Undo the probe the way `remove()` does.
+            pci_dev.write_control_register(0x0000);
+            if let Some(msi) = msi {
+                msi.disable();
+            }
+            return Err(e);
+        }
+    };
+    *e1000.msi.lock() = msi;
+
+    let mut nics = NICS.lock();
+    HANDLERS
+        .lock()
+        .entry(vector)
+        .or_insert_with(|| register_handler(vector, e1000_handler));
+    e1000.enable_interrupts();
+
+    let bdf = e1000.pci.bdf;
+    nics.insert(bdf, e1000.clone());
+    NETWORK_STACK
+        .lock()
+        .insert(bdf, Arc::new(Interface::new(bdf, e1000)));
+    if !BOTTOM_HALF_RUNNING.swap(true, AcqRel) {
+        sched::lock().new_task(e1000_bottom_half);
+    }
+    info!("E1000 NIC initialized");
+    Ok(())
+}
+
+/// Stops an e1000 or e1000e NIC and removes it from the network stack.
+fn remove(pci_dev: &PCIDevice) {
+    let Some(e1000) = NICS.lock().get(&pci_dev.bdf).cloned() else {
+        return;
+    };
+
+    unsafe {
+        // Mask all interrupts, and reset the device to stop it from accessing memory.
+        e1000.write_reg(REG_IMC, 0xffff_ffff);
+        e1000.write_reg(REG_CTRL, e1000.read_reg(REG_CTRL) | CTRL_RST);
+        while e1000.read_reg(REG_CTRL) & CTRL_RST != 0 {
+            spin_loop();
+        }
+        e1000.write_reg(REG_IMC, 0xffff_ffff);
+    }
+    e1000.pci.write_control_register(0x0000);
+    NICS.lock().remove(&pci_dev.bdf);
+    NETWORK_STACK.lock().remove(&pci_dev.bdf);
+
+    let vector_in_use = NICS.lock().values().any(|n| n.vector == e1000.vector);
+    if !vector_in_use {
+        if let Some(handler) = HANDLERS.lock().remove(&e1000.vector) {
+            interrupt::unregister_handler(handler);
+        }
+    }
+    if let Some(msi) = e1000.msi.lock().take() {
+        msi.disable();
+    }
+
+    // Wake the bottom half in case it is waiting for this NIC.
+    e1000.pending_irqs.try_signal();
+}
+
+/// Controller generations that need to be told apart by the driver.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Variant {
+    /// 8254x controllers.
+    E1000,
+
+    /// 82574 and later PCIe controllers.
+    E1000E,
+}
+
+/// Legacy receive descriptor.
+#[repr(C)]
+struct RxDesc {
+    addr: Volatile<u64>,
+    length: Volatile<u16>,
+    checksum: Volatile<u16>,
+    status: Volatile<u8>,
+    errors: Volatile<u8>,
+    special: Volatile<u16>,
+}
+
+/// Legacy transmit descriptor.
+#[repr(C)]
+struct TxDesc {
+    addr: Volatile<u64>,
+    length: Volatile<u16>,
+    cso: Volatile<u8>,
+    cmd: Volatile<u8>,
+    status: Volatile<u8>,
+    css: Volatile<u8>,
+    special: Volatile<u16>,
+}
+
+/// Page aligned descriptor ring, so that the ring is physically contiguous.
+#[repr(C, align(4096))]
+struct DescRing<D, const N: usize>([D; N]);
+
+/// Packet buffer, aligned to its size so that it never crosses a page boundary.
+#[repr(C, align(2048))]
+struct PacketBuf {
+    buf: [u8; PACKET_BUF_SIZE],
+}
+
+struct RxRing {
+    descs: Box<DescRing<RxDesc, RX_RING_LEN>>,
+    bufs: Vec<Box<PacketBuf>>,
+
+    // Next descriptor to be checked for a received frame.
+    next: usize,
+}
+
+struct TxRing {
+    descs: Box<DescRing<TxDesc, TX_RING_LEN>>,
+    bufs: Vec<Box<PacketBuf>>,
+
+    // Next descriptor to be handed to the NIC.
+    tail: usize,
+
+    // Oldest descriptor that has been handed to the NIC and not yet reclaimed.
+    clean: usize,
+}
+
+pub struct E1000 {
+    pub(crate) pci: PCIDevice,
+
+    // The interrupt vector
+    pub(crate) vector: u8,
+
+    // MSI or MSI-X, if the vector is not routed through the I/O APIC. Taken when the NIC is
+    // removed.
+    msi: WithSpinLock<Option<MessageInterrupts>>,
+
+    variant: Variant,
+
+    // Virtual address of the register space mapped from BAR0.
+    mmio_base: usize,
+
+    rx_ring: WithSpinLock<RxRing>,
+    tx_ring: WithSpinLock<TxRing>,
+
+    // Number of transmit descriptors that are free to use.
+    tx_slots: Semaphore,
+
+    // Semaphore for the pending irq count.
+    pending_irqs: Semaphore,
+
+    link_up: AtomicBool,
+
+    // Traffic counters
+    stats: Counters,
+}
+
+impl E1000 {
+    fn init(pci: PCIDevice, variant: Variant, vector: u8) -> Result<Arc<E1000>, &'static str> {
+        let phys_base = {
+            let bar_0 = pci.read_bar_register(BarNumber::BAR0);
+            if bar_0 & 0x1 != 0 {
+                return Err("BAR 0 is not a memory space BAR");
+            }
+            let mut base = (bar_0 & !0xf) as usize;
+            // 64-bit BARs keep the upper half of the address in the next BAR.
+            if (bar_0 >> 1) & 0b11 == 0b10 {
+                base |= (pci.read_bar_register(BarNumber::BAR1) as usize) << 32;
+            }
+            base
+        };
+        {
+            let mut mapper = mm::mapper();
+            let mapper = mapper.as_mut().unwrap();
+            for offset in (0..MMIO_SIZE).step_by(0x1000) {
+                mapper.map_mmio(phys_base + offset);
+            }
+        }
+
+        let rx_ring = RxRing {
+            // SAFETY: All-zero bytes are valid descriptors, and zeroing the memory makes sure that
+            //         it is mapped before we ask for its physical address.
+            descs: unsafe { Box::new_zeroed().assume_init() },
+            bufs: (0..RX_RING_LEN)
+                .map(|_| unsafe { Box::<PacketBuf>::new_zeroed().assume_init() })
+                .collect(),
+            next: 0,
+        };
+        let tx_ring = TxRing {
+            // SAFETY: Same as above.
+            descs: unsafe { Box::new_zeroed().assume_init() },
+            bufs: (0..TX_RING_LEN)
+                .map(|_| unsafe { Box::<PacketBuf>::new_zeroed().assume_init() })
+                .collect(),
+            tail: 0,
+            clean: 0,
+        };
+
+        let e1000 = Arc::new(E1000 {
+            pci,
+            vector,
+            // Set by `probe()` once the NIC is initialized.
+            msi: WithSpinLock::new(None),
+            variant,
+            mmio_base: phys_base + mm::MMIO_BASE,
+            rx_ring: WithSpinLock::new(rx_ring),
+            tx_ring: WithSpinLock::new(tx_ring),
+            // One descriptor is always left unused so that a full ring can be told apart from an
+            // empty one.
+            tx_slots: Semaphore::new(TX_RING_LEN - 1, TX_RING_LEN - 1),
+            pending_irqs: Semaphore::new(0, 128),
+            link_up: AtomicBool::new(false),
+            stats: Counters::new(),
+        });
+
+        // Enable bus mastering and memory space access. This lets the PCI device to perform DMA.
+        e1000.pci.write_control_register(0x0006);
+
+        unsafe {
+            // Mask all interrupts and reset the device.
+            e1000.write_reg(REG_IMC, 0xffff_ffff);
+            e1000.write_reg(REG_CTRL, e1000.read_reg(REG_CTRL) | CTRL_RST);
+            while e1000.read_reg(REG_CTRL) & CTRL_RST != 0 {
+                spin_loop();
+            }
+
+            // Interrupts are unmasked by the reset, so mask them again and clear pending causes.
+            e1000.write_reg(REG_IMC, 0xffff_ffff);
+            e1000.read_reg(REG_ICR);
+
+            // Set link up. Auto-negotiation takes care of speed and duplex.
+            e1000.write_reg(REG_CTRL, e1000.read_reg(REG_CTRL) | CTRL_SLU);
+
+            // Clear the multicast table.
+            for i in 0..128 {
+                e1000.write_reg(REG_MTA + i * 4, 0);
+            }
+        }
+
+        e1000.init_rx()?;
+        e1000.init_tx()?;
+
+        let link_up = e1000.link_status() == LinkStatus::Up;
+        e1000.link_up.store(link_up, Release);
+
+        Ok(e1000)
+    }
+
+    fn init_rx(&self) -> Result<(), &'static str> {
+        let ring = self.rx_ring.lock();
+        for (desc, buf) in ring.descs.0.iter().zip(ring.bufs.iter()) {
+            let Some(buf_addr) = mm::phys_addr(buf.buf.as_ptr() as usize) else {
+                return Err("unmapped rx buffer");
+            };
+            desc.addr.write(buf_addr as u64);
+            desc.status.write(0);
+        }
+        let Some(ring_addr) = mm::phys_addr(ring.descs.0.as_ptr() as usize) else {
+            return Err("unmapped rx descriptor ring");
+        };
+
+        unsafe {
+            self.write_reg(REG_RDBAL, ring_addr as u32);
+            self.write_reg(REG_RDBAH, (ring_addr >> 32) as u32);
+            self.write_reg(REG_RDLEN, (RX_RING_LEN * size_of::<RxDesc>()) as u32);
+
+            // Hand all but one descriptor to the NIC.
+            self.write_reg(REG_RDH, 0);
+            self.write_reg(REG_RDT, (RX_RING_LEN - 1) as u32);
+
+            // Receive configuration
+            // Accept
+            // - broadcast
+            // - multicast
+            // - unicast to any MAC address
+            // into 2048-byte buffers, leaving the CRC in the received frames like the RTL8139.
+            self.write_reg(REG_RCTL, RCTL_EN | RCTL_UPE | RCTL_MPE | RCTL_BAM);
+        }
+        Ok(())
+    }
+
+    fn init_tx(&self) -> Result<(), &'static str> {
+        let ring = self.tx_ring.lock();
+        for (desc, buf) in ring.descs.0.iter().zip(ring.bufs.iter()) {
+            let Some(buf_addr) = mm::phys_addr(buf.buf.as_ptr() as usize) else {
+                return Err("unmapped tx buffer");
+            };
+            desc.addr.write(buf_addr as u64);
+            desc.cmd.write(0);
+            desc.status.write(0);
+        }
+        let Some(ring_addr) = mm::phys_addr(ring.descs.0.as_ptr() as usize) else {
+            return Err("unmapped tx descriptor ring");
+        };
+
+        unsafe {
+            self.write_reg(REG_TDBAL, ring_addr as u32);
+            self.write_reg(REG_TDBAH, (ring_addr >> 32) as u32);
+            self.write_reg(REG_TDLEN, (TX_RING_LEN * size_of::<TxDesc>()) as u32);
+            self.write_reg(REG_TDH, 0);
+            self.write_reg(REG_TDT, 0);
+
+            // Inter packet gap values recommended by the datasheet for IEEE 802.3 copper.
+            self.write_reg(REG_TIPG, 10 | 8 << 10 | 6 << 20);
+            self.write_reg(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+        }
+        Ok(())
+    }
+
+    fn enable_interrupts(&self) {
+        unsafe {
+            self.write_reg(
+                REG_IMS,
+                INT_RXT0 | INT_RXO | INT_RXDMT0 | INT_LSC | INT_TXDW,
+            );
+        }
+    }
+
+    /// Reads a 32-bit register.
+    // SAFETY: Caller must ensure that `reg` is the offset of a 32-bit register.
+    unsafe fn read_reg(&self, reg: usize) -> u32 {
+        ptr::read_volatile((self.mmio_base + reg) as *const u32)
+    }
+
+    /// Writes a 32-bit register.
+    // SAFETY: Caller must ensure that `reg` is the offset of a 32-bit register.
+    unsafe fn write_reg(&self, reg: usize, value: u32) {
+        ptr::write_volatile((self.mmio_base + reg) as *mut u32, value)
+    }
+
+    /// Reads a 16-bit word from the EEPROM.
+    fn read_eeprom(&self, addr: u8) -> u16 {
+        // The 82574 moved the done bit and the address field of EERD.
+        let (addr_shift, done) = match self.variant {
+            Variant::E1000 => (8, 1 << 4),
+            Variant::E1000E => (2, 1 << 1),
+        };
+        unsafe {
+            self.write_reg(REG_EERD, (addr as u32) << addr_shift | 0x1);
+            loop {
+                let eerd = self.read_reg(REG_EERD);
+                if eerd & done != 0 {
+                    return (eerd >> 16) as u16;
+                }
+                spin_loop();
+            }
+        }
+    }
+
+    pub fn transmit(
+        &self,
+        dest: MACAddress,
+        vlan_tags: [Option<VLANTag>; 2],
+        ethertype: EtherType,
+        writer: impl FnOnce(&mut [u8]) -> usize,
+    ) {
+        let src = self.id();
+
+        // Wait for the NIC to finish sending a frame if the ring is full.
+        self.tx_slots.wait();
+
+        let mut ring = self.tx_ring.lock();
+        let i = ring.tail;
+        let len = {
+            let frame = FrameBuilder::new(ring.bufs[i].buf.as_mut())
+                .dest(dest.into())
+                .src(src.into())
+                .vlan_tags(vlan_tags)
+                .ethertype(ethertype)
+                .payload(writer);
+            frame.len()
+        };
+
+        let desc = &ring.descs.0[i];
+        desc.length.write(len as u16);
+        desc.status.write(0);
+        desc.cmd.write(TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS);
+
+        ring.tail = (i + 1) % TX_RING_LEN;
+        unsafe { self.write_reg(REG_TDT, ring.tail as u32) };
+    }
+
+    pub fn id(&self) -> MACAddress {
+        let (ral, rah) = unsafe { (self.read_reg(REG_RAL0), self.read_reg(REG_RAH0)) };
+        if ral != 0 {
+            let mut mac = [0u8; 6];
+            mac[0..4].copy_from_slice(&ral.to_le_bytes());
+            mac[4..6].copy_from_slice(&rah.to_le_bytes()[0..2]);
+            return mac.into();
+        }
+
+        // The receive address registers have not been loaded, so read the address from the EEPROM.
+        let mut mac = [0u8; 6];
+        for i in 0..3 {
+            let word = self.read_eeprom(i as u8);
+            mac[i * 2..i * 2 + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        mac.into()
+    }
+
+    pub fn link_status(&self) -> LinkStatus {
+        let status = unsafe { self.read_reg(REG_STATUS) };
+        if status & STATUS_LU != 0 {
+            LinkStatus::Up
+        } else {
+            LinkStatus::Down
+        }
+    }
+
+    /// Reports link status changes. Called from the ISR on a link status change interrupt.
+    fn update_link_status(&self) {
+        let status = unsafe { self.read_reg(REG_STATUS) };
+        let link_up = status & STATUS_LU != 0;
+        if self.link_up.swap(link_up, AcqRel) == link_up {
+            return;
+        }
+        if link_up {
+            let speed = match (status >> 6) & 0b11 {
+                0b00 => 10,
+                0b01 => 100,
+                _ => 1000,
+            };
+            let duplex = if status & STATUS_FD != 0 {
+                "full"
+            } else {
+                "half"
+            };
+            info!("link up, {} Mb/s {} duplex", speed, duplex);
+        } else {
+            info!("link down");
+        }
+    }
+
+    /// Reclaims descriptors of frames that the NIC has finished sending.
+    fn reclaim_tx(&self) {
+        let mut ring = self.tx_ring.lock();
+        while ring.clean != ring.tail {
+            let desc = &ring.descs.0[ring.clean];
+            let status = desc.status.read();
+            if status & TX_STATUS_DD == 0 {
+                break;
+            }
+            if status & (TX_STATUS_EC | TX_STATUS_LC) != 0 {
+                // Excess or late collisions. The NIC gave up on the frame.
+                self.stats.tx_error();
+                self.stats.tx_dropped();
+            } else {
+                self.stats.tx_packet(desc.length.read() as usize);
+            }
+            desc.cmd.write(0);
+            ring.clean = (ring.clean + 1) % TX_RING_LEN;
+            self.tx_slots.try_signal();
+        }
+    }
+
+    fn process_receive(&self) {
+        self.pending_irqs.wait();
+
+        // The interface is gone if the NIC was removed.
+        let Some(dev_net) = NETWORK_STACK.lock().get(&self.pci.bdf).cloned() else {
+            return;
+        };
+
+        let mut ring = self.rx_ring.lock();
+        loop {
+            let i = ring.next;
+            let desc = &ring.descs.0[i];
+            let status = desc.status.read();
+            if status & RX_STATUS_DD == 0 {
+                break;
+            }
+
+            let len = desc.length.read() as usize;
+            if status & RX_STATUS_EOP != 0 && desc.errors.read() == 0 {
+                if dev_net.recv_frame(&ring.bufs[i].buf[..len]) {
+                    self.stats.rx_packet(len);
+                } else {
+                    self.stats.rx_dropped(1);
+                }
+            } else {
+                self.stats.rx_error();
+                // Frames spanning multiple descriptors are larger than we accept.
+                warn!(
+                    "dropped frame, status: {:x}, errors: {:x}",
+                    status,
+                    desc.errors.read(),
+                );
+            }
+
+            // Hand the descriptor back to the NIC.
+            desc.status.write(0);
+            unsafe { self.write_reg(REG_RDT, i as u32) };
+            ring.next = (i + 1) % RX_RING_LEN;
+        }
+    }
+}
+
+impl NetworkDevice for E1000 {
+    fn id(&self) -> MACAddress {
+        E1000::id(self)
+    }
+
+    fn transmit(
+        &self,
+        dest: MACAddress,
+        vlan_tags: [Option<VLANTag>; 2],
+        ethertype: EtherType,
+        writer: Box<dyn FnOnce(&mut [u8]) -> usize + '_>,
+    ) {
+        E1000::transmit(self, dest, vlan_tags, ethertype, writer)
+    }
+
+    fn link_status(&self) -> LinkStatus {
+        E1000::link_status(self)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.stats.snapshot()
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn e1000_handler(vector: u64) {
+    let nics = {
+        // SAFETY: We are getting an exclusive lock in ISR context, which has interrupts disabled.
+        //         We are also not calling any blocking functions, so there is no risk of a deadlock.
+        let nics = NICS.lock();
+        nics.iter()
+            .filter(|(_, n)| n.vector == vector as u8)
+            .map(|(_, n)| n.clone())
+            .collect::<Vec<Arc<E1000>>>()
+    };
+    for n in nics.iter() {
+        // Reading ICR clears the interrupt causes.
+        let cause = unsafe { n.read_reg(REG_ICR) };
+        if cause & (INT_RXT0 | INT_RXO | INT_RXDMT0) != 0 {
+            n.pending_irqs.try_signal();
+        }
+        if cause & INT_RXO != 0 {
+            // Frames were missed because the RX ring was full. MPC is cleared on read.
+            let missed = unsafe { n.read_reg(REG_MPC) };
+            n.stats.rx_dropped(missed as u64);
+        }
+        if cause & INT_TXDW != 0 {
+            n.reclaim_tx();
+        }
+        if cause & INT_LSC != 0 {
+            n.update_link_status();
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub fn e1000_bottom_half() {
+    loop {
+        let nics = {
+            let nics = NICS.lock();
+            if nics.is_empty() {
+                // The next NIC to be probed starts the bottom half again.
+                BOTTOM_HALF_RUNNING.store(false, Release);
+                break;
+            }
+            nics.values().cloned().collect::<Vec<Arc<E1000>>>()
+        };
+        for nic in nics.iter() {
+            nic.process_receive();
+        }
+    }
+    sched::lock().exit();
+}
@@ -1 +1,147 @@
+use crate::net::ethernet::raw::VLANTag;
+use crate::net::ethernet::{EtherType, MACAddress};
+
+use alloc::boxed::Box;
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::Relaxed;
+
+pub mod e1000;
 pub mod rtl8139;
+
+/// Link state of a NIC's physical layer.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum LinkStatus {
+    Up,
+    Down,
+}
+
+/// Interface between NIC drivers and the network stack.
+///
+/// Each driver registers an `Arc<dyn NetworkDevice>` per NIC with the network stack, which uses it
+/// to transmit frames without knowing which driver is behind it.
+pub trait NetworkDevice: Send + Sync {
+    /// Returns the MAC address of the NIC.
+    fn id(&self) -> MACAddress;
+
+    /// Builds an Ethernet frame in one of the NIC's transmit buffers and queues it for sending.
+    /// `writer` writes the payload into the buffer it is given, and returns the payload length.
+    fn transmit(
+        &self,
+        dest: MACAddress,
+        vlan_tags: [Option<VLANTag>; 2],
+        ethertype: EtherType,
+        writer: Box<dyn FnOnce(&mut [u8]) -> usize + '_>,
+    );
+
+    /// Returns the current link status of the NIC.
+    fn link_status(&self) -> LinkStatus;
+
+    /// Returns a snapshot of the NIC's traffic counters.
+    fn statistics(&self) -> Statistics;
+}
+
+/// Snapshot of the traffic counters of a NIC.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct Statistics {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+    pub tx_dropped: u64,
+}
+
+impl Display for Statistics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "RX packets: {} bytes: {} errors: {} dropped: {}, \
+             TX packets: {} bytes: {} errors: {} dropped: {}",
+            self.rx_packets,
+            self.rx_bytes,
+            self.rx_errors,
+            self.rx_dropped,
+            self.tx_packets,
+            self.tx_bytes,
+            self.tx_errors,
+            self.tx_dropped,
+        )
+    }
+}
+
+/// Traffic counters of a NIC. These are updated by drivers from both ISRs and bottom halves, so
+/// they are kept in atomics rather than behind a lock.
+#[derive(Default)]
+pub struct Counters {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    rx_errors: AtomicU64,
+    rx_dropped: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_errors: AtomicU64,
+    tx_dropped: AtomicU64,
+}
+
+impl Counters {
+    pub const fn new() -> Self {
+        Counters {
+            rx_packets: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            rx_errors: AtomicU64::new(0),
+            rx_dropped: AtomicU64::new(0),
+            tx_packets: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            tx_errors: AtomicU64::new(0),
+            tx_dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a frame of `len` bytes that was handed to the network stack.
+    pub fn rx_packet(&self, len: usize) {
+        self.rx_packets.fetch_add(1, Relaxed);
+        self.rx_bytes.fetch_add(len as u64, Relaxed);
+    }
+
+    /// Counts a frame that was received with an error.
+    pub fn rx_error(&self) {
+        self.rx_errors.fetch_add(1, Relaxed);
+    }
+
+    /// Counts `n` frames that were received but could not be handed to the network stack.
+    pub fn rx_dropped(&self, n: u64) {
+        self.rx_dropped.fetch_add(n, Relaxed);
+    }
+
+    /// Counts a frame of `len` bytes that the NIC finished sending.
+    pub fn tx_packet(&self, len: usize) {
+        self.tx_packets.fetch_add(1, Relaxed);
+        self.tx_bytes.fetch_add(len as u64, Relaxed);
+    }
+
+    /// Counts a frame that the NIC reported a transmit error for.
+    pub fn tx_error(&self) {
+        self.tx_errors.fetch_add(1, Relaxed);
+    }
+
+    /// Counts a frame that was given up on without being sent.
+    pub fn tx_dropped(&self) {
+        self.tx_dropped.fetch_add(1, Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Statistics {
+        Statistics {
+            rx_packets: self.rx_packets.load(Relaxed),
+            rx_bytes: self.rx_bytes.load(Relaxed),
+            rx_errors: self.rx_errors.load(Relaxed),
+            rx_dropped: self.rx_dropped.load(Relaxed),
+            tx_packets: self.tx_packets.load(Relaxed),
+            tx_bytes: self.tx_bytes.load(Relaxed),
+            tx_errors: self.tx_errors.load(Relaxed),
+            tx_dropped: self.tx_dropped.load(Relaxed),
+        }
+    }
+}
@@ -1,7 +1,8 @@
-use crate::arch::x86_64::interrupt::{register_handler, IOAPIC, LOCAL_APIC};
+use crate::arch::x86_64::interrupt::{self, register_handler, HandlerId};
 use crate::arch::x86_64::{mm, port};
-use crate::drivers::pci::{BarNumber, PCIDevice};
-use crate::drivers::{acpi, pci};
+use crate::drivers::net::{Counters, LinkStatus, NetworkDevice, Statistics};
+use crate::drivers::pci;
+use crate::drivers::pci::{BarNumber, DeviceId, PCIDevice};
 use crate::kernel::sched;
 use crate::locking::semaphore::Semaphore;
 use crate::locking::spinlock::WithSpinLock;
@@ -16,13 +17,10 @@ use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::cell::RefCell;
-use core::fmt::Write;
 use core::hint::spin_loop;
-use core::sync::atomic::AtomicU8;
-use core::sync::atomic::Ordering::{AcqRel, Acquire};
-
-// For debugging
-use crate::drivers::serial;
+use core::sync::atomic::Ordering::{AcqRel, Acquire, Release};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8};
+use log::{error, info, trace, warn};
 
 pub static NICS: WithSpinLock<BTreeMap<pci::BDF, Arc<RTL8139>>> =
     WithSpinLock::new(BTreeMap::new());
@@ -67,69 +65,145 @@ const REG_RCR: u16 = 0x44;
 // CONFIG1: 0x52
 const REG_CONFIG_1: u16 = 0x52;
 
+// MPC (Missed Packet Counter): 0x4c-0x4f
+const REG_MPC: u16 = 0x4c;
+
+// MSR (Media Status Register): 0x58
+const REG_MSR: u16 = 0x58;
+
 // TSAD: 0x60-0x61
 const REG_TSAD: u16 = 0x60;
 
+// TSD bits
+// SIZE: bits 0-12
+const TSD_SIZE_MASK: u32 = 0x1fff;
+const TSD_TUN: u32 = 1 << 14;
+const TSD_TOK: u32 = 1 << 15;
+// ERTXTH (Early Tx Threshold): bits 16-21, in units of 32 bytes
+const TSD_ERTXTH_SHIFT: u32 = 16;
+const TSD_ERTXTH_MAX: u32 = 0x3f;
+const TSD_TABT: u32 = 1 << 30;
+
+// ISR and IMR bits
+const INT_ROK: u16 = 1 << 0;
+const INT_RER: u16 = 1 << 1;
+const INT_TOK: u16 = 1 << 2;
+const INT_TER: u16 = 1 << 3;
+const INT_RXOVW: u16 = 1 << 4;
+const INT_FOVW: u16 = 1 << 6;
+
+// CR bits
+const CR_BUFE: u8 = 1 << 0;
+const CR_TE: u8 = 1 << 2;
+const CR_RE: u8 = 1 << 3;
+
+// RSR (Receive Status Register) bits, found in the header of each received frame
+const RSR_ROK: u16 = 1 << 0;
+const RSR_FAE: u16 = 1 << 1;
+const RSR_CRC: u16 = 1 << 2;
+const RSR_LONG: u16 = 1 << 3;
+const RSR_RUNT: u16 = 1 << 4;
+const RSR_ISE: u16 = 1 << 5;
+
+// Frame length written to the header of a frame that is still being received.
+const RX_FRAME_SIZE_EARLY: u16 = 0xfff0;
+
+// Bounds of the size of a received frame, including the CRC. The upper bound allows for two
+// VLAN tags.
+const MIN_FRAME_SIZE: usize = 64;
+const MAX_FRAME_SIZE: usize = 1526;
+
+const NUM_TX_DESCS: usize = 4;
+
 const RX_BUF_SIZE: usize = 8192;
 
 // Size of Rx buffer(s).
-// The size is 8192 + 16 + 1500 bytes to a) write a `0` to the Rx Buffer Length field in the RCR and b) to allow for
-// WRAP mode operation.
-const RX_BUF_SIZE_WITH_WRAP: usize = RX_BUF_SIZE + 16 + 1500;
+// The size is 8192 + 16 bytes to write a `0` to the Rx Buffer Length field in the RCR. WRAP mode is
+// not used, so frames that reach the end of the ring continue at its start.
+const RX_BUF_SIZE_WITH_PAD: usize = RX_BUF_SIZE + 16;
+
+// Receive configuration
+// Accept
+// - broadcast
+// - multicast
+// - unicast to device MAC address
+// - unicast to any MAC address
+// In other words, any valid packet.
+const RCR_ACCEPT_ALL: u32 = 0b1111;
 
 const TX_BUF_SIZE: usize = 1530;
 
-/// Initializes all RTL8139s on the PCI bus.
-pub fn init<'a>(interrupt_mappings: &Vec<acpi::InterruptMapping>) -> usize {
-    let mut pci = pci::Handle::new();
-    let devices = pci.get_device(RTL8139_VENDOR_ID, RTL8139_DEVICE_ID);
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "rtl8139",
+    ids: &[DeviceId::device(RTL8139_VENDOR_ID, RTL8139_DEVICE_ID)],
+    probe,
+    remove,
+};
+
+// Handlers registered for the vectors of the NICs. NICs on a shared line share the handler.
+static HANDLERS: WithSpinLock<BTreeMap<u8, HandlerId>> = WithSpinLock::new(BTreeMap::new());
+
+// Whether `rtl8139_bottom_half` is running. It stops when the last NIC is removed.
+static BOTTOM_HALF_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Initializes an RTL8139.
+fn probe(pci_dev: &PCIDevice, _id: &DeviceId) -> Result<(), &'static str> {
+    // Route the PCI interrupt line to a vector through the I/O APIC.
+    let vector = interrupt::route_gsi(pci_dev.gsi()).ok_or("no free interrupt vector")?;
+    let rtl8139 = RTL8139::init(pci_dev.clone(), vector).map_err(|_| "failed to initialize NIC")?;
 
     // SAFETY: we have an exclusive lock on the static mut NICS.
     let mut nics = unsafe { NICS.lock() };
+    HANDLERS
+        .lock()
+        .entry(vector)
+        .or_insert_with(|| register_handler(vector, rtl8139_handler));
+    let bdf = rtl8139.pci.bdf;
+    nics.insert(bdf, rtl8139.clone());
+    NETWORK_STACK
+        .lock()
+        .insert(bdf, Arc::new(Interface::new(bdf, rtl8139)));
+    if !BOTTOM_HALF_RUNNING.swap(true, AcqRel) {
+        sched::lock().new_task(rtl8139_bottom_half);
+    }
+    info!("RTL8139 NIC initialized");
+    Ok(())
+}
 
-    for pci_dev in devices {
-        if let Ok(rtl8139) = RTL8139::init(pci_dev) {
-            {
-                let mapping = interrupt_mappings
-                    .iter()
-                    .filter(|x| x.irq_number == rtl8139.pci.interrupt_line)
-                    .next();
-                if let Some(mapping) = mapping {
-                    // Register the interrupt handler.
-                    register_handler(rtl8139.vector, rtl8139_handler);
-
-                    // SAFETY: Not really safe yet.
-                    unsafe {
-                        let lapic_id = LOCAL_APIC.lock().id();
-                        let ioapic = IOAPIC.lock();
-
-                        // Set up IDT vector with the I/O APIC. Map global_system_interrupt to any open IDT vector.
-                        // We know that 0x26 is empty.
-                        ioapic.remap(
-                            0,
-                            mapping.global_system_interrupt as u32,
-                            rtl8139.vector as u32,
-                        );
-                    }
-                }
-            }
-            let bdf = rtl8139.pci.bdf;
-            nics.insert(bdf, rtl8139);
-            NETWORK_STACK
-                .lock()
-                .insert(bdf, Arc::new(Interface::new(bdf)));
+/// Stops an RTL8139 and removes it from the network stack.
+fn remove(pci_dev: &PCIDevice) {
+    let Some(rtl8139) = NICS.lock().get(&pci_dev.bdf).cloned() else {
+        return;
+    };
+
+    // Mask all interrupts, and reset the device to stop it from accessing memory.
+    unsafe {
+        rtl8139.outw(REG_IMR, 0);
+        rtl8139.outb(REG_COMMAND, 0x10);
+        while rtl8139.inb(REG_COMMAND) & 0x10 != 0 {
+            spin_loop();
+        }
+    }
+    rtl8139.pci.write_control_register(0x0000);
+    NICS.lock().remove(&pci_dev.bdf);
+    NETWORK_STACK.lock().remove(&pci_dev.bdf);
+
+    let vector_in_use = NICS.lock().values().any(|n| n.vector == rtl8139.vector);
+    if !vector_in_use {
+        if let Some(handler) = HANDLERS.lock().remove(&rtl8139.vector) {
+            interrupt::unregister_handler(handler);
         }
     }
-    let mut s = sched::lock();
-    s.new_task(rtl8139_bottom_half);
-    nics.len()
+
+    // Wake the bottom half in case it is waiting for this NIC.
+    rtl8139.pending_irqs.try_signal();
 }
 
 /// Double-word aligned byte array type for use as the receive buffer.
 #[repr(C, align(4))]
 #[derive(Clone)]
 struct RxBuf {
-    buf: [u8; RX_BUF_SIZE_WITH_WRAP],
+    buf: [u8; RX_BUF_SIZE_WITH_PAD],
 }
 
 #[repr(C, align(4))]
@@ -148,17 +222,37 @@ pub struct RTL8139 {
     rx_buf: WithSpinLock<Box<RxBuf>>,
 
     // The TX buffers
-    tx_bufs: [WithSpinLock<Box<TxBuf>>; 4],
+    tx_bufs: [WithSpinLock<Box<TxBuf>>; NUM_TX_DESCS],
 
     // Next TX buffer to use
     next_tx_desc: AtomicU8,
 
+    // Oldest TX buffer that has been handed to the NIC and not yet reclaimed. Only touched by the
+    // ISR.
+    dirty_tx_desc: AtomicU8,
+
+    // Length of the frame in each TX buffer that is owned by the NIC, or 0 if the buffer is free.
+    tx_lens: [AtomicU16; NUM_TX_DESCS],
+
+    // Number of TX buffers that are free to use. Signaled from the ISR when a transmission ends.
+    tx_free: Semaphore,
+
+    // Early TX threshold, as the ERTXTH field of TSD. Raised after each TX FIFO underrun.
+    tx_threshold: AtomicU32,
+
+    // Traffic counters
+    stats: Counters,
+
+    // Set by the ISR when the RX buffer or FIFO overflowed, so that the bottom half resets the
+    // receiver.
+    rx_overflow: AtomicBool,
+
     // Semaphore for the pending irq count.
     pending_irqs: Semaphore,
 }
 
 impl RTL8139 {
-    fn init<'a>(mut pci: pci::PCIDevice) -> Result<Arc<RTL8139>, ()> {
+    fn init<'a>(mut pci: pci::PCIDevice, vector: u8) -> Result<Arc<RTL8139>, ()> {
         let mut rx_buf = Box::<RxBuf>::new_uninit();
 
         // SAFETY: This is a buffer that will be written to later by the device, so we don't care
@@ -197,7 +291,13 @@ impl RTL8139 {
                 WithSpinLock::new(tx_buf_3),
             ],
             next_tx_desc: AtomicU8::new(0),
-            vector: 0x26, // We magically know that this vector is empty, for now.
+            dirty_tx_desc: AtomicU8::new(0),
+            tx_lens: [const { AtomicU16::new(0) }; NUM_TX_DESCS],
+            tx_free: Semaphore::new(NUM_TX_DESCS, NUM_TX_DESCS),
+            tx_threshold: AtomicU32::new(0),
+            stats: Counters::new(),
+            rx_overflow: AtomicBool::new(false),
+            vector,
             pending_irqs: Semaphore::new(0, 128),
         };
         let rtl8139 = Arc::new(rtl8139);
@@ -227,7 +327,7 @@ impl RTL8139 {
         }
 
         // Initialize tx buffers
-        for i in 0..4 {
+        for i in 0..NUM_TX_DESCS {
             let tx_buf_virt_addr = rtl8139.tx_bufs[i].lock().buf.as_ptr() as usize;
             let tx_buf_addr = mm::phys_addr(tx_buf_virt_addr);
             match tx_buf_addr {
@@ -238,32 +338,24 @@ impl RTL8139 {
             }
         }
 
-        // Receive configuration
-        // Accept
-        // - broadcast
-        // - multicast
-        // - unicast to device MAC address
-        // - unicast to any MAC address
-        // In other words, any valid packet.
-        let accept_config: u32 = 0b1111;
-
-        // Configure WRAP behavior so that packets overflowing the rx ring buffer would be written
-        // to the end in space following the buffer.
-        let wrap: u32 = 0b1 << 7;
         unsafe {
-            rtl8139.outl(REG_RCR, accept_config | wrap);
+            rtl8139.outl(REG_RCR, RCR_ACCEPT_ALL);
         }
 
         // Enable transmitter and receiver.
         unsafe {
-            rtl8139.outb(REG_COMMAND, 0x0c);
+            rtl8139.outb(REG_COMMAND, CR_TE | CR_RE);
         }
 
         // Set up interrupts.
-        // 0x0005 sets the ROK and TOK bits, which means we get interrupts when successfully
-        //  sending or receiving packets.
+        // We get interrupts when receiving packets, successfully or not, when a transmission
+        // completes either successfully (TOK) or with an abort (TER), and on RX buffer and FIFO
+        // overflows.
         unsafe {
-            rtl8139.outw(REG_IMR, 0x0005 | 1 << 4);
+            rtl8139.outw(
+                REG_IMR,
+                INT_ROK | INT_RER | INT_TOK | INT_TER | INT_RXOVW | INT_FOVW,
+            );
         }
 
         Ok(rtl8139)
@@ -304,6 +396,10 @@ impl RTL8139 {
         port::inw(self.ioaddr(offset))
     }
 
+    unsafe fn inl(&self, offset: u16) -> u32 {
+        port::inl(self.ioaddr(offset))
+    }
+
     unsafe fn outl(&self, offset: u16, data: u32) {
         port::outl(self.ioaddr(offset), data)
     }
@@ -316,7 +412,13 @@ impl RTL8139 {
         writer: impl FnOnce(&mut [u8]) -> usize,
     ) {
         let src = self.id();
-        let tx_i = self.next_tx_desc.update(AcqRel, Acquire, |n| (n + 1) % 4) as usize;
+
+        // Wait for the NIC to finish sending from a TX buffer if all of them are in use.
+        // The NIC uses the buffers in order, so the next buffer is always the one that was freed.
+        self.tx_free.wait();
+        let tx_i = self
+            .next_tx_desc
+            .update(AcqRel, Acquire, |n| (n + 1) % NUM_TX_DESCS as u8) as usize;
         let mut tx_buf = self.tx_bufs[tx_i].lock();
         let frame = FrameBuilder::new(tx_buf.buf.as_mut())
             .dest(dest.into())
@@ -330,8 +432,59 @@ impl RTL8139 {
                 panic!("Tx buffer must be mapped");
             };
             self.outl(REG_TSAD0 + (tx_i * 4) as u16, phys_addr as u32);
-            self.outl(REG_TSD0 + (tx_i * 4) as u16, frame.len() as u32 & 0x1fff)
+            let threshold = self.tx_threshold.load(Acquire) << TSD_ERTXTH_SHIFT;
+            self.outl(
+                REG_TSD0 + (tx_i * 4) as u16,
+                threshold | (frame.len() as u32 & TSD_SIZE_MASK),
+            )
         };
+
+        // Interrupts are disabled while we hold the lock on the TX buffer, so the ISR cannot see
+        // the buffer as owned by the NIC before TSD has been written.
+        self.tx_lens[tx_i].store(frame.len() as u16, Release);
+    }
+
+    /// Reclaims TX buffers of frames that the NIC has finished sending, and accounts for them.
+    /// Called from the ISR on TOK and TER interrupts.
+    fn complete_transmit(&self) {
+        loop {
+            let tx_i = self.dirty_tx_desc.load(Acquire) as usize;
+            let len = self.tx_lens[tx_i].load(Acquire) as usize;
+            if len == 0 {
+                break;
+            }
+            let tsd = unsafe { self.inl(REG_TSD0 + (tx_i * 4) as u16) };
+            if tsd & (TSD_TOK | TSD_TUN | TSD_TABT) == 0 {
+                // Still being sent.
+                break;
+            }
+
+            if tsd & TSD_TABT != 0 {
+                // The NIC gave up on the frame, e.g. after excessive collisions. We do not retry it.
+                self.stats.tx_error();
+                self.stats.tx_dropped();
+                warn!("transmit aborted, TSD: {:x}", tsd);
+            } else {
+                if tsd & TSD_TUN != 0 {
+                    // The TX FIFO ran dry while sending. Wait for more of the frame to be in the
+                    // FIFO before starting to send the next ones.
+                    self.stats.tx_error();
+                    let threshold = self
+                        .tx_threshold
+                        .update(AcqRel, Acquire, |t| (t + 1).min(TSD_ERTXTH_MAX));
+                    warn!(
+                        "transmit FIFO underrun, raising early TX threshold to {} bytes",
+                        (threshold + 1).min(TSD_ERTXTH_MAX) * 32,
+                    );
+                }
+                self.stats.tx_packet(len);
+            }
+
+            self.tx_lens[tx_i].store(0, Release);
+            self.dirty_tx_desc
+                .store(((tx_i + 1) % NUM_TX_DESCS) as u8, Release);
+            self.tx_free.try_signal();
+        }
     }
 
     pub fn id(&self) -> MACAddress {
@@ -344,20 +497,40 @@ impl RTL8139 {
         mac.into()
     }
 
+    pub fn link_status(&self) -> LinkStatus {
+        // LINKB is set when the link has failed.
+        let msr = unsafe { self.inb(REG_MSR) };
+        if msr & 0b100 == 0 {
+            LinkStatus::Up
+        } else {
+            LinkStatus::Down
+        }
+    }
+
     fn process_receive(&self) {
         self.pending_irqs.wait();
 
+        if self.rx_overflow.swap(false, AcqRel) {
+            self.reset_receiver();
+            return;
+        }
+
         // Current Buffer Address: where the NIC has written data up to
-        let cbr = unsafe { self.inw(REG_CBR) } as usize;
+        let cbr = unsafe { self.inw(REG_CBR) } as usize % RX_BUF_SIZE;
 
         // Current Address of Packet Read: where we have read data up to
         // This register is offset by -0x10, so adjust.
         let capr = unsafe { self.inw(REG_CAPR) } as usize + 0x10;
         let mut capr = capr % RX_BUF_SIZE;
 
+        // The interface is gone if the NIC was removed.
+        let Some(dev_net) = NETWORK_STACK.lock().get(&self.pci.bdf).cloned() else {
+            return;
+        };
+
         loop {
             let cr = unsafe { self.inb(REG_COMMAND) };
-            if cr & 0x1 == 0x1 {
+            if cr & CR_BUFE != 0 {
                 break;
             };
 
@@ -367,30 +540,49 @@ impl RTL8139 {
                 break;
             }
 
-            let mut rx_buf = &self.rx_buf.lock().buf[capr..];
-            let (rsr, remaining) = rx_buf.split_at(2);
-            let rsr = {
-                // Panics on failure to get 2 bytes off of the rx buffer.
-                // This should not happen.
-                let rsr = rsr.try_into();
-                let rsr = rsr.unwrap();
-                u16::from_le_bytes(rsr)
-            };
-            let (frame_size, remaining) = remaining.split_at(2);
-            let frame_size = {
-                let fs = frame_size.try_into();
-                let fs = fs.unwrap();
-                u16::from_le_bytes(fs)
-            };
+            let mut frame = [0u8; MAX_FRAME_SIZE];
+            let (rsr, frame_size) = {
+                let rx_buf = self.rx_buf.lock();
+
+                // The header is 4-byte aligned, so it never wraps around the end of the ring.
+                let header = &rx_buf.buf[capr..capr + 4];
+                let rsr = u16::from_le_bytes([header[0], header[1]]);
+                let frame_size = u16::from_le_bytes([header[2], header[3]]);
 
-            // ..frame_size may read past the 8k buffer
-            // TODO: copy this to a buffer that is owned by the network stack, instead of
-            //       keeping it in the NIC buffer.
-            let frame = &remaining[..frame_size as usize];
+                // The NIC is still writing this frame.
+                if frame_size == RX_FRAME_SIZE_EARLY {
+                    break;
+                }
+
+                // A frame size that can not be right means the ring is corrupted, and we do not
+                // know where the next frame starts. Runts are not accepted, so this holds
+                // whatever the status says.
+                let size = frame_size as usize;
+                if !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&size) {
+                    drop(rx_buf);
+                    warn!(
+                        "bad frame header, RSR: {:x}, SIZE: {:x}, CAPR: {:x}, CBR: {:x}",
+                        rsr, frame_size, capr, cbr,
+                    );
+                    self.stats.rx_error();
+                    self.reset_receiver();
+                    return;
+                }
+
+                // Copy the frame out of the ring, continuing at the start of the ring if the
+                // frame wraps around its end.
+                let size = size.min(MAX_FRAME_SIZE);
+                let start = capr + 4;
+                let first = size.min(RX_BUF_SIZE.saturating_sub(start));
+                frame[..first].copy_from_slice(&rx_buf.buf[start..start + first]);
+                let start = (start + first) % RX_BUF_SIZE;
+                frame[first..size].copy_from_slice(&rx_buf.buf[start..start + size - first]);
+
+                (rsr, frame_size)
+            };
 
             // Process frame
-            writeln!(
-                serial::Handle::new(),
+            trace!(
                 "RSR: {:x}, SIZE: {:x}, CAPR: {:x}, CBR: {:x}",
                 rsr,
                 frame_size,
@@ -398,14 +590,16 @@ impl RTL8139 {
                 cbr,
             );
 
-            let dev_net = {
-                NETWORK_STACK
-                    .lock()
-                    .get(&self.pci.bdf)
-                    .expect("Device network stack should have been initialized")
-                    .clone()
-            };
-            dev_net.recv_frame(frame);
+            let frame = &frame[..(frame_size as usize).min(MAX_FRAME_SIZE)];
+            let errors = RSR_FAE | RSR_CRC | RSR_LONG | RSR_RUNT | RSR_ISE;
+            if rsr & RSR_ROK == 0 || rsr & errors != 0 || frame.len() < MIN_FRAME_SIZE {
+                // Runts, and frames with CRC or alignment errors.
+                self.stats.rx_error();
+            } else if dev_net.recv_frame(frame) {
+                self.stats.rx_packet(frame.len());
+            } else {
+                self.stats.rx_dropped(1);
+            }
 
             capr = {
                 let capr = capr + 4 + frame_size as usize;
@@ -420,15 +614,70 @@ impl RTL8139 {
             unsafe { self.outw(REG_CAPR, (capr as u16).wrapping_sub(0x10)) };
         }
     }
+
+    /// Recovers from an RX buffer or FIFO overflow, or a corrupted RX ring, by setting up the
+    /// ring again and restarting the receiver, like Linux does on receive errors. Frames in the
+    /// ring are dropped.
+    fn reset_receiver(&self) {
+        let rx_buf_virt_addr = self.rx_buf.lock().buf.as_ptr() as usize;
+        let Some(rx_buf_addr) = mm::phys_addr(rx_buf_virt_addr) else {
+            panic!("rtl8139: unmapped rx_buf: {:x}", rx_buf_virt_addr);
+        };
+        unsafe {
+            // Stop the receiver, leaving the transmitter running.
+            self.outb(REG_COMMAND, CR_TE);
+            while self.inb(REG_COMMAND) & CR_RE != 0 {
+                spin_loop();
+            }
+
+            // Frames missed while the ring was full. Writing to MPC clears it.
+            let missed = self.inl(REG_MPC) & 0xff_ffff;
+            self.outl(REG_MPC, 0);
+            self.stats.rx_dropped(missed as u64);
+
+            // Restart the receiver on the ring. The receive configuration needs to be written
+            // again after the receiver has been enabled.
+            self.outl(REG_RBSTART, rx_buf_addr as u32);
+            self.outb(REG_COMMAND, CR_TE | CR_RE);
+            self.outl(REG_RCR, RCR_ACCEPT_ALL);
+
+            // The NIC writes from the start of the ring again. CBR is read-only, and some NICs
+            // (QEMU's at least) only clear it on a software reset, so the ring is emptied by
+            // moving the read pointer to wherever CBR is.
+            let cbr = self.inw(REG_CBR) as usize % RX_BUF_SIZE;
+            let capr = (cbr + 3) & !3;
+            self.outw(REG_CAPR, (capr as u16).wrapping_sub(0x10));
+        }
+        warn!("receiver reset");
+    }
 }
 
-// This function should be called from the ISR for all RTL8139s, and should determine which one
-// got the interrupt.
-// TODO: This can be made into a common stub handler for all (PCI) devices.
-//       PCIDevice should own each of the per-device structs as trait objects, as well as
-//       the vector number.
-//       ISRs in isr.s would be defined (generated by a macro at compile time) for each *vector*, instead of each device type.
-//       ISRs will call the common stub handler, passing the vector as an argument like how it's done below.
+impl NetworkDevice for RTL8139 {
+    fn id(&self) -> MACAddress {
+        RTL8139::id(self)
+    }
+
+    fn transmit(
+        &self,
+        dest: MACAddress,
+        vlan_tags: [Option<VLANTag>; 2],
+        ethertype: EtherType,
+        writer: Box<dyn FnOnce(&mut [u8]) -> usize + '_>,
+    ) {
+        RTL8139::transmit(self, dest, vlan_tags, ethertype, writer)
+    }
+
+    fn link_status(&self) -> LinkStatus {
+        RTL8139::link_status(self)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.stats.snapshot()
+    }
+}
+
+// This function is called for interrupts on the vectors of RTL8139s, and determines which ones
+// got the interrupt. Other devices may share the vector.
 #[unsafe(no_mangle)]
 pub extern "C" fn rtl8139_handler(vector: u64) {
     let nics = {
@@ -442,36 +691,38 @@ pub extern "C" fn rtl8139_handler(vector: u64) {
     };
     for n in nics.iter() {
         let status = unsafe { n.inw(REG_ISR) };
-        writeln!(serial::Handle::new(), "ISR: 0x{:x}\n", status);
+        trace!("ISR: 0x{:x}", status);
 
         // Reset status register so that another frame can be sent / received.
         // SAFETY: Not confirmed to be safe yet. The same for all following port IO calls.
-        unsafe { n.outw(REG_ISR, 0x05) }
-        if status & 1 == 1 {
+        unsafe { n.outw(REG_ISR, status) }
+        if status & (INT_RXOVW | INT_FOVW) != 0 {
+            n.rx_overflow.store(true, Release);
+        }
+        if status & (INT_ROK | INT_RER | INT_RXOVW | INT_FOVW) != 0 {
             n.pending_irqs.try_signal();
         }
-        if status & 4 == 4 {
-            // Send complete
+        if status & (INT_TOK | INT_TER) != 0 {
+            n.complete_transmit();
         }
     }
-
-    unsafe {
-        let lapic = LOCAL_APIC.lock();
-        lapic.end_of_interrupt();
-    }
 }
 
 #[unsafe(no_mangle)]
 pub fn rtl8139_bottom_half() {
-    let mut nics = Vec::<Arc<RTL8139>>::new();
-    {
-        for (_, n) in NICS.lock().iter() {
-            nics.push(n.clone());
-        }
-    }
     loop {
+        let nics = {
+            let nics = NICS.lock();
+            if nics.is_empty() {
+                // The next NIC to be probed starts the bottom half again.
+                BOTTOM_HALF_RUNNING.store(false, Release);
+                break;
+            }
+            nics.values().cloned().collect::<Vec<Arc<RTL8139>>>()
+        };
         for nic in nics.iter() {
             nic.process_receive();
         }
     }
+    sched::lock().exit();
 }
@@ -0,0 +1,163 @@
+//! Base address registers.
+
+use super::BDF;
+
+use core::fmt::{self, Display, Formatter};
+
+// Low bits of a BAR.
+const BAR_IO: u32 = 0x1;
+const BAR_TYPE_64: u32 = 0b100;
+const BAR_PREFETCHABLE: u32 = 0b1000;
+
+// Command register bits that turn on decoding of I/O and memory BARs.
+const COMMAND_IO: u32 = 1 << 0;
+const COMMAND_MEMORY: u32 = 1 << 1;
+
+/// A decoded base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        base: usize,
+        size: usize,
+        is_64: bool,
+        prefetchable: bool,
+    },
+    Io {
+        base: u32,
+        size: u32,
+    },
+}
+
+impl Bar {
+    pub fn base(&self) -> usize {
+        match *self {
+            Bar::Memory { base, .. } => base,
+            Bar::Io { base, .. } => base as usize,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match *self {
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => size as usize,
+        }
+    }
+}
+
+impl Display for Bar {
+    /// Formats the BAR like `lspci -v` does.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Bar::Memory {
+                base,
+                size,
+                is_64,
+                prefetchable,
+            } => write!(
+                f,
+                "Memory at {:x} ({}-bit, {}) [size={}]",
+                base,
+                if is_64 { 64 } else { 32 },
+                if prefetchable {
+                    "prefetchable"
+                } else {
+                    "non-prefetchable"
+                },
+                Size(size)
+            ),
+            Bar::Io { base, size } => {
+                write!(f, "I/O ports at {:x} [size={}]", base, Size(size as usize))
+            }
+        }
+    }
+}
+
+/// Size in bytes, shown with the largest binary unit that divides it.
+struct Size(usize);
+
+impl Display for Size {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (shift, unit) in [(30, "G"), (20, "M"), (10, "K")] {
+            if self.0 >= 1 << shift && self.0 % (1 << shift) == 0 {
+                return write!(f, "{}{}", self.0 >> shift, unit);
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Returns how many BARs functions with `header_type` have.
+pub(super) fn count(header_type: u8) -> usize {
+    match header_type & 0x7f {
+        0x0 => 6,
+        // PCI-to-PCI bridges
+        0x1 => 2,
+        _ => 0,
+    }
+}
+
+/// Decodes the first `count` BARs of the function at `bdf`, and finds their sizes by writing
+/// all ones to them. BARs that are not implemented, and the upper halves of 64-bit BARs, are
+/// `None`. Decoding is turned off while the BARs are probed, so no driver may be using the function.
+pub(super) fn probe(bdf: &BDF, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let command = unsafe { bdf.inl(0x4) } & 0xffff;
+    unsafe { bdf.outl(0x4, command & !(COMMAND_IO | COMMAND_MEMORY)) };
+
+    let mut i = 0;
+    while i < count.min(6) {
+        let offset = 0x10 + i as u16 * 4;
+        let (value, mask) = unsafe { probe_register(bdf, offset) };
+        if value & BAR_IO != 0 {
+            // Some devices don't implement the upper 16 bits of I/O BARs.
+            let mask = match mask & 0xffff_0000 {
+                0 => mask | 0xffff_0000,
+                _ => mask,
+            };
+            let mask = mask & !0x3;
+            if mask != 0 {
+                bars[i] = Some(Bar::Io {
+                    base: value & !0x3,
+                    size: (!mask).wrapping_add(1),
+                });
+            }
+            i += 1;
+            continue;
+        }
+
+        let is_64 = value & 0b110 == BAR_TYPE_64 && i + 1 < count;
+        let (base, mask) = match is_64 {
+            true => {
+                let (high, high_mask) = unsafe { probe_register(bdf, offset + 4) };
+                (
+                    (high as u64) << 32 | (value & !0xf) as u64,
+                    (high_mask as u64) << 32 | (mask & !0xf) as u64,
+                )
+            }
+            // The upper half of the mask is all ones, as if it were a 64-bit BAR.
+            false => ((value & !0xf) as u64, !0xffff_ffff | (mask & !0xf) as u64),
+        };
+        if mask & 0xffff_ffff != 0 || is_64 && mask != 0 {
+            bars[i] = Some(Bar::Memory {
+                base: base as usize,
+                size: (!mask).wrapping_add(1) as usize,
+                is_64,
+                prefetchable: value & BAR_PREFETCHABLE != 0,
+            });
+        }
+        i += if is_64 { 2 } else { 1 };
+    }
+
+    unsafe { bdf.outl(0x4, command) };
+    bars
+}
+
+/// Writes all ones to the register at `offset`, and returns its value and what was read back. The
+/// value is written back.
+unsafe fn probe_register(bdf: &BDF, offset: u16) -> (u32, u32) {
+    let value = bdf.inl(offset);
+    bdf.outl(offset, 0xffff_ffff);
+    let mask = bdf.inl(offset);
+    bdf.outl(offset, value);
+    (value, mask)
+}
@@ -0,0 +1,42 @@
+//! Names of PCI class codes.
+
+/// Returns the name of the class and subclass, or of the base class if the subclass has no name
+/// here.
+pub fn name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        _ => match class {
+            0x00 => "Unclassified device",
+            0x01 => "Mass storage controller",
+            0x02 => "Network controller",
+            0x03 => "Display controller",
+            0x04 => "Multimedia controller",
+            0x05 => "Memory controller",
+            0x06 => "Bridge",
+            0x07 => "Communication controller",
+            0x08 => "Generic system peripheral",
+            0x09 => "Input device controller",
+            0x0a => "Docking station",
+            0x0b => "Processor",
+            0x0c => "Serial bus controller",
+            0x0d => "Wireless controller",
+            0x0e => "Intelligent controller",
+            0x0f => "Satellite communications controller",
+            0x10 => "Encryption controller",
+            0x11 => "Signal processing controller",
+            0x12 => "Processing accelerators",
+            0x13 => "Non-Essential Instrumentation",
+            0xff => "Unassigned class",
+            _ => "Unknown class",
+        },
+    }
+}
@@ -0,0 +1,78 @@
+//! Binding of PCI devices to drivers.
+//!
+//! A driver describes the devices it supports with a table of `DeviceId`s and is registered with
+//! `pci::register_driver()`. Its `probe` function is called for each device that matches and that
+//! no other driver has taken. Devices that no driver takes stay listed, unbound.
+
+use super::PCIDevice;
+
+/// Matches the devices a driver supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceId {
+    /// A device with this vendor and device ID.
+    Device { vendor_id: u16, device_id: u16 },
+
+    /// Any device with this class and subclass, and this programming interface if it is given.
+    Class {
+        class: u8,
+        subclass: u8,
+        prog_if: Option<u8>,
+    },
+}
+
+impl DeviceId {
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        DeviceId::Device {
+            vendor_id,
+            device_id,
+        }
+    }
+
+    pub const fn class(class: u8, subclass: u8, prog_if: Option<u8>) -> Self {
+        DeviceId::Class {
+            class,
+            subclass,
+            prog_if,
+        }
+    }
+
+    pub fn matches(&self, device: &PCIDevice) -> bool {
+        match *self {
+            DeviceId::Device {
+                vendor_id,
+                device_id,
+            } => device.vendor_id == vendor_id && device.device_id == device_id,
+            DeviceId::Class {
+                class,
+                subclass,
+                prog_if,
+            } => {
+                device.class == class
+                    && device.subclass == subclass
+                    && prog_if.is_none_or(|p| p == device.prog_if)
+            }
+        }
+    }
+}
+
+/// A PCI device driver.
+pub struct Driver {
+    pub name: &'static str,
+
+    /// Devices the driver supports.
+    pub ids: &'static [DeviceId],
+
+    /// Sets up a device that matched `id`. The driver keeps a copy of the device if it takes it,
+    /// and returns an error otherwise, which leaves the device unbound.
+    pub probe: fn(device: &PCIDevice, id: &DeviceId) -> Result<(), &'static str>,
+
+    /// Stops using a device that `probe` took. The device has no driver when this returns.
+    pub remove: fn(device: &PCIDevice),
+}
+
+impl Driver {
+    /// Returns the first entry of the ID table that matches `device`.
+    pub(super) fn match_device(&self, device: &PCIDevice) -> Option<&'static DeviceId> {
+        self.ids.iter().find(|id| id.matches(device))
+    }
+}
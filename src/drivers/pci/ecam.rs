@@ -0,0 +1,83 @@
+//! Memory-mapped configuration access (ECAM) for PCI Express.
+//!
+//! The MCFG table gives the physical address of the configuration space of a range of buses. Each
+//! function has 4 KiB of it, so the extended configuration space after the first 256 bytes can be
+//! reached. The page of a function is mapped at `MMIO_BASE` when it is first accessed.
+
+use crate::arch::x86_64::mm::{self, MMIO_BASE};
+use crate::drivers::acpi::MCFG;
+use crate::drivers::pci::BDF;
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use log::info;
+
+static ECAM: WithSpinLock<Ecam> = WithSpinLock::new(Ecam::new());
+
+struct Ecam {
+    regions: Vec<Region>,
+    // Physical addresses of the pages mapped so far.
+    mapped: BTreeSet<usize>,
+}
+
+#[derive(Clone, Copy)]
+struct Region {
+    base: usize,
+    start_bus: u16,
+    end_bus: u16,
+}
+
+impl Ecam {
+    const fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+            mapped: BTreeSet::new(),
+        }
+    }
+}
+
+/// Uses the ECAM regions of `mcfg`. We only know about PCI segment group 0, so regions of other
+/// groups are ignored.
+pub(super) fn init(mcfg: &MCFG) {
+    let mut ecam = ECAM.lock();
+    for config in mcfg.configurations.iter() {
+        let (base, segment) = (config.base_address, config.segment_group_number);
+        let (start_bus, end_bus) = (config.start_bus_number, config.end_bus_number);
+        if segment != 0 {
+            continue;
+        }
+        info!(
+            "PCIe ECAM at {:#x} for buses {:02x}-{:02x}",
+            base, start_bus, end_bus
+        );
+        ecam.regions.push(Region {
+            base: base as usize,
+            start_bus: start_bus as u16,
+            end_bus: end_bus as u16,
+        });
+    }
+}
+
+/// Returns the virtual address of the configuration space of the function at `bdf`, or `None` if
+/// no ECAM region has its bus.
+pub(super) fn function_base(bdf: &BDF) -> Option<usize> {
+    let mut ecam = ECAM.lock();
+    let bus = bdf.bus_number();
+    let region = *ecam
+        .regions
+        .iter()
+        .find(|r| r.start_bus <= bus && bus <= r.end_bus)?;
+    // Bus, device and function numbers make up bits 27:12 of the offset in the region, so the
+    // region of bus 0 starts at the base even if the range starts at a later bus.
+    let phys_addr = region.base
+        + ((bus as usize) << 20
+            | (bdf.device_number() as usize) << 15
+            | (bdf.function_number() as usize) << 12);
+    if ecam.mapped.insert(phys_addr) {
+        let mut mapper = mm::mapper();
+        let mapper = mapper.as_mut().expect("memory manager is not initialized");
+        mapper.map_mmio(phys_addr);
+    }
+    Some(phys_addr + MMIO_BASE)
+}
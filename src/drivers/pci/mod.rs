@@ -0,0 +1,556 @@
+use crate::arch::x86_64::port;
+use crate::drivers::acpi::{InterruptMapping, MCFG};
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::vec::{self, Vec};
+use core::cmp::Ordering;
+use core::num::Wrapping;
+use core::ptr::{read_volatile, write_volatile};
+use log::{info, warn};
+
+mod bar;
+pub mod class;
+mod driver;
+mod ecam;
+mod msi;
+pub use bar::Bar;
+pub use driver::{DeviceId, Driver};
+pub use msi::MessageInterrupts;
+
+const REG_CAP_PTR: u16 = 0x34;
+
+// Header type bit that tells the device has functions other than 0.
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const HEADER_TYPE_BRIDGE: u8 = 0x1;
+
+// Status register bit that tells the device has a capability list.
+const STATUS_CAP_LIST: u16 = 1 << 4;
+// Command register bit that stops the device from asserting INTx.
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+// At most this many capabilities fit in the configuration space after the header, and this many
+// extended capabilities in the extended configuration space, so a longer list must loop.
+const MAX_CAPABILITIES: usize = 48;
+const MAX_EXTENDED_CAPABILITIES: usize = 960;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+// Size of the configuration space that port I/O can reach. The rest of the 4 KiB of PCI Express
+// functions is only reachable through ECAM.
+const LEGACY_CONFIG_SIZE: u16 = 0x100;
+
+static PCI: WithSpinLock<PCI> = WithSpinLock::new(PCI::new());
+
+/// Enumerates the devices. Configuration space is accessed through the ECAM regions of `mcfg`,
+/// and through port I/O for buses that they don't cover. `interrupt_mappings` are the interrupt
+/// source overrides, which tell which I/O APIC line each INTx interrupt line is wired to.
+pub fn init(mcfg: Option<&MCFG>, interrupt_mappings: &[InterruptMapping]) {
+    if let Some(mcfg) = mcfg {
+        ecam::init(mcfg);
+    }
+    let mut pci = unsafe { PCI.lock() };
+    pci.enumerate_pci_bus();
+    for device in pci.devices.iter_mut() {
+        // Lines without an override are identity mapped to global system interrupts.
+        device.pci.gsi = interrupt_mappings
+            .iter()
+            .find(|x| x.irq_number == device.pci.interrupt_line)
+            .map_or(device.pci.interrupt_line as u32, |x| {
+                x.global_system_interrupt
+            });
+    }
+}
+
+/// Adds `driver` to the registered drivers, and probes it with each unbound device that it
+/// matches. Returns the number of devices that it took.
+pub fn register_driver(driver: &'static Driver) -> usize {
+    // Claim the matching devices, so that no other driver probes them meanwhile. The lock is not
+    // held while probing, as drivers may sleep.
+    let candidates = {
+        let mut pci = PCI.lock();
+        pci.drivers.push(driver);
+        let mut candidates = Vec::new();
+        for device in pci.devices.iter_mut().filter(|d| d.driver.is_none()) {
+            if let Some(id) = driver.match_device(&device.pci) {
+                device.driver = Some(driver);
+                candidates.push((device.pci.clone(), id));
+            }
+        }
+        candidates
+    };
+
+    let mut bound = 0;
+    for (device, id) in candidates {
+        match (driver.probe)(&device, id) {
+            Ok(()) => {
+                info!("pci {}: bound to {}", device.bdf, driver.name);
+                bound += 1;
+            }
+            Err(e) => {
+                warn!("pci {}: {} failed to probe: {}", device.bdf, driver.name, e);
+                PCI.lock().set_driver(&device.bdf, None);
+            }
+        }
+    }
+    bound
+}
+
+/// Detaches the device at `bdf` from its driver, which stops using it.
+pub fn unbind(bdf: BDF) -> Result<(), &'static str> {
+    let (device, driver) = {
+        let pci = PCI.lock();
+        let device = pci.device(&bdf).ok_or("no such device")?;
+        (
+            device.pci.clone(),
+            device.driver.ok_or("device has no driver")?,
+        )
+    };
+    (driver.remove)(&device);
+    PCI.lock().set_driver(&bdf, None);
+    info!("pci {}: unbound from {}", bdf, driver.name);
+    Ok(())
+}
+
+pub enum BarNumber {
+    BAR0,
+    BAR1,
+    BAR2,
+    BAR3,
+    BAR4,
+    BAR5,
+}
+
+impl From<BarNumber> for usize {
+    fn from(bar_num: BarNumber) -> usize {
+        match bar_num {
+            BarNumber::BAR0 => 0,
+            BarNumber::BAR1 => 1,
+            BarNumber::BAR2 => 2,
+            BarNumber::BAR3 => 3,
+            BarNumber::BAR4 => 4,
+            BarNumber::BAR5 => 5,
+        }
+    }
+}
+
+pub struct PCI {
+    // Every device found on the bus, whether or not it is bound to a driver.
+    devices: Vec<Device>,
+
+    drivers: Vec<&'static Driver>,
+}
+
+struct Device {
+    pci: PCIDevice,
+    driver: Option<&'static Driver>,
+}
+
+// Note: An instance of PCI that is static mut should exist. See comment in rtl8139 driver code.
+
+impl PCI {
+    const fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+            drivers: Vec::new(),
+        }
+    }
+
+    /// Reads the function at `bdf`, or returns `None` if there is none.
+    fn visit_configuration_space(&mut self, bdf: BDF) -> Option<PCIDevice> {
+        let cfg_data = unsafe { bdf.inl(0x0) };
+
+        if cfg_data & 0xffff == 0xffff {
+            // Invalid vendor ID.
+            // Nothing found at bus:device combination.
+            return None;
+        }
+
+        let vendor_id = (cfg_data & 0xffff) as u16;
+        let device_id = ((cfg_data & 0xffff0000) >> 16) as u16;
+        let class = unsafe { bdf.inl(0x8) };
+        let header_type = ((unsafe { bdf.inl(0xc) } >> 16) & 0xff) as u8;
+
+        let mut device = PCIDevice {
+            bdf,
+            vendor_id,
+            device_id,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            bars: bar::probe(&bdf, bar::count(header_type)),
+
+            interrupt_line: 0,
+            interrupt_pin: 0,
+            subsystem_id: 0,
+            subsystem_vendor_id: 0,
+            gsi: 0,
+        };
+
+        unsafe {
+            // Bridges have no subsystem IDs in their header.
+            if header_type & 0x7f == 0x0 {
+                device.subsystem_vendor_id = (device.inl(0x2c) & 0xffff) as u16;
+                device.subsystem_id = ((device.inl(0x2c) & 0xffff0000) >> 16) as u16;
+            }
+            device.interrupt_line = (device.inl(0x3c) & 0xff) as u8;
+            device.interrupt_pin = ((device.inl(0x3c) & 0xff00) >> 8) as u8;
+        }
+
+        Some(device)
+    }
+
+    /// Finds the devices on every bus reachable from the host bridges.
+    fn enumerate_pci_bus(&mut self) {
+        let mut scanned = [false; 256];
+        let host = BDF::new(0, 0, 0);
+        let is_multifunction = (unsafe { host.inl(0xc) } >> 16) as u8 & HEADER_MULTIFUNCTION != 0;
+        if !is_multifunction {
+            return self.scan_bus(0, &mut scanned);
+        }
+        // With several host bridges, function N of the first one is the bridge to bus N.
+        for n_function in 0..8 {
+            let bdf = BDF::new(0, 0, n_function);
+            if unsafe { bdf.inl(0x0) } & 0xffff != 0xffff {
+                self.scan_bus(n_function as u16, &mut scanned);
+            }
+        }
+    }
+
+    /// Finds the devices on bus `n_bus` and the buses behind its bridges.
+    fn scan_bus(&mut self, n_bus: u16, scanned: &mut [bool; 256]) {
+        // A misconfigured bridge could lead back to a bus that was already scanned.
+        if core::mem::replace(&mut scanned[n_bus as usize], true) {
+            return;
+        }
+        for n_device in 0..32 {
+            let Some(device) = self.visit_configuration_space(BDF::new(n_bus, n_device, 0)) else {
+                continue;
+            };
+            let is_multifunction = device.header_type & HEADER_MULTIFUNCTION != 0;
+            self.add_function(device, scanned);
+            if !is_multifunction {
+                continue;
+            }
+            for n_function in 1..8 {
+                let bdf = BDF::new(n_bus, n_device, n_function);
+                if let Some(device) = self.visit_configuration_space(bdf) {
+                    self.add_function(device, scanned);
+                }
+            }
+        }
+    }
+
+    fn add_function(&mut self, device: PCIDevice, scanned: &mut [bool; 256]) {
+        // The bus numbers of PCI-to-PCI bridges are expected to have been set by the firmware.
+        let secondary_bus = match device.header_type & 0x7f {
+            HEADER_TYPE_BRIDGE => ((unsafe { device.inl(0x18) } >> 8) & 0xff) as u16,
+            _ => 0,
+        };
+        self.devices.push(Device {
+            pci: device,
+            driver: None,
+        });
+        if secondary_bus != 0 {
+            self.scan_bus(secondary_bus, scanned);
+        }
+    }
+
+    fn device(&self, bdf: &BDF) -> Option<&Device> {
+        self.devices.iter().find(|d| d.pci.bdf == *bdf)
+    }
+
+    fn set_driver(&mut self, bdf: &BDF, driver: Option<&'static Driver>) {
+        if let Some(device) = self.devices.iter_mut().find(|d| d.pci.bdf == *bdf) {
+            device.driver = driver;
+        }
+    }
+}
+
+/// Returns every device found on the PCI bus, whether or not a driver has claimed it.
+pub fn devices() -> Vec<DeviceInfo> {
+    PCI.lock()
+        .devices
+        .iter()
+        .map(|d| DeviceInfo {
+            driver: d.driver.map(|driver| driver.name),
+            ..d.pci.info()
+        })
+        .collect()
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub struct BDF {
+    bus_number: u16,
+    device_number: u16,
+    function_number: u8,
+}
+
+impl BDF {
+    const fn new(bus_number: u16, device_number: u16, function_number: u8) -> Self {
+        Self {
+            bus_number,
+            device_number,
+            function_number,
+        }
+    }
+
+    pub fn bus_number(&self) -> u16 {
+        self.bus_number
+    }
+
+    pub fn device_number(&self) -> u16 {
+        self.device_number
+    }
+
+    pub fn function_number(&self) -> u8 {
+        self.function_number
+    }
+
+    /// Stops the function from signalling interrupts on its INTx pin, or lets it again.
+    fn set_intx_disabled(&self, disabled: bool) {
+        // The status register in the upper half is cleared by writing ones, so write zeros to it.
+        let control = unsafe { self.inl(0x4) } & 0xffff;
+        let control = match disabled {
+            true => control | COMMAND_INTX_DISABLE as u32,
+            false => control & !(COMMAND_INTX_DISABLE as u32),
+        };
+        unsafe { self.outl(0x4, control) }
+    }
+
+    /// Writes the configuration space register at `offset` of the function. Writes past the
+    /// first 256 bytes are dropped unless the bus is covered by ECAM.
+    unsafe fn outl(&self, offset: u16, data: u32) {
+        if let Some(base) = ecam::function_base(self) {
+            return write_volatile((base + (offset & 0xffc) as usize) as *mut u32, data);
+        }
+        if offset >= LEGACY_CONFIG_SIZE {
+            return;
+        }
+        port::outl(CONFIG_ADDRESS, self.config_address(offset));
+        port::outl(CONFIG_DATA, data)
+    }
+
+    /// Reads the configuration space register at `offset` of the function. Reads past the first
+    /// 256 bytes return all ones, like a missing register, unless the bus is covered by ECAM.
+    unsafe fn inl(&self, offset: u16) -> u32 {
+        if let Some(base) = ecam::function_base(self) {
+            return read_volatile((base + (offset & 0xffc) as usize) as *const u32);
+        }
+        if offset >= LEGACY_CONFIG_SIZE {
+            return 0xffff_ffff;
+        }
+        port::outl(CONFIG_ADDRESS, self.config_address(offset));
+        port::inl(CONFIG_DATA)
+    }
+
+    /// Returns the value for CONFIG_ADDRESS that selects the register at `offset`.
+    fn config_address(&self, offset: u16) -> u32 {
+        let n_bus = self.bus_number as u32;
+        let n_device = self.device_number as u32;
+        let function = (self.function_number as u32 & 0b111) << 8;
+        // Bit 31 enables the configuration space access.
+        0x8000_0000 | n_bus << 16 | n_device << 11 | function | (offset & 0xfc) as u32
+    }
+}
+
+impl core::fmt::Display for BDF {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{}",
+            self.bus_number, self.device_number, self.function_number
+        )
+    }
+}
+
+/// Identification of a PCI device, kept for listing devices after they are handed to drivers.
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceInfo {
+    pub bdf: BDF,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub bars: [Option<Bar>; 6],
+    pub interrupt_pin: u8,
+    pub interrupt_line: u8,
+    /// Name of the driver the device is bound to.
+    pub driver: Option<&'static str>,
+}
+
+/// This represents a PCI device on a PCI bus. Drivers keep a copy of the devices they are bound
+/// to.
+/// TODO: Consider field visibility.
+#[derive(Clone)]
+pub struct PCIDevice {
+    pub bdf: BDF,
+
+    device_id: u16,
+    vendor_id: u16,
+    subsystem_id: u16,
+    subsystem_vendor_id: u16,
+
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    revision: u8,
+    header_type: u8,
+    // Decoded when the device was found, before any driver changed them.
+    bars: [Option<Bar>; 6],
+
+    interrupt_pin: u8,
+    pub(crate) interrupt_line: u8,
+    // I/O APIC line that the interrupt line is wired to.
+    gsi: u32,
+}
+
+impl PCIDevice {
+    pub fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            bdf: self.bdf,
+            vendor_id: self.vendor_id,
+            device_id: self.device_id,
+            subsystem_vendor_id: self.subsystem_vendor_id,
+            subsystem_id: self.subsystem_id,
+            class: self.class,
+            subclass: self.subclass,
+            prog_if: self.prog_if,
+            revision: self.revision,
+            header_type: self.header_type,
+            bars: self.bars,
+            interrupt_pin: self.interrupt_pin,
+            interrupt_line: self.interrupt_line,
+            driver: None,
+        }
+    }
+
+    unsafe fn outl(&self, offset: u16, data: u32) {
+        self.bdf.outl(offset, data)
+    }
+
+    unsafe fn inl(&self, offset: u16) -> u32 {
+        self.bdf.inl(offset)
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.device_id
+    }
+
+    /// Returns the global system interrupt that the device's INTx pin signals.
+    pub fn gsi(&self) -> u32 {
+        self.gsi
+    }
+
+    /// Reads the configuration space dword at `offset`, such as a field of a capability.
+    pub fn read_config(&self, offset: u16) -> u32 {
+        unsafe { self.inl(offset) }
+    }
+
+    pub fn read_control_register(&self) -> u16 {
+        let read = unsafe { self.inl(0x4) };
+        (read & 0x0000FFFF) as u16
+    }
+
+    pub fn write_control_register(&self, control: u16) {
+        let status: u32 = 0x0 << 16;
+        let data = status | control as u32;
+        unsafe { self.outl(0x4, data) }
+    }
+
+    pub fn read_status_register(&self) -> u16 {
+        let read = unsafe { self.inl(0x4) };
+        ((read & 0xffff0000) >> 16) as u16
+    }
+
+    fn read_header_type(&self) -> u8 {
+        ((unsafe { self.inl(0xC) } & 0x00FF0000) >> 16) as u8
+    }
+
+    pub fn read_bar_register(&self, bar: BarNumber) -> u32 {
+        let bar = usize::from(bar);
+        match bar < bar::count(self.header_type) {
+            // BAR0 lives at config offset 0x10, with each subsequent BAR 4 bytes after.
+            true => unsafe { self.inl(0x10 + (bar as u16) * 4) },
+            false => 0,
+        }
+    }
+
+    pub fn write_bar_register(&self, bar: BarNumber, data: u32) {
+        unsafe { self.outl(0x10 + (usize::from(bar) as u16) * 4, data) }
+    }
+
+    /// Returns BAR `index` as it was decoded when the device was found.
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.bars.get(index).copied().flatten()
+    }
+
+    /// Returns the address of memory BAR `index`, or `None` if it is an I/O BAR or not
+    /// implemented.
+    fn memory_bar(&self, index: usize) -> Option<usize> {
+        match self.bar(index)? {
+            Bar::Memory { base, .. } => Some(base),
+            Bar::Io { .. } => None,
+        }
+    }
+
+    /// Returns the IDs and offsets of the device's capabilities, in the order of the list.
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u16)> + '_ {
+        let mut ptr = match self.read_status_register() & STATUS_CAP_LIST {
+            0 => 0,
+            _ => (unsafe { self.inl(REG_CAP_PTR) } & 0xfc) as u16,
+        };
+        core::iter::from_fn(move || {
+            if ptr == 0 {
+                return None;
+            }
+            let v = unsafe { self.inl(ptr) };
+            let capability = ((v & 0xff) as u8, ptr);
+            // The bottom two bits of the next pointer are reserved.
+            ptr = ((v >> 8) & 0xfc) as u16;
+            Some(capability)
+        })
+        .take(MAX_CAPABILITIES)
+    }
+
+    /// Returns the IDs and offsets of the PCI Express extended capabilities, which are in the
+    /// extended configuration space from 0x100. There are none without ECAM.
+    pub fn extended_capabilities(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        let mut ptr = LEGACY_CONFIG_SIZE;
+        core::iter::from_fn(move || {
+            if ptr < LEGACY_CONFIG_SIZE {
+                return None;
+            }
+            let v = unsafe { self.inl(ptr) };
+            // An empty list has a header of 0, and reads without ECAM return all ones.
+            if v == 0 || v == 0xffff_ffff {
+                return None;
+            }
+            let capability = ((v & 0xffff) as u16, ptr);
+            ptr = ((v >> 20) & 0xffc) as u16;
+            Some(capability)
+        })
+        .take(MAX_EXTENDED_CAPABILITIES)
+    }
+
+    /// Returns the offset of the first capability with ID `id`.
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .find(|(cap_id, _)| *cap_id == id)
+            .map(|(_, ptr)| ptr)
+    }
+}
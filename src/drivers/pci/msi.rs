@@ -0,0 +1,247 @@
+//! Message signalled interrupts (MSI and MSI-X).
+//!
+//! Drivers ask for them with `PCIDevice::enable_msi()` or `PCIDevice::enable_msix()`. Each message
+//! gets a vector of its own from the IDT vector allocator, and the device writes it straight to
+//! the local APIC, so the I/O APIC is not involved.
+
+use super::{PCIDevice, BDF, CAP_ID_MSI, CAP_ID_MSIX};
+use crate::arch::x86_64::interrupt::{self, LOCAL_APIC};
+use crate::arch::x86_64::mm::{self, MMIO_BASE};
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use log::warn;
+
+// Messages are written to this address, with the destination local APIC ID in bits 19:12.
+const MESSAGE_ADDRESS: u32 = 0xfee0_0000;
+
+// Bits of the MSI capability header. Message control is its upper half.
+const MSI_ENABLE: u32 = 1 << 16;
+const MSI_MULTIPLE_MESSAGE_CAPABLE_SHIFT: u32 = 17;
+const MSI_MULTIPLE_MESSAGE_ENABLE_SHIFT: u32 = 20;
+const MSI_64BIT: u32 = 1 << 23;
+const MSI_PER_VECTOR_MASK: u32 = 1 << 24;
+
+// Bits of the MSI-X capability header.
+const MSIX_TABLE_SIZE_SHIFT: u32 = 16;
+const MSIX_FUNCTION_MASK: u32 = 1 << 30;
+const MSIX_ENABLE: u32 = 1 << 31;
+
+// Each MSI-X table entry is the message address (low and high), the message data and the vector
+// control, whose bit 0 masks the entry.
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_VECTOR_MASKED: u32 = 1;
+
+const PAGE_SIZE: usize = 0x1000;
+
+/// Interrupt vectors that a device signals with MSI or MSI-X. Message `i` is delivered on
+/// `vectors()[i]`.
+pub struct MessageInterrupts {
+    bdf: BDF,
+    kind: Kind,
+    vectors: Vec<u8>,
+}
+
+enum Kind {
+    /// `mask` is the offset of the mask bits register, if the device can mask each message.
+    Msi { cap: u16, mask: Option<u16> },
+    /// `table` is the virtual address of the MSI-X table.
+    MsiX { cap: u16, table: usize },
+}
+
+impl PCIDevice {
+    /// Enables MSI with `count` messages, rounded up to a power of two. Their vectors are
+    /// contiguous, because the device puts the message number in the low bits of the data.
+    pub fn enable_msi(&self, count: usize) -> Result<MessageInterrupts, &'static str> {
+        let cap = self
+            .find_capability(CAP_ID_MSI)
+            .ok_or("device has no MSI capability")?;
+        let header = unsafe { self.inl(cap) };
+        let count = count.max(1).next_power_of_two();
+        let capable = 1 << ((header >> MSI_MULTIPLE_MESSAGE_CAPABLE_SHIFT) & 0b111);
+        if count > capable {
+            return Err("device supports fewer MSI messages");
+        }
+        let first = interrupt::allocate_vectors(count).ok_or("no free interrupt vectors")?;
+
+        // The data and mask bits registers come after the upper half of 64-bit addresses.
+        let (data, mask) = match header & MSI_64BIT {
+            0 => (cap + 0x8, cap + 0xc),
+            _ => (cap + 0xc, cap + 0x10),
+        };
+        let mask = (header & MSI_PER_VECTOR_MASK != 0).then_some(mask);
+        unsafe {
+            self.outl(cap + 0x4, message_address());
+            if header & MSI_64BIT != 0 {
+                self.outl(cap + 0x8, 0);
+            }
+            // Edge triggered, fixed delivery mode.
+            self.outl(data, first as u32);
+            if let Some(mask) = mask {
+                self.outl(mask, 0);
+            }
+            let header = header & !(0b111 << MSI_MULTIPLE_MESSAGE_ENABLE_SHIFT);
+            let enable = count.trailing_zeros() << MSI_MULTIPLE_MESSAGE_ENABLE_SHIFT;
+            self.outl(cap, header | enable | MSI_ENABLE);
+        }
+        self.bdf.set_intx_disabled(true);
+
+        Ok(MessageInterrupts {
+            bdf: self.bdf,
+            kind: Kind::Msi { cap, mask },
+            vectors: (first..first + count as u8).collect(),
+        })
+    }
+
+    /// Enables MSI-X with `count` messages, which are the first entries of the MSI-X table.
+    pub fn enable_msix(&self, count: usize) -> Result<MessageInterrupts, &'static str> {
+        let cap = self
+            .find_capability(CAP_ID_MSIX)
+            .ok_or("device has no MSI-X capability")?;
+        let header = unsafe { self.inl(cap) };
+        let table_size = ((header >> MSIX_TABLE_SIZE_SHIFT) & 0x7ff) as usize + 1;
+        if count > table_size {
+            return Err("device supports fewer MSI-X messages");
+        }
+
+        // The table is in one of the memory BARs, which the low 3 bits select.
+        let table_reg = unsafe { self.inl(cap + 0x4) };
+        let bar = self
+            .memory_bar((table_reg & 0b111) as usize)
+            .ok_or("MSI-X table is not in a memory BAR")?;
+        let table_phys = bar + (table_reg & !0b111) as usize;
+        {
+            let mut mapper = mm::mapper();
+            let mapper = mapper.as_mut().expect("memory manager is not initialized");
+            let start = table_phys & !(PAGE_SIZE - 1);
+            let end = table_phys + table_size * MSIX_ENTRY_SIZE;
+            for page in (start..end).step_by(PAGE_SIZE) {
+                mapper.map_mmio(page);
+            }
+        }
+        let table = table_phys + MMIO_BASE;
+
+        let mut vectors = Vec::with_capacity(count);
+        for _ in 0..count {
+            match interrupt::allocate_vector() {
+                Some(vector) => vectors.push(vector),
+                None => {
+                    vectors.into_iter().for_each(interrupt::free_vector);
+                    return Err("no free interrupt vectors");
+                }
+            }
+        }
+
+        // Mask the whole function while the table is written.
+        unsafe { self.outl(cap, header | MSIX_ENABLE | MSIX_FUNCTION_MASK) };
+        let address = message_address();
+        for (i, vector) in vectors.iter().enumerate() {
+            let entry = (table + i * MSIX_ENTRY_SIZE) as *mut u32;
+            unsafe {
+                write_volatile(entry, address);
+                write_volatile(entry.add(1), 0);
+                write_volatile(entry.add(2), *vector as u32);
+                write_volatile(entry.add(3), 0);
+            }
+        }
+        unsafe { self.outl(cap, (header | MSIX_ENABLE) & !MSIX_FUNCTION_MASK) };
+        self.bdf.set_intx_disabled(true);
+
+        Ok(MessageInterrupts {
+            bdf: self.bdf,
+            kind: Kind::MsiX { cap, table },
+            vectors,
+        })
+    }
+
+    /// Enables `count` messages with MSI-X, or with MSI if the device doesn't have MSI-X or MSI-X
+    /// cannot be enabled. The MSI-X error is returned if the device has no MSI either.
+    pub fn enable_message_interrupts(
+        &self,
+        count: usize,
+    ) -> Result<MessageInterrupts, &'static str> {
+        if self.find_capability(CAP_ID_MSIX).is_none() {
+            return self.enable_msi(count);
+        }
+        match self.enable_msix(count) {
+            Ok(interrupts) => Ok(interrupts),
+            Err(e) if self.find_capability(CAP_ID_MSI).is_some() => {
+                warn!("pci {}: {}, falling back to MSI", self.bdf, e);
+                self.enable_msi(count)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl MessageInterrupts {
+    /// Returns the vector of each message.
+    pub fn vectors(&self) -> &[u8] {
+        &self.vectors
+    }
+
+    /// Returns whether the messages are MSI-X messages rather than MSI ones.
+    pub fn is_msix(&self) -> bool {
+        matches!(self.kind, Kind::MsiX { .. })
+    }
+
+    /// Masks or unmasks message `index`. A masked message is held pending until it is unmasked.
+    pub fn set_masked(&self, index: usize, masked: bool) -> Result<(), &'static str> {
+        if index >= self.vectors.len() {
+            return Err("no such message");
+        }
+        match self.kind {
+            Kind::Msi { mask: None, .. } => Err("device cannot mask MSI messages"),
+            Kind::Msi {
+                mask: Some(mask), ..
+            } => {
+                unsafe {
+                    let bits = self.bdf.inl(mask);
+                    let bits = match masked {
+                        true => bits | 1 << index,
+                        false => bits & !(1 << index),
+                    };
+                    self.bdf.outl(mask, bits);
+                }
+                Ok(())
+            }
+            Kind::MsiX { table, .. } => {
+                let control = (table + index * MSIX_ENTRY_SIZE + 12) as *mut u32;
+                unsafe {
+                    let bits = read_volatile(control);
+                    let bits = match masked {
+                        true => bits | MSIX_VECTOR_MASKED,
+                        false => bits & !MSIX_VECTOR_MASKED,
+                    };
+                    write_volatile(control, bits);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Disables the messages and frees their vectors, whose handlers must have been unregistered.
+    /// The device goes back to signalling interrupts on its INTx pin.
+    pub fn disable(self) {
+        let cap = match self.kind {
+            Kind::Msi { cap, .. } => cap,
+            Kind::MsiX { cap, .. } => cap,
+        };
+        let enable = match self.kind {
+            Kind::Msi { .. } => MSI_ENABLE,
+            Kind::MsiX { .. } => MSIX_ENABLE,
+        };
+        unsafe {
+            let header = self.bdf.inl(cap);
+            self.bdf.outl(cap, header & !enable);
+        }
+        self.bdf.set_intx_disabled(false);
+        self.vectors.into_iter().for_each(interrupt::free_vector);
+    }
+}
+
+/// Returns the message address that sends interrupts to this processor.
+fn message_address() -> u32 {
+    let lapic_id = LOCAL_APIC.lock().id();
+    MESSAGE_ADDRESS | (lapic_id & 0xff) << 12
+}
@@ -1,78 +1,270 @@
+use crate::arch::x86_64::interrupt::interrupts_enabled;
 use crate::arch::x86_64::port;
-use crate::locking::spinlock::{WithSpinLock, WithSpinLockGuard};
+use crate::locking::semaphore::Semaphore;
+use crate::locking::spinlock::WithSpinLock;
 
-const COM1_PORT: u16 = 0x3f8;
+use util::ring_buffer::RingBuffer;
 
-static COM1: WithSpinLock<Com> = WithSpinLock::new(Com { port: None });
+// Register offsets
+// RBR/THR (Receive Buffer / Transmit Holding Register): 0, DLL (Divisor Latch Low) when DLAB is set
+const REG_DATA: u16 = 0;
 
-pub fn init() {
-    let divisor: u16 = 0x2;
+// IER (Interrupt Enable Register): 1, DLH (Divisor Latch High) when DLAB is set
+const REG_IER: u16 = 1;
 
-    let com = Com::new(COM1_PORT, divisor);
+// IIR (Interrupt Identification Register) on read, FCR (FIFO Control Register) on write: 2
+const REG_IIR: u16 = 2;
+const REG_FCR: u16 = 2;
+
+// LCR (Line Control Register): 3
+const REG_LCR: u16 = 3;
+
+// MCR (Modem Control Register): 4
+const REG_MCR: u16 = 4;
+
+// LSR (Line Status Register): 5
+const REG_LSR: u16 = 5;
+
+// MSR (Modem Status Register): 6
+const REG_MSR: u16 = 6;
+
+// SCR (Scratch Register): 7
+const REG_SCR: u16 = 7;
+
+// IER bits
+const IER_RDA: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IER_RLS: u8 = 1 << 2;
+
+// IIR bits
+const IIR_NO_INTERRUPT: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0b1110;
+const IIR_ID_MODEM_STATUS: u8 = 0b0000;
+const IIR_ID_THRE: u8 = 0b0010;
+const IIR_ID_RDA: u8 = 0b0100;
+const IIR_ID_RLS: u8 = 0b0110;
+const IIR_ID_TIMEOUT: u8 = 0b1100;
+
+// LSR bits
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+
+// Frequency the UART divides by the divisor latch to get the baud rate.
+const UART_CLOCK: u32 = 115_200;
+
+/// Divisor for 57600 baud.
+pub const DEFAULT_DIVISOR: u16 = 0x2;
+
+// Size of the transmit FIFO of a 16550A.
+const TX_FIFO_SIZE: usize = 16;
+
+const RX_BUF_SIZE: usize = 256;
+/// Size of the transmit buffer of each port.
+pub const TX_BUF_SIZE: usize = 4096;
+
+/// The legacy serial ports of a PC.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Port {
+    COM1,
+    COM2,
+    COM3,
+    COM4,
+}
+
+impl Port {
+    pub const ALL: [Port; 4] = [Port::COM1, Port::COM2, Port::COM3, Port::COM4];
+
+    /// Base I/O port address of the serial port.
+    const fn io_port(self) -> u16 {
+        match self {
+            Port::COM1 => 0x3f8,
+            Port::COM2 => 0x2f8,
+            Port::COM3 => 0x3e8,
+            Port::COM4 => 0x2e8,
+        }
+    }
+
+    const fn index(self) -> usize {
+        self as usize
+    }
+}
+
+static COMS: [WithSpinLock<Com>; 4] = [const { WithSpinLock::new(Com::empty()) }; 4];
+
+// Signaled from the ISR when bytes are received, so that blocked readers wake up.
+static RX_READY: [Semaphore; 4] = [const { Semaphore::new(0, 1) }; 4];
+
+// Signaled from the ISR when bytes are moved out of the transmit buffer, so that writers that
+// found it full wake up.
+static TX_SPACE: [Semaphore; 4] = [const { Semaphore::new(0, 1) }; 4];
+
+/// Initializes the serial ports that are present, with a baud rate of `UART_CLOCK / divisor`.
+pub fn init(divisor: u16) {
+    for port in Port::ALL {
+        let mut com = COMS[port.index()].lock();
+        if port != Port::COM1 && !Com::probe(port.io_port()) {
+            continue;
+        }
+        com.init(port.io_port(), divisor);
+    }
 
     // Set New line mode (set LNM).
     // This doesn't seem to work in the QEMU GUI serial console.
-    com.write(b"\x1b[20h");
+    tmp_write_com1(b"\x1b[20h");
+}
+
+/// Returns the divisor for `baud_rate`, or `None` if the UART cannot run at that rate.
+pub fn divisor(baud_rate: u32) -> Option<u16> {
+    if baud_rate == 0 || UART_CLOCK % baud_rate != 0 {
+        return None;
+    }
+    u16::try_from(UART_CLOCK / baud_rate).ok()
+}
+
+/// Returns whether `port` was found and initialized.
+pub fn is_present(port: Port) -> bool {
+    COMS[port.index()].lock().port.is_some()
+}
 
-    unsafe {
-        let mut com1 = COM1.lock();
-        *com1 = com;
+/// Stops using interrupts for COM1 and writes out anything that is still buffered. Writes to COM1
+/// wait for the UART from here on. This is for when interrupts can no longer be relied on, such as
+/// in the panic handler.
+pub fn use_polling() {
+    let mut com1 = COMS[Port::COM1.index()].lock();
+    if com1.port.is_none() {
+        com1.init(Port::COM1.io_port(), DEFAULT_DIVISOR);
     }
+    com1.polled = true;
+    com1.flush();
 }
 
 pub fn tmp_write_com1(buf: &[u8]) {
-    let com1 = unsafe { COM1.lock() };
-    match com1.port {
-        Some(_) => {
-            com1.write_all(buf);
-        }
-        None => {}
-    }
-}
-
-pub fn read_com1() {
-    let mut buf: [u8; 16] = [0; 16];
-    unsafe {
-        let com1 = COM1.lock();
-        match com1.port {
-            Some(_) => {
-                if let Ok(len) = com1.read(&mut buf) {
-                    // TODO: read byte into the driver's buffer instead of writing it out.
-                    //       deciding to write the byte out should be the job of whoever gets the
-                    //       byte from the buffer.
-                    let buf = &buf[0..len];
-                    com1.write_all(buf);
-                }
+    write(Port::COM1, buf);
+}
+
+/// Writes all the bytes in `buf` to `port`. The bytes are buffered and sent in the background.
+/// While the buffer is full, the calling task is blocked until the ISR makes room.
+///
+/// With interrupts disabled, as in ISRs and while a spin lock is held, the ISR cannot make room,
+/// so the bytes that don't fit are dropped. The kernel log keeps what is logged from there.
+pub fn write(port: Port, buf: &[u8]) {
+    // Locking the port disables interrupts, so check before.
+    let can_wait = interrupts_enabled();
+    let mut buf = buf;
+    while !buf.is_empty() {
+        let queued = {
+            let mut com = COMS[port.index()].lock();
+            if com.port.is_none() {
+                return;
+            }
+            com.queue(buf)
+        };
+        buf = &buf[queued..];
+        if buf.is_empty() || !can_wait {
+            return;
+        }
+        TX_SPACE[port.index()].wait();
+    }
+}
+
+/// Reads bytes from `port` into `buf`, and returns the number of bytes read. If no bytes have been
+/// received yet, the calling task is blocked until there are.
+pub fn read(port: Port, buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    loop {
+        let len = try_read(port, buf);
+        if len > 0 {
+            return len;
+        }
+        RX_READY[port.index()].wait();
+    }
+}
+
+/// Reads bytes that have already been received from `port` into `buf`, without blocking. Returns
+/// the number of bytes read.
+pub fn try_read(port: Port, buf: &mut [u8]) -> usize {
+    COMS[port.index()].lock().read(buf)
+}
+
+/// Services interrupts of all serial ports. COM1 and COM3 share IRQ 4, and COM2 and COM4 share
+/// IRQ 3, so we check every port.
+pub fn handle_interrupt() {
+    for port in Port::ALL {
+        let (received, sent) = {
+            let mut com = COMS[port.index()].lock();
+            if com.port.is_none() {
+                continue;
             }
-            None => {}
+            com.handle_interrupt()
+        };
+        if received {
+            RX_READY[port.index()].try_signal();
+        }
+        if sent {
+            TX_SPACE[port.index()].try_signal();
         }
     }
 }
 
 pub struct Com {
     port: Option<u16>,
-    // TODO: Add read and write buffers. Keep in mind that these will be accessed in IRQ context, so
-    //       task switching while manipulating them is a big no-no.
+
+    // Bytes received by the ISR that have not been read yet. Incoming bytes are dropped when this
+    // is full.
+    rx: RingBuffer<RX_BUF_SIZE>,
+
+    // Bytes waiting to be sent.
+    tx: RingBuffer<TX_BUF_SIZE>,
+
+    // Whether writes wait for the UART instead of relying on THRE interrupts, which is only the
+    // case after a panic.
+    polled: bool,
 }
 
 impl Com {
-    fn new(port: u16, divisor: u16) -> Self {
+    const fn empty() -> Self {
+        Self {
+            port: None,
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            polled: false,
+        }
+    }
+
+    /// Returns whether there is a UART at `port`, by checking that the scratch register holds what
+    /// was written to it.
+    fn probe(port: u16) -> bool {
+        unsafe {
+            port::outb(port + REG_SCR, 0xae);
+            port::inb(port + REG_SCR) == 0xae
+        }
+    }
+
+    fn init(&mut self, port: u16, divisor: u16) {
+        self.port = Some(port);
+        self.rx.clear();
+        self.tx.clear();
+        self.polled = false;
+
         // set divisor
-        let com = Self { port: Some(port) };
-        com.outb(1, 0);
-        com.outb(3, 0x80);
-        com.outb(0, divisor as u8);
-        com.outb(1, (divisor >> 8) as u8);
-        com.outb(3, 0x03);
+        self.outb(REG_IER, 0);
+        self.outb(REG_LCR, 0x80);
+        self.outb(REG_DATA, divisor as u8);
+        self.outb(REG_IER, (divisor >> 8) as u8);
+
+        // 8 bits, no parity, one stop bit
+        self.outb(REG_LCR, 0x03);
 
-        // Enable FIFO, clear them, with 14-byte threshold?
-        com.outb(2, 0xc7);
+        // Enable FIFO, clear them, with 14-byte threshold
+        self.outb(REG_FCR, 0xc7);
 
-        // Enable IRQs, RTS/DTS set?
-        com.outb(1, 0b00000111);
-        com.outb(4, 0x0b);
+        // Enable IRQs for received data, an empty transmitter, and receive errors.
+        self.outb(REG_IER, IER_RDA | IER_THRE | IER_RLS);
 
-        com
+        // Set DTR and RTS, and OUT2 which connects the UART's interrupt line.
+        self.outb(REG_MCR, 0x0b);
     }
 
     fn inb(&self, offset: u16) -> u8 {
@@ -92,121 +284,139 @@ impl Com {
     }
 
     fn line_status(&self) -> u8 {
-        self.inb(5)
-    }
-
-    /// Writes `byte` to serial port `self`
-    ///
-    /// ## TODO
-    /// - This should be abstracted as a character device down the road.
-    /// - Add a buffer to store bytes until port is ready for more data. Drop further outgoing data
-    ///   if this buffer is full.
-    fn write_byte(&self, byte: u8) {
-        match byte {
-            b'\n' => {
-                self.outb(0, b'\r');
-                self.outb(0, b'\n');
+        self.inb(REG_LSR)
+    }
+
+    /// Queues as many bytes of `buf` as fit in the transmit buffer, and starts sending them. New
+    /// lines are sent as CR LF. Returns the number of bytes of `buf` queued. A polled port waits
+    /// for the UART instead, and always queues all of `buf`.
+    fn queue(&mut self, buf: &[u8]) -> usize {
+        let mut queued = 0;
+        for &byte in buf {
+            let len = if byte == b'\n' { 2 } else { 1 };
+            if TX_BUF_SIZE - self.tx.len() < len {
+                if !self.polled {
+                    break;
+                }
+                self.flush();
             }
-            _ => {
-                self.outb(0, byte);
+            if byte == b'\n' {
+                self.tx.push(b'\r');
             }
+            self.tx.push(byte);
+            queued += 1;
         }
+        self.start_transmit();
+        if self.polled {
+            self.flush();
+        }
+        queued
     }
 
-    /// Reads a byte from serial port `self`
-    ///
-    /// ## TODO
-    /// - This should be abstracted as a character device down the road.
-    /// - Add a buffer to store bytes until read by this methods. Drop further incoming data if this
-    ///   buffer is full.
-    fn read_byte(&self) -> u8 {
-        self.inb(0) as u8
+    /// Moves buffered bytes into the transmit FIFO if it is empty.
+    fn start_transmit(&mut self) {
+        if self.line_status() & LSR_THRE != 0 {
+            self.fill_fifo();
+        }
     }
 
-    /// Reads bytes from serial port `self` into `buf`. This method reads what is available
-    /// in the serial ports internal buffer, and does not block. This method returns the number of
-    /// bytes read, or an error value if something goes wrong.
-    ///
-    /// # Errors
-    /// An value of core::result::Result::Err(()) will be returned if an unexpected error is encountered.
-    pub fn read(&self, buf: &mut [u8]) -> Result<usize, ()> {
-        let mut len: usize = 0;
-        for b in buf.iter_mut() {
-            if self.line_status() & 0x1 != 1 {
-                break;
+    /// Moves as many buffered bytes as fit into the transmit FIFO. The FIFO must be empty.
+    fn fill_fifo(&mut self) {
+        for _ in 0..TX_FIFO_SIZE {
+            match self.tx.pop() {
+                Some(byte) => self.outb(REG_DATA, byte),
+                None => break,
             }
-            *b = self.read_byte();
-            len += 1;
-        }
-        Ok(len)
-    }
-
-    /// Writes bytes from `buf` into serial port `self`. This method will block until all the data
-    /// in `buf` is written to the serial port. This method returns the number of
-    /// bytes written, or an error value if something goes wrong.
-    ///
-    /// # Errors
-    /// An value of core::result::Result::Err(()) will be returned if an unexpected error is encountered.
-    pub fn write(&self, buf: &[u8]) -> Result<usize, ()> {
-        let mut len: usize = 0;
-        for b in buf {
-            if self.line_status() & 0b100000 != 0b100000 {
-                break;
+        }
+    }
+
+    /// Waits until all buffered bytes have been handed to the UART.
+    fn flush(&mut self) {
+        while !self.tx.is_empty() {
+            while self.line_status() & LSR_THRE == 0 {
+                core::hint::spin_loop();
             }
-            self.write_byte(*b);
-            len += 1;
+            self.fill_fifo();
         }
-        Ok(len)
     }
 
-    /// Writes all the bytes in `buf` to serial port `self`. This method will block and wait if
-    /// necessary.
-    pub fn write_all(&self, buf: &[u8]) -> Result<(), ()> {
-        let mut slice = buf;
-        while slice.len() > 0 {
-            match self.write(slice) {
-                Ok(written) => {
-                    let (_, t) = slice.split_at(written);
-                    slice = t;
-                }
-                Err(_) => return Err(()),
-            };
+    /// Moves received bytes into the receive buffer. Returns whether any bytes were received.
+    fn receive(&mut self) -> bool {
+        let mut received = false;
+        while self.line_status() & LSR_DR != 0 {
+            let byte = self.inb(REG_DATA);
+            self.rx.push(byte);
+            received = true;
         }
-        Ok(())
+        received
     }
-}
 
-impl core::fmt::Write for Com {
-    fn write_str(&mut self, s: &str) -> Result<(), core::fmt::Error> {
-        if let Some(port) = self.port {
-            match self.write_all(s.as_bytes()) {
-                Ok(()) => Ok(()),
-                Err(()) => Err(core::fmt::Error),
+    /// Handles all pending interrupt causes of the UART. Returns whether any bytes were received,
+    /// and whether any were sent from the transmit buffer.
+    fn handle_interrupt(&mut self) -> (bool, bool) {
+        let mut received = false;
+        let mut sent = false;
+        loop {
+            let iir = self.inb(REG_IIR);
+            if iir & IIR_NO_INTERRUPT != 0 {
+                break;
+            }
+            match iir & IIR_ID_MASK {
+                IIR_ID_RLS => {
+                    // Reading LSR clears the error. The byte in error is read and kept like any
+                    // other.
+                    received |= self.receive();
+                }
+                IIR_ID_RDA | IIR_ID_TIMEOUT => {
+                    received |= self.receive();
+                }
+                IIR_ID_THRE => {
+                    // Reading IIR cleared the interrupt.
+                    sent |= !self.tx.is_empty();
+                    self.fill_fifo();
+                }
+                IIR_ID_MODEM_STATUS => {
+                    self.inb(REG_MSR);
+                }
+                _ => break,
             }
-        } else {
-            Err(core::fmt::Error)
         }
+        (received, sent)
+    }
+
+    /// Reads bytes received from serial port `self` into `buf`. This method reads what is
+    /// available in the receive buffer, and does not block. This method returns the number of
+    /// bytes read.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        // Pick up anything the ISR has not gotten to yet.
+        self.receive();
+        self.rx.pop_into(buf)
     }
 }
 
-/// Handle to expose WithSpinLock<Com> to outside of this module for use with write_ln!.
-/// This is just for experimentation. Final interface is to be designed
-// Think about abstraction for singletons (for objects that control a shared hardware resource)
-// (serial, PCI ...)
-// Like, a queue of requests to the singleton
-pub struct Handle<'a> {
-    com1: WithSpinLockGuard<'a, Com>,
+/// Handle to write to a serial port with `write!`. Each write goes to `write()`, so the lines of
+/// other writers may come in between.
+pub struct Handle {
+    port: Port,
 }
 
-impl<'a> Handle<'a> {
+impl Handle {
+    /// Returns a handle to COM1.
     pub fn new() -> Self {
-        let com1 = unsafe { COM1.lock() };
-        Self { com1 }
+        Self::port(Port::COM1)
+    }
+
+    pub fn port(port: Port) -> Self {
+        Self { port }
     }
 }
 
-impl<'a> core::fmt::Write for Handle<'a> {
+impl core::fmt::Write for Handle {
     fn write_str(&mut self, s: &str) -> Result<(), core::fmt::Error> {
-        self.com1.write_str(s)
+        if !is_present(self.port) {
+            return Err(core::fmt::Error);
+        }
+        write(self.port, s.as_bytes());
+        Ok(())
     }
 }
@@ -0,0 +1,272 @@
+//! Virtio devices on PCI, through the modern (virtio 1.0) interface.
+//!
+//! The device describes where its configuration structures are with vendor-specific PCI
+//! capabilities, each pointing into one of its memory BARs. Drivers of device types use
+//! `VirtioPci` to negotiate features and set up virtqueues, and then talk to the device through
+//! the queues.
+
+use crate::arch::x86_64::mm;
+use crate::drivers::pci::{Bar, PCIDevice};
+
+use core::ptr;
+
+mod queue;
+pub(crate) use queue::{Buffer, Virtqueue};
+
+/// Vendor ID of virtio devices.
+pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+/// PCI device ID of the modern interface of a virtio device type.
+pub const fn modern_device_id(device_type: u16) -> u16 {
+    0x1040 + device_type
+}
+
+// Vendor-specific capability, which virtio uses for its structures.
+const CAP_ID_VENDOR: u8 = 0x09;
+
+// Types of virtio capabilities.
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_ISR: u8 = 3;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+// Common configuration structure
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_CONFIG_MSIX_VECTOR: usize = 0x10;
+const COMMON_NUM_QUEUES: usize = 0x12;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_CONFIG_GENERATION: usize = 0x15;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1a;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FEATURES_OK: u8 = 1 << 3;
+const STATUS_FAILED: u8 = 1 << 7;
+
+/// The device follows virtio 1.0 rather than the legacy interface.
+pub const F_VERSION_1: u64 = 1 << 32;
+
+// MSI-X vector number that disables the interrupt.
+const NO_VECTOR: u16 = 0xffff;
+
+// ISR status bit of a used buffer notification.
+const ISR_QUEUE: u8 = 1 << 0;
+
+/// The configuration structures of a virtio device.
+pub struct VirtioPci {
+    // Virtual addresses of the structures mapped from the BARs.
+    common: usize,
+    notify: usize,
+    isr: usize,
+    device: usize,
+
+    // Queue notification addresses are this many bytes apart.
+    notify_off_multiplier: u32,
+}
+
+impl VirtioPci {
+    /// Finds the configuration structures of `pci_dev` and maps them.
+    pub fn new(pci_dev: &PCIDevice) -> Result<VirtioPci, &'static str> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+        let mut notify_off_multiplier = 0;
+
+        for (_, cap) in pci_dev
+            .capabilities()
+            .filter(|(id, _)| *id == CAP_ID_VENDOR)
+        {
+            let cfg_type = (pci_dev.read_config(cap) >> 24) as u8;
+            let bar = pci_dev.read_config(cap + 4) as u8;
+            let offset = pci_dev.read_config(cap + 8) as usize;
+            let length = pci_dev.read_config(cap + 12) as usize;
+            let slot = match cfg_type {
+                CFG_TYPE_COMMON => &mut common,
+                CFG_TYPE_NOTIFY => &mut notify,
+                CFG_TYPE_ISR => &mut isr,
+                CFG_TYPE_DEVICE => &mut device,
+                _ => continue,
+            };
+            // The device may offer a structure more than once, with the preferred one first.
+            if slot.is_some() {
+                continue;
+            }
+            let Some(Bar::Memory { base, size, .. }) = pci_dev.bar(bar as usize) else {
+                continue;
+            };
+            if offset + length > size {
+                return Err("virtio structure is outside its BAR");
+            }
+            *slot = Some(map(base + offset, length));
+            if cfg_type == CFG_TYPE_NOTIFY {
+                notify_off_multiplier = pci_dev.read_config(cap + 16);
+            }
+        }
+
+        Ok(VirtioPci {
+            common: common.ok_or("no common configuration structure")?,
+            notify: notify.ok_or("no notification structure")?,
+            isr: isr.ok_or("no ISR status structure")?,
+            device: device.unwrap_or(0),
+            notify_off_multiplier,
+        })
+    }
+
+    /// Resets the device, which stops it from using its queues.
+    pub fn reset(&self) {
+        unsafe { self.write_common::<u8>(COMMON_DEVICE_STATUS, 0) };
+        // The reset is complete when the status reads back as 0.
+        while unsafe { self.read_common::<u8>(COMMON_DEVICE_STATUS) } != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Resets the device and agrees on the features that both the device and the driver, which
+    /// wants `wanted`, support. Returns the agreed features.
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, &'static str> {
+        self.reset();
+        self.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let offered = unsafe {
+            self.write_common::<u32>(COMMON_DEVICE_FEATURE_SELECT, 0);
+            let low = self.read_common::<u32>(COMMON_DEVICE_FEATURE);
+            self.write_common::<u32>(COMMON_DEVICE_FEATURE_SELECT, 1);
+            let high = self.read_common::<u32>(COMMON_DEVICE_FEATURE);
+            (high as u64) << 32 | low as u64
+        };
+        if offered & F_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err("device does not support virtio 1.0");
+        }
+
+        let features = offered & (wanted | F_VERSION_1);
+        unsafe {
+            self.write_common::<u32>(COMMON_DRIVER_FEATURE_SELECT, 0);
+            self.write_common::<u32>(COMMON_DRIVER_FEATURE, features as u32);
+            self.write_common::<u32>(COMMON_DRIVER_FEATURE_SELECT, 1);
+            self.write_common::<u32>(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+        }
+        self.add_status(STATUS_FEATURES_OK);
+        if unsafe { self.read_common::<u8>(COMMON_DEVICE_STATUS) } & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err("device does not accept the features");
+        }
+        Ok(features)
+    }
+
+    /// Sets up queue `index` with at most `max_size` entries. Its used buffer notifications are
+    /// sent with MSI-X table entry `msix_vector`, or on INTx if MSI-X is not enabled.
+    pub(crate) fn setup_queue(
+        &self,
+        index: u16,
+        max_size: u16,
+        msix_vector: Option<u16>,
+    ) -> Result<Virtqueue, &'static str> {
+        unsafe {
+            if index >= self.read_common::<u16>(COMMON_NUM_QUEUES) {
+                return Err("no such queue");
+            }
+            self.write_common::<u16>(COMMON_QUEUE_SELECT, index);
+            let size = self.read_common::<u16>(COMMON_QUEUE_SIZE).min(max_size);
+            if size == 0 {
+                return Err("queue is not available");
+            }
+            let notify_off = self.read_common::<u16>(COMMON_QUEUE_NOTIFY_OFF) as usize;
+            let notify = self.notify + notify_off * self.notify_off_multiplier as usize;
+            let queue = Virtqueue::new(index, size, notify)?;
+
+            // The size must be a power of two. Smaller queues than the device offers are fine.
+            self.write_common::<u16>(COMMON_QUEUE_SIZE, queue.size());
+            let (desc, driver, device) = queue.addresses();
+            // 64-bit fields are written as two 32-bit halves.
+            for (offset, addr) in [
+                (COMMON_QUEUE_DESC, desc),
+                (COMMON_QUEUE_DRIVER, driver),
+                (COMMON_QUEUE_DEVICE, device),
+            ] {
+                self.write_common::<u32>(offset, addr as u32);
+                self.write_common::<u32>(offset + 4, (addr >> 32) as u32);
+            }
+            if let Some(vector) = msix_vector {
+                self.write_common::<u16>(COMMON_QUEUE_MSIX_VECTOR, vector);
+                if self.read_common::<u16>(COMMON_QUEUE_MSIX_VECTOR) != vector {
+                    return Err("device cannot use the MSI-X vector for the queue");
+                }
+            }
+            self.write_common::<u16>(COMMON_QUEUE_ENABLE, 1);
+            Ok(queue)
+        }
+    }
+
+    /// Turns off configuration change notifications, which we don't handle, when MSI-X is in use.
+    pub fn disable_config_interrupt(&self) {
+        unsafe { self.write_common::<u16>(COMMON_CONFIG_MSIX_VECTOR, NO_VECTOR) }
+    }
+
+    /// Tells the device that the driver is ready, which lets it use the queues.
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Reads and clears the ISR status, and returns whether a queue has used buffers. Only
+    /// meaningful for interrupts on INTx.
+    pub fn queue_interrupt(&self) -> bool {
+        unsafe { ptr::read_volatile(self.isr as *const u8) & ISR_QUEUE != 0 }
+    }
+
+    /// Reads the device-specific configuration with `read`, which is given the address of the
+    /// structure. Reads are retried until the device didn't change the configuration meanwhile.
+    pub fn read_device_config<T>(&self, read: impl Fn(usize) -> T) -> Result<T, &'static str> {
+        if self.device == 0 {
+            return Err("no device configuration structure");
+        }
+        loop {
+            let generation = unsafe { self.read_common::<u8>(COMMON_CONFIG_GENERATION) };
+            let value = read(self.device);
+            if unsafe { self.read_common::<u8>(COMMON_CONFIG_GENERATION) } == generation {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn add_status(&self, status: u8) {
+        unsafe {
+            let current = self.read_common::<u8>(COMMON_DEVICE_STATUS);
+            self.write_common::<u8>(COMMON_DEVICE_STATUS, current | status);
+        }
+    }
+
+    // SAFETY: Caller must ensure that `offset` is the offset of a field of type T.
+    unsafe fn read_common<T>(&self, offset: usize) -> T {
+        ptr::read_volatile((self.common + offset) as *const T)
+    }
+
+    // SAFETY: Caller must ensure that `offset` is the offset of a field of type T.
+    unsafe fn write_common<T>(&self, offset: usize, value: T) {
+        ptr::write_volatile((self.common + offset) as *mut T, value)
+    }
+}
+
+/// Maps the `len` bytes of physical memory at `phys_addr`, and returns their virtual address.
+fn map(phys_addr: usize, len: usize) -> usize {
+    let mut mapper = mm::mapper();
+    let mapper = mapper.as_mut().unwrap();
+    let start = phys_addr & !0xfff;
+    for page in (start..phys_addr + len).step_by(0x1000) {
+        mapper.map_mmio(page);
+    }
+    phys_addr + mm::MMIO_BASE
+}
@@ -0,0 +1,184 @@
+//! Split virtqueues.
+//!
+//! The driver puts chains of descriptors in the available ring, and the device returns them in
+//! the used ring when it is done with them. Each part of the queue is in a page of its own, so that
+//! it is physically contiguous.
+
+use crate::arch::x86_64::mm;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use util::volatile::Volatile;
+
+// Largest queue that fits each part in a page.
+const MAX_QUEUE_SIZE: usize = 256;
+
+// Descriptor flags
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+
+#[repr(C)]
+struct Desc {
+    addr: Volatile<u64>,
+    len: Volatile<u32>,
+    flags: Volatile<u16>,
+    next: Volatile<u16>,
+}
+
+#[repr(C, align(4096))]
+struct DescTable([Desc; MAX_QUEUE_SIZE]);
+
+/// Available ring. The device only looks at the first `size` entries of the ring.
+#[repr(C, align(4096))]
+struct AvailRing {
+    flags: Volatile<u16>,
+    idx: Volatile<u16>,
+    ring: [Volatile<u16>; MAX_QUEUE_SIZE],
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: Volatile<u32>,
+    len: Volatile<u32>,
+}
+
+/// Used ring. The device only writes the first `size` entries of the ring.
+#[repr(C, align(4096))]
+struct UsedRing {
+    flags: Volatile<u16>,
+    idx: Volatile<u16>,
+    ring: [UsedElem; MAX_QUEUE_SIZE],
+}
+
+/// A physically contiguous piece of memory handed to the device.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Buffer {
+    pub(crate) phys_addr: usize,
+    pub(crate) len: usize,
+    /// Written by the device, rather than read.
+    pub(crate) device_writes: bool,
+}
+
+pub(crate) struct Virtqueue {
+    index: u16,
+    size: u16,
+
+    // Virtual address that the queue index is written to, to notify the device.
+    notify: usize,
+
+    desc: Box<DescTable>,
+    avail: Box<AvailRing>,
+    used: Box<UsedRing>,
+
+    // Descriptors that are not in a chain handed to the device.
+    free: Vec<u16>,
+
+    // Token given with the chain that starts at each descriptor.
+    tokens: Vec<usize>,
+
+    // Next entry of the used ring to look at.
+    last_used: u16,
+}
+
+impl Virtqueue {
+    /// Allocates a queue of up to `max_size` entries, rounded down to a power of two.
+    pub(super) fn new(index: u16, max_size: u16, notify: usize) -> Result<Self, &'static str> {
+        let max_size = (max_size as usize).min(MAX_QUEUE_SIZE);
+        let size = 1 << max_size.ilog2();
+        Ok(Virtqueue {
+            index,
+            size,
+            notify,
+            // SAFETY: All-zero bytes are valid rings, and zeroing the memory makes sure that it is
+            //         mapped before we ask for its physical address.
+            desc: unsafe { Box::new_zeroed().assume_init() },
+            avail: unsafe { Box::new_zeroed().assume_init() },
+            used: unsafe { Box::new_zeroed().assume_init() },
+            free: (0..size).rev().collect(),
+            tokens: alloc::vec![0; size as usize],
+            last_used: 0,
+        })
+    }
+
+    pub(crate) fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the physical addresses of the descriptor table, the available ring and the used
+    /// ring.
+    pub(super) fn addresses(&self) -> (usize, usize, usize) {
+        let addr = |p: usize| mm::phys_addr(p).expect("virtqueue memory is mapped");
+        (
+            addr(&raw const *self.desc as usize),
+            addr(&raw const *self.avail as usize),
+            addr(&raw const *self.used as usize),
+        )
+    }
+
+    /// Returns whether a chain of `count` descriptors can be added.
+    pub(crate) fn has_room(&self, count: usize) -> bool {
+        self.free.len() >= count
+    }
+
+    /// Hands `buffers` to the device as one chain, and notifies it. `token` is returned by
+    /// `pop_used()` when the device is done with the chain.
+    pub(crate) fn add(&mut self, buffers: &[Buffer], token: usize) -> Result<(), &'static str> {
+        if buffers.is_empty() || !self.has_room(buffers.len()) {
+            return Err("virtqueue is full");
+        }
+        let descs: Vec<u16> = (0..buffers.len())
+            .map(|_| self.free.pop().unwrap())
+            .collect();
+        for (i, (&d, buffer)) in descs.iter().zip(buffers).enumerate() {
+            let desc = &self.desc.0[d as usize];
+            desc.addr.write(buffer.phys_addr as u64);
+            desc.len.write(buffer.len as u32);
+            let mut flags = 0;
+            if buffer.device_writes {
+                flags |= DESC_F_WRITE;
+            }
+            if let Some(&next) = descs.get(i + 1) {
+                flags |= DESC_F_NEXT;
+                desc.next.write(next);
+            }
+            desc.flags.write(flags);
+        }
+        let head = descs[0];
+        self.tokens[head as usize] = token;
+
+        let idx = self.avail.idx.read();
+        self.avail.ring[(idx % self.size) as usize].write(head);
+        // The device must see the descriptors and the ring entry before the new index.
+        fence(Ordering::SeqCst);
+        self.avail.idx.write(idx.wrapping_add(1));
+        fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile(self.notify as *mut u16, self.index) };
+        Ok(())
+    }
+
+    /// Returns the token of a chain that the device is done with, and the number of bytes that it
+    /// wrote, and frees the descriptors of the chain.
+    pub(crate) fn pop_used(&mut self) -> Option<(usize, u32)> {
+        if self.used.idx.read() == self.last_used {
+            return None;
+        }
+        // Read the ring entry only after seeing the index.
+        fence(Ordering::SeqCst);
+        let elem = &self.used.ring[(self.last_used % self.size) as usize];
+        let (head, len) = (elem.id.read() as u16, elem.len.read());
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut d = head;
+        loop {
+            self.free.push(d);
+            let desc = &self.desc.0[d as usize];
+            if desc.flags.read() & DESC_F_NEXT == 0 {
+                break;
+            }
+            d = desc.next.read();
+        }
+        Some((self.tokens[head as usize], len))
+    }
+}
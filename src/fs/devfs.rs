@@ -0,0 +1,166 @@
+//! Device filesystem.
+//!
+//! The root directory of a devfs has a file for each block device, which reads and writes the
+//! device through the buffer cache, and the character devices `null` and `zero`. Block devices
+//! come and go with their disks. No files can be created.
+
+use crate::drivers::block::{self, cache, BlockDevice, RequestQueue};
+use crate::fs::{DirEntry, FileSystem, FileType, Inode, Stat, NOT_FOUND};
+
+use alloc::string::ToString;
+use alloc::sync::Arc;
+
+const ROOT_INODE: u64 = 1;
+
+// Block devices have inode numbers from this one, in the order their queues were made.
+const FIRST_BLOCK_INODE: u64 = 16;
+
+pub struct DevFs;
+
+/// Mounts a devfs.
+pub fn mount(device: Option<Arc<RequestQueue>>) -> Result<Arc<dyn FileSystem>, &'static str> {
+    if device.is_some() {
+        return Err("devfs does not use a block device");
+    }
+    Ok(Arc::new(DevFs))
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+}
+
+struct Root;
+
+impl Inode for Root {
+    fn stat(&self) -> Stat {
+        Stat {
+            inode: ROOT_INODE,
+            file_type: FileType::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, &'static str> {
+        match name {
+            "null" => Ok(Arc::new(CharDevice::Null)),
+            "zero" => Ok(Arc::new(CharDevice::Zero)),
+            name => match block::get(name) {
+                Some(queue) => Ok(Arc::new(BlockFile(queue))),
+                None => Err(NOT_FOUND),
+            },
+        }
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, &'static str> {
+        let char_devices = [CharDevice::Null, CharDevice::Zero];
+        if let Some(device) = char_devices.get(index) {
+            return Ok(Some(DirEntry {
+                name: device.name().to_string(),
+                inode: device.stat().inode,
+                file_type: FileType::CharDevice,
+            }));
+        }
+        Ok(block::devices()
+            .into_iter()
+            .nth(index - char_devices.len())
+            .map(|(name, queue)| DirEntry {
+                name,
+                inode: FIRST_BLOCK_INODE + queue.id() as u64,
+                file_type: FileType::BlockDevice,
+            }))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, &'static str> {
+        Err("devfs files cannot be created")
+    }
+}
+
+#[derive(Clone, Copy)]
+enum CharDevice {
+    /// Reads nothing, and discards what is written.
+    Null,
+    /// Reads zeros, and discards what is written.
+    Zero,
+}
+
+impl CharDevice {
+    fn name(self) -> &'static str {
+        match self {
+            CharDevice::Null => "null",
+            CharDevice::Zero => "zero",
+        }
+    }
+}
+
+impl Inode for CharDevice {
+    fn stat(&self) -> Stat {
+        Stat {
+            inode: ROOT_INODE + 1 + *self as u64,
+            file_type: FileType::CharDevice,
+            size: 0,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        match self {
+            CharDevice::Null => Ok(0),
+            CharDevice::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        Ok(buf.len())
+    }
+}
+
+/// A block device, which can be read and written at any offset and length within it.
+struct BlockFile(Arc<RequestQueue>);
+
+impl BlockFile {
+    fn size(&self) -> u64 {
+        self.0.sectors() * self.0.sector_size() as u64
+    }
+
+    /// Returns the length of a transfer of `len` bytes from `offset`, cut at the end of the
+    /// device.
+    fn clamp(&self, offset: u64, len: usize) -> usize {
+        let left = self.size().saturating_sub(offset);
+        len.min(usize::try_from(left).unwrap_or(usize::MAX))
+    }
+}
+
+impl Inode for BlockFile {
+    fn stat(&self) -> Stat {
+        Stat {
+            inode: FIRST_BLOCK_INODE + self.0.id() as u64,
+            file_type: FileType::BlockDevice,
+            size: self.size(),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let len = self.clamp(offset, buf.len());
+        if len > 0 {
+            cache::read(&self.0, offset, &mut buf[..len])?;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        let len = self.clamp(offset, buf.len());
+        if len == 0 && !buf.is_empty() {
+            return Err("write is past the end of the device");
+        }
+        cache::write(&self.0, offset, &buf[..len])?;
+        Ok(len)
+    }
+}
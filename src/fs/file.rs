@@ -0,0 +1,198 @@
+//! File descriptors.
+//!
+//! Each task has a table of the files it opened, and a file descriptor is an index in the table of
+//! the current task. The lowest free descriptor is used first. A table is made by the first
+//! `open()` of its task, and is dropped when the task closes its last file, or when the task exits
+//! and the scheduler calls `close_all()` for it.
+
+use crate::fs::{self, Dentry, DirEntry, FileType, Stat, IS_DIRECTORY, NOT_FOUND};
+use crate::kernel::sched::{self, TaskHandle};
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::BitOr;
+
+pub type Fd = usize;
+
+// Open files of each task that has any, by task ID.
+static TABLES: WithSpinLock<BTreeMap<usize, Vec<Option<Arc<File>>>>> =
+    WithSpinLock::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Create a regular file if there is none.
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Truncate a regular file that is opened for writing to 0 bytes.
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// Write at the end of the file, wherever the offset is.
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+
+    pub fn contains(self, flags: OpenFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+struct File {
+    dentry: Arc<Dentry>,
+    flags: OpenFlags,
+    // Offset in bytes, or for a directory the index of the next entry that `readdir()` returns.
+    position: WithSpinLock<u64>,
+}
+
+fn current_task() -> usize {
+    usize::from(sched::current_task())
+}
+
+/// Returns the open file `fd` of the current task.
+fn get(fd: Fd) -> Result<Arc<File>, &'static str> {
+    TABLES
+        .lock()
+        .get(&current_task())
+        .and_then(|files| files.get(fd))
+        .and_then(Option::clone)
+        .ok_or("bad file descriptor")
+}
+
+/// Opens `path`, and returns its file descriptor. Directories can only be opened for reading.
+pub fn open(path: &str, flags: OpenFlags) -> Result<Fd, &'static str> {
+    let dentry = match fs::lookup(path) {
+        Err(NOT_FOUND) if flags.contains(OpenFlags::CREATE) => fs::create(path, FileType::Regular)?,
+        result => result?,
+    };
+    let file_type = dentry.inode().stat().file_type;
+    if file_type == FileType::Directory && flags.contains(OpenFlags::WRITE) {
+        return Err(IS_DIRECTORY);
+    }
+    if file_type == FileType::Regular && flags.contains(OpenFlags::WRITE | OpenFlags::TRUNCATE) {
+        dentry.inode().truncate(0)?;
+    }
+
+    let file = Arc::new(File {
+        dentry,
+        flags,
+        position: WithSpinLock::new(0),
+    });
+    let mut tables = TABLES.lock();
+    let files = tables.entry(current_task()).or_default();
+    match files.iter().position(Option::is_none) {
+        Some(fd) => {
+            files[fd] = Some(file);
+            Ok(fd)
+        }
+        None => {
+            files.push(Some(file));
+            Ok(files.len() - 1)
+        }
+    }
+}
+
+/// Closes `fd`.
+pub fn close(fd: Fd) -> Result<(), &'static str> {
+    let file = {
+        let mut tables = TABLES.lock();
+        let task = current_task();
+        let files = tables.get_mut(&task).ok_or("bad file descriptor")?;
+        let file = files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or("bad file descriptor")?;
+        while files.last().is_some_and(Option::is_none) {
+            files.pop();
+        }
+        if files.is_empty() {
+            tables.remove(&task);
+        }
+        file
+    };
+    // The file is dropped without the tables locked.
+    drop(file);
+    Ok(())
+}
+
+/// Closes the files that `task` still has open. The scheduler calls it when the task exits.
+pub(crate) fn close_all(task: TaskHandle) {
+    let files = TABLES.lock().remove(&usize::from(task));
+    // The files are dropped without the tables locked.
+    drop(files);
+}
+
+/// Reads from `fd` at its offset into `buf`, and moves the offset past the bytes read. Returns
+/// the number of bytes read, which is 0 at the end of the file.
+pub fn read(fd: Fd, buf: &mut [u8]) -> Result<usize, &'static str> {
+    let file = get(fd)?;
+    if !file.flags.contains(OpenFlags::READ) {
+        return Err("file is not open for reading");
+    }
+    let offset = *file.position.lock();
+    let len = file.dentry.inode().read_at(offset, buf)?;
+    *file.position.lock() = offset + len as u64;
+    Ok(len)
+}
+
+/// Writes `buf` to `fd` at its offset, and moves the offset past the bytes written. Returns the
+/// number of bytes written.
+pub fn write(fd: Fd, buf: &[u8]) -> Result<usize, &'static str> {
+    let file = get(fd)?;
+    if !file.flags.contains(OpenFlags::WRITE) {
+        return Err("file is not open for writing");
+    }
+    let inode = file.dentry.inode();
+    let offset = if file.flags.contains(OpenFlags::APPEND) {
+        inode.stat().size
+    } else {
+        *file.position.lock()
+    };
+    let len = inode.write_at(offset, buf)?;
+    *file.position.lock() = offset + len as u64;
+    Ok(len)
+}
+
+/// Moves the offset of `fd`, and returns the new offset. Seeking to 0 restarts `readdir()` on a
+/// directory.
+pub fn seek(fd: Fd, pos: SeekFrom) -> Result<u64, &'static str> {
+    let file = get(fd)?;
+    let mut position = file.position.lock();
+    let new_position = match pos {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::Current(delta) => position.checked_add_signed(delta),
+        SeekFrom::End(delta) => file.dentry.inode().stat().size.checked_add_signed(delta),
+    };
+    *position = new_position.ok_or("invalid offset")?;
+    Ok(*position)
+}
+
+pub fn fstat(fd: Fd) -> Result<Stat, &'static str> {
+    Ok(get(fd)?.dentry.inode().stat())
+}
+
+/// Returns the next entry of the directory `fd`, or `None` after the last one.
+pub fn readdir(fd: Fd) -> Result<Option<DirEntry>, &'static str> {
+    let file = get(fd)?;
+    let index = *file.position.lock();
+    let entry = file.dentry.inode().read_dir(index as usize)?;
+    if entry.is_some() {
+        *file.position.lock() = index + 1;
+    }
+    Ok(entry)
+}
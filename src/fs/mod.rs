@@ -0,0 +1,428 @@
+//! Virtual filesystem.
+//!
+//! Filesystems provide their files through the `FileSystem` and `Inode` traits, and are mounted on
+//! directories of the tree that starts at the filesystem mounted on `/`. Paths are looked up
+//! through dentries, which cache the names found in a directory and record the filesystem mounted
+//! on it, if any. Tasks use files through file descriptors (see `file`).
+//!
+//! A filesystem type is listed in `FILESYSTEMS` with a function that mounts it, given the block
+//! device it lives on if it needs one. The root filesystem is a ramfs with the files of the
+//! initrd, and the device files are in a devfs on `/dev`.
+
+use crate::drivers::block::{self, RequestQueue};
+use crate::kernel::initrd;
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use log::{info, warn};
+
+pub mod devfs;
+mod file;
+pub mod ramfs;
+
+pub(crate) use file::close_all;
+pub use file::{close, fstat, open, read, readdir, seek, write, Fd, OpenFlags, SeekFrom};
+
+pub const NOT_FOUND: &str = "no such file or directory";
+pub const EXISTS: &str = "file exists";
+pub const NOT_DIRECTORY: &str = "not a directory";
+pub const IS_DIRECTORY: &str = "is a directory";
+
+/// Mounts a filesystem, given the block device it lives on.
+type MountFn = fn(Option<Arc<RequestQueue>>) -> Result<Arc<dyn FileSystem>, &'static str>;
+
+/// Filesystem types that `mount()` knows, by name.
+const FILESYSTEMS: [(&str, MountFn); 2] = [("ramfs", ramfs::mount), ("devfs", devfs::mount)];
+
+// Mounted filesystems, in the order they were mounted. The first one is mounted on `/`.
+static MOUNTS: WithSpinLock<Vec<Mount>> = WithSpinLock::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    /// Number of the inode, which is unique within its filesystem.
+    pub inode: u64,
+    pub file_type: FileType,
+    /// Size in bytes, or 0 for directories and character devices.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+/// A mounted filesystem.
+pub trait FileSystem: Send + Sync {
+    /// Returns the name of the filesystem type, as in `FILESYSTEMS`.
+    fn name(&self) -> &'static str;
+
+    /// Returns the root directory.
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+/// A file, directory or device file of a filesystem.
+///
+/// The methods that don't apply to the type of the inode return an error by default. Reads and
+/// writes may transfer fewer bytes than asked for, and reads return 0 at the end of the file.
+pub trait Inode: Send + Sync {
+    fn stat(&self) -> Stat;
+
+    /// Reads from `offset` into `buf`, and returns the number of bytes read.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        Err(IS_DIRECTORY)
+    }
+
+    /// Writes `buf` at `offset`, and returns the number of bytes written.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        Err(IS_DIRECTORY)
+    }
+
+    /// Changes the size of a regular file, filling it with zeros if it grows.
+    fn truncate(&self, size: u64) -> Result<(), &'static str> {
+        Err("file cannot be truncated")
+    }
+
+    /// Returns the inode named `name` in this directory.
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, &'static str> {
+        Err(NOT_DIRECTORY)
+    }
+
+    /// Returns entry `index` of this directory, or `None` if it has fewer entries. `.` and `..`
+    /// are not listed.
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, &'static str> {
+        Err(NOT_DIRECTORY)
+    }
+
+    /// Creates a regular file or a directory named `name` in this directory.
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, &'static str> {
+        Err(NOT_DIRECTORY)
+    }
+}
+
+/// A name in the tree of mounted filesystems.
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+
+    // The directory the dentry is in, or None for the root of a filesystem.
+    parent: Option<Arc<Dentry>>,
+
+    // For the root of a filesystem other than the one on `/`, the dentry it is mounted on.
+    mountpoint: Option<Arc<Dentry>>,
+
+    // Names that were looked up in this directory. The cache doesn't keep them alive: a dentry
+    // lives while an open file, a dentry in it or a mount uses it.
+    children: WithSpinLock<BTreeMap<String, Weak<Dentry>>>,
+
+    // Root of the filesystem mounted on this directory, which hides what is in it.
+    mounted: WithSpinLock<Option<Arc<Dentry>>>,
+}
+
+impl Dentry {
+    fn new(
+        name: String,
+        inode: Arc<dyn Inode>,
+        parent: Option<Arc<Dentry>>,
+        mountpoint: Option<Arc<Dentry>>,
+    ) -> Dentry {
+        Dentry {
+            name,
+            inode,
+            parent,
+            mountpoint,
+            children: WithSpinLock::new(BTreeMap::new()),
+            mounted: WithSpinLock::new(None),
+        }
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    /// Returns the absolute path of the dentry.
+    pub fn path(&self) -> String {
+        let mut names = Vec::new();
+        let mut dentry = self;
+        loop {
+            if let Some(parent) = &dentry.parent {
+                names.push(dentry.name.as_str());
+                dentry = parent;
+            } else if let Some(mountpoint) = &dentry.mountpoint {
+                dentry = mountpoint;
+            } else {
+                break;
+            }
+        }
+        if names.is_empty() {
+            return "/".to_string();
+        }
+        names.iter().rev().flat_map(|name| ["/", *name]).collect()
+    }
+
+    /// Returns the dentry that paths through this one lead to: the root of the filesystem mounted
+    /// on it last, if any.
+    fn followed(dentry: Arc<Dentry>) -> Arc<Dentry> {
+        let mut dentry = dentry;
+        loop {
+            let mounted = dentry.mounted.lock().clone();
+            match mounted {
+                Some(root) => dentry = root,
+                None => return dentry,
+            }
+        }
+    }
+
+    /// Returns the directory that `..` leads to from this one. `..` of `/` is `/`.
+    fn parent_dir(self: &Arc<Self>) -> Arc<Dentry> {
+        let mut dentry = self.clone();
+        // `..` of the root of a mounted filesystem is `..` of its mount point.
+        while dentry.parent.is_none() {
+            match dentry.mountpoint.clone() {
+                Some(mountpoint) => dentry = mountpoint,
+                None => return dentry,
+            }
+        }
+        dentry.parent.clone().unwrap()
+    }
+
+    /// Returns the root dentry of the filesystem this dentry is in.
+    fn fs_root(self: &Arc<Self>) -> Arc<Dentry> {
+        let mut dentry = self.clone();
+        while let Some(parent) = dentry.parent.clone() {
+            dentry = parent;
+        }
+        dentry
+    }
+
+    /// Returns the dentry named `name` in this directory, looking it up if it isn't cached.
+    fn child(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, &'static str> {
+        if let Some(child) = self.children.lock().get(name).and_then(Weak::upgrade) {
+            return Ok(Dentry::followed(child));
+        }
+        // The filesystem may have to read its device, so the cache isn't locked meanwhile.
+        let inode = self.inode.lookup(name)?;
+        Ok(Dentry::followed(self.add_child(name, inode)))
+    }
+
+    /// Creates a file or directory named `name` in this directory.
+    fn create(
+        self: &Arc<Self>,
+        name: &str,
+        file_type: FileType,
+    ) -> Result<Arc<Dentry>, &'static str> {
+        let inode = self.inode.create(name, file_type)?;
+        Ok(self.add_child(name, inode))
+    }
+
+    /// Adds a dentry for `inode` to the cache, unless another task did meanwhile. Returns the
+    /// cached dentry.
+    fn add_child(self: &Arc<Self>, name: &str, inode: Arc<dyn Inode>) -> Arc<Dentry> {
+        let mut children = self.children.lock();
+        if let Some(child) = children.get(name).and_then(Weak::upgrade) {
+            return child;
+        }
+        let child = Arc::new(Dentry::new(
+            name.to_string(),
+            inode,
+            Some(self.clone()),
+            None,
+        ));
+        children.insert(name.to_string(), Arc::downgrade(&child));
+        // Drop the names of dentries that are no longer used.
+        children.retain(|_, child| child.strong_count() > 0);
+        child
+    }
+}
+
+struct Mount {
+    fs: Arc<dyn FileSystem>,
+    // Name of the block device the filesystem is on.
+    source: Option<String>,
+    root: Arc<Dentry>,
+}
+
+/// Returns the root of the filesystem on `/`.
+fn root() -> Result<Arc<Dentry>, &'static str> {
+    MOUNTS
+        .lock()
+        .first()
+        .map(|mount| mount.root.clone())
+        .ok_or("no filesystem is mounted on /")
+}
+
+/// Returns the dentry of `path`, following mount points. Tasks have no working directory, so
+/// paths are looked up from `/` whether or not they start with it.
+pub fn lookup(path: &str) -> Result<Arc<Dentry>, &'static str> {
+    let mut dentry = Dentry::followed(root()?);
+    for name in path.split('/') {
+        dentry = match name {
+            "" | "." => continue,
+            ".." => Dentry::followed(dentry.parent_dir()),
+            name => dentry.child(name)?,
+        };
+    }
+    Ok(dentry)
+}
+
+/// Splits `path` into the path of its directory and its last name, which must be a name that
+/// can be created.
+fn split_path(path: &str) -> Result<(&str, &str), &'static str> {
+    let path = path.trim_end_matches('/');
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    match name {
+        "" | "." | ".." => Err(EXISTS),
+        name => Ok((dir, name)),
+    }
+}
+
+/// Creates a file or directory at `path`, whose directory must exist.
+fn create(path: &str, file_type: FileType) -> Result<Arc<Dentry>, &'static str> {
+    let (dir, name) = split_path(path)?;
+    let dir = lookup(dir)?;
+    match dir.child(name) {
+        Ok(_) => Err(EXISTS),
+        Err(NOT_FOUND) => dir.create(name, file_type),
+        Err(e) => Err(e),
+    }
+}
+
+/// Creates the directory `path`.
+pub fn mkdir(path: &str) -> Result<(), &'static str> {
+    create(path, FileType::Directory).map(|_| ())
+}
+
+pub fn stat(path: &str) -> Result<Stat, &'static str> {
+    Ok(lookup(path)?.inode.stat())
+}
+
+/// Mounts a filesystem of type `fs_type` on the directory `path`, which hides what is in the
+/// directory until it is unmounted. `source` names the block device of filesystems that live
+/// on one. The first filesystem must be mounted on `/`.
+pub fn mount(fs_type: &str, source: Option<&str>, path: &str) -> Result<(), &'static str> {
+    let (_, mount_fn) = FILESYSTEMS
+        .iter()
+        .find(|(name, _)| *name == fs_type)
+        .ok_or("unknown filesystem type")?;
+    let device = match source {
+        Some(name) => Some(block::get(name).ok_or("no such block device")?),
+        None => None,
+    };
+    let fs = mount_fn(device)?;
+
+    let has_root = !MOUNTS.lock().is_empty();
+    let root = if has_root {
+        let mountpoint = lookup(path)?;
+        if mountpoint.inode.stat().file_type != FileType::Directory {
+            return Err(NOT_DIRECTORY);
+        }
+        let root = Arc::new(Dentry::new(
+            String::new(),
+            fs.root(),
+            None,
+            Some(mountpoint.clone()),
+        ));
+        let mut mounted = mountpoint.mounted.lock();
+        if mounted.is_some() {
+            return Err("a filesystem was mounted there meanwhile");
+        }
+        *mounted = Some(root.clone());
+        root
+    } else if path.trim_matches('/').is_empty() {
+        Arc::new(Dentry::new(String::new(), fs.root(), None, None))
+    } else {
+        return Err("no filesystem is mounted on /");
+    };
+    info!("Mounted {} on {}", fs.name(), root.path());
+    MOUNTS.lock().push(Mount {
+        fs,
+        source: source.map(String::from),
+        root,
+    });
+    Ok(())
+}
+
+/// Unmounts the filesystem mounted last on `path`, which must not have filesystems mounted in it.
+/// Files that are open in it can still be used. The filesystem on `/` cannot be unmounted.
+pub fn unmount(path: &str) -> Result<(), &'static str> {
+    let root = lookup(path)?;
+    let Some(mountpoint) = &root.mountpoint else {
+        return Err(if root.parent.is_none() {
+            "cannot unmount /"
+        } else {
+            "not a mount point"
+        });
+    };
+    let mut mounts = MOUNTS.lock();
+    let busy = mounts.iter().any(|mount| {
+        mount
+            .root
+            .mountpoint
+            .as_ref()
+            .is_some_and(|dentry| Arc::ptr_eq(&dentry.fs_root(), &root))
+    });
+    if busy {
+        return Err("a filesystem is mounted in it");
+    }
+    mounts.retain(|mount| !Arc::ptr_eq(&mount.root, &root));
+    *mountpoint.mounted.lock() = None;
+    Ok(())
+}
+
+/// Returns the path, filesystem type and block device of each mounted filesystem.
+pub fn mounts() -> Vec<(String, &'static str, Option<String>)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (mount.root.path(), mount.fs.name(), mount.source.clone()))
+        .collect()
+}
+
+/// Mounts a ramfs with the files of the initrd on `/`, and a devfs on `/dev`. The initrd is
+/// released once its files are copied.
+pub fn init() {
+    mount("ramfs", None, "/").expect("cannot mount the root filesystem");
+    if let Some(archive) = initrd::archive() {
+        match copy_initrd(archive) {
+            // SAFETY: The files were copied to the heap, and nothing else uses the archive.
+            Ok(()) => unsafe { initrd::release() },
+            Err(e) => warn!("Cannot copy the initrd to /: {}", e),
+        }
+    }
+    let result = match mkdir("/dev") {
+        Ok(()) | Err(EXISTS) => mount("devfs", None, "/dev"),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warn!("Cannot mount devfs on /dev: {}", e);
+    }
+}
+
+/// Creates the directories and regular files of the initrd. Other entries are skipped.
+fn copy_initrd(archive: util::cpio::Archive) -> Result<(), &'static str> {
+    for entry in archive.entries() {
+        let entry = entry.map_err(|_| "initrd is not a valid cpio archive")?;
+        if entry.is_dir() {
+            match mkdir(entry.name) {
+                Ok(()) | Err(EXISTS) => {}
+                Err(e) => return Err(e),
+            }
+        } else if entry.is_file() {
+            let dentry = create(entry.name, FileType::Regular)?;
+            dentry.inode.write_at(0, entry.data)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,156 @@
+//! In-memory filesystem.
+//!
+//! Files and directories of a ramfs are kept on the heap, and are lost when the machine resets.
+//! The filesystem on `/` is a ramfs.
+
+use crate::drivers::block::RequestQueue;
+use crate::fs::{
+    DirEntry, FileSystem, FileType, Inode, Stat, EXISTS, IS_DIRECTORY, NOT_DIRECTORY, NOT_FOUND,
+};
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Inode numbers are unique among all ramfs instances.
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+pub struct RamFs {
+    root: Arc<RamInode>,
+}
+
+/// Mounts a new, empty ramfs.
+pub fn mount(device: Option<Arc<RequestQueue>>) -> Result<Arc<dyn FileSystem>, &'static str> {
+    if device.is_some() {
+        return Err("ramfs does not use a block device");
+    }
+    Ok(Arc::new(RamFs {
+        root: RamInode::new(FileType::Directory),
+    }))
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+struct RamInode {
+    inode: u64,
+    contents: WithSpinLock<Contents>,
+}
+
+enum Contents {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<RamInode>>),
+}
+
+impl RamInode {
+    fn new(file_type: FileType) -> Arc<RamInode> {
+        let contents = match file_type {
+            FileType::Directory => Contents::Directory(BTreeMap::new()),
+            _ => Contents::File(Vec::new()),
+        };
+        Arc::new(RamInode {
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            contents: WithSpinLock::new(contents),
+        })
+    }
+
+    fn file_type(&self) -> FileType {
+        match *self.contents.lock() {
+            Contents::File(_) => FileType::Regular,
+            Contents::Directory(_) => FileType::Directory,
+        }
+    }
+}
+
+impl Inode for RamInode {
+    fn stat(&self) -> Stat {
+        let (file_type, size) = match &*self.contents.lock() {
+            Contents::File(data) => (FileType::Regular, data.len() as u64),
+            Contents::Directory(_) => (FileType::Directory, 0),
+        };
+        Stat {
+            inode: self.inode,
+            file_type,
+            size,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let Contents::File(data) = &*self.contents.lock() else {
+            return Err(IS_DIRECTORY);
+        };
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        let Contents::File(data) = &mut *self.contents.lock() else {
+            return Err(IS_DIRECTORY);
+        };
+        let start = usize::try_from(offset).map_err(|_| "file is too large")?;
+        let end = start.checked_add(buf.len()).ok_or("file is too large")?;
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), &'static str> {
+        let Contents::File(data) = &mut *self.contents.lock() else {
+            return Err(IS_DIRECTORY);
+        };
+        data.resize(usize::try_from(size).map_err(|_| "file is too large")?, 0);
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, &'static str> {
+        let Contents::Directory(entries) = &*self.contents.lock() else {
+            return Err(NOT_DIRECTORY);
+        };
+        match entries.get(name) {
+            Some(inode) => Ok(inode.clone()),
+            None => Err(NOT_FOUND),
+        }
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, &'static str> {
+        let Contents::Directory(entries) = &*self.contents.lock() else {
+            return Err(NOT_DIRECTORY);
+        };
+        Ok(entries.iter().nth(index).map(|(name, inode)| DirEntry {
+            name: name.clone(),
+            inode: inode.inode,
+            file_type: inode.file_type(),
+        }))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, &'static str> {
+        if !matches!(file_type, FileType::Regular | FileType::Directory) {
+            return Err("ramfs cannot hold device files");
+        }
+        let Contents::Directory(entries) = &mut *self.contents.lock() else {
+            return Err(NOT_DIRECTORY);
+        };
+        if entries.contains_key(name) {
+            return Err(EXISTS);
+        }
+        let inode = RamInode::new(file_type);
+        entries.insert(name.to_string(), inode.clone());
+        Ok(inode)
+    }
+}
@@ -76,6 +76,22 @@ pub fn tick_fn() -> fn(u64) {
     }
 }
 
+/// Returns the time in nanoseconds from the clock source, or 0 if the clock is not initialized yet.
+pub fn get_time() -> u64 {
+    match unsafe { CLOCK.get().as_ref() }.and_then(Option::as_ref) {
+        Some(clock) => clock.clocksource.get_tick(),
+        None => 0,
+    }
+}
+
+/// Like `get_time()`, but returns `None` instead of waiting if the clock source is locked.
+pub fn try_get_time() -> Option<u64> {
+    match unsafe { CLOCK.get().as_ref() }.and_then(Option::as_ref) {
+        Some(clock) => clock.clocksource.try_get_tick(),
+        None => Some(0),
+    }
+}
+
 /// Temporary API to sleep in a busy-loop.
 pub fn sleep(ms: u64) {
     let clock = unsafe { CLOCK.get().as_mut().expect("null pointer in UnsafeCell") };
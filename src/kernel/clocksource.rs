@@ -1,3 +1,9 @@
 pub trait ClockSource {
     fn get_tick(&self) -> u64;
+
+    /// Like `get_tick()`, but returns `None` instead of waiting if the clock source is locked,
+    /// such as when called from its tick handler.
+    fn try_get_tick(&self) -> Option<u64> {
+        Some(self.get_tick())
+    }
 }
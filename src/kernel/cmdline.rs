@@ -0,0 +1,44 @@
+//! Boot options from the kernel command line.
+//!
+//! The bootloader passes the command line of the selected boot entry in `BootData`. Subsystems
+//! read their options from here as they are initialized.
+
+use crate::locking::spinlock::WithSpinLock;
+
+use core::str::FromStr;
+use log::warn;
+use util::cmdline::CommandLine;
+
+static CMDLINE: WithSpinLock<CommandLine<'static>> = WithSpinLock::new(CommandLine::new(""));
+
+/// Sets the command line. `cmdline` is in memory that stays mapped and is never freed, as is the
+/// case for the one in `BootData`.
+pub fn init(cmdline: &'static str) {
+    *CMDLINE.lock() = CommandLine::new(cmdline);
+}
+
+/// Returns the command line.
+pub fn get() -> CommandLine<'static> {
+    *CMDLINE.lock()
+}
+
+/// Returns the value of `key` parsed as `T`. Values that cannot be parsed are ignored with a
+/// warning.
+pub fn option<T: FromStr>(key: &str) -> Option<T> {
+    match get().parse(key)? {
+        Ok(value) => Some(value),
+        Err(value) => {
+            warn!("Ignoring invalid value for {}: {:?}", key, value);
+            None
+        }
+    }
+}
+
+/// Returns whether the flag `key` is set. Values that are not a boolean are ignored with a
+/// warning.
+pub fn flag(key: &str) -> bool {
+    get().flag(key).unwrap_or_else(|value| {
+        warn!("Ignoring invalid value for {}: {:?}", key, value);
+        false
+    })
+}
@@ -0,0 +1,80 @@
+//! Initial ramdisk loaded by the bootloader.
+//!
+//! The initrd is a cpio archive in the newc format. Its pages are mapped at `MMIO_BASE`, and are
+//! kept out of the page allocator until `release()` is called, which `fs::init()` does once it has
+//! copied the files to `/`.
+
+use crate::arch::x86_64::mm::{self, MMIO_BASE};
+use crate::locking::spinlock::WithSpinLock;
+
+use core::ptr::slice_from_raw_parts;
+use log::{info, warn};
+use util::cpio::Archive;
+
+const PAGE_SIZE: usize = 0x1000;
+
+static INITRD: WithSpinLock<Option<Initrd>> = WithSpinLock::new(None);
+
+#[derive(Clone, Copy)]
+struct Initrd {
+    phys_addr: usize,
+    len: usize,
+}
+
+impl Initrd {
+    // Pages that hold the initrd.
+    fn pages(&self) -> impl Iterator<Item = usize> {
+        (self.phys_addr..self.phys_addr + self.len).step_by(PAGE_SIZE)
+    }
+}
+
+/// Maps the initrd at `phys_addr`, which the bootloader loaded into pages of
+/// `bootlib::types::INITRD_MEMORY_TYPE`.
+pub fn init(phys_addr: usize, len: usize) {
+    let initrd = Initrd { phys_addr, len };
+    {
+        let mut mapper = mm::mapper();
+        let mapper = mapper.as_mut().expect("memory manager is not initialized");
+        for page in initrd.pages() {
+            mapper.map_mmio(page);
+        }
+    }
+    *INITRD.lock() = Some(initrd);
+    info!("Initrd at {:#x}, {} bytes", phys_addr, len);
+}
+
+/// Returns the contents of the initrd, or `None` if there is none or it has been released.
+pub fn data() -> Option<&'static [u8]> {
+    let initrd = (*INITRD.lock())?;
+    let ptr = (initrd.phys_addr + MMIO_BASE) as *const u8;
+    // SAFETY: The pages are mapped in `init()` and stay mapped until `release()`, whose caller
+    //         makes sure that the slice is no longer used.
+    Some(unsafe { &*slice_from_raw_parts(ptr, initrd.len) })
+}
+
+/// Returns the initrd as a cpio archive.
+pub fn archive() -> Option<Archive<'static>> {
+    data().map(Archive::new)
+}
+
+/// Unmaps the initrd and gives its pages to the page allocator.
+///
+/// # Safety
+/// Nothing returned by `data()` or `archive()`, or borrowed from them, may be used after this.
+pub unsafe fn release() {
+    let Some(initrd) = INITRD.lock().take() else {
+        return;
+    };
+    let mut mapper = mm::mapper();
+    let mapper = mapper.as_mut().expect("memory manager is not initialized");
+    for page in initrd.pages() {
+        // The pages cannot be reused while any of them is still mapped.
+        if let Err(e) = mapper.unmap_mmio(page) {
+            warn!("Failed to unmap initrd page {:#x}: {:?}", page, e);
+            return;
+        }
+    }
+    let size = initrd.len.next_multiple_of(PAGE_SIZE);
+    mapper.free_pages(initrd.phys_addr, size);
+    info!("Released initrd, {} KiB freed", size / 1024);
+}
@@ -0,0 +1,243 @@
+//! Kernel logger for the `log` crate.
+//!
+//! Records are tagged with a timestamp, level, the current task and the module they come from.
+//! They are kept in an in-memory ring buffer, which can be dumped with `dmesg`, and are written
+//! to COM1 and the framebuffer console.
+
+use crate::drivers::serial::Port;
+use crate::drivers::{framebuffer, serial};
+use crate::kernel::clock;
+use crate::kernel::sched;
+use crate::locking::spinlock::WithSpinLock;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::Relaxed;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use util::ring_buffer::RingBuffer;
+
+// Size of the in-memory log. The oldest records are dropped when it is full.
+const LOG_BUF_SIZE: usize = 64 * 1024;
+
+// Longest record we write. Longer records are truncated.
+const MAX_RECORD_LEN: usize = 256;
+
+// Module paths of this crate start with this, which we leave out of records and filters.
+const CRATE_PREFIX: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+
+static LOGGER: Logger = Logger;
+
+static LOG_BUF: WithSpinLock<RingBuffer<LOG_BUF_SIZE>> = WithSpinLock::new(RingBuffer::new());
+
+static FILTER: WithSpinLock<Filter> = WithSpinLock::new(Filter::new(LevelFilter::Info));
+
+// Timestamp of the last record, used when the clock cannot be read without deadlocking.
+static LAST_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+/// Installs the logger. Records below `level` are discarded, unless a module's level is set lower
+/// with `set_module_level()`.
+pub fn init(level: LevelFilter) {
+    FILTER.lock().default = level;
+    update_max_level();
+    log::set_logger(&LOGGER).expect("logger is already set");
+}
+
+/// Sets the level of records that are logged from modules without a level of their own.
+pub fn set_level(level: LevelFilter) {
+    FILTER.lock().default = level;
+    update_max_level();
+}
+
+/// Sets the level of records that are logged from `module` and its submodules, such as
+/// `drivers::net`. The level of the longest matching module applies.
+pub fn set_module_level(module: &str, level: LevelFilter) {
+    let module = module.strip_prefix(CRATE_PREFIX).unwrap_or(module);
+    {
+        let mut filter = FILTER.lock();
+        match filter.modules.iter_mut().find(|(m, _)| m == module) {
+            Some((_, l)) => *l = level,
+            None => filter.modules.push((String::from(module), level)),
+        }
+    }
+    update_max_level();
+}
+
+/// Returns the default level, and the modules with a level of their own.
+pub fn levels() -> (LevelFilter, Vec<(String, LevelFilter)>) {
+    let filter = FILTER.lock();
+    (filter.default, filter.modules.clone())
+}
+
+/// Returns a copy of the log, oldest record first.
+pub fn read_buffer() -> Vec<u8> {
+    let log_buf = LOG_BUF.lock();
+    let (first, second) = complete_records(&log_buf);
+    let mut buf = Vec::with_capacity(first.len() + second.len());
+    buf.extend_from_slice(first);
+    buf.extend_from_slice(second);
+    buf
+}
+
+/// Writes the log to COM1 from the panic handler. Nothing is written if the log is locked, which
+/// happens when we panicked while logging.
+pub fn dump_after_panic() {
+    let Some(log_buf) = LOG_BUF.try_lock() else {
+        serial::write(Port::COM1, b"dmesg: log is locked\n");
+        return;
+    };
+    let (first, second) = complete_records(&log_buf);
+    serial::write(Port::COM1, b"---- dmesg ----\n");
+    serial::write(Port::COM1, first);
+    serial::write(Port::COM1, second);
+    serial::write(Port::COM1, b"---- end of dmesg ----\n");
+}
+
+/// Returns the contents of `log_buf` like `RingBuffer::as_slices()`, leaving out the record at
+/// the start if it was partially overwritten.
+fn complete_records(log_buf: &RingBuffer<LOG_BUF_SIZE>) -> (&[u8], &[u8]) {
+    let (first, second) = log_buf.as_slices();
+    if !log_buf.is_full() {
+        return (first, second);
+    }
+    match first.iter().position(|b| *b == b'\n') {
+        Some(i) => (&first[i + 1..], second),
+        None => {
+            let i = second.iter().position(|b| *b == b'\n').map_or(0, |i| i + 1);
+            (&[], &second[i..])
+        }
+    }
+}
+
+fn update_max_level() {
+    let filter = FILTER.lock();
+    let max = filter
+        .modules
+        .iter()
+        .map(|(_, level)| *level)
+        .fold(filter.default, Ord::max);
+    log::set_max_level(max);
+}
+
+struct Filter {
+    // Level of modules that don't have one of their own.
+    default: LevelFilter,
+
+    // Module paths without the crate name, and their levels.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    const fn new(default: LevelFilter) -> Self {
+        Self {
+            default,
+            modules: Vec::new(),
+        }
+    }
+
+    /// Returns the level of records from `module`.
+    fn level(&self, module: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(m, _)| {
+                module
+                    .strip_prefix(m.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(m, _)| m.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let module = metadata.target();
+        let module = module.strip_prefix(CRATE_PREFIX).unwrap_or(module);
+        metadata.level() <= FILTER.lock().level(module)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // The clock source is locked while its tick handler runs, so records logged from there get
+        // the timestamp of the record before them.
+        let timestamp = match clock::try_get_time() {
+            Some(t) => {
+                LAST_TIMESTAMP.fetch_max(t, Relaxed);
+                t
+            }
+            None => LAST_TIMESTAMP.load(Relaxed),
+        };
+        let module = record.target();
+        let module = module.strip_prefix(CRATE_PREFIX).unwrap_or(module);
+
+        let (color, reset) = match record.level() {
+            Level::Error => ("\x1b[31m", "\x1b[0m"),
+            Level::Warn => ("\x1b[33m", "\x1b[0m"),
+            _ => ("", ""),
+        };
+
+        let mut line = RecordBuffer::new();
+        writeln!(
+            line,
+            "[{:5}.{:06}] {}{:5}{} task {} {}: {}",
+            timestamp / 1_000_000_000,
+            timestamp % 1_000_000_000 / 1_000,
+            color,
+            record.level(),
+            reset,
+            sched::current_task(),
+            module,
+            record.args(),
+        );
+        let line = line.as_bytes();
+
+        {
+            let mut log_buf = LOG_BUF.lock();
+            for b in line {
+                log_buf.push_overwrite(*b);
+            }
+        }
+        serial::write(Port::COM1, line);
+        framebuffer::write(line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Fixed-size buffer a record is formatted into, so that logging doesn't allocate.
+struct RecordBuffer {
+    buf: [u8; MAX_RECORD_LEN],
+    len: usize,
+}
+
+impl RecordBuffer {
+    fn new() -> Self {
+        Self {
+            buf: [0; MAX_RECORD_LEN],
+            len: 0,
+        }
+    }
+
+    /// Returns the record. Truncated records still end with a new line.
+    fn as_bytes(&mut self) -> &[u8] {
+        if self.len == MAX_RECORD_LEN {
+            self.buf[MAX_RECORD_LEN - 1] = b'\n';
+        }
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for RecordBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(MAX_RECORD_LEN - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
@@ -1,4 +1,9 @@
 pub(crate) mod clock;
 pub(crate) mod clocksource;
+pub(crate) mod cmdline;
+pub(crate) mod initrd;
+pub(crate) mod logger;
+pub(crate) mod symbols;
 
 pub(crate) mod sched;
+pub(crate) mod shell;
@@ -1,9 +1,10 @@
-use crate::arch::x86_64::hpet;
 use crate::arch::x86_64::interrupt::{disable_interrupts, enable_interrupts, interrupts_enabled};
+use crate::fs;
 use crate::kernel::clock;
 use crate::kernel::sched::task::TaskList;
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::ffi::c_void;
 use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
@@ -13,6 +14,7 @@ mod task;
 pub(crate) use crate::some_task;
 pub(crate) use task::current_task;
 pub(crate) use task::TaskHandle;
+pub(crate) use task::TaskStatus;
 
 const SCHED_LATENCY: u64 = 20_000_000; // 20 ms.
 
@@ -67,9 +69,7 @@ impl Drop for SchedulerGuard<'_> {
 
 impl<'a> SchedulerGuard<'a> {
     pub(crate) fn switch(mut self) {
-        // Hardcode use of HPET for now.
-        // TODO: abstract clocksources and get time from the trait object.
-        let now = hpet::get_time();
+        let now = clock::get_time();
 
         let switch_from = current_task();
 
@@ -128,11 +128,25 @@ impl<'a> SchedulerGuard<'a> {
         }
     }
 
+    /// Returns the scheduling state of every task.
+    pub(crate) fn tasks(&self) -> Vec<TaskStatus> {
+        self.task_list.statuses()
+    }
+
     /// Wakes the specified Task.
     pub(crate) fn wake(&mut self, task: TaskHandle) {
         self.task_list.set_runnable(task, true);
     }
 
+    /// Ends the currently running Task, and closes the files it has open. Its kernel stack is
+    /// not freed, because we are still running on it.
+    pub(crate) fn exit(mut self) -> ! {
+        fs::close_all(current_task());
+        self.task_list.set_exited(current_task());
+        self.switch();
+        unreachable!("an exited task was scheduled");
+    }
+
     /// Blocks the currently running Task.
     pub(crate) fn block(mut self) {
         self.task_list.set_runnable(current_task(), false);
@@ -159,7 +173,7 @@ pub(crate) fn lock() -> SchedulerGuard<'static> {
 
 #[unsafe(no_mangle)]
 extern "C" fn check_runtime() {
-    let now = hpet::get_time();
+    let now = clock::get_time();
     let scheduler = lock();
     if scheduler.task_list.get_run_until(current_task()) <= now {
         scheduler.switch()
@@ -1,6 +1,6 @@
-use crate::arch::x86_64::hpet;
 use crate::arch::x86_64::interrupt::interrupts_enabled;
 use crate::arch::x86_64::mm::mapper;
+use crate::kernel::clock;
 use crate::kernel::sched::SchedulerGuard;
 use crate::kernel::sched::{Scheduler, SCHED_LATENCY};
 use crate::some_task;
@@ -8,6 +8,7 @@ use crate::some_task;
 use alloc::alloc::alloc;
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, BinaryHeap};
+use alloc::vec::Vec;
 
 use core::alloc::Layout;
 use core::arch::asm;
@@ -115,12 +116,26 @@ impl TaskList {
             .tasks
             .get_mut(&id.into())
             .expect("Task with issued handle must exist");
+        // An exited task never runs again, even if something it waited for wakes it.
+        if task.info.flags.is_exited() {
+            return;
+        }
         task.info.flags.set_is_runnable(runnable);
         if runnable {
             self.schedulable.push(task.info)
         }
     }
 
+    /// Marks the task as exited, so that it is never scheduled again.
+    pub fn set_exited(&mut self, id: TaskHandle) {
+        self.set_runnable(id, false);
+        let task = self
+            .tasks
+            .get_mut(&id.into())
+            .expect("Task with issued handle must exist");
+        task.info.flags.set_is_exited();
+    }
+
     pub fn next(&mut self) -> Option<TaskHandle> {
         let next = self.schedulable.pop()?;
         Some(TaskHandle(next.task_id))
@@ -194,8 +209,7 @@ impl TaskList {
             );
         }
 
-        // TODO: abstract clocksources and get time from the trait object.
-        let now = hpet::get_time();
+        let now = clock::get_time();
 
         idle_task_stack.info = TaskInfo {
             task_id: 0,
@@ -215,6 +229,20 @@ impl TaskList {
         self.current = Some(id);
     }
 
+    /// Returns the scheduling state of every task, ordered by task ID.
+    pub fn statuses(&self) -> Vec<TaskStatus> {
+        self.tasks
+            .values()
+            .map(|task| TaskStatus {
+                handle: task.get_handle(),
+                total_runtime: task.info.total_runtime,
+                runnable: task.info.flags.is_runnable(),
+                exited: task.info.flags.is_exited(),
+                current: self.current == Some(task.info.task_id),
+            })
+            .collect()
+    }
+
     fn create_task(&mut self, mut kernel_stack: Box<Task>) -> usize {
         let id = self.next_task_id;
         kernel_stack.info.task_id = self.next_task_id;
@@ -285,6 +313,14 @@ impl TaskFlags {
             false => self.0 &= !1,
         }
     }
+
+    fn is_exited(&self) -> bool {
+        (self.0 & 2) != 0
+    }
+
+    fn set_is_exited(&mut self) {
+        self.0 |= 2;
+    }
 }
 
 /// Kernel context registers for saving when context switching.
@@ -299,6 +335,16 @@ struct Registers {
     cr3: usize,
 }
 
+/// Snapshot of the scheduling state of a task.
+#[derive(Copy, Clone)]
+pub(crate) struct TaskStatus {
+    pub(crate) handle: TaskHandle,
+    pub(crate) total_runtime: u64,
+    pub(crate) runnable: bool,
+    pub(crate) exited: bool,
+    pub(crate) current: bool,
+}
+
 #[derive(Copy, Clone)]
 pub struct TaskHandle(usize);
 
@@ -0,0 +1,706 @@
+use crate::arch::x86_64::mm::{self, MMIO_BASE};
+use crate::arch::x86_64::reset;
+use crate::drivers::block::{self, BlockDevice};
+use crate::drivers::pci;
+use crate::drivers::serial;
+use crate::drivers::serial::Port;
+use crate::fs::{self, FileType, OpenFlags};
+use crate::kernel::logger;
+use crate::kernel::sched;
+use crate::net;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::ptr;
+use log::LevelFilter;
+use util::partition;
+use x86_64::paging::table::{
+    ACCESSED_FLAG, DIRTY_FLAG, GLOBAL_FLAG, PCD_FLAG, PRESENT_FLAG, PS_FLAG, PWT_FLAG, RW_FLAG,
+    US_FLAG,
+};
+use x86_64::paging::PAGING_STRUCTURE_BASE;
+
+const PROMPT: &str = "aosir> ";
+
+// Longest command line we accept.
+const MAX_LINE_LEN: usize = 128;
+
+// Physical memory is accessed through the MMIO mapping, which ends where the paging structures
+// are mapped.
+const MAX_PHYS_ADDR: usize = PAGING_STRUCTURE_BASE - MMIO_BASE;
+
+// Most bytes `peek` dumps at once.
+const MAX_PEEK_LEN: usize = 0x1000;
+
+// Most free blocks `free` lists for an order.
+const MAX_LISTED_BLOCKS: usize = 64;
+
+type Command = fn(&[&str]) -> Result<(), &'static str>;
+
+/// Name, usage and handler of each command.
+const COMMANDS: [(&str, &str, Command); 19] = [
+    ("help", "help - list commands", help),
+    ("ps", "ps - list tasks", ps),
+    (
+        "free",
+        "free [order] - show the physical page allocator's free lists",
+        free,
+    ),
+    (
+        "map",
+        "map <addr> - show the mapping of a virtual address",
+        map,
+    ),
+    (
+        "lspci",
+        "lspci [-v] - list PCI devices, with -v their BARs",
+        lspci,
+    ),
+    ("ifstat", "ifstat - show NIC statistics", ifstat),
+    ("lsblk", "lsblk - list block devices", lsblk),
+    (
+        "readblk",
+        "readblk <device> <sector> - dump a sector of a block device",
+        readblk,
+    ),
+    (
+        "sync",
+        "sync - write the dirty pages of the buffer cache to the disks",
+        sync,
+    ),
+    ("ls", "ls [path] - list a directory", ls),
+    ("cat", "cat <path> - print a file", cat),
+    ("mkdir", "mkdir <path> - create a directory", mkdir),
+    (
+        "mount",
+        "mount [<type> <path> [device]] - list or mount filesystems",
+        mount,
+    ),
+    ("dmesg", "dmesg - show the kernel log", dmesg),
+    (
+        "initrd",
+        "initrd - list the files that were copied from the initrd to /",
+        list_initrd,
+    ),
+    (
+        "loglevel",
+        "loglevel [[module] <off|error|warn|info|debug|trace>] - show or set log levels",
+        loglevel,
+    ),
+    (
+        "peek",
+        "peek <phys addr> [len] - dump physical memory",
+        peek,
+    ),
+    (
+        "poke",
+        "poke <phys addr> <value> [width] - write 1, 2, 4 (default) or 8 bytes to physical memory",
+        poke,
+    ),
+    ("reboot", "reboot - reset the machine", reboot),
+];
+
+/// Runs the debug shell on COM1. This is the entry point of the shell task.
+pub fn run() {
+    writeln!(
+        serial::Handle::new(),
+        "\nKernel debug shell. Type `help` for a list of commands."
+    );
+    let mut history = String::new();
+    loop {
+        serial::write(Port::COM1, PROMPT.as_bytes());
+        let line = read_line(&history);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        execute(line);
+        history = String::from(line);
+    }
+}
+
+fn execute(line: &str) {
+    let args = line.split_whitespace().collect::<Vec<_>>();
+    let Some((_, _, command)) = COMMANDS.iter().find(|(name, _, _)| *name == args[0]) else {
+        writeln!(serial::Handle::new(), "{}: command not found", args[0]);
+        return;
+    };
+    if let Err(e) = command(&args[1..]) {
+        writeln!(serial::Handle::new(), "{}: {}", args[0], e);
+    }
+}
+
+/// Reads a line from COM1, echoing what is typed.
+///
+/// Supports backspace, Ctrl-U (erase line), Ctrl-W (erase word), Ctrl-C (discard line), and the up
+/// arrow, which recalls `previous`.
+fn read_line(previous: &str) -> String {
+    let mut line = String::new();
+    let mut escape = Escape::None;
+    let mut last = 0u8;
+    loop {
+        let mut buf = [0u8; 16];
+        let len = serial::read(Port::COM1, &mut buf);
+        for &byte in &buf[..len] {
+            // Skip over escape sequences, only acting on the up arrow.
+            match (escape, byte) {
+                (Escape::None, 0x1b) => {
+                    escape = Escape::Esc;
+                    continue;
+                }
+                (Escape::Esc, b'[') => {
+                    escape = Escape::Csi;
+                    continue;
+                }
+                (Escape::Esc, _) => {
+                    escape = Escape::None;
+                    continue;
+                }
+                (Escape::Csi, b'A') => {
+                    escape = Escape::None;
+                    erase(&mut line, usize::MAX);
+                    line.push_str(previous);
+                    serial::write(Port::COM1, line.as_bytes());
+                    continue;
+                }
+                (Escape::Csi, 0x40..=0x7e) => {
+                    escape = Escape::None;
+                    continue;
+                }
+                (Escape::Csi, _) => continue,
+                (Escape::None, _) => {}
+            }
+
+            match byte {
+                b'\r' => {
+                    serial::write(Port::COM1, b"\n");
+                    return line;
+                }
+                // Terminals that send CR LF on Enter.
+                b'\n' if last == b'\r' => {}
+                b'\n' => {
+                    serial::write(Port::COM1, b"\n");
+                    return line;
+                }
+                // Backspace and DEL
+                0x08 | 0x7f => erase(&mut line, 1),
+                // Ctrl-C
+                0x03 => {
+                    serial::write(Port::COM1, b"^C\n");
+                    line.clear();
+                    serial::write(Port::COM1, PROMPT.as_bytes());
+                }
+                // Ctrl-U
+                0x15 => erase(&mut line, usize::MAX),
+                // Ctrl-W
+                0x17 => {
+                    let trimmed = line.trim_end().len();
+                    let word_start = line[..trimmed].rfind(' ').map_or(0, |i| i + 1);
+                    let n = line.len() - word_start;
+                    erase(&mut line, n);
+                }
+                0x20..=0x7e if line.len() < MAX_LINE_LEN => {
+                    line.push(byte as char);
+                    serial::write(Port::COM1, &[byte]);
+                }
+                _ => {}
+            }
+            last = byte;
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Escape {
+    None,
+    Esc,
+    Csi,
+}
+
+/// Removes up to `n` characters from the end of `line`, and from the terminal.
+fn erase(line: &mut String, n: usize) {
+    let n = n.min(line.len());
+    let mut out = serial::Handle::new();
+    for _ in 0..n {
+        line.pop();
+        write!(out, "\x08 \x08");
+    }
+}
+
+/// Parses a hexadecimal number with a `0x` prefix, or a decimal number.
+fn parse_number(s: &str) -> Result<usize, &'static str> {
+    let result = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    result.map_err(|_| "invalid number")
+}
+
+fn help(_args: &[&str]) -> Result<(), &'static str> {
+    let mut out = serial::Handle::new();
+    for (_, usage, _) in COMMANDS.iter() {
+        writeln!(out, "  {}", usage);
+    }
+    Ok(())
+}
+
+fn ps(_args: &[&str]) -> Result<(), &'static str> {
+    let tasks = sched::lock().tasks();
+    let mut out = serial::Handle::new();
+    writeln!(out, "{:>6}  {:>20}  STATE", "ID", "TOTAL_RUNTIME");
+    for task in tasks {
+        let state = match (task.current, task.runnable, task.exited) {
+            (_, _, true) => "exited",
+            (true, _, _) => "running",
+            (false, true, _) => "runnable",
+            (false, false, _) => "blocked",
+        };
+        writeln!(
+            out,
+            "{:>6}  {:>20}  {}",
+            task.handle, task.total_runtime, state
+        );
+    }
+    Ok(())
+}
+
+fn free(args: &[&str]) -> Result<(), &'static str> {
+    let order = args.first().map(|s| parse_number(s)).transpose()?;
+
+    // Copy out what we need, so that the mapper is not locked while we write to the serial port.
+    let lists = {
+        let mapper = mm::mapper();
+        let mapper = mapper.as_ref().ok_or("memory manager is not initialized")?;
+        mapper
+            .page_allocator()
+            .free_lists()
+            .filter(|(o, _)| order.is_none() || order == Some(*o))
+            .map(|(o, list)| {
+                let blocks = match order {
+                    Some(_) => list.iter().take(MAX_LISTED_BLOCKS).copied().collect(),
+                    None => Vec::new(),
+                };
+                (o, list.len(), blocks)
+            })
+            .collect::<Vec<(usize, usize, Vec<usize>)>>()
+    };
+    if lists.is_empty() {
+        return Err("no such order");
+    }
+
+    let mut out = serial::Handle::new();
+    writeln!(
+        out,
+        "{:>5}  {:>10}  {:>11}",
+        "ORDER", "BLOCK_SIZE", "FREE_BLOCKS"
+    );
+    let mut total = 0;
+    for (order, count, blocks) in lists.iter() {
+        writeln!(
+            out,
+            "{:>5}  {:>#10x}  {:>11}",
+            order,
+            1usize << order,
+            count
+        );
+        total += count << order;
+        for block in blocks {
+            writeln!(out, "       {:#x}", block);
+        }
+        if blocks.len() < *count && !blocks.is_empty() {
+            writeln!(out, "       ... and {} more", count - blocks.len());
+        }
+    }
+    writeln!(out, "total free: {} KiB", total / 1024);
+    Ok(())
+}
+
+fn map(args: &[&str]) -> Result<(), &'static str> {
+    let addr = parse_number(args.first().ok_or("missing address")?)?;
+    let mapping = mm::mapper()
+        .as_ref()
+        .ok_or("memory manager is not initialized")?
+        .mapping(addr);
+    let Some(mapping) = mapping else {
+        writeln!(serial::Handle::new(), "{:#x} is not mapped", addr);
+        return Ok(());
+    };
+
+    let page_size: usize = mapping.page_size.into();
+    let mut flags = String::new();
+    for (flag, name) in [
+        (PRESENT_FLAG, "P"),
+        (RW_FLAG, "RW"),
+        (US_FLAG, "US"),
+        (PWT_FLAG, "PWT"),
+        (PCD_FLAG, "PCD"),
+        (ACCESSED_FLAG, "A"),
+        (DIRTY_FLAG, "D"),
+        (PS_FLAG, "PS"),
+        (GLOBAL_FLAG, "G"),
+    ] {
+        if mapping.flags & flag != 0 {
+            flags.push_str(name);
+            flags.push(' ');
+        }
+    }
+    writeln!(
+        serial::Handle::new(),
+        "{:#x} -> {:#x} ({} page at {:#x}, flags: {})",
+        addr,
+        mapping.phys_addr | (addr & (page_size - 1)),
+        mapping.page_size,
+        mapping.phys_addr,
+        flags.trim_end(),
+    );
+    Ok(())
+}
+
+fn lspci(args: &[&str]) -> Result<(), &'static str> {
+    let verbose = match args {
+        [] => false,
+        ["-v"] => true,
+        _ => return Err("usage: lspci [-v]"),
+    };
+    let devices = pci::devices();
+    let mut out = serial::Handle::new();
+    for d in devices {
+        write!(
+            out,
+            "{} {:02x}{:02x}: {} [{:02x}{:02x}] {:04x}:{:04x}",
+            d.bdf,
+            d.class,
+            d.subclass,
+            pci::class::name(d.class, d.subclass),
+            d.class,
+            d.subclass,
+            d.vendor_id,
+            d.device_id,
+        );
+        if d.revision != 0 {
+            write!(out, " (rev {:02x})", d.revision);
+        }
+        if d.prog_if != 0 {
+            write!(out, " (prog-if {:02x})", d.prog_if);
+        }
+        writeln!(out);
+        if !verbose {
+            continue;
+        }
+        if d.subsystem_vendor_id != 0 {
+            writeln!(
+                out,
+                "\tSubsystem: {:04x}:{:04x}",
+                d.subsystem_vendor_id, d.subsystem_id
+            );
+        }
+        if d.interrupt_pin != 0 {
+            writeln!(
+                out,
+                "\tInterrupt: pin {} routed to IRQ {}",
+                (b'A' + d.interrupt_pin - 1) as char,
+                d.interrupt_line
+            );
+        }
+        for (i, bar) in d.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                writeln!(out, "\tBAR{}: {}", i, bar);
+            }
+        }
+        if let Some(driver) = d.driver {
+            writeln!(out, "\tKernel driver in use: {}", driver);
+        }
+    }
+    Ok(())
+}
+
+fn ifstat(_args: &[&str]) -> Result<(), &'static str> {
+    let stats = net::statistics();
+    let mut out = serial::Handle::new();
+    if stats.is_empty() {
+        writeln!(out, "no network interfaces");
+    }
+    for (bdf, stats) in stats {
+        writeln!(out, "{}: {}", bdf, stats);
+    }
+    Ok(())
+}
+
+fn lsblk(_args: &[&str]) -> Result<(), &'static str> {
+    let mut out = serial::Handle::new();
+    for (name, device) in block::devices() {
+        let size = device.sectors() * device.sector_size() as u64;
+        writeln!(
+            out,
+            "{}: {} MiB ({} sectors of {} bytes) {}",
+            name,
+            size >> 20,
+            device.sectors(),
+            device.sector_size(),
+            device.model()
+        );
+        // Disks without a partition table are listed alone.
+        let Ok((_, partitions)) = block::partitions(device.as_ref()) else {
+            continue;
+        };
+        for p in partitions {
+            let kind = match p.kind {
+                partition::Kind::Gpt { type_guid, .. } => format!("{}", type_guid),
+                partition::Kind::Mbr { system_id, .. } => format!("type {:#04x}", system_id),
+            };
+            writeln!(
+                out,
+                "  {}p{}: {} MiB from sector {}, {} {}",
+                name,
+                p.number,
+                (p.sectors * device.sector_size() as u64) >> 20,
+                p.first_sector,
+                kind,
+                p.name().collect::<String>()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn readblk(args: &[&str]) -> Result<(), &'static str> {
+    let [name, sector] = args else {
+        return Err("usage: readblk <device> <sector>");
+    };
+    let device = block::get(name).ok_or("no such block device")?;
+    let sector = parse_number(sector)? as u64;
+    let mut buf = vec![0; device.sector_size()];
+    device.read(sector, &mut buf)?;
+
+    let mut out = serial::Handle::new();
+    for (i, line) in buf.chunks(16).enumerate() {
+        write!(out, "{:#06x}:", i * 16);
+        for byte in line {
+            write!(out, " {:02x}", byte);
+        }
+        writeln!(out);
+    }
+    Ok(())
+}
+
+fn sync(_args: &[&str]) -> Result<(), &'static str> {
+    let (pages, dirty) = block::cache::statistics();
+    block::cache::sync_all()?;
+    let mut out = serial::Handle::new();
+    writeln!(out, "wrote {} of {} cached pages", dirty, pages);
+    Ok(())
+}
+
+fn ls(args: &[&str]) -> Result<(), &'static str> {
+    let path = match args {
+        [] => "/",
+        [path] => path,
+        _ => return Err("usage: ls [path]"),
+    };
+    if fs::stat(path)?.file_type != FileType::Directory {
+        writeln!(serial::Handle::new(), "{}", path);
+        return Ok(());
+    }
+    let fd = fs::open(path, OpenFlags::READ)?;
+    let mut out = serial::Handle::new();
+    let result = loop {
+        match fs::readdir(fd) {
+            Ok(Some(entry)) => {
+                let kind = match entry.file_type {
+                    FileType::Regular => '-',
+                    FileType::Directory => 'd',
+                    FileType::CharDevice => 'c',
+                    FileType::BlockDevice => 'b',
+                };
+                writeln!(out, "{} {:>6} {}", kind, entry.inode, entry.name);
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    fs::close(fd)?;
+    result
+}
+
+fn cat(args: &[&str]) -> Result<(), &'static str> {
+    let [path] = args else {
+        return Err("usage: cat <path>");
+    };
+    let fd = fs::open(path, OpenFlags::READ)?;
+    let mut buf = vec![0; 0x1000];
+    let result = loop {
+        match fs::read(fd, &mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(len) => serial::write(Port::COM1, &buf[..len]),
+            Err(e) => break Err(e),
+        }
+    };
+    fs::close(fd)?;
+    result
+}
+
+fn mkdir(args: &[&str]) -> Result<(), &'static str> {
+    let [path] = args else {
+        return Err("usage: mkdir <path>");
+    };
+    fs::mkdir(path)
+}
+
+fn mount(args: &[&str]) -> Result<(), &'static str> {
+    match *args {
+        [] => {
+            let mut out = serial::Handle::new();
+            for (path, fs_type, source) in fs::mounts() {
+                let source = source.unwrap_or_else(|| String::from("none"));
+                writeln!(out, "{} on {} type {}", source, path, fs_type);
+            }
+            Ok(())
+        }
+        [fs_type, path] => fs::mount(fs_type, None, path),
+        [fs_type, path, device] => fs::mount(fs_type, Some(device), path),
+        _ => Err("usage: mount [<type> <path> [device]]"),
+    }
+}
+
+fn dmesg(_args: &[&str]) -> Result<(), &'static str> {
+    // The log is much larger than the transmit buffer, so it is written a bufferful at a time,
+    // and other tasks get to write in between.
+    for chunk in logger::read_buffer().chunks(serial::TX_BUF_SIZE) {
+        serial::write(Port::COM1, chunk);
+    }
+    Ok(())
+}
+
+/// Lists the files of the root filesystem, which holds the files of the initrd. Directories that
+/// other filesystems are mounted on are not entered.
+fn list_initrd(_args: &[&str]) -> Result<(), &'static str> {
+    let mount_points: Vec<String> = fs::mounts().into_iter().map(|(path, ..)| path).collect();
+    let mut out = serial::Handle::new();
+    // Directories are listed in the order they are found, without recursion, so that deep trees
+    // fit on the kernel stack.
+    let mut dirs = vec![String::from("/")];
+    while let Some(dir) = dirs.pop() {
+        let fd = fs::open(&dir, OpenFlags::READ)?;
+        let mut entries = Vec::new();
+        let result = loop {
+            match fs::readdir(fd) {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        fs::close(fd)?;
+        result?;
+
+        for entry in entries.into_iter().rev() {
+            let path = match dir.as_str() {
+                "/" => format!("/{}", entry.name),
+                _ => format!("{}/{}", dir, entry.name),
+            };
+            if entry.file_type == FileType::Directory {
+                writeln!(out, "d {:>10} {}", "", path);
+                if !mount_points.contains(&path) {
+                    dirs.push(path);
+                }
+            } else {
+                writeln!(out, "- {:>10} {}", fs::stat(&path)?.size, path);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn loglevel(args: &[&str]) -> Result<(), &'static str> {
+    let parse_level = |s: &str| s.parse::<LevelFilter>().map_err(|_| "invalid level");
+    match args.len() {
+        0 => {
+            let (default, modules) = logger::levels();
+            let mut out = serial::Handle::new();
+            writeln!(out, "default: {}", default);
+            for (module, level) in modules {
+                writeln!(out, "{}: {}", module, level);
+            }
+        }
+        1 => logger::set_level(parse_level(args[0])?),
+        2 => logger::set_module_level(args[0], parse_level(args[1])?),
+        _ => return Err("too many arguments"),
+    }
+    Ok(())
+}
+
+/// Returns a pointer to `len` bytes of physical memory at `phys_addr`, mapping them if needed.
+fn map_phys(phys_addr: usize, len: usize) -> Result<*mut u8, &'static str> {
+    let end = phys_addr.checked_add(len).ok_or("address out of range")?;
+    if end > MAX_PHYS_ADDR {
+        return Err("address out of range");
+    }
+    let mut mapper = mm::mapper();
+    let mapper = mapper.as_mut().ok_or("memory manager is not initialized")?;
+    for page in (phys_addr & !0xfff..end).step_by(0x1000) {
+        if mapper.phys_addr(page + MMIO_BASE).is_none() {
+            mapper.map_mmio(page);
+        }
+    }
+    Ok((phys_addr + MMIO_BASE) as *mut u8)
+}
+
+fn peek(args: &[&str]) -> Result<(), &'static str> {
+    let addr = parse_number(args.first().ok_or("missing address")?)?;
+    let len = match args.get(1) {
+        Some(len) => parse_number(len)?.min(MAX_PEEK_LEN),
+        None => 64,
+    };
+    let ptr = map_phys(addr, len)?;
+
+    let mut out = serial::Handle::new();
+    for line_start in (0..len).step_by(16) {
+        write!(out, "{:#014x}:", addr + line_start);
+        for i in line_start..(line_start + 16).min(len) {
+            // SAFETY: The range was mapped by map_phys.
+            let byte = unsafe { ptr::read_volatile(ptr.add(i)) };
+            write!(out, " {:02x}", byte);
+        }
+        writeln!(out);
+    }
+    Ok(())
+}
+
+fn poke(args: &[&str]) -> Result<(), &'static str> {
+    let addr = parse_number(args.first().ok_or("missing address")?)?;
+    let value = parse_number(args.get(1).ok_or("missing value")?)?;
+    let width = match args.get(2) {
+        Some(width) => parse_number(width)?,
+        None => 4,
+    };
+    if !matches!(width, 1 | 2 | 4 | 8) {
+        return Err("width must be 1, 2, 4 or 8");
+    }
+    if addr % width != 0 {
+        return Err("address is not aligned to the width");
+    }
+    if width < 8 && value >> (width * 8) != 0 {
+        return Err("value does not fit in the width");
+    }
+    let ptr = map_phys(addr, width)?;
+
+    // SAFETY: The range was mapped by map_phys, and is aligned to the width. Whether writing to it
+    //         is a good idea is up to the user.
+    unsafe {
+        match width {
+            1 => ptr::write_volatile(ptr, value as u8),
+            2 => ptr::write_volatile(ptr as *mut u16, value as u16),
+            4 => ptr::write_volatile(ptr as *mut u32, value as u32),
+            _ => ptr::write_volatile(ptr as *mut u64, value as u64),
+        }
+    }
+    Ok(())
+}
+
+fn reboot(_args: &[&str]) -> Result<(), &'static str> {
+    writeln!(serial::Handle::new(), "Rebooting...");
+    serial::use_polling();
+    reset::reboot()
+}
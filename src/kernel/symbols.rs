@@ -0,0 +1,24 @@
+//! Symbol table of the kernel, for symbolized backtraces.
+//!
+//! The bootloader copies `.symtab` and `.strtab` out of the kernel ELF into pages that stay
+//! mapped at `MMIO_BASE`.
+
+use crate::locking::spinlock::WithSpinLock;
+
+use log::info;
+use util::symbols::{Symbol, SymbolTable};
+
+static SYMBOLS: WithSpinLock<Option<SymbolTable<'static>>> = WithSpinLock::new(None);
+
+pub fn init(symtab: &'static [u8], strtab: &'static [u8]) {
+    let table = SymbolTable::new(symtab, strtab);
+    info!("Kernel symbols: {} functions", table.functions().count());
+    *SYMBOLS.lock() = Some(table);
+}
+
+/// Returns the function that contains `addr`. It doesn't wait for the lock, so that it can be
+/// called while panicking.
+pub fn lookup(addr: usize) -> Option<Symbol<'static>> {
+    let table = (*SYMBOLS.try_lock()?)?;
+    table.lookup(addr)
+}
@@ -24,6 +24,24 @@ impl<A> WithSpinLock<A> {
             was_enabled,
         }
     }
+
+    /// Like `lock()`, but returns `None` instead of spinning if the lock is held. Code that may
+    /// run while the lock is held on the same CPU, such as the panic handler, uses this to avoid
+    /// deadlocking.
+    pub fn try_lock(&self) -> Option<WithSpinLockGuard<'_, A>> {
+        let was_enabled = interrupts_enabled();
+        disable_interrupts();
+
+        match self.inner.try_lock() {
+            Some(inner) => Some(WithSpinLockGuard { inner, was_enabled }),
+            None => {
+                if was_enabled {
+                    enable_interrupts();
+                }
+                None
+            }
+        }
+    }
 }
 
 pub struct WithSpinLockGuard<'a, T> {
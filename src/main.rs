@@ -15,6 +15,7 @@ extern crate x86_64_bare_metal;
 use core::arch::asm;
 use core::fmt::{Debug, Write};
 use core::panic::PanicInfo;
+use log::{info, warn, LevelFilter};
 
 mod arch;
 use arch::x86_64::interrupt;
@@ -24,8 +25,10 @@ use arch::x86_64::{hpet, pit};
 
 mod boot;
 mod drivers;
+mod fs;
 use drivers::acpi;
-use drivers::net::rtl8139;
+use drivers::block;
+use drivers::framebuffer;
 use drivers::pci;
 use drivers::serial;
 
@@ -34,7 +37,11 @@ mod mm;
 mod kernel;
 use crate::kernel::clock;
 use crate::kernel::clock::{sleep, Clock};
+use crate::kernel::cmdline;
+use crate::kernel::initrd;
+use crate::kernel::logger;
 use crate::kernel::sched;
+use crate::kernel::symbols;
 
 mod locking;
 
@@ -47,6 +54,9 @@ mod net;
 pub unsafe extern "C" fn start(boot_data: *mut bootlib::types::BootData) {
     let mut boot_data = boot::BootData::relocate(boot_data, MMIO_BASE);
     init_mm(boot_data.memory_map); // TODO: error handling
+    if let Some((symtab, strtab)) = boot_data.symbols {
+        symbols::init(symtab, strtab);
+    }
     let madt = acpi::parse_madt(boot_data.acpi_rsdp).expect("failed to parse ACPI tables");
     let gdt = pm::init();
 
@@ -54,33 +64,68 @@ pub unsafe extern "C" fn start(boot_data: *mut bootlib::types::BootData) {
 
     let lapic_id = interrupt::init(&madt);
 
-    serial::init();
-    serial::tmp_write_com1(b"[OK]\tSerial console initialized\n");
+    // Records logged before the serial console is initialized are kept in the log buffer.
+    cmdline::init(boot_data.cmdline);
+    logger::init(LevelFilter::Info);
+    set_log_levels();
 
-    let mcfg = acpi::parse_mcfg(boot_data.acpi_rsdp).expect("failed to parse ACPI tables");
+    let divisor = match cmdline::option::<u32>("serial.baud") {
+        Some(baud_rate) => serial::divisor(baud_rate).unwrap_or_else(|| {
+            warn!("Unsupported baud rate {}", baud_rate);
+            serial::DEFAULT_DIVISOR
+        }),
+        None => serial::DEFAULT_DIVISOR,
+    };
+    serial::init(divisor);
+    info!("Serial console initialized");
+    match framebuffer::init(boot_data.framebuffer) {
+        Ok(()) => info!("Framebuffer console initialized"),
+        Err(e) => warn!("No framebuffer console: {}", e),
+    }
+    info!("Command line: {}", boot_data.cmdline);
+    if let Some((phys_addr, len)) = boot_data.initrd {
+        initrd::init(phys_addr, len);
+    }
 
-    let hpet = acpi::parse_hpet(boot_data.acpi_rsdp);
-    let clock = match hpet {
-        Ok(hpet) => {
+    let mcfg = acpi::parse_mcfg(boot_data.acpi_rsdp)
+        .inspect_err(|e| warn!("No PCIe ECAM, using port I/O for PCI: {}", e))
+        .ok();
+
+    // Use the HPET unless the PIT is asked for or there is no HPET.
+    let hpet = match cmdline::get().value("clocksource") {
+        None | Some("hpet") => acpi::parse_hpet(boot_data.acpi_rsdp).ok(),
+        Some("pit") => None,
+        Some(name) => {
+            warn!("Unknown clock source {:?}", name);
+            acpi::parse_hpet(boot_data.acpi_rsdp).ok()
+        }
+    };
+    let clocksource = match hpet {
+        Some(hpet) => {
             let hpet = hpet::init(hpet);
             hpet::register_tick(clock::tick_fn());
-            clock::init(hpet);
+            info!("Using the HPET as the clock source");
             hpet
         }
-        Err(_) => {
-            // pit::start();
-            // pit::register_tick(clock::tick_fn());
-            panic!("No supported clocksource found!")
+        None => {
+            let pit = pit::init();
+            pit::register_tick(clock::tick_fn());
+            info!("Using the PIT as the clock source");
+            pit
         }
     };
+    clock::init(clocksource);
+
+    if let Some(address) = cmdline::option("ip") {
+        net::set_address(address);
+    }
+    info!("IPv4 address: {}", net::address());
 
     // Initialize PCI devices
-    pci::init(lapic_id);
-    let nics = rtl8139::init(&madt.interrupt_mappings);
-    if nics == 1 {
-        serial::tmp_write_com1(b"[OK]\tRTL8139 NIC initialized\n");
-    } else {
-        serial::tmp_write_com1(b"[OK]\tNo NICs found\n")
+    pci::init(mcfg.as_ref(), &madt.interrupt_mappings);
+    drivers::init();
+    if net::statistics().is_empty() {
+        info!("No NICs found");
     }
 
     // Initialize network stack
@@ -89,24 +134,37 @@ pub unsafe extern "C" fn start(boot_data: *mut bootlib::types::BootData) {
         scheduler.new_task(net::run);
     }
 
-    // Create several tasks to demonstrate switching.
+    // Start the block layer, which dispatches disk requests and writes back the buffer cache.
     {
         let mut scheduler = sched::lock();
-        scheduler.new_task(some_task);
+        scheduler.new_task(block::run);
+        scheduler.new_task(block::cache::flusher);
     }
+
+    // Mount the root filesystem, with the files of the initrd, and the device files.
+    fs::init();
+
+    // Start the debug shell on COM1.
     {
         let mut scheduler = sched::lock();
-        scheduler.new_task(some_task);
+        scheduler.new_task(kernel::shell::run);
     }
-    {
-        let mut scheduler = sched::lock();
-        scheduler.new_task(some_task);
+
+    // Create several tasks to demonstrate switching.
+    let demo = cmdline::flag("demo");
+    if demo {
+        for _ in 0..3 {
+            let mut scheduler = sched::lock();
+            scheduler.new_task(some_task);
+        }
     }
 
     // Start kernel main loop, where we handle queued data from interrupts.
     loop {
         let current = sched::current_task();
-        writeln!(serial::Handle::new(), "Yo! from kernel main loop");
+        if demo {
+            info!("Yo! from kernel main loop");
+        }
         sleep(1000);
 
         let ptr = 0x1000 as *mut u32;
@@ -123,11 +181,35 @@ pub unsafe extern "C" fn start(boot_data: *mut bootlib::types::BootData) {
     }
 }
 
+/// Sets the log levels from `loglevel=<level>` and `loglevel.<module>=<level>`, such as
+/// `loglevel.drivers::net=trace`.
+fn set_log_levels() {
+    for (key, value) in cmdline::get().iter() {
+        let module = match key.strip_prefix("loglevel") {
+            Some("") => None,
+            Some(rest) => match rest.strip_prefix('.') {
+                Some(module) => Some(module),
+                None => continue,
+            },
+            None => continue,
+        };
+        let value = value.unwrap_or("");
+        let Ok(level) = value.parse::<LevelFilter>() else {
+            warn!("Ignoring invalid value for {}: {:?}", key, value);
+            continue;
+        };
+        match module {
+            Some(module) => logger::set_module_level(module, level),
+            None => logger::set_level(level),
+        }
+    }
+}
+
 /// This is a placeholder for actual code that a newly created task would run.
 pub fn some_task() {
     loop {
         let current = sched::current_task();
-        writeln!(serial::Handle::new(), "Yo! from some task: {:}", current);
+        info!("Yo! from some task: {:}", current);
 
         // Sleep for 1000 ms with nanosleep:
         unsafe {
@@ -148,7 +230,8 @@ pub fn some_task() {
 /// panic() handles panics!()'s in the kernel. These are called "kernel panic"s.
 fn panic(info: &PanicInfo) -> ! {
     fn do_panic(info: &PanicInfo) -> Option<()> {
-        serial::init();
+        serial::use_polling();
+        logger::dump_after_panic();
         let args = info.message();
         let location = info.location()?;
         writeln!(
@@ -158,7 +241,7 @@ fn panic(info: &PanicInfo) -> ! {
             location.line(),
             location.column(),
         );
-        write!(&mut serial::Handle::new(), "{}", info.message());
+        writeln!(serial::Handle::new(), "{}", info.message());
         Some(())
     };
     let r = do_panic(info);
@@ -166,6 +249,11 @@ fn panic(info: &PanicInfo) -> ! {
         writeln!(serial::Handle::new(), "Failed to get panic message");
     }
 
-    // TODO: Paint screen red.
-    loop {}
+    writeln!(serial::Handle::new(), "Backtrace:");
+    arch::x86_64::backtrace::write(&mut serial::Handle::new());
+
+    framebuffer::show_panic(info);
+    loop {
+        unsafe { asm!("cli; hlt") };
+    }
 }
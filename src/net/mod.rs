@@ -1,18 +1,18 @@
-use crate::drivers::net::rtl8139::{NICS, RTL8139};
+use crate::drivers::net::{NetworkDevice, Statistics};
 use crate::drivers::pci;
 use crate::locking::semaphore::Semaphore;
 use crate::locking::spinlock::WithSpinLock;
-use crate::serial;
 
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::fmt::Write;
 use core::fmt::{Debug, Display, Formatter};
+use core::net::Ipv4Addr;
 use core::ptr::addr_of_mut;
-use core::sync::atomic::AtomicUsize;
-use core::sync::atomic::Ordering::{AcqRel, Acquire};
+use core::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed};
+use core::sync::atomic::{AtomicU64, AtomicUsize};
+use log::{debug, warn};
 
 pub mod arp;
 pub mod ethernet;
@@ -39,8 +39,27 @@ impl Display for Error<'_> {
 
 impl core::error::Error for Error<'_> {}
 
+// Address used when none is set with `set_address()`.
+const DEFAULT_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 16, 40);
+
+// IPv4 address of this host. It is shared by all interfaces for now.
+static ADDRESS: WithSpinLock<Ipv4Addr> = WithSpinLock::new(DEFAULT_ADDRESS);
+
+/// Sets the IPv4 address that the network stack answers ARP requests for.
+pub fn set_address(address: Ipv4Addr) {
+    *ADDRESS.lock() = address;
+}
+
+/// Returns the IPv4 address of this host.
+pub fn address() -> Ipv4Addr {
+    *ADDRESS.lock()
+}
+
+// Largest frame the RX ring holds, including two VLAN tags and the CRC.
+const MAX_FRAME_SIZE: usize = 1526;
+
 struct RxBuf {
-    bytes: Box<[u8; 1526]>,
+    bytes: Box<[u8; MAX_FRAME_SIZE]>,
     len: usize,
 }
 
@@ -56,16 +75,20 @@ impl RxBuf {
 /// The network stack for a network interface, with an RX ring buffer.
 pub struct Interface {
     device: pci::BDF,
+    nic: Arc<dyn NetworkDevice>,
 
     bufs: [WithSpinLock<RxBuf>; 4],
     recv_empty: Semaphore,
     recv_full: Semaphore,
     recv_head: AtomicUsize,
     recv_tail: AtomicUsize,
+
+    // Frames that the NIC accepted but the network stack could not parse.
+    invalid_frames: AtomicU64,
 }
 
 impl Interface {
-    pub fn new(device: pci::BDF) -> Self {
+    pub fn new(device: pci::BDF, nic: Arc<dyn NetworkDevice>) -> Self {
         Interface {
             bufs: [
                 WithSpinLock::new(RxBuf::new()),
@@ -77,13 +100,17 @@ impl Interface {
             recv_full: Semaphore::new(0, 4),
             recv_head: AtomicUsize::new(0),
             recv_tail: AtomicUsize::new(0),
+            invalid_frames: AtomicU64::new(0),
             device,
+            nic,
         }
     }
 
-    pub fn recv_frame(&self, bytes: &[u8]) {
-        if !self.recv_empty.try_wait() {
-            return;
+    /// Copies a received frame into the RX ring. Returns `false` if the ring is full or the frame
+    /// is too large, and the frame was dropped.
+    pub fn recv_frame(&self, bytes: &[u8]) -> bool {
+        if bytes.len() > MAX_FRAME_SIZE || !self.recv_empty.try_wait() {
+            return false;
         }
         let next = self.recv_head.update(AcqRel, Acquire, |n| (n + 1) % 4);
         let mut buf = self.bufs[next].lock();
@@ -91,6 +118,15 @@ impl Interface {
         buf.len = bytes.len();
 
         self.recv_full.signal();
+        true
+    }
+
+    /// Returns the traffic counters of the interface. Frames that the network stack could not parse
+    /// are counted as receive errors, on top of the errors reported by the NIC.
+    pub fn statistics(&self) -> Statistics {
+        let mut stats = self.nic.statistics();
+        stats.rx_errors += self.invalid_frames.load(Relaxed);
+        stats
     }
 
     fn handle_frame(&self) -> Result<(), Error> {
@@ -101,8 +137,7 @@ impl Interface {
 
         let result = match ethernet::Frame::try_from_bytes(&buf) {
             Ok(frame) => {
-                writeln!(
-                    serial::Handle::new(),
+                debug!(
                     "src: {}, dest: {}, EtherType: {}",
                     &frame.src(),
                     &frame.dest(),
@@ -112,21 +147,28 @@ impl Interface {
                     EtherType::ARP => {
                         let request = ARP::from_bytes(&frame.payload());
                         let target_protocol_address = request.target_protocol_address();
-                        let me: [u8; 4] = [192, 168, 16, 40];
+                        let me = address().octets();
                         if target_protocol_address == me {
-                            let interface = { NICS.lock().get(&self.device).unwrap().clone() };
-                            let arp_writer = arp::reply_writer(frame.payload(), interface.id());
-                            interface.transmit(frame.dest(), [None; 2], EtherType::ARP, arp_writer);
+                            let arp_writer = arp::reply_writer(frame.payload(), self.nic.id());
+                            self.nic.transmit(
+                                frame.dest(),
+                                [None; 2],
+                                EtherType::ARP,
+                                Box::new(arp_writer),
+                            );
                         }
                         Ok(())
                     }
                     _ => Ok(()),
                 }
             }
-            Err(s) => Err(Error {
-                error_type: ErrorType::InvalidFrame,
-                message: s,
-            }),
+            Err(s) => {
+                self.invalid_frames.fetch_add(1, Relaxed);
+                Err(Error {
+                    error_type: ErrorType::InvalidFrame,
+                    message: s,
+                })
+            }
         };
         self.recv_empty.signal();
         result
@@ -136,6 +178,15 @@ impl Interface {
 pub static NETWORK_STACK: WithSpinLock<BTreeMap<pci::BDF, Arc<Interface>>> =
     WithSpinLock::new(BTreeMap::new());
 
+/// Returns the traffic counters of every interface.
+pub fn statistics() -> Vec<(pci::BDF, Statistics)> {
+    NETWORK_STACK
+        .lock()
+        .iter()
+        .map(|(bdf, net)| (*bdf, net.statistics()))
+        .collect()
+}
+
 pub fn run() {
     // TODO: The following will likely deadlock if we have multiple NICS, but in order to do things
     //       properly we need Scheduler.spawn() to receive closures.
@@ -151,7 +202,7 @@ pub fn run() {
             match net.handle_frame() {
                 Ok(_) => {}
                 Err(e) => {
-                    writeln!(serial::Handle::new(), "Error receiving frame: {}", e);
+                    warn!("Error receiving frame: {}", e);
                 }
             };
         }
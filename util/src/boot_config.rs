@@ -0,0 +1,268 @@
+//! Boot configuration of the bootloader.
+//!
+//! The config file lists options and boot entries, one per line. Lines starting with `#` are
+//! comments.
+//!
+//! ```text
+//! # Seconds to show the menu for. 0 boots the default entry right away.
+//! timeout 5
+//! # Entry booted when the timeout expires, counting from 1.
+//! default 1
+//!
+//! entry aosir
+//!     kernel \aosir
+//!     cmdline loglevel=debug
+//!     initrd \initrd.cpio
+//! ```
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Seconds to wait for a selection before booting the default entry.
+    pub timeout: usize,
+
+    /// Index of the entry to boot when the timeout expires.
+    pub default: usize,
+
+    /// Boot entries. There is at least one.
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub title: String,
+
+    /// Path of the kernel on the ESP.
+    pub kernel: String,
+
+    /// Command line passed to the kernel.
+    pub cmdline: String,
+
+    /// Path of the initial ramdisk on the ESP.
+    pub initrd: Option<String>,
+}
+
+/// Why a config file was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Error {
+    /// Line the error is on, counting from 1, or `None` for errors about the whole file.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl Entry {
+    pub fn new(title: &str, kernel: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            kernel: kernel.to_string(),
+            cmdline: String::new(),
+            initrd: None,
+        }
+    }
+}
+
+impl Config {
+    /// Returns a config that boots `kernel` right away.
+    pub fn single(title: &str, kernel: &str) -> Self {
+        Self {
+            timeout: 0,
+            default: 0,
+            entries: vec![Entry::new(title, kernel)],
+        }
+    }
+
+    /// Parses a config file. Command lines longer than `max_cmdline_len` bytes are rejected.
+    pub fn parse(s: &str, max_cmdline_len: usize) -> Result<Self, Error> {
+        let mut timeout = 0;
+        let mut default = 0;
+        let mut entries: Vec<Entry> = Vec::new();
+        // Whether the kernel of the last entry has been set.
+        let mut has_kernel = false;
+
+        for (line_no, line) in s.lines().enumerate() {
+            let error = |message: &str| Error {
+                line: Some(line_no + 1),
+                message: message.to_string(),
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once(char::is_whitespace) {
+                Some((key, value)) => (key, value.trim()),
+                None => (line, ""),
+            };
+            if value.is_empty() {
+                return Err(error(&format!("missing value for `{}`", key)));
+            }
+
+            match key {
+                "timeout" => timeout = value.parse().map_err(|_| error("invalid timeout"))?,
+                "default" => {
+                    default = value
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|n| n.checked_sub(1))
+                        .ok_or_else(|| error("invalid default entry"))?;
+                }
+                "entry" => {
+                    if !entries.is_empty() && !has_kernel {
+                        return Err(error("previous entry has no kernel"));
+                    }
+                    entries.push(Entry::new(value, ""));
+                    has_kernel = false;
+                }
+                "kernel" | "cmdline" | "initrd" => {
+                    let entry = entries
+                        .last_mut()
+                        .ok_or_else(|| error(&format!("`{}` outside of an entry", key)))?;
+                    match key {
+                        "kernel" => {
+                            entry.kernel = value.to_string();
+                            has_kernel = true;
+                        }
+                        "cmdline" => {
+                            if value.len() > max_cmdline_len {
+                                return Err(error("command line is too long"));
+                            }
+                            entry.cmdline = value.to_string();
+                        }
+                        _ => entry.initrd = Some(value.to_string()),
+                    }
+                }
+                _ => return Err(error(&format!("unknown option `{}`", key))),
+            }
+        }
+
+        let error = |message: &str| Error {
+            line: None,
+            message: message.to_string(),
+        };
+        if entries.is_empty() {
+            return Err(error("no entries"));
+        }
+        if !has_kernel {
+            return Err(error("last entry has no kernel"));
+        }
+        if default >= entries.len() {
+            return Err(error("default entry does not exist"));
+        }
+        Ok(Self {
+            timeout,
+            default,
+            entries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAX_CMDLINE_LEN: usize = 32;
+
+    fn parse(s: &str) -> Result<Config, Error> {
+        Config::parse(s, MAX_CMDLINE_LEN)
+    }
+
+    /// Returns the line of the error that `s` is rejected with.
+    fn error_line(s: &str) -> Option<usize> {
+        parse(s).expect_err("config should be rejected").line
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = parse(
+            "timeout 5\n\
+             default 2\n\
+             \n\
+             entry first\n\
+             \tkernel \\aosir\n\
+             entry second one\n\
+             \tkernel \\aosir.new\n\
+             \tcmdline loglevel=debug  demo\n\
+             \tinitrd \\initrd.cpio\n",
+        )
+        .unwrap();
+        assert_eq!(config.timeout, 5);
+        assert_eq!(config.default, 1);
+        assert_eq!(config.entries.len(), 2);
+        assert_eq!(config.entries[0], Entry::new("first", "\\aosir"));
+        let second = &config.entries[1];
+        assert_eq!(second.title, "second one");
+        assert_eq!(second.kernel, "\\aosir.new");
+        assert_eq!(second.cmdline, "loglevel=debug  demo");
+        assert_eq!(second.initrd.as_deref(), Some("\\initrd.cpio"));
+    }
+
+    #[test]
+    fn test_comments_and_blank_lines() {
+        let config = parse(
+            "# boot menu\n\
+             \n   \n\
+             entry aosir\n\
+             \x20   # indented comment\n\
+             \x20   kernel \\aosir\n",
+        )
+        .unwrap();
+        assert_eq!(config, Config::single("aosir", "\\aosir"));
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let s = "entry aosir\n  kernel \\aosir\n  append quiet\n";
+        let e = parse(s).unwrap_err();
+        assert_eq!(e.line, Some(3));
+        assert_eq!(e.message, "unknown option `append`");
+        assert_eq!(e.to_string(), "line 3: unknown option `append`");
+    }
+
+    #[test]
+    fn test_malformed_lines() {
+        // A key without a value
+        assert_eq!(error_line("entry aosir\n  kernel\n"), Some(2));
+        assert_eq!(error_line("timeout\nentry a\n  kernel k\n"), Some(1));
+        // Values that are not numbers, and default entries that don't count from 1
+        assert_eq!(error_line("timeout soon\nentry a\n  kernel k\n"), Some(1));
+        assert_eq!(error_line("default 0\nentry a\n  kernel k\n"), Some(1));
+        assert_eq!(error_line("default -1\nentry a\n  kernel k\n"), Some(1));
+        // Entry options before any entry
+        assert_eq!(error_line("kernel \\aosir\n"), Some(1));
+        // An entry without a kernel, followed by another one
+        assert_eq!(error_line("entry a\nentry b\n  kernel k\n"), Some(2));
+        // A command line that is too long
+        let s = format!("entry a\n  kernel k\n  cmdline {}\n", "x".repeat(33));
+        assert_eq!(error_line(&s), Some(3));
+    }
+
+    #[test]
+    fn test_incomplete_configs() {
+        assert_eq!(parse("").unwrap_err().message, "no entries");
+        assert_eq!(parse("# nothing\n").unwrap_err().message, "no entries");
+        let e = parse("entry a\n  cmdline demo\n").unwrap_err();
+        assert_eq!(
+            e,
+            Error {
+                line: None,
+                message: "last entry has no kernel".to_string(),
+            }
+        );
+        let e = parse("default 2\nentry a\n  kernel k\n").unwrap_err();
+        assert_eq!(e.message, "default entry does not exist");
+        assert_eq!(e.to_string(), "default entry does not exist");
+    }
+}
@@ -0,0 +1,145 @@
+/// Kernel command line, such as `loglevel=debug ip=10.0.2.15 demo`.
+///
+/// Options are separated by whitespace. Each is either `key=value` or a bare `key`, which is a
+/// flag. Values can be put in double quotes to include whitespace: `key="a b"`. When an option is
+/// given more than once, the last one wins.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandLine<'a> {
+    line: &'a str,
+}
+
+impl<'a> CommandLine<'a> {
+    pub const fn new(line: &'a str) -> Self {
+        Self { line }
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.line
+    }
+
+    /// Returns the options in order, as `(key, value)` pairs. The value of a bare `key` is `None`.
+    pub fn iter(&self) -> Options<'a> {
+        Options { rest: self.line }
+    }
+
+    /// Returns whether `key` is given, with or without a value.
+    pub fn contains(&self, key: &str) -> bool {
+        self.iter().any(|(k, _)| k == key)
+    }
+
+    /// Returns the value of the last `key=value`. A bare `key` has an empty value.
+    pub fn value(&self, key: &str) -> Option<&'a str> {
+        self.iter()
+            .filter(|(k, _)| *k == key)
+            .last()
+            .map(|(_, v)| v.unwrap_or(""))
+    }
+
+    /// Parses the value of `key`. Returns `None` if `key` is not given, and the raw value if it
+    /// cannot be parsed.
+    pub fn parse<T: core::str::FromStr>(&self, key: &str) -> Option<Result<T, &'a str>> {
+        let value = self.value(key)?;
+        Some(value.parse().map_err(|_| value))
+    }
+
+    /// Returns whether the flag `key` is set. A bare `key` sets it, and `key=<value>` sets or
+    /// clears it with `1`, `true`, `yes`, `on` or `0`, `false`, `no`, `off`. Any other value is
+    /// returned as the error.
+    pub fn flag(&self, key: &str) -> Result<bool, &'a str> {
+        match self.value(key) {
+            None => Ok(false),
+            Some("" | "1" | "true" | "yes" | "on") => Ok(true),
+            Some("0" | "false" | "no" | "off") => Ok(false),
+            Some(value) => Err(value),
+        }
+    }
+}
+
+/// Iterator over the options of a `CommandLine`.
+pub struct Options<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (&'a str, Option<&'a str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+
+        // The option ends at the first whitespace outside of quotes.
+        let mut in_quotes = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                c.is_whitespace() && !in_quotes
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let (option, rest) = rest.split_at(end);
+        self.rest = rest;
+
+        Some(match option.split_once('=') {
+            Some((key, value)) => {
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                (key, Some(value))
+            }
+            None => (option, None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_iter() {
+        let cmdline = CommandLine::new("  a=1 b  c=\"x y\" d= e=f=g\t");
+        let mut options = cmdline.iter();
+        assert_eq!(options.next(), Some(("a", Some("1"))));
+        assert_eq!(options.next(), Some(("b", None)));
+        assert_eq!(options.next(), Some(("c", Some("x y"))));
+        assert_eq!(options.next(), Some(("d", Some(""))));
+        assert_eq!(options.next(), Some(("e", Some("f=g"))));
+        assert_eq!(options.next(), None);
+        assert_eq!(CommandLine::new("").iter().next(), None);
+    }
+
+    #[test]
+    fn test_value() {
+        let cmdline = CommandLine::new("a=1 b a=2 loglevel.net=trace");
+        assert_eq!(cmdline.value("a"), Some("2"));
+        assert_eq!(cmdline.value("b"), Some(""));
+        assert_eq!(cmdline.value("c"), None);
+        assert_eq!(cmdline.value("loglevel.net"), Some("trace"));
+        assert!(cmdline.contains("b"));
+        assert!(!cmdline.contains("loglevel"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let cmdline = CommandLine::new("n=42 bad=4x2");
+        assert_eq!(cmdline.parse::<u32>("n"), Some(Ok(42)));
+        assert_eq!(cmdline.parse::<u32>("bad"), Some(Err("4x2")));
+        assert_eq!(cmdline.parse::<u32>("missing"), None);
+    }
+
+    #[test]
+    fn test_flag() {
+        let cmdline = CommandLine::new("a b=on c=0 d=maybe");
+        assert_eq!(cmdline.flag("a"), Ok(true));
+        assert_eq!(cmdline.flag("b"), Ok(true));
+        assert_eq!(cmdline.flag("c"), Ok(false));
+        assert_eq!(cmdline.flag("d"), Err("maybe"));
+        assert_eq!(cmdline.flag("e"), Ok(false));
+    }
+}
@@ -0,0 +1,255 @@
+/// Reader for cpio archives in the "newc" format, as written by `cpio -H newc`.
+///
+/// Each entry is a 110-byte ASCII header, the NUL-terminated path name and the file data, with
+/// the name and the data padded to 4 bytes. The archive ends with an entry named `TRAILER!!!`.
+#[derive(Debug, Clone, Copy)]
+pub struct Archive<'a> {
+    data: &'a [u8],
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Error {
+    /// The archive ends in the middle of an entry, or has no trailer.
+    Truncated,
+    /// An entry does not start with the newc magic number.
+    BadMagic,
+    /// A header field is not a hexadecimal number.
+    BadHeader,
+    /// A path name is not NUL-terminated UTF-8.
+    BadName,
+}
+
+/// A file, directory or other inode in an archive.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Entry<'a> {
+    /// Path name as stored in the archive, usually without a leading `/`.
+    pub name: &'a str,
+    /// File type and permissions, as in `st_mode`.
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+const HEADER_LEN: usize = 110;
+const MAGIC: &[u8] = b"070701";
+// Same as MAGIC, but the header has a checksum of the data. We don't check it.
+const MAGIC_CRC: &[u8] = b"070702";
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+// Offsets of the header fields we use. Each field is 8 hexadecimal digits.
+const FIELD_MODE: usize = 14;
+const FIELD_FILESIZE: usize = 54;
+const FIELD_NAMESIZE: usize = 94;
+
+impl<'a> Archive<'a> {
+    pub const fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Returns the entries in the order they were archived. Iteration stops at the first error.
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            rest: self.data,
+            done: false,
+        }
+    }
+
+    /// Returns the entry at `path`. Leading `/` and `./` are ignored on both sides.
+    pub fn find(&self, path: &str) -> Result<Option<Entry<'a>>, Error> {
+        let path = normalize(path);
+        for entry in self.entries() {
+            let entry = entry?;
+            if normalize(entry.name) == path {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Entry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+}
+
+/// Iterator over the entries of an `Archive`.
+pub struct Entries<'a> {
+    rest: &'a [u8],
+    done: bool,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match parse_entry(self.rest) {
+            Ok((entry, _)) if entry.name == TRAILER => {
+                self.done = true;
+                None
+            }
+            Ok((entry, rest)) => {
+                self.rest = rest;
+                Some(Ok(entry))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Parses the entry at the start of `buf`, and returns it with the bytes after it.
+fn parse_entry(buf: &[u8]) -> Result<(Entry<'_>, &[u8]), Error> {
+    let header = buf.get(..HEADER_LEN).ok_or(Error::Truncated)?;
+    if &header[..MAGIC.len()] != MAGIC && &header[..MAGIC.len()] != MAGIC_CRC {
+        return Err(Error::BadMagic);
+    }
+    let mode = parse_field(header, FIELD_MODE)?;
+    let file_size = parse_field(header, FIELD_FILESIZE)? as usize;
+    let name_size = parse_field(header, FIELD_NAMESIZE)? as usize;
+
+    // The name size includes the terminating NUL.
+    let name = buf
+        .get(HEADER_LEN..HEADER_LEN + name_size)
+        .ok_or(Error::Truncated)?;
+    let name = match name.split_last() {
+        Some((0, name)) => core::str::from_utf8(name).map_err(|_| Error::BadName)?,
+        _ => return Err(Error::BadName),
+    };
+
+    let data_start = align4(HEADER_LEN + name_size);
+    let data = buf
+        .get(data_start..data_start + file_size)
+        .ok_or(Error::Truncated)?;
+    let next = align4(data_start + file_size).min(buf.len());
+    Ok((Entry { name, mode, data }, &buf[next..]))
+}
+
+fn parse_field(header: &[u8], offset: usize) -> Result<u32, Error> {
+    let field = core::str::from_utf8(&header[offset..offset + 8]).map_err(|_| Error::BadHeader)?;
+    u32::from_str_radix(field, 16).map_err(|_| Error::BadHeader)
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn normalize(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else {
+            return path;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::format;
+    use alloc::vec::Vec;
+
+    fn push_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let header = format!(
+            "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            0,
+            mode,
+            0,
+            0,
+            1,
+            0,
+            data.len(),
+            0,
+            0,
+            0,
+            0,
+            name.len() + 1,
+            0
+        );
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align4(archive.len()), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align4(archive.len()), 0);
+    }
+
+    fn sample() -> Vec<u8> {
+        let mut archive = Vec::new();
+        push_entry(&mut archive, ".", 0o040755, b"");
+        push_entry(&mut archive, "etc", 0o040755, b"");
+        push_entry(&mut archive, "etc/motd", 0o100644, b"hello\n");
+        push_entry(&mut archive, "init", 0o100755, b"\x7fELF");
+        push_entry(&mut archive, TRAILER, 0, b"");
+        archive
+    }
+
+    #[test]
+    fn test_entries() {
+        let archive = sample();
+        let archive = Archive::new(&archive);
+        let entries = archive.entries().collect::<Result<Vec<_>, _>>().unwrap();
+        let names = entries.iter().map(|e| e.name).collect::<Vec<_>>();
+        assert_eq!(names, [".", "etc", "etc/motd", "init"]);
+        assert!(entries[1].is_dir());
+        assert!(entries[2].is_file());
+        assert_eq!(entries[2].data, b"hello\n");
+        assert_eq!(entries[3].data, b"\x7fELF");
+    }
+
+    #[test]
+    fn test_find() {
+        let archive = sample();
+        let archive = Archive::new(&archive);
+        let motd = archive.find("/etc/motd").unwrap().unwrap();
+        assert_eq!(motd.data, b"hello\n");
+        assert_eq!(archive.find("./init").unwrap().unwrap().name, "init");
+        assert_eq!(archive.find("etc/passwd"), Ok(None));
+    }
+
+    #[test]
+    fn test_errors() {
+        let archive = sample();
+        // Cut off in the middle of an entry.
+        let truncated = Archive::new(&archive[..archive.len() / 2]);
+        assert_eq!(truncated.entries().last(), Some(Err(Error::Truncated)));
+
+        let mut bad_magic = archive.clone();
+        bad_magic[0] = b'1';
+        assert_eq!(
+            Archive::new(&bad_magic).entries().next(),
+            Some(Err(Error::BadMagic))
+        );
+
+        let mut bad_header = archive.clone();
+        bad_header[FIELD_MODE] = b'x';
+        assert_eq!(
+            Archive::new(&bad_header).entries().next(),
+            Some(Err(Error::BadHeader))
+        );
+
+        // An archive without a trailer is truncated.
+        assert_eq!(
+            Archive::new(&[]).entries().next(),
+            Some(Err(Error::Truncated))
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use core::fmt::{Display, Formatter, Write};
+
+/// Displays a symbol name demangled, if it is a Rust symbol in the legacy mangling scheme, such
+/// as `_ZN5aosir6kernel5shell3run17h0123456789abcdefE` for `aosir::kernel::shell::run`. Other
+/// names are displayed as is.
+pub struct Demangle<'a>(pub &'a str);
+
+// Escapes in path segments of legacy symbols.
+const ESCAPES: [(&str, &str); 14] = [
+    ("$SP$", "@"),
+    ("$BP$", "*"),
+    ("$RF$", "&"),
+    ("$LT$", "<"),
+    ("$GT$", ">"),
+    ("$LP$", "("),
+    ("$RP$", ")"),
+    ("$C$", ","),
+    ("$u20$", " "),
+    ("$u27$", "'"),
+    ("$u5b$", "["),
+    ("$u5d$", "]"),
+    ("$u7b$", "{"),
+    ("$u7d$", "}"),
+];
+
+impl Display for Demangle<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let Some(path) = legacy_path(self.0) else {
+            return f.write_str(self.0);
+        };
+        // Check the whole path before writing any of it.
+        if segments(path).any(|s| s.is_none()) {
+            return f.write_str(self.0);
+        }
+
+        let segments = segments(path).flatten();
+        let count = segments.clone().count();
+        for (i, segment) in segments.enumerate() {
+            // The last segment is a hash that tells apart different instances of the function.
+            if i == count - 1 && count > 1 && is_hash(segment) {
+                break;
+            }
+            if i > 0 {
+                f.write_str("::")?;
+            }
+            write_segment(f, segment)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the length-prefixed segments of a legacy symbol, without `_ZN` and `E`.
+fn legacy_path(name: &str) -> Option<&str> {
+    let name = name
+        .strip_prefix("__ZN")
+        .or_else(|| name.strip_prefix("_ZN"))?;
+    name.strip_suffix('E')
+}
+
+/// Returns the segments of `path`, or `None` for a segment that cannot be parsed.
+fn segments(path: &str) -> impl Iterator<Item = Option<&str>> + Clone {
+    let mut rest = path;
+    core::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let segment = rest[..digits]
+            .parse::<usize>()
+            .ok()
+            .and_then(|len| rest.get(digits..digits + len));
+        match segment {
+            Some(segment) => {
+                rest = &rest[digits + segment.len()..];
+                Some(Some(segment))
+            }
+            None => {
+                rest = "";
+                Some(None)
+            }
+        }
+    })
+}
+
+fn is_hash(segment: &str) -> bool {
+    segment.len() == 17
+        && segment.starts_with('h')
+        && segment[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn write_segment(f: &mut Formatter<'_>, segment: &str) -> core::fmt::Result {
+    // Segments that would start with `$` are prefixed with `_`.
+    let mut rest = match segment.strip_prefix("_$") {
+        Some(_) => &segment[1..],
+        None => segment,
+    };
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = r;
+            continue;
+        }
+        if let Some((escape, s)) = ESCAPES.iter().find(|(e, _)| rest.starts_with(e)) {
+            f.write_str(s)?;
+            rest = &rest[escape.len()..];
+            continue;
+        }
+        let c = rest.chars().next().unwrap();
+        f.write_char(c)?;
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::format;
+    use alloc::string::String;
+
+    /// Mangles `segments` in the legacy scheme, with a hash.
+    fn mangle(segments: &[&str]) -> String {
+        let mut name = String::from("_ZN");
+        for s in segments.iter().chain(&["h0123456789abcdef"]) {
+            name += &format!("{}{}", s.len(), s);
+        }
+        name + "E"
+    }
+
+    #[test]
+    fn test_path() {
+        let name = mangle(&["aosir", "kernel", "shell", "run"]);
+        assert_eq!(format!("{}", Demangle(&name)), "aosir::kernel::shell::run");
+    }
+
+    #[test]
+    fn test_escapes() {
+        let name = mangle(&[
+            "core",
+            "ptr",
+            "drop_in_place$LT$alloc..vec..Vec$LT$u8$GT$$GT$",
+        ]);
+        assert_eq!(
+            format!("{}", Demangle(&name)),
+            "core::ptr::drop_in_place<alloc::vec::Vec<u8>>"
+        );
+        let name = mangle(&["_$LT$aosir..Foo$u20$as$u20$core..fmt..Display$GT$", "fmt"]);
+        assert_eq!(
+            format!("{}", Demangle(&name)),
+            "<aosir::Foo as core::fmt::Display>::fmt"
+        );
+    }
+
+    #[test]
+    fn test_not_mangled() {
+        assert_eq!(format!("{}", Demangle("hpet_isr")), "hpet_isr");
+        // The length of the second segment runs past the end.
+        assert_eq!(
+            format!("{}", Demangle("_ZN5aosir9kernelE")),
+            "_ZN5aosir9kernelE"
+        );
+        assert_eq!(
+            format!("{}", Demangle("_RNvCs1234_5aosir5start")),
+            "_RNvCs1234_5aosir5start"
+        );
+    }
+}
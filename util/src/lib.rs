@@ -1,4 +1,13 @@
 #![no_std]
 #![feature(sync_unsafe_cell)]
 
+extern crate alloc;
+
+pub mod boot_config;
+pub mod cmdline;
+pub mod cpio;
+pub mod demangle;
+pub mod partition;
+pub mod ring_buffer;
+pub mod symbols;
 pub mod volatile;
@@ -0,0 +1,904 @@
+//! Parser for GPT and MBR partition tables.
+//!
+//! The table is read through a `SectorReader`, one sector at a time into a buffer from the
+//! caller, so that it works without an allocator and on small stacks. A disk with a protective
+//! MBR has a GPT, whose primary header and entries are checked against their CRC32 and replaced
+//! by the backup at the end of the disk if they don't match. Other disks with an MBR signature
+//! have an MBR, whose extended partition is followed through the chain of EBRs for the logical
+//! partitions.
+
+use core::fmt;
+use core::ops::Range;
+
+/// A disk, as far as the parser is concerned.
+pub trait SectorReader {
+    /// Returns the size of a sector in bytes, which is a power of two of at least 512.
+    fn sector_size(&self) -> usize;
+
+    /// Returns the number of sectors.
+    fn sectors(&self) -> u64;
+
+    /// Reads sector `sector` into `buf`, which is one sector long.
+    fn read_sector(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+}
+
+/// A disk image in memory.
+pub struct SliceReader<'a> {
+    data: &'a [u8],
+    sector_size: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    pub const fn new(data: &'a [u8], sector_size: usize) -> Self {
+        Self { data, sector_size }
+    }
+}
+
+impl SectorReader for SliceReader<'_> {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sectors(&self) -> u64 {
+        (self.data.len() / self.sector_size) as u64
+    }
+
+    fn read_sector(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let start = usize::try_from(sector)
+            .ok()
+            .and_then(|sector| sector.checked_mul(self.sector_size))
+            .ok_or("sector is past the end of the image")?;
+        let data = self
+            .data
+            .get(start..start + self.sector_size)
+            .ok_or("sector is past the end of the image")?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Error {
+    /// The reader failed.
+    Read(&'static str),
+    /// The sector size is not a power of two of at least 512, or the buffer is smaller.
+    BadSectorSize,
+    /// The disk has neither a GPT nor an MBR.
+    NoTable,
+    /// The disk has a protective MBR, but neither GPT header is valid with valid entries.
+    BadGpt,
+}
+
+/// A GUID, in the byte order of the disk.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Guid = Guid([0; 16]);
+
+    /// EFI system partition, C12A7328-F81F-11D2-BA4B-00A0C93EC93B.
+    pub const EFI_SYSTEM: Guid = Guid([
+        0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+        0x3b,
+    ]);
+
+    /// Linux filesystem data, 0FC63DAF-8483-4772-8E79-3D69D8477DE4.
+    pub const LINUX_FILESYSTEM: Guid = Guid([
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+        0xe4,
+    ]);
+}
+
+/// Formats the GUID as usual, with the first three fields stored little-endian.
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        b[10..].iter().try_for_each(|b| write!(f, "{:02X}", b))
+    }
+}
+
+/// The kind of partition table of a disk.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Table {
+    /// `backup` is set if the primary header or entries were invalid, and the backup was used.
+    Gpt {
+        disk_guid: Guid,
+        backup: bool,
+    },
+    Mbr {
+        disk_signature: u32,
+    },
+}
+
+/// What the partition table tells about the contents of a partition.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Kind {
+    Gpt {
+        type_guid: Guid,
+        guid: Guid,
+        attributes: u64,
+        /// UTF-16 name, padded with NULs.
+        name: [u16; 36],
+    },
+    Mbr {
+        system_id: u8,
+        bootable: bool,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Partition {
+    /// Number of the partition from 1: its index in the GPT entries, or its MBR entry, with the
+    /// logical partitions numbered from 5.
+    pub number: u32,
+    pub first_sector: u64,
+    pub sectors: u64,
+    pub kind: Kind,
+}
+
+impl Partition {
+    /// Returns the bytes of the disk that the partition covers.
+    pub fn byte_range(&self, sector_size: usize) -> Range<u64> {
+        let start = self.first_sector * sector_size as u64;
+        start..start + self.sectors * sector_size as u64
+    }
+
+    /// Returns the name of a GPT partition, which is empty for MBR partitions.
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        let name: &[u16] = match &self.kind {
+            Kind::Gpt { name, .. } => name,
+            Kind::Mbr { .. } => &[],
+        };
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        char::decode_utf16(name[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_DISK_SIGNATURE_OFFSET: usize = 440;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_LEN: usize = 16;
+const MBR_BOOTABLE: u8 = 0x80;
+
+// System IDs of the protective MBR partition, and of extended partitions.
+const SYSTEM_ID_GPT: u8 = 0xee;
+const SYSTEM_IDS_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+// Logical partitions followed at most, in case the EBRs form a loop.
+const MAX_LOGICAL_PARTITIONS: u32 = 128;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HEADER_MIN_LEN: usize = 92;
+const GPT_ENTRY_MIN_LEN: usize = 128;
+// Entries read at most, which is 32 times the usual 128.
+const GPT_MAX_ENTRIES: u32 = 4096;
+
+/// Reads the partition table of the disk behind `reader` through `buf`, which must hold a
+/// sector, and calls `found` with each partition, in the order of the table. Partitions that
+/// don't fit on the disk are skipped.
+pub fn parse<R: SectorReader>(
+    reader: &mut R,
+    buf: &mut [u8],
+    mut found: impl FnMut(Partition),
+) -> Result<Table, Error> {
+    let sector_size = reader.sector_size();
+    if !sector_size.is_power_of_two() || sector_size < 512 || sector_size > buf.len() {
+        return Err(Error::BadSectorSize);
+    }
+    let buf = &mut buf[..sector_size];
+    read(reader, 0, buf)?;
+
+    let has_mbr = buf[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] == MBR_SIGNATURE;
+    let protective = has_mbr && mbr_entries(buf).any(|e| e.system_id == SYSTEM_ID_GPT);
+    if protective {
+        return parse_gpt(reader, buf, &mut found);
+    }
+    if has_mbr {
+        return parse_mbr(reader, buf, &mut found);
+    }
+    Err(Error::NoTable)
+}
+
+fn read<R: SectorReader>(reader: &mut R, sector: u64, buf: &mut [u8]) -> Result<(), Error> {
+    reader.read_sector(sector, buf).map_err(Error::Read)
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn guid_at(buf: &[u8], offset: usize) -> Guid {
+    Guid(buf[offset..offset + 16].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MbrEntry {
+    bootable: bool,
+    system_id: u8,
+    first_sector: u64,
+    sectors: u64,
+}
+
+/// Returns the four entries of the MBR or EBR in `buf`.
+fn mbr_entries(buf: &[u8]) -> impl Iterator<Item = MbrEntry> + '_ {
+    (0..4).map(move |i| {
+        let entry = &buf[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_LEN..][..MBR_ENTRY_LEN];
+        MbrEntry {
+            bootable: entry[0] & MBR_BOOTABLE != 0,
+            system_id: entry[4],
+            first_sector: u32_at(entry, 8) as u64,
+            sectors: u32_at(entry, 12) as u64,
+        }
+    })
+}
+
+fn parse_mbr<R: SectorReader>(
+    reader: &mut R,
+    mbr: &mut [u8],
+    found: &mut impl FnMut(Partition),
+) -> Result<Table, Error> {
+    let disk_sectors = reader.sectors();
+    let report = |found: &mut dyn FnMut(Partition), number, entry: MbrEntry| {
+        let fits = entry
+            .first_sector
+            .checked_add(entry.sectors)
+            .is_some_and(|end| end <= disk_sectors);
+        if entry.sectors != 0 && fits {
+            found(Partition {
+                number,
+                first_sector: entry.first_sector,
+                sectors: entry.sectors,
+                kind: Kind::Mbr {
+                    system_id: entry.system_id,
+                    bootable: entry.bootable,
+                },
+            });
+        }
+    };
+
+    let disk_signature = u32_at(mbr, MBR_DISK_SIGNATURE_OFFSET);
+    let mut extended = None;
+    for (i, entry) in mbr_entries(mbr).enumerate() {
+        if entry.system_id == 0 {
+            continue;
+        }
+        if SYSTEM_IDS_EXTENDED.contains(&entry.system_id) {
+            extended.get_or_insert(entry.first_sector);
+            continue;
+        }
+        report(found, i as u32 + 1, entry);
+    }
+
+    // Each EBR has the logical partition, relative to the EBR, and the next EBR, relative to the
+    // extended partition.
+    if let Some(extended) = extended {
+        let mut ebr = extended;
+        for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+            read(reader, ebr, mbr)?;
+            if mbr[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] != MBR_SIGNATURE {
+                break;
+            }
+            let mut entries = mbr_entries(mbr);
+            let (logical, next) = (entries.next().unwrap(), entries.next().unwrap());
+            if logical.system_id != 0 {
+                let logical = MbrEntry {
+                    first_sector: ebr + logical.first_sector,
+                    ..logical
+                };
+                report(found, number, logical);
+            }
+            if !SYSTEM_IDS_EXTENDED.contains(&next.system_id) || next.first_sector == 0 {
+                break;
+            }
+            ebr = extended + next.first_sector;
+        }
+    }
+    Ok(Table::Mbr { disk_signature })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GptHeader {
+    alternate_lba: u64,
+    first_usable: u64,
+    last_usable: u64,
+    disk_guid: Guid,
+    entries_lba: u64,
+    entries: u32,
+    entry_len: usize,
+    entries_crc: u32,
+}
+
+fn parse_gpt<R: SectorReader>(
+    reader: &mut R,
+    buf: &mut [u8],
+    found: &mut impl FnMut(Partition),
+) -> Result<Table, Error> {
+    let last_sector = reader.sectors().saturating_sub(1);
+    let primary = read_gpt_header(reader, buf, 1)?;
+    if let Some(header) = primary {
+        if check_gpt_entries(reader, buf, &header)? {
+            return report_gpt(reader, buf, &header, false, found);
+        }
+    }
+    // The primary header tells where the backup is, but it may be wrong if the header is.
+    let backup_lba = match primary {
+        Some(header) if header.alternate_lba <= last_sector => header.alternate_lba,
+        _ => last_sector,
+    };
+    if let Some(header) = read_gpt_header(reader, buf, backup_lba)? {
+        if check_gpt_entries(reader, buf, &header)? {
+            return report_gpt(reader, buf, &header, true, found);
+        }
+    }
+    Err(Error::BadGpt)
+}
+
+/// Reads the GPT header at `lba`, and returns it if it is valid.
+fn read_gpt_header<R: SectorReader>(
+    reader: &mut R,
+    buf: &mut [u8],
+    lba: u64,
+) -> Result<Option<GptHeader>, Error> {
+    let sector_size = reader.sector_size();
+    read(reader, lba, buf)?;
+
+    if &buf[..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let header_len = u32_at(buf, 12) as usize;
+    if !(GPT_HEADER_MIN_LEN..=sector_size).contains(&header_len) {
+        return Ok(None);
+    }
+    // The CRC is computed with its own field zeroed.
+    let crc = u32_at(buf, 16);
+    buf[16..20].fill(0);
+    if crc32(&buf[..header_len]) != crc || u64_at(buf, 24) != lba {
+        return Ok(None);
+    }
+
+    let header = GptHeader {
+        alternate_lba: u64_at(buf, 32),
+        first_usable: u64_at(buf, 40),
+        last_usable: u64_at(buf, 48),
+        disk_guid: guid_at(buf, 56),
+        entries_lba: u64_at(buf, 72),
+        entries: u32_at(buf, 80),
+        entry_len: u32_at(buf, 84) as usize,
+        entries_crc: u32_at(buf, 88),
+    };
+    // Entries are 128 bytes times a power of two, so they don't cross sectors.
+    let entry_len_valid = header.entry_len >= GPT_ENTRY_MIN_LEN
+        && header.entry_len.is_power_of_two()
+        && header.entry_len <= sector_size;
+    let entries_fit = header.entries <= GPT_MAX_ENTRIES
+        && header
+            .entries_lba
+            .checked_add(entry_sectors(&header, sector_size))
+            .is_some_and(|end| end <= reader.sectors());
+    let usable_valid = header
+        .last_usable
+        .checked_add(1)
+        .is_some_and(|end| header.first_usable <= end);
+    if !entry_len_valid || !entries_fit || !usable_valid {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+/// Number of sectors that the entries of `header` take.
+fn entry_sectors(header: &GptHeader, sector_size: usize) -> u64 {
+    (header.entries as usize * header.entry_len).div_ceil(sector_size) as u64
+}
+
+/// Calls `f` with the index and bytes of each entry of `header`.
+fn for_each_gpt_entry<R: SectorReader>(
+    reader: &mut R,
+    buf: &mut [u8],
+    header: &GptHeader,
+    mut f: impl FnMut(u32, &[u8]),
+) -> Result<(), Error> {
+    let sector_size = reader.sector_size();
+    let per_sector = sector_size / header.entry_len;
+    let mut index = 0;
+    for sector in 0..entry_sectors(header, sector_size) {
+        read(reader, header.entries_lba + sector, buf)?;
+        for entry in buf.chunks(header.entry_len).take(per_sector) {
+            if index == header.entries {
+                return Ok(());
+            }
+            f(index, entry);
+            index += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether the entries of `header` match their CRC.
+fn check_gpt_entries<R: SectorReader>(
+    reader: &mut R,
+    buf: &mut [u8],
+    header: &GptHeader,
+) -> Result<bool, Error> {
+    let mut crc = Crc32::new();
+    for_each_gpt_entry(reader, buf, header, |_, entry| crc.update(entry))?;
+    Ok(crc.finish() == header.entries_crc)
+}
+
+fn report_gpt<R: SectorReader>(
+    reader: &mut R,
+    buf: &mut [u8],
+    header: &GptHeader,
+    backup: bool,
+    found: &mut impl FnMut(Partition),
+) -> Result<Table, Error> {
+    // The usable range of a header that was copied from a larger disk goes past the end.
+    let sectors = reader.sectors();
+    for_each_gpt_entry(reader, buf, header, |index, entry| {
+        let type_guid = guid_at(entry, 0);
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        let usable = header.first_usable..=header.last_usable;
+        if type_guid == Guid::UNUSED
+            || first > last
+            || !usable.contains(&first)
+            || !usable.contains(&last)
+            || last >= sectors
+        {
+            return;
+        }
+        let mut name = [0; 36];
+        for (c, bytes) in name.iter_mut().zip(entry[56..128].chunks(2)) {
+            *c = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        found(Partition {
+            number: index + 1,
+            first_sector: first,
+            sectors: last - first + 1,
+            kind: Kind::Gpt {
+                type_guid,
+                guid: guid_at(entry, 16),
+                attributes: u64_at(entry, 48),
+                name,
+            },
+        });
+    })?;
+    Ok(Table::Gpt {
+        disk_guid: header.disk_guid,
+        backup,
+    })
+}
+
+/// CRC-32 as used by GPT, zlib and Ethernet: reflected, polynomial 0x04C11DB7.
+struct Crc32(u32);
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ 0xedb8_8320,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Crc32 {
+    fn new() -> Self {
+        Crc32(0xffff_ffff)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = CRC32_TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// Returns the CRC-32 of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    fn collect(image: &[u8], sector_size: usize) -> (Result<Table, Error>, Vec<Partition>) {
+        let mut partitions = Vec::new();
+        let mut reader = SliceReader::new(image, sector_size);
+        let mut buf = vec![0; 4096];
+        let table = parse(&mut reader, &mut buf, |p| partitions.push(p));
+        (table, partitions)
+    }
+
+    fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u64(buf: &mut [u8], offset: usize, value: u64) {
+        buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes an MBR or EBR entry to the sector at `sector`.
+    fn put_mbr_entry(
+        image: &mut [u8],
+        sector: usize,
+        index: usize,
+        system_id: u8,
+        first: u32,
+        len: u32,
+    ) {
+        let mbr = &mut image[sector * 512..][..512];
+        let entry = &mut mbr[MBR_ENTRIES_OFFSET + index * MBR_ENTRY_LEN..][..MBR_ENTRY_LEN];
+        entry[4] = system_id;
+        put_u32(entry, 8, first);
+        put_u32(entry, 12, len);
+        mbr[MBR_SIGNATURE_OFFSET..].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    struct GptPartition {
+        type_guid: Guid,
+        first: u64,
+        last: u64,
+        name: &'static str,
+    }
+
+    /// Returns a disk of `sectors` with a protective MBR, and primary and backup GPTs of 128
+    /// entries with `partitions`.
+    fn gpt_image(sectors: usize, sector_size: usize, partitions: &[GptPartition]) -> Vec<u8> {
+        let mut image = vec![0; sectors * sector_size];
+        put_mbr_entry(&mut image, 0, 0, SYSTEM_ID_GPT, 1, (sectors - 1) as u32);
+
+        let mut entries = vec![0; 128 * 128];
+        for (i, p) in partitions.iter().enumerate() {
+            let entry = &mut entries[i * 128..][..128];
+            entry[..16].copy_from_slice(&p.type_guid.0);
+            entry[16..32].fill(i as u8 + 1);
+            put_u64(entry, 32, p.first);
+            put_u64(entry, 40, p.last);
+            for (c, unit) in p.name.encode_utf16().enumerate() {
+                entry[56 + c * 2..][..2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+        let entry_sectors = entries.len() / sector_size;
+        let last = sectors - 1;
+        let backup_entries = last - entry_sectors;
+
+        for (lba, alternate, entries_lba) in [(1, last, 2), (last, 1, backup_entries)] {
+            let start = entries_lba * sector_size;
+            image[start..start + entries.len()].copy_from_slice(&entries);
+
+            let header = &mut image[lba * sector_size..][..sector_size];
+            header[..8].copy_from_slice(GPT_SIGNATURE);
+            put_u32(header, 8, 0x0001_0000);
+            put_u32(header, 12, 92);
+            put_u64(header, 24, lba as u64);
+            put_u64(header, 32, alternate as u64);
+            put_u64(header, 40, (2 + entry_sectors) as u64);
+            put_u64(header, 48, (backup_entries - 1) as u64);
+            header[56..72].fill(0xd1);
+            put_u64(header, 72, entries_lba as u64);
+            put_u32(header, 80, 128);
+            put_u32(header, 84, 128);
+            put_u32(header, 88, crc32(&entries));
+            let crc = crc32(&header[..92]);
+            put_u32(header, 16, crc);
+        }
+        image
+    }
+
+    /// Changes the 512-byte sector GPT header at `lba` with `f`, and updates its CRC.
+    fn patch_gpt_header(image: &mut [u8], lba: usize, f: impl FnOnce(&mut [u8])) {
+        let header = &mut image[lba * 512..][..512];
+        f(header);
+        put_u32(header, 16, 0);
+        let crc = crc32(&header[..92]);
+        put_u32(header, 16, crc);
+    }
+
+    fn sample_gpt(sector_size: usize) -> Vec<u8> {
+        gpt_image(
+            256,
+            sector_size,
+            &[
+                GptPartition {
+                    type_guid: Guid::EFI_SYSTEM,
+                    first: 40,
+                    last: 99,
+                    name: "EFI system",
+                },
+                GptPartition {
+                    type_guid: Guid::LINUX_FILESYSTEM,
+                    first: 100,
+                    last: 199,
+                    name: "root",
+                },
+            ],
+        )
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_guid_display() {
+        assert_eq!(
+            alloc::format!("{}", Guid::EFI_SYSTEM),
+            "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        );
+        assert_eq!(
+            alloc::format!("{}", Guid::LINUX_FILESYSTEM),
+            "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+        );
+    }
+
+    #[test]
+    fn test_gpt() {
+        let image = sample_gpt(512);
+        let (table, partitions) = collect(&image, 512);
+        assert_eq!(
+            table,
+            Ok(Table::Gpt {
+                disk_guid: Guid([0xd1; 16]),
+                backup: false
+            })
+        );
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].number, 1);
+        assert_eq!(partitions[0].byte_range(512), 40 * 512..100 * 512);
+        assert_eq!(
+            partitions[0].name().collect::<alloc::string::String>(),
+            "EFI system"
+        );
+        assert_eq!(partitions[1].number, 2);
+        assert_eq!(partitions[1].first_sector, 100);
+        assert_eq!(partitions[1].sectors, 100);
+        let Kind::Gpt {
+            type_guid, guid, ..
+        } = partitions[1].kind
+        else {
+            panic!("not a GPT partition");
+        };
+        assert_eq!(type_guid, Guid::LINUX_FILESYSTEM);
+        assert_eq!(guid, Guid([2; 16]));
+    }
+
+    #[test]
+    fn test_gpt_4k_sectors() {
+        let image = sample_gpt(4096);
+        let (table, partitions) = collect(&image, 4096);
+        assert!(matches!(table, Ok(Table::Gpt { backup: false, .. })));
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[1].byte_range(4096), 100 * 4096..200 * 4096);
+    }
+
+    #[test]
+    fn test_gpt_backup() {
+        let good = sample_gpt(512);
+        let (_, expected) = collect(&good, 512);
+
+        // A corrupt primary header.
+        let mut image = good.clone();
+        image[512 + 40] ^= 1;
+        let (table, partitions) = collect(&image, 512);
+        assert!(matches!(table, Ok(Table::Gpt { backup: true, .. })));
+        assert_eq!(partitions, expected);
+
+        // A valid primary header with corrupt entries.
+        let mut image = good.clone();
+        image[2 * 512 + 32] ^= 1;
+        let (table, partitions) = collect(&image, 512);
+        assert!(matches!(table, Ok(Table::Gpt { backup: true, .. })));
+        assert_eq!(partitions, expected);
+
+        // Both copies are corrupt.
+        let last = image.len() - 512;
+        image[last + 40] ^= 1;
+        assert_eq!(collect(&image, 512), (Err(Error::BadGpt), Vec::new()));
+    }
+
+    #[test]
+    fn test_gpt_skips_unusable_entries() {
+        let image = gpt_image(
+            256,
+            512,
+            &[
+                GptPartition {
+                    type_guid: Guid::LINUX_FILESYSTEM,
+                    first: 1,
+                    last: 50,
+                    name: "overlaps the header",
+                },
+                GptPartition {
+                    type_guid: Guid::LINUX_FILESYSTEM,
+                    first: 60,
+                    last: 59,
+                    name: "ends before it starts",
+                },
+                GptPartition {
+                    type_guid: Guid::LINUX_FILESYSTEM,
+                    first: 60,
+                    last: 70,
+                    name: "fine",
+                },
+            ],
+        );
+        let (_, partitions) = collect(&image, 512);
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].number, 3);
+    }
+
+    #[test]
+    fn test_gpt_rejects_out_of_range_headers() {
+        let good = sample_gpt(512);
+        let last = good.len() / 512 - 1;
+
+        // Entries that wrap around the end of the address space, in a header with a valid CRC.
+        let mut image = good.clone();
+        patch_gpt_header(&mut image, 1, |h| put_u64(h, 72, u64::MAX - 1));
+        let (table, _) = collect(&image, 512);
+        assert!(matches!(table, Ok(Table::Gpt { backup: true, .. })));
+        patch_gpt_header(&mut image, last, |h| put_u64(h, 72, u64::MAX - 1));
+        assert_eq!(collect(&image, 512), (Err(Error::BadGpt), Vec::new()));
+
+        // A usable range that ends at the last LBA there can be.
+        let mut image = good;
+        for lba in [1, last] {
+            patch_gpt_header(&mut image, lba, |h| put_u64(h, 48, u64::MAX));
+        }
+        assert_eq!(collect(&image, 512), (Err(Error::BadGpt), Vec::new()));
+    }
+
+    #[test]
+    fn test_gpt_skips_entries_past_the_end() {
+        let mut image = gpt_image(
+            256,
+            512,
+            &[
+                GptPartition {
+                    type_guid: Guid::LINUX_FILESYSTEM,
+                    first: 60,
+                    last: 70,
+                    name: "fine",
+                },
+                GptPartition {
+                    type_guid: Guid::LINUX_FILESYSTEM,
+                    first: 200,
+                    last: 999,
+                    name: "past the end",
+                },
+            ],
+        );
+        // Headers copied from a disk of 1024 sectors.
+        for lba in [1, 255] {
+            patch_gpt_header(&mut image, lba, |h| put_u64(h, 48, 1000));
+        }
+        let (table, partitions) = collect(&image, 512);
+        assert!(matches!(table, Ok(Table::Gpt { backup: false, .. })));
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].number, 1);
+    }
+
+    #[test]
+    fn test_mbr() {
+        let mut image = vec![0; 512 * 512];
+        put_u32(&mut image, MBR_DISK_SIGNATURE_OFFSET, 0x1234_5678);
+        put_mbr_entry(&mut image, 0, 0, 0x83, 2048 / 8, 64);
+        image[MBR_ENTRIES_OFFSET] = MBR_BOOTABLE;
+        // An extended partition at 320 with two logical partitions.
+        put_mbr_entry(&mut image, 0, 1, 0x05, 320, 192);
+        put_mbr_entry(&mut image, 320, 0, 0x83, 1, 31);
+        put_mbr_entry(&mut image, 320, 1, 0x05, 64, 64);
+        put_mbr_entry(&mut image, 384, 0, 0x82, 1, 63);
+        // A partition past the end of the disk.
+        put_mbr_entry(&mut image, 0, 3, 0x83, 500, 100);
+
+        let (table, partitions) = collect(&image, 512);
+        assert_eq!(
+            table,
+            Ok(Table::Mbr {
+                disk_signature: 0x1234_5678
+            })
+        );
+        let summary: Vec<(u32, u64, u64)> = partitions
+            .iter()
+            .map(|p| (p.number, p.first_sector, p.sectors))
+            .collect();
+        assert_eq!(summary, [(1, 256, 64), (5, 321, 31), (6, 385, 63)]);
+        assert_eq!(
+            partitions[0].kind,
+            Kind::Mbr {
+                system_id: 0x83,
+                bootable: true
+            }
+        );
+        assert_eq!(
+            partitions[2].kind,
+            Kind::Mbr {
+                system_id: 0x82,
+                bootable: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_mbr_ebr_loop() {
+        let mut image = vec![0; 512 * 512];
+        put_mbr_entry(&mut image, 0, 0, 0x0f, 100, 100);
+        put_mbr_entry(&mut image, 100, 0, 0x83, 1, 9);
+        // The next EBR is the first one again.
+        put_mbr_entry(&mut image, 100, 1, 0x05, 0, 100);
+        let (_, partitions) = collect(&image, 512);
+        assert_eq!(partitions.len(), 1);
+
+        // An EBR pointing to itself through a non-zero offset stops after the limit.
+        put_mbr_entry(&mut image, 100, 1, 0x05, 50, 50);
+        put_mbr_entry(&mut image, 150, 0, 0x83, 1, 9);
+        put_mbr_entry(&mut image, 150, 1, 0x05, 50, 50);
+        let (table, partitions) = collect(&image, 512);
+        assert!(table.is_ok());
+        assert_eq!(partitions.len(), MAX_LOGICAL_PARTITIONS as usize);
+    }
+
+    #[test]
+    fn test_errors() {
+        let image = vec![0; 64 * 512];
+        assert_eq!(collect(&image, 512), (Err(Error::NoTable), Vec::new()));
+        assert_eq!(
+            collect(&image, 1000),
+            (Err(Error::BadSectorSize), Vec::new())
+        );
+        let mut small = [0; 256];
+        assert_eq!(
+            parse(&mut SliceReader::new(&image, 512), &mut small, |_| {}),
+            Err(Error::BadSectorSize)
+        );
+        assert_eq!(
+            collect(&image[..100], 512),
+            (
+                Err(Error::Read("sector is past the end of the image")),
+                Vec::new()
+            )
+        );
+
+        // A protective MBR without a GPT.
+        let mut image = image;
+        put_mbr_entry(&mut image, 0, 0, SYSTEM_ID_GPT, 1, 63);
+        assert_eq!(collect(&image, 512), (Err(Error::BadGpt), Vec::new()));
+    }
+}
@@ -0,0 +1,163 @@
+/// Fixed-size FIFO of bytes.
+///
+/// The buffer does not lock by itself. Buffers shared with interrupt handlers should be kept
+/// behind a lock that disables interrupts.
+pub struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+
+    // Index of the oldest byte.
+    head: usize,
+
+    // Number of bytes in the buffer.
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Appends `byte` to the buffer. Returns `false` without appending if the buffer is full.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    /// Appends `byte` to the buffer, dropping the oldest byte if the buffer is full.
+    pub fn push_overwrite(&mut self, byte: u8) {
+        if self.is_full() {
+            self.pop();
+        }
+        self.push(byte);
+    }
+
+    /// Removes and returns the oldest byte in the buffer.
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    /// Moves bytes from the buffer into `buf`, oldest first, and returns the number of bytes moved.
+    pub fn pop_into(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for b in buf.iter_mut() {
+            match self.pop() {
+                Some(byte) => *b = byte,
+                None => break,
+            }
+            len += 1;
+        }
+        len
+    }
+
+    /// Returns the contents of the buffer, oldest first, as two slices. The second slice is
+    /// non-empty when the contents wrap around the end of the underlying array.
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        let end = self.head + self.len;
+        if end <= N {
+            (&self.buf[self.head..end], &[])
+        } else {
+            (&self.buf[self.head..], &self.buf[..end - N])
+        }
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_push_pop() {
+        let mut ring = RingBuffer::<4>::new();
+        assert!(ring.is_empty());
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert_eq!(ring.len(), 2);
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn test_push_full() {
+        let mut ring = RingBuffer::<2>::new();
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert!(ring.is_full());
+        assert!(!ring.push(3));
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+    }
+
+    #[test]
+    fn test_push_overwrite() {
+        let mut ring = RingBuffer::<3>::new();
+        for byte in 1..=5 {
+            ring.push_overwrite(byte);
+        }
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.as_slices(), (&[3][..], &[4, 5][..]));
+    }
+
+    #[test]
+    fn test_pop_into_wrapped() {
+        let mut ring = RingBuffer::<4>::new();
+        for byte in 1..=4 {
+            ring.push(byte);
+        }
+        ring.pop();
+        ring.pop();
+        ring.push(5);
+        ring.push(6);
+
+        let mut buf = [0u8; 8];
+        assert_eq!(ring.pop_into(&mut buf), 4);
+        assert_eq!(&buf[..4], &[3, 4, 5, 6]);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut ring = RingBuffer::<4>::new();
+        ring.push(1);
+        ring.clear();
+        assert!(ring.is_empty());
+        assert_eq!(ring.as_slices(), (&[][..], &[][..]));
+    }
+}
@@ -0,0 +1,133 @@
+/// Function symbols of an ELF64 executable, from its `.symtab` and `.strtab` sections.
+///
+/// Lookups scan the table without allocating, so they can be done from a panic handler.
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable<'a> {
+    symtab: &'a [u8],
+    strtab: &'a [u8],
+}
+
+/// A function symbol, or a label in assembly code.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Symbol<'a> {
+    /// Symbol name as in the string table, which is mangled for Rust functions.
+    pub name: &'a str,
+    pub addr: usize,
+    /// Size of the function in bytes. It is 0 for symbols without a size, such as labels.
+    pub size: usize,
+}
+
+// Size of an Elf64_Sym.
+const SYM_SIZE: usize = 24;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+
+impl<'a> SymbolTable<'a> {
+    pub const fn new(symtab: &'a [u8], strtab: &'a [u8]) -> Self {
+        Self { symtab, strtab }
+    }
+
+    /// Returns the function symbols in the order of the table. Labels without a type, such as the
+    /// ones in assembly code, are included unless they are absolute, like those defined in the
+    /// linker script.
+    pub fn functions(&self) -> impl Iterator<Item = Symbol<'a>> + 'a {
+        let strtab = self.strtab;
+        self.symtab
+            .chunks_exact(SYM_SIZE)
+            .filter(|sym| {
+                let shndx = u16::from_le_bytes([sym[6], sym[7]]);
+                matches!(sym[4] & 0xf, STT_NOTYPE | STT_FUNC)
+                    && shndx != SHN_UNDEF
+                    && shndx != SHN_ABS
+            })
+            .filter_map(move |sym| {
+                let name = u32::from_le_bytes(sym[0..4].try_into().unwrap()) as usize;
+                let addr = u64::from_le_bytes(sym[8..16].try_into().unwrap()) as usize;
+                let size = u64::from_le_bytes(sym[16..24].try_into().unwrap()) as usize;
+                Some(Symbol {
+                    name: c_str(strtab.get(name..)?)?,
+                    addr,
+                    size,
+                })
+            })
+    }
+
+    /// Returns the function that contains `addr`. For functions without a size, the closest one
+    /// at or below `addr` is taken, as long as no sized function contains it.
+    pub fn lookup(&self, addr: usize) -> Option<Symbol<'a>> {
+        let mut best: Option<Symbol<'a>> = None;
+        for sym in self.functions().filter(|s| s.addr <= addr) {
+            if sym.size != 0 {
+                if addr < sym.addr + sym.size {
+                    return Some(sym);
+                }
+                continue;
+            }
+            if best.is_none_or(|b| sym.addr > b.addr) {
+                best = Some(sym);
+            }
+        }
+        best
+    }
+}
+
+/// Returns the NUL-terminated UTF-8 string at the start of `buf`.
+fn c_str(buf: &[u8]) -> Option<&str> {
+    let len = buf.iter().position(|b| *b == 0)?;
+    core::str::from_utf8(&buf[..len]).ok()
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::vec::Vec;
+
+    const STT_OBJECT: u8 = 1;
+
+    fn push_symbol(symtab: &mut Vec<u8>, name: u32, ty: u8, shndx: u16, addr: u64, size: u64) {
+        symtab.extend_from_slice(&name.to_le_bytes());
+        symtab.push(ty);
+        symtab.push(0);
+        symtab.extend_from_slice(&shndx.to_le_bytes());
+        symtab.extend_from_slice(&addr.to_le_bytes());
+        symtab.extend_from_slice(&size.to_le_bytes());
+    }
+
+    fn sample() -> (Vec<u8>, &'static [u8]) {
+        let strtab = b"\0foo\0bar\0isr\0data\0end\0";
+        let mut symtab = Vec::new();
+        push_symbol(&mut symtab, 0, STT_NOTYPE, SHN_UNDEF, 0, 0);
+        push_symbol(&mut symtab, 1, STT_FUNC, 1, 0x1000, 0x100);
+        push_symbol(&mut symtab, 5, STT_FUNC, 1, 0x1100, 0x80);
+        push_symbol(&mut symtab, 9, STT_NOTYPE, 1, 0x2000, 0);
+        push_symbol(&mut symtab, 13, STT_OBJECT, 2, 0x3000, 0x10);
+        push_symbol(&mut symtab, 18, STT_NOTYPE, SHN_ABS, 0x3000, 0);
+        (symtab, strtab)
+    }
+
+    #[test]
+    fn test_functions() {
+        let (symtab, strtab) = sample();
+        let table = SymbolTable::new(&symtab, strtab);
+        let names = table.functions().map(|s| s.name).collect::<Vec<_>>();
+        assert_eq!(names, ["foo", "bar", "isr"]);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let (symtab, strtab) = sample();
+        let table = SymbolTable::new(&symtab, strtab);
+        assert_eq!(table.lookup(0x1000).map(|s| s.name), Some("foo"));
+        assert_eq!(table.lookup(0x10ff).map(|s| s.name), Some("foo"));
+        assert_eq!(table.lookup(0x1100).map(|s| s.name), Some("bar"));
+        // A symbol without a size covers every address above it that no sized symbol covers.
+        assert_eq!(table.lookup(0x2010).map(|s| s.name), Some("isr"));
+        assert_eq!(table.lookup(0x3004).map(|s| s.name), Some("isr"));
+        assert_eq!(table.lookup(0x1180), None);
+        assert_eq!(table.lookup(0x800), None);
+    }
+}